@@ -0,0 +1,28 @@
+//! Serves the queries in charts.yml with generated data, point the sources to
+//! the printed URL to try the charts without a Prometheus server.
+use circular_buffer_metrics::mock_prometheus::{MockPrometheus, MockResponse, MockSeries};
+use env_logger::Env;
+use std::thread;
+use std::time::Duration;
+
+fn main() {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+    let mock = MockPrometheus::start().expect("Unable to start the mock server");
+    for (idx, minutes) in [1u64, 5, 15].iter().enumerate() {
+        let period = (minutes * 60) as f64;
+        mock.set_response(
+            &format!("node_load{}", minutes),
+            MockResponse::Series(vec![MockSeries::new(&[], move |epoch| {
+                1. + idx as f64 + (epoch as f64 * std::f64::consts::PI * 2. / period).sin()
+            })]),
+        );
+    }
+    mock.set_response(
+        "up",
+        MockResponse::Series(vec![MockSeries::new(&[("job", "node_exporter")], |_| 1.)]),
+    );
+    println!("Mock Prometheus listening on {}", mock.base_url());
+    loop {
+        thread::sleep(Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,254 @@
+//! Parses the colors of the series and decorations, and picks well separated
+//! colors for the series that do not set one.
+//! A color is written as `0xRRGGBB`, `#RRGGBB`, `#RGB` or by name, i.e.
+//! `orange`. The `0xRRGGBBAA` and `#RRGGBBAA` forms also set the alpha.
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// `DEFAULT_SERIES_COLOR` is drawn for the series without color
+pub const DEFAULT_SERIES_COLOR: Rgb = Rgb::new(0x00, 0xff, 0x00);
+
+/// `DEFAULT_DECORATION_COLOR` is drawn for the decorations without color
+pub const DEFAULT_DECORATION_COLOR: Rgb = Rgb::new(0xff, 0x00, 0x00);
+
+/// The hue rotation between palette colors, the golden angle keeps the
+/// consecutive colors far apart
+const PALETTE_HUE_STEP: f64 = 137.508;
+
+/// The named colors, from the CSS basic and extended keywords
+const NAMED_COLORS: &[(&str, Rgb)] = &[
+    ("black", Rgb::new(0x00, 0x00, 0x00)),
+    ("silver", Rgb::new(0xc0, 0xc0, 0xc0)),
+    ("gray", Rgb::new(0x80, 0x80, 0x80)),
+    ("grey", Rgb::new(0x80, 0x80, 0x80)),
+    ("white", Rgb::new(0xff, 0xff, 0xff)),
+    ("maroon", Rgb::new(0x80, 0x00, 0x00)),
+    ("red", Rgb::new(0xff, 0x00, 0x00)),
+    ("purple", Rgb::new(0x80, 0x00, 0x80)),
+    ("fuchsia", Rgb::new(0xff, 0x00, 0xff)),
+    ("magenta", Rgb::new(0xff, 0x00, 0xff)),
+    ("green", Rgb::new(0x00, 0x80, 0x00)),
+    ("lime", Rgb::new(0x00, 0xff, 0x00)),
+    ("olive", Rgb::new(0x80, 0x80, 0x00)),
+    ("yellow", Rgb::new(0xff, 0xff, 0x00)),
+    ("navy", Rgb::new(0x00, 0x00, 0x80)),
+    ("blue", Rgb::new(0x00, 0x00, 0xff)),
+    ("teal", Rgb::new(0x00, 0x80, 0x80)),
+    ("aqua", Rgb::new(0x00, 0xff, 0xff)),
+    ("cyan", Rgb::new(0x00, 0xff, 0xff)),
+    ("orange", Rgb::new(0xff, 0xa5, 0x00)),
+    ("pink", Rgb::new(0xff, 0xc0, 0xcb)),
+    ("brown", Rgb::new(0xa5, 0x2a, 0x2a)),
+    ("gold", Rgb::new(0xff, 0xd7, 0x00)),
+    ("violet", Rgb::new(0xee, 0x82, 0xee)),
+    ("indigo", Rgb::new(0x4b, 0x00, 0x82)),
+    ("turquoise", Rgb::new(0x40, 0xe0, 0xd0)),
+    ("coral", Rgb::new(0xff, 0x7f, 0x50)),
+    ("salmon", Rgb::new(0xfa, 0x80, 0x72)),
+    ("crimson", Rgb::new(0xdc, 0x14, 0x3c)),
+    ("chocolate", Rgb::new(0xd2, 0x69, 0x1e)),
+    ("skyblue", Rgb::new(0x87, 0xce, 0xeb)),
+    ("steelblue", Rgb::new(0x46, 0x82, 0xb4)),
+    ("tomato", Rgb::new(0xff, 0x63, 0x47)),
+];
+
+/// `Rgb` is a 24 bit color
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    /// `new` returns the color of the `r`, `g` and `b` components
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+
+    /// `from_hsv` converts a `hue` in degrees, a `saturation` and a `value`
+    /// in [0, 1] to RGB
+    pub fn from_hsv(hue: f64, saturation: f64, value: f64) -> Rgb {
+        let hue = hue.rem_euclid(360.);
+        let chroma = value * saturation;
+        let x = chroma * (1. - ((hue / 60.) % 2. - 1.).abs());
+        let (r, g, b) = match (hue / 60.) as u8 {
+            0 => (chroma, x, 0.),
+            1 => (x, chroma, 0.),
+            2 => (0., chroma, x),
+            3 => (0., x, chroma),
+            4 => (x, 0., chroma),
+            _ => (chroma, 0., x),
+        };
+        let to_u8 = |c: f64| ((c + value - chroma) * 255.).round() as u8;
+        Rgb::new(to_u8(r), to_u8(g), to_u8(b))
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+/// `Color` is the color of a series or decoration, the alpha overrides the
+/// `alpha` setting of the series when set
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Color {
+    pub rgb: Rgb,
+    pub alpha: Option<f32>,
+}
+
+impl From<Rgb> for Color {
+    fn from(rgb: Rgb) -> Color {
+        Color { rgb, alpha: None }
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let color = input.trim();
+        let hex = color
+            .strip_prefix("0x")
+            .or_else(|| color.strip_prefix("0X"))
+            .or_else(|| color.strip_prefix('#'));
+        let hex = match hex {
+            Some(hex) => hex,
+            None => {
+                return NAMED_COLORS
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(color))
+                    .map(|(_, rgb)| Color::from(*rgb))
+                    .ok_or_else(|| format!("Unknown color '{}'", input))
+            }
+        };
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Invalid hexadecimal color '{}'", input));
+        }
+        let byte = |idx: usize| u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).unwrap_or(0);
+        match hex.len() {
+            // #RGB is #RRGGBB with each digit doubled
+            3 if color.starts_with('#') => {
+                let digit =
+                    |idx: usize| u8::from_str_radix(&hex[idx..=idx], 16).unwrap_or(0) * 0x11;
+                Ok(Color::from(Rgb::new(digit(0), digit(1), digit(2))))
+            }
+            6 => Ok(Color::from(Rgb::new(byte(0), byte(1), byte(2)))),
+            8 => Ok(Color {
+                rgb: Rgb::new(byte(0), byte(1), byte(2)),
+                alpha: Some(f32::from(byte(3)) / 255.),
+            }),
+            _ => Err(format!(
+                "Invalid color '{}', expected 0xRRGGBB, #RRGGBB, #RGB or 0xRRGGBBAA",
+                input
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.rgb)?;
+        if let Some(alpha) = self.alpha {
+            write!(f, "{:02x}", (alpha.clamp(0., 1.) * 255.).round() as u8)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let color = String::deserialize(deserializer)?;
+        color.parse().map_err(D::Error::custom)
+    }
+}
+
+/// `palette_color` returns the color number `idx` of the palette, the hue
+/// is rotated by the golden angle so that consecutive colors are distinct
+pub fn palette_color(idx: usize) -> Rgb {
+    Rgb::from_hsv(idx as f64 * PALETTE_HUE_STEP, 0.65, 0.9)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_colors() {
+        let purple = Color::from(Rgb::new(0x88, 0x39, 0x97));
+        assert_eq!("0x883997".parse(), Ok(purple));
+        assert_eq!("#883997".parse(), Ok(purple));
+        assert_eq!(" 0X883997 ".parse(), Ok(purple));
+        assert_eq!("#fa0".parse(), Ok(Color::from(Rgb::new(0xff, 0xaa, 0x00))));
+        assert_eq!(
+            "Orange".parse(),
+            Ok(Color::from(Rgb::new(0xff, 0xa5, 0x00)))
+        );
+        assert_eq!(
+            "0x88399780".parse(),
+            Ok(Color {
+                rgb: purple.rgb,
+                alpha: Some(128. / 255.)
+            })
+        );
+        assert!("883997".parse::<Color>().is_err());
+        assert!("0x8839".parse::<Color>().is_err());
+        assert!("0xfa0".parse::<Color>().is_err());
+        assert!("0xgg3997".parse::<Color>().is_err());
+        assert!("#+12345".parse::<Color>().is_err());
+        assert!("blurple".parse::<Color>().is_err());
+        assert_eq!(purple.to_string(), "0x883997");
+        assert_eq!(
+            "#88399780".parse::<Color>().unwrap().to_string(),
+            "0x88399780"
+        );
+    }
+
+    #[test]
+    fn it_deserializes_colors() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Series {
+            #[serde(default)]
+            color: Option<Color>,
+        }
+        let series: Series = serde_yaml::from_str("color: '#ff0000'").unwrap();
+        assert_eq!(series.color, Some(Color::from(Rgb::new(0xff, 0, 0))));
+        let series: Series = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(series.color, None);
+        let err = serde_yaml::from_str::<Series>("color: 'reddish'").unwrap_err();
+        assert!(err.to_string().contains("Unknown color 'reddish'"));
+        let color = Some(Color {
+            rgb: Rgb::new(0, 0x80, 0xff),
+            alpha: Some(0.2),
+        });
+        let yaml = serde_yaml::to_string(&Series { color }).unwrap();
+        assert!(yaml.contains("\"0x0080ff33\""), "{}", yaml);
+        assert_eq!(serde_yaml::from_str::<Series>(&yaml).unwrap().color, color);
+    }
+
+    #[test]
+    fn it_generates_palette_colors() {
+        assert_eq!(palette_color(0), Rgb::new(0xe6, 0x50, 0x50));
+        let palette: Vec<Rgb> = (0..8).map(palette_color).collect();
+        for (idx, color) in palette.iter().enumerate() {
+            assert!(!palette[idx + 1..].contains(color));
+        }
+        assert_eq!(Rgb::from_hsv(-120., 1., 1.), Rgb::new(0, 0, 0xff));
+    }
+}
@@ -0,0 +1,252 @@
+//! Charts the output of a shell command, the command is run every `refresh`
+//! seconds and a number is parsed from its output.
+use crate::prometheus::RetryConfig;
+use crate::SourceError;
+use log::*;
+use regex::Regex;
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// `CommandTimeSeries` runs a command through `sh -c` and loads the number
+/// in its output
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CommandTimeSeries {
+    /// The name of the series
+    #[serde(default)]
+    pub name: String,
+
+    /// The TimeSeries that contains the data
+    #[serde(default = "crate::prometheus::default_series")]
+    #[serde(deserialize_with = "crate::prometheus::deserialize_series")]
+    pub series: crate::TimeSeries,
+
+    /// The command to run, i.e. "df --output=pcent / | tail -1"
+    #[serde(default)]
+    pub command: String,
+
+    /// The regex that finds the number in the output, the first capture
+    /// group is used if any. The first number in the output by default.
+    #[serde(default)]
+    pub regex: Option<String>,
+
+    /// The time in seconds between runs
+    #[serde(default)]
+    #[serde(rename = "refresh")]
+    pub pull_interval: usize,
+
+    /// The color of the TimeSeries
+    #[serde(default)]
+    pub color: Option<crate::color::Color>,
+
+    /// The transparency of the TimeSeries
+    #[serde(default)]
+    pub alpha: f32,
+
+    /// The timeout and retry settings
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// The outcome of the latest runs
+    #[serde(skip)]
+    pub health: crate::SourceHealth,
+}
+
+impl Default for CommandTimeSeries {
+    fn default() -> CommandTimeSeries {
+        CommandTimeSeries {
+            name: String::from("Unset"),
+            series: crate::prometheus::default_series(),
+            command: String::from(""),
+            regex: None,
+            pull_interval: 15,
+            color: None,
+            alpha: 1.0,
+            retry: RetryConfig::default(),
+            health: crate::SourceHealth::default(),
+        }
+    }
+}
+
+impl PartialEq<CommandTimeSeries> for CommandTimeSeries {
+    fn eq(&self, other: &CommandTimeSeries) -> bool {
+        self.series == other.series
+            && self.command == other.command
+            && self.regex == other.regex
+            && self.pull_interval == other.pull_interval
+    }
+}
+
+impl CommandTimeSeries {
+    /// `compiled_regex` returns the compiled regex, if any
+    pub fn compiled_regex(&self) -> Result<Option<Regex>, String> {
+        match self.regex {
+            Some(ref regex) => Regex::new(regex)
+                .map(Some)
+                .map_err(|err| format!("Invalid regex {}: {}", regex, err)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// `parse_output` returns the number in `output`, the first capture group
+/// of `regex`, or its whole match, or the first word that is a number
+pub fn parse_output(output: &str, regex: Option<&Regex>) -> Result<f64, SourceError> {
+    let number = match regex {
+        Some(regex) => {
+            let captures = regex.captures(output).ok_or_else(|| {
+                SourceError::InvalidOutput(format!("No match for {} in output", regex))
+            })?;
+            let matched = captures.get(1).or_else(|| captures.get(0));
+            matched.map(|matched| matched.as_str()).unwrap_or_default()
+        }
+        None => output
+            .split_whitespace()
+            .find(|word| word.parse::<f64>().is_ok())
+            .ok_or_else(|| SourceError::InvalidOutput(String::from("No number in output")))?,
+    };
+    number
+        .trim()
+        .parse()
+        .map_err(|err| SourceError::InvalidOutput(format!("Invalid number '{}': {}", number, err)))
+}
+
+/// `spawn_reader` reads `pipe` to the end on its own thread, the output is
+/// sent on the returned channel
+fn spawn_reader<R: Read + Send + 'static>(mut pipe: R) -> mpsc::Receiver<io::Result<String>> {
+    let (output_tx, output_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = String::new();
+        let _ = output_tx.send(pipe.read_to_string(&mut output).map(|_| output));
+    });
+    output_rx
+}
+
+/// `run_command` runs `command` through `sh -c` and returns its standard
+/// output, the command is killed after `timeout`. A process it left in the
+/// background that keeps the output open is not waited for past `timeout`.
+/// It blocks the thread.
+pub fn run_command(command: &str, timeout: Duration) -> Result<String, SourceError> {
+    debug!("run_command: {}", command);
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| SourceError::Execution(format!("Unable to run {}: {}", command, err)))?;
+    // The outputs are read while the command runs so that it cannot block on
+    // a full pipe
+    let stdout = spawn_reader(child.stdout.take().expect("stdout is piped"));
+    let stderr = spawn_reader(child.stderr.take().expect("stderr is piped"));
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() >= timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(SourceError::Timeout(format!(
+                    "{} killed after {}s",
+                    command,
+                    timeout.as_secs()
+                )));
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(err) => {
+                return Err(SourceError::Execution(format!(
+                    "Unable to wait for {}: {}",
+                    command, err
+                )))
+            }
+        }
+    };
+    let deadline = started + timeout;
+    let read_output = |output: mpsc::Receiver<io::Result<String>>, name: &str| match output
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+    {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(err)) => Err(SourceError::Execution(format!(
+            "Unable to read the {} of {}: {}",
+            name, command, err
+        ))),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(SourceError::Timeout(format!(
+            "The {} of {} was still open after {}s",
+            name,
+            command,
+            timeout.as_secs()
+        ))),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(SourceError::Execution(format!(
+            "The {} reader of {} panicked",
+            name, command
+        ))),
+    };
+    if !status.success() {
+        let stderr = read_output(stderr, "stderr").unwrap_or_default();
+        return Err(SourceError::Execution(format!(
+            "{} exited with {}: {}",
+            command,
+            status,
+            stderr.trim()
+        )));
+    }
+    read_output(stdout, "stdout")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_command_output() {
+        assert_eq!(parse_output("42\n", None), Ok(42.));
+        assert_eq!(parse_output("Use% 87.5 of /", None), Ok(87.5));
+        let regex = Regex::new(r"Mem:\s+\d+\s+(\d+)").unwrap();
+        assert_eq!(
+            parse_output("        total used\nMem:   7859  1234\n", Some(&regex)),
+            Ok(1234.)
+        );
+        let regex = Regex::new(r"\d+%").unwrap();
+        assert!(parse_output("used 87%", Some(&regex)).is_err());
+        let regex = Regex::new(r"(\d+)%").unwrap();
+        assert_eq!(parse_output("used 87%", Some(&regex)), Ok(87.));
+        assert!(parse_output("no number", None).is_err());
+        let test = CommandTimeSeries {
+            regex: Some(String::from("(unclosed")),
+            ..CommandTimeSeries::default()
+        };
+        assert!(test.compiled_regex().is_err());
+    }
+
+    #[test]
+    fn it_runs_commands() {
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            run_command("echo 12; echo 13", timeout),
+            Ok(String::from("12\n13\n"))
+        );
+        match run_command("echo oops >&2; exit 3", timeout) {
+            Err(SourceError::Execution(err)) => assert!(err.ends_with("oops")),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match run_command("sleep 5", Duration::from_millis(100)) {
+            Err(SourceError::Timeout(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        // A full stderr pipe does not block the command
+        assert_eq!(
+            run_command("head -c 200000 /dev/zero >&2; echo 1", timeout),
+            Ok(String::from("1\n"))
+        );
+        // A background process holding stdout open is not waited for
+        let started = Instant::now();
+        match run_command("sleep 5 & echo 1", Duration::from_millis(300)) {
+            Err(SourceError::Timeout(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -4,16 +4,27 @@ use serde_yaml;
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
-static DEFAULT_CHART_CONFIG: &'static str =
+static DEFAULT_CHART_CONFIG: &str =
     include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/charts.yml"));
 /// Top-level config type
 #[derive(Debug, PartialEq, Deserialize, Clone)]
 pub struct Config {
     pub charts: Vec<crate::TimeSeriesChart>,
+
+    /// Periodically persist the charts data to restore it on restart
+    #[serde(default)]
+    pub snapshot: Option<crate::snapshot::SnapshotConfig>,
+
+    /// Serve the series and the polls stats on a Prometheus /metrics endpoint
+    #[serde(default)]
+    pub export: Option<crate::export::ExportConfig>,
 }
 impl Default for Config {
     fn default() -> Self {
-        serde_yaml::from_str(DEFAULT_CHART_CONFIG).expect("default config is invalid")
+        let mut config: Config =
+            serde_yaml::from_str(DEFAULT_CHART_CONFIG).expect("default config is invalid");
+        config.prepare().expect("default config is invalid");
+        config
     }
 }
 impl Config {
@@ -22,9 +33,8 @@ impl Config {
     pub fn read_config(path: &PathBuf) -> Result<Config, String> {
         let mut contents = String::new();
         File::open(path)
-            .unwrap()
-            .read_to_string(&mut contents)
-            .unwrap();
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|err| format!("Unable to read {:?}: {}", path, err))?;
 
         // Prevent parsing error with empty string
         if contents.is_empty() {
@@ -32,11 +42,23 @@ impl Config {
             return Ok(Config::default());
         }
 
-        let config: Config = serde_yaml::from_str(&contents).unwrap();
+        let mut config: Config = serde_yaml::from_str(&contents)
+            .map_err(|err| format!("Invalid config in {:?}: {}", path, err))?;
+        config.prepare()?;
 
         Ok(config)
     }
 
+    /// `prepare` validates the charts and assigns a palette color to the
+    /// series without color
+    fn prepare(&mut self) -> Result<(), String> {
+        for chart in &mut self.charts {
+            chart.validate()?;
+            chart.assign_colors();
+        }
+        Ok(())
+    }
+
     /// `load_config_file` will return the loaded configuration. If the config is
     /// invalid it will return the default config
     pub fn load_config_file() -> Config {
@@ -64,3 +86,73 @@ impl Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::{palette_color, Rgb};
+
+    #[test]
+    fn it_validates_and_colors_the_config() {
+        let path = std::env::temp_dir().join(format!("config-test-{}.yml", std::process::id()));
+        let read = |contents: &str| {
+            std::fs::write(&path, contents).unwrap();
+            Config::read_config(&path)
+        };
+        let config = read(
+            "
+            charts:
+            - name: load
+              series:
+              - name: load 1
+                type: alacritty_input
+              - name: load 5
+                type: alacritty_input
+                color: '#ff000080'
+              - name: load 15
+                type: alacritty_input
+            ",
+        )
+        .unwrap();
+        let colors: Vec<Rgb> = config.charts[0]
+            .sources
+            .iter()
+            .map(|source| source.color())
+            .collect();
+        assert_eq!(
+            colors,
+            vec![palette_color(0), Rgb::new(0xff, 0, 0), palette_color(1)]
+        );
+        assert!((config.charts[0].sources[1].alpha() - 128. / 255.).abs() < 1e-6);
+        let err = read(
+            "
+            charts:
+            - name: load
+              series:
+              - name: load 1
+                type: alacritty_input
+                color: '0xff00'
+            ",
+        )
+        .unwrap_err();
+        assert!(err.contains("Invalid color '0xff00'"), "{}", err);
+        let err = read(
+            "
+            charts:
+            - name: load
+              series:
+              - name: load 1
+                type: alacritty_input
+                alpha: 1.5
+            ",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "Chart 'load' series 'load 1': alpha 1.5 is not in [0, 1]"
+        );
+        std::fs::remove_file(&path).unwrap();
+        // The charts.yml of the repository is valid
+        assert!(!Config::default().charts.is_empty());
+    }
+}
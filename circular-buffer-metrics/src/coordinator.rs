@@ -0,0 +1,1089 @@
+//! The coordinator owns the charts, it receives the data loaded from the
+//! network by the polling tasks and serves the drawable vectors.
+use crate::command;
+use crate::export;
+use crate::line_protocol::{self, LineProtocol, Transport};
+use crate::prometheus;
+use crate::scrape;
+use crate::snapshot::Snapshot;
+use crate::statsd;
+use crate::tail;
+use crate::SizeInfo; // XXX: remove on merge.
+use crate::SourceError;
+use crate::TimeSeriesChart;
+use crate::TimeSeriesSource;
+use futures::future::{self, lazy};
+use futures::sync::{mpsc, oneshot};
+use log::*;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::codec::{BytesCodec, FramedRead, LinesCodec};
+use tokio::net::{TcpListener, UdpFramed, UdpSocket};
+use tokio::prelude::*;
+use tokio::timer::{Delay, Interval};
+
+// TODO:
+// - Add color fetch
+// - Maybe the coordinator should talk to OpenGL directly to avoid copyign arrays.
+/// `PollTarget` is what a MetricRequest polls
+#[derive(Debug, Clone)]
+pub enum PollTarget {
+    /// A Prometheus API query
+    Query(prometheus::PrometheusQuery),
+    /// The metrics endpoint of a target, scraped directly
+    Scrape(hyper::Uri),
+    /// A shell command and the regex that finds the number in its output
+    Command(String, Option<regex::Regex>),
+    /// A file whose appended lines are loaded
+    Tail(PathBuf),
+}
+
+impl fmt::Display for PollTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PollTarget::Query(query) => write!(f, "{}", query.expr),
+            PollTarget::Scrape(url) => write!(f, "{}", url),
+            PollTarget::Command(command, _) => write!(f, "{}", command),
+            PollTarget::Tail(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// `MetricRequest` contains a way to address a particular
+/// item in our TimeSeriesCharts vectors
+#[derive(Debug, Clone)]
+pub struct MetricRequest {
+    pull_interval: u64,
+    target: PollTarget,
+    chart_index: usize,  // For Vec<TimeSeriesChart>
+    series_index: usize, // For Vec<TimeSeriesSource>
+    data: Option<prometheus::HTTPResponse>,
+    capacity: usize,  // This maps to the time range in slots to query.
+    granularity: u64, // The number of seconds per slot.
+    http: prometheus::HTTPClientConfig,
+    retry: prometheus::RetryConfig,
+    last_loaded: Option<u64>, // The last epoch loaded, or offset read of a tailed file.
+}
+
+impl MetricRequest {
+    /// `from_prometheus` creates the request to poll the Prometheus series at
+    /// `series_index` in the chart at `chart_index`
+    pub fn from_prometheus(
+        chart_index: usize,
+        series_index: usize,
+        prom: &prometheus::PrometheusTimeSeries,
+    ) -> Result<MetricRequest, String> {
+        Ok(MetricRequest {
+            target: PollTarget::Query(prom.prometheus_query()?),
+            pull_interval: prom.pull_interval as u64,
+            chart_index,
+            series_index,
+            capacity: prom.series.metrics_capacity,
+            granularity: prom.series.granularity,
+            http: prom.http.clone(),
+            retry: prom.retry.clone(),
+            last_loaded: None,
+            data: None,
+        })
+    }
+
+    /// `from_scrape` creates the request to scrape the metrics endpoint of
+    /// the series at `series_index` in the chart at `chart_index`
+    pub fn from_scrape(
+        chart_index: usize,
+        series_index: usize,
+        scraped: &scrape::ScrapeTimeSeries,
+    ) -> Result<MetricRequest, String> {
+        let mut http = scraped.http.clone();
+        if !http
+            .headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("accept"))
+        {
+            http.headers.insert(
+                String::from("Accept"),
+                String::from(scrape::SCRAPE_ACCEPT_HEADER),
+            );
+        }
+        Ok(MetricRequest {
+            target: PollTarget::Scrape(scraped.url()?),
+            pull_interval: scraped.pull_interval as u64,
+            chart_index,
+            series_index,
+            capacity: scraped.series.metrics_capacity,
+            granularity: scraped.series.granularity,
+            http,
+            retry: scraped.retry.clone(),
+            last_loaded: None,
+            data: None,
+        })
+    }
+
+    /// `from_command` creates the request to run the command of the series at
+    /// `series_index` in the chart at `chart_index`
+    pub fn from_command(
+        chart_index: usize,
+        series_index: usize,
+        command: &command::CommandTimeSeries,
+    ) -> Result<MetricRequest, String> {
+        Ok(MetricRequest {
+            target: PollTarget::Command(command.command.clone(), command.compiled_regex()?),
+            pull_interval: command.pull_interval as u64,
+            chart_index,
+            series_index,
+            capacity: command.series.metrics_capacity,
+            granularity: command.series.granularity,
+            http: prometheus::HTTPClientConfig::default(),
+            retry: command.retry.clone(),
+            last_loaded: None,
+            data: None,
+        })
+    }
+
+    /// `from_tail` creates the request to read the lines appended to the file
+    /// of the series at `series_index` in the chart at `chart_index`
+    pub fn from_tail(
+        chart_index: usize,
+        series_index: usize,
+        tailed: &tail::TailTimeSeries,
+    ) -> Result<MetricRequest, String> {
+        Ok(MetricRequest {
+            target: PollTarget::Tail(tailed.path.clone()),
+            pull_interval: tailed.pull_interval as u64,
+            chart_index,
+            series_index,
+            capacity: tailed.series.metrics_capacity,
+            granularity: tailed.series.granularity,
+            http: prometheus::HTTPClientConfig::default(),
+            retry: tailed.retry.clone(),
+            last_loaded: None,
+            data: None,
+        })
+    }
+}
+
+/// `AsyncChartTask` contains message types that async_coordinator can work on
+#[derive(Debug)]
+pub enum AsyncChartTask {
+    LoadResponse(Box<MetricRequest>, oneshot::Sender<Option<u64>>),
+    LoadError(usize, usize, SourceError),
+    LoadScrape(usize, usize, u64, Vec<scrape::MetricFamily>),
+    PollDuration(usize, usize, Duration),
+    LoadStatsd(String, u64, Vec<statsd::StatsdLine>),
+    LoadSamples(usize, usize, Vec<(u64, f64)>),
+    LoadLines(String, u64, Vec<line_protocol::LineSample>),
+    GetMetricsOpenGLData(usize, usize, oneshot::Sender<Vec<f32>>),
+    GetDecorationsOpenGLData(usize, usize, oneshot::Sender<Vec<f32>>),
+    WriteSnapshot(PathBuf),
+    GetChart(usize, oneshot::Sender<Option<TimeSeriesChart>>),
+    GetExposition(oneshot::Sender<String>),
+    Subscribe(mpsc::Sender<()>),
+}
+
+/// `load_http_response` is called by async_coordinator when a task of type
+/// LoadResponse is received. Returns the last epoch loaded into the series
+/// after a successful load.
+pub fn load_http_response(charts: &mut [TimeSeriesChart], response: MetricRequest) -> Option<u64> {
+    let mut last_loaded = None;
+    if let Some(data) = response.data {
+        let mut ok_records = 0;
+        if response.chart_index < charts.len()
+            && response.series_index < charts[response.chart_index].sources.len()
+        {
+            let chart = &mut charts[response.chart_index];
+            let warnings = data.warnings.clone();
+            match chart.load_prometheus_response(response.series_index, data) {
+                Ok(num_records) => {
+                    info!(
+                        "Loaded {} records from {} into TimeSeries",
+                        num_records, response.target
+                    );
+                    ok_records = num_records;
+                    chart.record_success(response.series_index, epoch_now(), &warnings);
+                    chart.record_loaded(response.series_index, num_records);
+                    last_loaded = chart.last_loaded_epoch(response.series_index);
+                }
+                Err(err) => {
+                    debug!("Error from {} into TimeSeries: {:?}", response.target, err);
+                    chart.record_failure(response.series_index, epoch_now(), &err.to_string());
+                }
+            }
+        }
+        update_loaded_charts(
+            charts,
+            response.chart_index,
+            response.series_index,
+            ok_records,
+        );
+    }
+    last_loaded
+}
+
+/// `update_loaded_charts` updates the drawable vectors of the series at
+/// `series_index` of the chart at `chart_index` and the loaded item counters
+/// after `ok_records` have been loaded into it
+fn update_loaded_charts(
+    charts: &mut [TimeSeriesChart],
+    chart_index: usize,
+    series_index: usize,
+    ok_records: usize,
+) {
+    if let Some(chart) = charts.get_mut(chart_index) {
+        // The response may have been split into several series
+        for series_index in chart.loaded_series(series_index) {
+            chart.update_opengl_vecs(
+                series_index,
+                SizeInfo {
+                    padding_x: 0.,
+                    padding_y: 0.,
+                    height: 100.,
+                    width: 100.,
+                    ..SizeInfo::default()
+                },
+            );
+        }
+    }
+    update_loaded_items(charts, ok_records);
+}
+
+/// `update_loaded_items` pushes the `ok_records` loaded by a task into the
+/// loaded item counters of all the charts
+fn update_loaded_items(charts: &mut [TimeSeriesChart], ok_records: usize) {
+    for chart in charts {
+        info!("Searching for AsyncLoadedItems in '{}'", chart.name);
+        let mut loaded_items = vec![];
+        for (series_index, series) in chart.sources.iter_mut().enumerate() {
+            if let TimeSeriesSource::AsyncLoadedItems(ref mut loaded) = series {
+                loaded.series.push_current_epoch(ok_records as f64);
+                loaded_items.push(series_index);
+            }
+        }
+        for series_index in loaded_items {
+            chart.update_opengl_vecs(
+                series_index,
+                SizeInfo {
+                    padding_x: 0.,
+                    padding_y: 0.,
+                    height: 100.,
+                    width: 100.,
+                    ..SizeInfo::default()
+                },
+            );
+        }
+    }
+}
+
+/// `load_samples` is called by async_coordinator when a task of type
+/// LoadSamples is received, the samples of a command or a tailed file go
+/// through the same updates as a Prometheus response
+pub fn load_samples(
+    charts: &mut [TimeSeriesChart],
+    chart_index: usize,
+    series_index: usize,
+    samples: &[(u64, f64)],
+) {
+    let mut ok_records = 0;
+    if let Some(chart) = charts.get_mut(chart_index) {
+        match chart.load_samples(series_index, samples) {
+            Ok(num_records) => {
+                debug!("Loaded {} samples into TimeSeries", num_records);
+                ok_records = num_records;
+                chart.record_success(series_index, epoch_now(), &[]);
+                chart.record_loaded(series_index, num_records);
+            }
+            Err(err) => {
+                debug!("Error loading samples into TimeSeries: {}", err);
+                chart.record_failure(series_index, epoch_now(), &err);
+            }
+        }
+    }
+    update_loaded_charts(charts, chart_index, series_index, ok_records);
+}
+
+/// `load_scrape` is called by async_coordinator when a task of type
+/// LoadScrape is received
+pub fn load_scrape(
+    charts: &mut [TimeSeriesChart],
+    chart_index: usize,
+    series_index: usize,
+    epoch: u64,
+    families: &[scrape::MetricFamily],
+) {
+    let mut ok_records = 0;
+    if let Some(chart) = charts.get_mut(chart_index) {
+        match chart.load_scrape(series_index, epoch, families) {
+            Ok(num_records) => {
+                debug!("Loaded {} scraped records into TimeSeries", num_records);
+                ok_records = num_records;
+                chart.record_success(series_index, epoch_now(), &[]);
+                chart.record_loaded(series_index, num_records);
+            }
+            Err(err) => {
+                debug!("Error loading scraped records into TimeSeries: {}", err);
+                chart.record_failure(series_index, epoch_now(), &err);
+            }
+        }
+    }
+    update_loaded_charts(charts, chart_index, series_index, ok_records);
+}
+
+/// `load_statsd` is called by async_coordinator when a task of type
+/// LoadStatsd is received, the lines are loaded into the StatsD series
+/// listening on `listen` in all the charts
+pub fn load_statsd(
+    charts: &mut [TimeSeriesChart],
+    listen: &str,
+    epoch: u64,
+    lines: &[statsd::StatsdLine],
+) {
+    let mut ok_records = 0;
+    for chart in charts.iter_mut() {
+        for (series_index, num_records) in chart.load_statsd(listen, epoch, lines) {
+            ok_records += num_records;
+            chart.update_opengl_vecs(
+                series_index,
+                SizeInfo {
+                    padding_x: 0.,
+                    padding_y: 0.,
+                    height: 100.,
+                    width: 100.,
+                    ..SizeInfo::default()
+                },
+            );
+        }
+    }
+    update_loaded_items(charts, ok_records);
+}
+
+/// `load_lines` is called by async_coordinator when a task of type LoadLines
+/// is received, the samples are loaded into the Influx and Graphite series
+/// listening on `listen` in all the charts
+pub fn load_lines(
+    charts: &mut [TimeSeriesChart],
+    listen: &str,
+    epoch: u64,
+    samples: &[line_protocol::LineSample],
+) {
+    let mut ok_records = 0;
+    for chart in charts.iter_mut() {
+        for (series_index, num_records) in chart.load_lines(listen, epoch, samples) {
+            ok_records += num_records;
+            chart.update_opengl_vecs(
+                series_index,
+                SizeInfo {
+                    padding_x: 0.,
+                    padding_y: 0.,
+                    height: 100.,
+                    width: 100.,
+                    ..SizeInfo::default()
+                },
+            );
+        }
+    }
+    update_loaded_items(charts, ok_records);
+}
+
+/// `load_http_error` is called by async_coordinator when a task of type
+/// LoadError is received, it updates the health of the series
+pub fn load_http_error(
+    charts: &mut [TimeSeriesChart],
+    chart_index: usize,
+    series_index: usize,
+    err: &SourceError,
+) {
+    if let Some(chart) = charts.get_mut(chart_index) {
+        chart.record_failure(series_index, epoch_now(), &err.to_string());
+    }
+}
+
+/// `get_opengl_vecs` is called by async_coordinator when an task or type GetMetricsOpenGLData
+/// is received, it should contain the chart index to represent
+pub fn get_opengl_vecs(
+    charts: &[TimeSeriesChart],
+    chart_index: usize,
+    data_index: usize,
+    channel: oneshot::Sender<Vec<f32>>,
+    is_decoration: bool,
+) {
+    debug!("get_opengl_vecs for chart_index: {}", chart_index);
+    match channel.send(if chart_index >= charts.len() {
+        vec![]
+    } else if is_decoration {
+        if data_index >= charts[chart_index].decorations.len() {
+            vec![]
+        } else {
+            charts[chart_index].decorations[data_index].opengl_vertices()
+        }
+    } else {
+        if data_index >= charts[chart_index].opengl_vecs.len() {
+            vec![]
+        } else {
+            charts[chart_index].opengl_vecs[data_index].clone()
+        }
+    }) {
+        Ok(()) => {
+            if chart_index > charts.len() {
+                debug!(
+                    "get_opengl_vecs: oneshot::message sent for {}[OutOfBounds]",
+                    chart_index
+                );
+            } else {
+                debug!(
+                    "get_opengl_vecs: oneshot::message sent for {}[InsideBounds]",
+                    chart_index
+                );
+            }
+        }
+        Err(err) => error!("get_opengl_vecs: Error sending: {:?}", err),
+    };
+    /*.and_then(|_| {
+        let vec_len = charts[chart_index].series_opengl_vecs.len();
+        debug!("Sent vec with {} items", vec_len);
+        Ok(vec_len)
+    })
+    .map_err(|e| {
+        error!("get_opengl_vecs; err={:?}", e);
+    });*/
+}
+
+/// `write_snapshot` is called by async_coordinator when a task of type
+/// WriteSnapshot is received
+pub fn write_snapshot(charts: &[TimeSeriesChart], path: &Path) {
+    match Snapshot::from_charts(charts).write_to_file(path) {
+        Ok(()) => debug!("write_snapshot: Wrote snapshot to {:?}", path),
+        Err(err) => error!("write_snapshot: {}", err),
+    }
+}
+
+/// `restore_snapshot` loads the charts data from a previous run, if any
+pub fn restore_snapshot(charts: &mut [TimeSeriesChart], path: &Path) {
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    match Snapshot::read_from_file(path) {
+        Ok(snapshot) => {
+            snapshot.restore(charts, now);
+        }
+        Err(err) => info!("restore_snapshot: Starting with empty charts: {}", err),
+    }
+}
+
+/// `spawn_snapshot_writes` periodically requests the coordinator to persist
+/// the charts data
+pub fn spawn_snapshot_writes(
+    path: PathBuf,
+    interval: u64,
+    tx: mpsc::Sender<AsyncChartTask>,
+) -> impl Future<Item = (), Error = ()> {
+    debug!("spawn_snapshot_writes: Starting for path={:?}", path);
+    Interval::new(
+        Instant::now() + Duration::from_secs(interval),
+        Duration::from_secs(interval),
+    )
+    .map_err(|e| error!("snapshot interval errored; err={:?}", e))
+    .for_each(move |_instant| {
+        tx.clone()
+            .send(AsyncChartTask::WriteSnapshot(path.clone()))
+            .map(|_| ())
+            .map_err(|e| error!("Sending WriteSnapshot Task: err={:?}", e))
+    })
+}
+
+/// `notify_subscribers` tells the subscribers that the charts have changed,
+/// a notification that is still pending is not repeated. The subscribers
+/// that have gone away are dropped.
+fn notify_subscribers(subscribers: &mut Vec<mpsc::Sender<()>>) {
+    subscribers.retain_mut(|subscriber| match subscriber.try_send(()) {
+        Ok(()) => true,
+        Err(err) => !err.is_disconnected(),
+    });
+}
+
+/// `async_coordinator` receives messages from the tasks about data loaded from
+/// the network, it owns the charts data.
+pub fn async_coordinator(
+    rx: mpsc::Receiver<AsyncChartTask>,
+    mut charts: Vec<TimeSeriesChart>,
+) -> impl Future<Item = (), Error = ()> {
+    debug!("async_coordinator: Starting");
+    let mut subscribers = vec![];
+    rx.for_each(move |message| {
+        debug!("async_coordinator: message: {:?}", message);
+        let is_load = matches!(
+            message,
+            AsyncChartTask::LoadResponse(..)
+                | AsyncChartTask::LoadError(..)
+                | AsyncChartTask::LoadScrape(..)
+                | AsyncChartTask::LoadSamples(..)
+                | AsyncChartTask::LoadLines(..)
+                | AsyncChartTask::LoadStatsd(..)
+        );
+        match message {
+            AsyncChartTask::LoadResponse(req, channel) => {
+                let last_loaded = load_http_response(&mut charts, *req);
+                if channel.send(last_loaded).is_err() {
+                    debug!("LoadResponse: The poll is no longer waiting for the load");
+                }
+            }
+            AsyncChartTask::LoadError(chart_index, series_index, err) => {
+                load_http_error(&mut charts, chart_index, series_index, &err)
+            }
+            AsyncChartTask::LoadScrape(chart_index, series_index, epoch, families) => {
+                load_scrape(&mut charts, chart_index, series_index, epoch, &families)
+            }
+            AsyncChartTask::LoadSamples(chart_index, series_index, samples) => {
+                load_samples(&mut charts, chart_index, series_index, &samples)
+            }
+            AsyncChartTask::LoadLines(listen, epoch, samples) => {
+                load_lines(&mut charts, &listen, epoch, &samples)
+            }
+            AsyncChartTask::LoadStatsd(listen, epoch, lines) => {
+                load_statsd(&mut charts, &listen, epoch, &lines)
+            }
+            AsyncChartTask::PollDuration(chart_index, series_index, duration) => {
+                if let Some(chart) = charts.get_mut(chart_index) {
+                    chart.record_poll_duration(series_index, duration);
+                }
+            }
+            AsyncChartTask::GetMetricsOpenGLData(chart_index, data_index, channel) => {
+                get_opengl_vecs(&charts, chart_index, data_index, channel, false);
+            }
+            AsyncChartTask::GetDecorationsOpenGLData(chart_index, data_index, channel) => {
+                get_opengl_vecs(&charts, chart_index, data_index, channel, true);
+            }
+            AsyncChartTask::WriteSnapshot(path) => write_snapshot(&charts, &path),
+            AsyncChartTask::GetChart(chart_index, channel) => {
+                if channel.send(charts.get(chart_index).cloned()).is_err() {
+                    error!("GetChart: Error sending chart {}", chart_index);
+                }
+            }
+            AsyncChartTask::GetExposition(channel) => {
+                if channel
+                    .send(export::render_exposition(&charts, epoch_now()))
+                    .is_err()
+                {
+                    error!("GetExposition: Error sending the exposition");
+                }
+            }
+            AsyncChartTask::Subscribe(subscriber) => subscribers.push(subscriber),
+        };
+        if is_load {
+            notify_subscribers(&mut subscribers);
+        }
+        Ok(())
+    })
+}
+
+/// `fetch_prometheus_response` gets data from prometheus and once data is ready
+/// it sends the results to the coordinator. Returns the last epoch loaded
+/// into the series, as reported by the coordinator once it is loaded.
+pub fn fetch_prometheus_response(
+    item: MetricRequest,
+    tx: mpsc::Sender<AsyncChartTask>,
+) -> impl Future<Item = Option<u64>, Error = prometheus::PrometheusError> {
+    debug!("fetch_prometheus_response: Starting");
+    let query = match item.target {
+        PollTarget::Query(ref query) => query,
+        _ => {
+            return future::Either::A(future::err(prometheus::PrometheusError::Config(
+                String::from("Not a Prometheus query"),
+            )))
+        }
+    };
+    let url = match query.to_url(
+        item.capacity as u64,
+        item.granularity,
+        epoch_now(),
+        item.last_loaded,
+    ) {
+        Ok(url) => url,
+        Err(err) => {
+            return future::Either::A(future::err(prometheus::PrometheusError::Config(err)))
+        }
+    };
+    let res = get_with_timeout(url, &item)
+        .and_then(move |value| {
+            debug!("Got prometheus raw value={:?}", value);
+            let res = prometheus::parse_response(&value);
+            debug!("Parsed JSON to res={:?}", res);
+            res
+        })
+        .and_then(move |res| {
+            let (loaded_tx, loaded_rx) = oneshot::channel();
+            tx.send(AsyncChartTask::LoadResponse(
+                Box::new(MetricRequest {
+                    data: Some(res),
+                    ..item
+                }),
+                loaded_tx,
+            ))
+            .map_err(|e| {
+                prometheus::PrometheusError::Request(format!(
+                    "fetch_prometheus_response: send data back to coordinator; err={:?}",
+                    e
+                ))
+            })
+            .and_then(move |_| {
+                loaded_rx.map_err(|_| {
+                    prometheus::PrometheusError::Request(String::from(
+                        "fetch_prometheus_response: the coordinator dropped the load",
+                    ))
+                })
+            })
+        });
+    future::Either::B(res)
+}
+
+/// `get_with_timeout` requests `url` with the HTTP settings of `item`, the
+/// request fails once the timeout, or the pull interval, has elapsed
+fn get_with_timeout(
+    url: hyper::Uri,
+    item: &MetricRequest,
+) -> impl Future<Item = hyper::Chunk, Error = prometheus::PrometheusError> {
+    let timeout = item.retry.timeout.unwrap_or(item.pull_interval).max(1);
+    prometheus::get_from_prometheus(url, &item.http)
+        .timeout(Duration::from_secs(timeout))
+        .map_err(move |e| {
+            if e.is_elapsed() {
+                prometheus::PrometheusError::Timeout(format!("No response after {}s", timeout))
+            } else if let Some(err) = e.into_inner() {
+                err
+            } else {
+                prometheus::PrometheusError::Request(String::from("Timer error"))
+            }
+        })
+}
+
+/// `fetch_scrape_response` scrapes a metrics endpoint and sends the parsed
+/// metric families to the coordinator. The whole endpoint is read on every
+/// scrape so there is no last epoch to return.
+pub fn fetch_scrape_response(
+    item: MetricRequest,
+    tx: mpsc::Sender<AsyncChartTask>,
+) -> impl Future<Item = Option<u64>, Error = prometheus::PrometheusError> {
+    let url = match item.target {
+        PollTarget::Scrape(ref url) => url.clone(),
+        _ => {
+            return future::Either::A(future::err(prometheus::PrometheusError::Config(
+                String::from("Not a scrape target"),
+            )))
+        }
+    };
+    let res = get_with_timeout(url, &item)
+        .and_then(|body| {
+            scrape::parse_exposition(&String::from_utf8_lossy(&body))
+                .map_err(prometheus::PrometheusError::InvalidResponse)
+        })
+        .and_then(move |families| {
+            debug!("fetch_scrape_response: {} families", families.len());
+            tx.send(AsyncChartTask::LoadScrape(
+                item.chart_index,
+                item.series_index,
+                epoch_now(),
+                families,
+            ))
+            .map_err(|e| {
+                prometheus::PrometheusError::Request(format!(
+                    "fetch_scrape_response: send data back to coordinator; err={:?}",
+                    e
+                ))
+            })
+            .map(|_| None)
+        });
+    future::Either::B(res)
+}
+
+/// `run_blocking` runs `f` in its own thread so that the runtime is not
+/// blocked by commands or file reads
+fn run_blocking<T, F>(f: F) -> impl Future<Item = T, Error = SourceError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, SourceError> + Send + 'static,
+{
+    let (result_tx, result_rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = result_tx.send(f());
+    });
+    result_rx
+        .map_err(|_| SourceError::Execution(String::from("Worker thread died")))
+        .and_then(|res| res)
+}
+
+/// `send_samples` sends the samples loaded for `item` to the coordinator
+fn send_samples(
+    item: &MetricRequest,
+    tx: mpsc::Sender<AsyncChartTask>,
+    samples: Vec<(u64, f64)>,
+) -> impl Future<Item = (), Error = SourceError> {
+    tx.send(AsyncChartTask::LoadSamples(
+        item.chart_index,
+        item.series_index,
+        samples,
+    ))
+    .map(|_| ())
+    .map_err(|e| {
+        SourceError::Coordinator(format!(
+            "send_samples: send data back to coordinator; err={:?}",
+            e
+        ))
+    })
+}
+
+/// `fetch_command_output` runs the command of `item` and sends the number in
+/// its output to the coordinator
+pub fn fetch_command_output(
+    item: MetricRequest,
+    tx: mpsc::Sender<AsyncChartTask>,
+) -> impl Future<Item = Option<u64>, Error = SourceError> {
+    let (command, regex) = match item.target {
+        PollTarget::Command(ref command, ref regex) => (command.clone(), regex.clone()),
+        _ => {
+            return future::Either::A(future::err(SourceError::Config(String::from(
+                "Not a command",
+            ))))
+        }
+    };
+    let timeout = Duration::from_secs(item.retry.timeout.unwrap_or(item.pull_interval).max(1));
+    let res = run_blocking(move || {
+        let output = command::run_command(&command, timeout)?;
+        command::parse_output(&output, regex.as_ref())
+    })
+    .and_then(move |value| send_samples(&item, tx, vec![(epoch_now(), value)]))
+    .map(|_| None);
+    future::Either::B(res)
+}
+
+/// `fetch_appended_lines` reads the lines appended to the file of `item` and
+/// sends them to the coordinator. Returns the offset of the next read.
+pub fn fetch_appended_lines(
+    item: MetricRequest,
+    tx: mpsc::Sender<AsyncChartTask>,
+) -> impl Future<Item = Option<u64>, Error = SourceError> {
+    let path = match item.target {
+        PollTarget::Tail(ref path) => path.clone(),
+        _ => {
+            return future::Either::A(future::err(SourceError::Config(String::from(
+                "Not a tailed file",
+            ))))
+        }
+    };
+    let offset = item.last_loaded;
+    let res = run_blocking(move || tail::read_appended(&path, offset)).and_then(
+        move |(samples, offset)| {
+            let sent = if samples.is_empty() {
+                future::Either::A(future::ok(()))
+            } else {
+                future::Either::B(send_samples(&item, tx, samples))
+            };
+            sent.map(move |_| Some(offset))
+        },
+    );
+    future::Either::B(res)
+}
+
+/// `fetch_target` loads the target of `item`, the data is sent to the
+/// coordinator. Returns where the next load should resume from.
+pub fn fetch_target(
+    item: MetricRequest,
+    tx: mpsc::Sender<AsyncChartTask>,
+) -> Box<dyn Future<Item = Option<u64>, Error = SourceError> + Send> {
+    match item.target {
+        PollTarget::Query(_) => Box::new(fetch_prometheus_response(item, tx).from_err()),
+        PollTarget::Scrape(_) => Box::new(fetch_scrape_response(item, tx).from_err()),
+        PollTarget::Command(..) => Box::new(fetch_command_output(item, tx)),
+        PollTarget::Tail(_) => Box::new(fetch_appended_lines(item, tx)),
+    }
+}
+
+/// `epoch_now` returns the current epoch in seconds
+pub fn epoch_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// `jitter_sample` returns a number in [0, 1) that is random enough to spread
+/// the retries of the different sources
+fn jitter_sample() -> f64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos();
+    f64::from(nanos % 1_000_000) / 1_000_000.
+}
+
+/// `spawn_interval_polls` polls the series every pull_interval, after a
+/// failure the error is sent to the coordinator and the request is retried
+/// with an exponential backoff
+pub fn spawn_interval_polls(
+    item: &MetricRequest,
+    tx: mpsc::Sender<AsyncChartTask>,
+) -> impl Future<Item = (), Error = ()> {
+    debug!("spawn_interval_polls: Starting for item={:?}", item);
+    future::loop_fn((item.clone(), 0u32), move |(mut item, failures)| {
+        let started = Instant::now();
+        debug!(
+            "Poll triggered for {} at instant={:?}",
+            item.target, started
+        );
+        let tx = tx.clone();
+        fetch_target(item.clone(), tx.clone()).then(move |res| {
+            let (failures, next_poll, report) = match res {
+                Ok(last_epoch) => {
+                    // Only the missing range is requested on the next poll,
+                    // None requests the full range or reads a truncated file
+                    // again from the start
+                    item.last_loaded = last_epoch;
+                    // Series without refresh have a pull_interval of 0
+                    let pull_interval = Duration::from_secs(item.pull_interval.max(1));
+                    (0, started + pull_interval, None)
+                }
+                Err(err) => {
+                    let failures = failures.saturating_add(1);
+                    let backoff = item.retry.backoff(failures, jitter_sample());
+                    let message = format!(
+                        "Poll {} failed {} times, retrying in {:?}: {}",
+                        item.target, failures, backoff, err
+                    );
+                    // A bad query will not fix itself, it needs to stand out
+                    match err {
+                        SourceError::Prometheus(prometheus::PrometheusError::BadQuery(_))
+                        | SourceError::Prometheus(prometheus::PrometheusError::Config(_))
+                        | SourceError::Config(_) => error!("{}", message),
+                        _ => warn!("{}", message),
+                    }
+                    (failures, Instant::now() + backoff, Some(err))
+                }
+            };
+            let (chart_index, series_index) = (item.chart_index, item.series_index);
+            let report = tx
+                .send(AsyncChartTask::PollDuration(
+                    chart_index,
+                    series_index,
+                    started.elapsed(),
+                ))
+                .map_err(|e| error!("Sending PollDuration Task: err={:?}", e))
+                .and_then(move |tx| match report {
+                    Some(err) => future::Either::A(
+                        tx.send(AsyncChartTask::LoadError(chart_index, series_index, err))
+                            .map(|_| ())
+                            .map_err(|e| error!("Sending LoadError Task: err={:?}", e)),
+                    ),
+                    None => future::Either::B(future::ok(())),
+                });
+            report.and_then(move |_| {
+                Delay::new(next_poll)
+                    .map_err(|e| error!("poll delay errored; err={:?}", e))
+                    .map(move |_| future::Loop::Continue((item, failures)))
+            })
+        })
+    })
+}
+
+/// `spawn_prometheus_polls` spawns the polling of every Prometheus, scrape,
+/// command and tail series in `charts`, it must be called from a tokio runtime
+pub fn spawn_prometheus_polls(charts: &[TimeSeriesChart], tx: &mpsc::Sender<AsyncChartTask>) {
+    for (chart_index, chart) in charts.iter().enumerate() {
+        debug!("Loading chart series with name: '{}'", chart.name);
+        for (series_index, series) in chart.sources.iter().enumerate() {
+            let data_request = match series {
+                TimeSeriesSource::PrometheusTimeSeries(ref prom) => {
+                    MetricRequest::from_prometheus(chart_index, series_index, prom)
+                }
+                TimeSeriesSource::ScrapeTimeSeries(ref scraped) => {
+                    MetricRequest::from_scrape(chart_index, series_index, scraped)
+                }
+                TimeSeriesSource::CommandTimeSeries(ref command) => {
+                    MetricRequest::from_command(chart_index, series_index, command)
+                }
+                TimeSeriesSource::TailTimeSeries(ref tailed) => {
+                    MetricRequest::from_tail(chart_index, series_index, tailed)
+                }
+                _ => continue,
+            };
+            debug!(" - Found time_series, adding interval run");
+            let data_request = match data_request {
+                Ok(data_request) => data_request,
+                Err(err) => {
+                    error!("Skipping series '{}': {}", series.name(), err);
+                    continue;
+                }
+            };
+            let poll_tx = tx.clone();
+            tokio::spawn(lazy(move || spawn_interval_polls(&data_request, poll_tx)));
+        }
+    }
+}
+
+/// `spawn_statsd_listeners` binds a UDP socket for each address the StatsD
+/// series listen on, the lines received are sent to the coordinator. It must
+/// be called from a tokio runtime and returns the bound addresses.
+pub fn spawn_statsd_listeners(
+    charts: &[TimeSeriesChart],
+    tx: &mpsc::Sender<AsyncChartTask>,
+) -> Vec<(String, SocketAddr)> {
+    let mut listens: Vec<String> = vec![];
+    for chart in charts {
+        for series in &chart.sources {
+            if let TimeSeriesSource::StatsdTimeSeries(ref statsd) = series {
+                if !listens.iter().any(|listen| listen == statsd.listen_addr()) {
+                    listens.push(statsd.listen_addr().to_string());
+                }
+            }
+        }
+    }
+    let mut bound = vec![];
+    for listen in listens {
+        let socket = match listen
+            .parse()
+            .map_err(|err| format!("{}", err))
+            .and_then(|addr: SocketAddr| UdpSocket::bind(&addr).map_err(|err| format!("{}", err)))
+        {
+            Ok(socket) => socket,
+            Err(err) => {
+                error!("Not listening for StatsD on {}: {}", listen, err);
+                continue;
+            }
+        };
+        match socket.local_addr() {
+            Ok(addr) => {
+                info!("Listening for StatsD on {}", addr);
+                bound.push((listen.clone(), addr));
+            }
+            Err(err) => error!("Unable to get StatsD address {}: {}", listen, err),
+        }
+        let tx = tx.clone();
+        tokio::spawn(
+            UdpFramed::new(socket, BytesCodec::new())
+                .map_err(|e| error!("StatsD socket errored; err={:?}", e))
+                .for_each(move |(packet, _peer)| {
+                    let lines = statsd::parse_packet(&String::from_utf8_lossy(&packet));
+                    debug!("Received {} StatsD lines", lines.len());
+                    tx.clone()
+                        .send(AsyncChartTask::LoadStatsd(
+                            listen.clone(),
+                            epoch_now(),
+                            lines,
+                        ))
+                        .map(|_| ())
+                        .map_err(|e| error!("Sending LoadStatsd Task: err={:?}", e))
+                }),
+        );
+    }
+    bound
+}
+
+/// `send_lines` parses the `text` received on `listen` and sends the samples
+/// to the coordinator
+fn send_lines(
+    tx: &mpsc::Sender<AsyncChartTask>,
+    listen: &str,
+    protocol: LineProtocol,
+    text: &str,
+) -> impl Future<Item = (), Error = ()> {
+    let samples = line_protocol::parse_lines(protocol, text);
+    debug!("Received {} {:?} samples", samples.len(), protocol);
+    tx.clone()
+        .send(AsyncChartTask::LoadLines(
+            listen.to_string(),
+            epoch_now(),
+            samples,
+        ))
+        .map(|_| ())
+        .map_err(|e| error!("Sending LoadLines Task: err={:?}", e))
+}
+
+/// `spawn_line_listener` listens for `protocol` on `listen`, a connection is
+/// served per TCP client and every datagram may contain several lines
+fn spawn_line_listener(
+    listen: String,
+    protocol: LineProtocol,
+    tx: mpsc::Sender<AsyncChartTask>,
+) -> Result<SocketAddr, String> {
+    let (transport, addr) = line_protocol::parse_listen(&listen)?;
+    match transport {
+        Transport::Udp => {
+            let socket = UdpSocket::bind(&addr).map_err(|err| err.to_string())?;
+            let addr = socket.local_addr().map_err(|err| err.to_string())?;
+            tokio::spawn(
+                UdpFramed::new(socket, BytesCodec::new())
+                    .map_err(|e| error!("Line protocol socket errored; err={:?}", e))
+                    .for_each(move |(packet, _peer)| {
+                        send_lines(&tx, &listen, protocol, &String::from_utf8_lossy(&packet))
+                    }),
+            );
+            Ok(addr)
+        }
+        Transport::Tcp => {
+            let listener = TcpListener::bind(&addr).map_err(|err| err.to_string())?;
+            let addr = listener.local_addr().map_err(|err| err.to_string())?;
+            tokio::spawn(
+                listener
+                    .incoming()
+                    .map_err(|e| error!("Line protocol listener errored; err={:?}", e))
+                    .for_each(move |socket| {
+                        let tx = tx.clone();
+                        let listen = listen.clone();
+                        tokio::spawn(
+                            FramedRead::new(socket, LinesCodec::new_with_max_length(64 * 1024))
+                                .map_err(|e| {
+                                    error!("Line protocol connection errored; err={:?}", e)
+                                })
+                                .for_each(move |line| send_lines(&tx, &listen, protocol, &line)),
+                        );
+                        Ok(())
+                    }),
+            );
+            Ok(addr)
+        }
+    }
+}
+
+/// `spawn_line_listeners` listens on the sockets of the Influx and Graphite
+/// series, the lines received are sent to the coordinator. It must be called
+/// from a tokio runtime and returns the bound addresses.
+pub fn spawn_line_listeners(
+    charts: &[TimeSeriesChart],
+    tx: &mpsc::Sender<AsyncChartTask>,
+) -> Vec<(String, SocketAddr)> {
+    let mut listens: Vec<(String, LineProtocol)> = vec![];
+    for chart in charts {
+        for series in &chart.sources {
+            let (listen, protocol) = match series {
+                TimeSeriesSource::InfluxTimeSeries(ref influx) => {
+                    (influx.listen_addr(), LineProtocol::Influx)
+                }
+                TimeSeriesSource::GraphiteTimeSeries(ref graphite) => {
+                    (graphite.listen_addr(), LineProtocol::Graphite)
+                }
+                _ => continue,
+            };
+            match listens.iter().find(|(other, _)| other == listen) {
+                Some((_, other)) if *other != protocol => error!(
+                    "Series '{}' listens for {:?} on {} which receives {:?}",
+                    series.name(),
+                    protocol,
+                    listen,
+                    other
+                ),
+                Some(_) => {}
+                None => listens.push((listen.to_string(), protocol)),
+            }
+        }
+    }
+    let mut bound = vec![];
+    for (listen, protocol) in listens {
+        match spawn_line_listener(listen.clone(), protocol, tx.clone()) {
+            Ok(addr) => {
+                info!("Listening for {:?} on {}", protocol, addr);
+                bound.push((listen, addr));
+            }
+            Err(err) => error!("Not listening for {:?} on {}: {}", protocol, listen, err),
+        }
+    }
+    bound
+}
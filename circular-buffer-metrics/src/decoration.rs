@@ -0,0 +1,611 @@
+//! Decorations drawn from the values of a series of the chart: threshold
+//! coloring, moving average, min/max envelope and alert marker.
+//! They work on the opengl vertices of the series, the Y coordinate grows
+//! linearly with the value so the averages, the minimums and the maximums of
+//! the vertices are those of the values. Like the ReferencePointDecoration,
+//! the vertices are GL_LINES, 4 floats per line.
+use crate::color::Color;
+use crate::{SizeInfo, TimeSeries, Value2D};
+use log::*;
+
+/// `finite_points` returns the (x, y) vertices of `series`, None for the
+/// missing values
+fn finite_points(series: &[f32]) -> Vec<Option<(f32, f32)>> {
+    series
+        .chunks(2)
+        .filter(|vertex| vertex.len() == 2)
+        .map(|vertex| {
+            if vertex[0].is_finite() && vertex[1].is_finite() {
+                Some((vertex[0], vertex[1]))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// `value_points` returns the values of `series` as points, as they are drawn
+/// after the missing values policy is applied. Only their Y is used.
+fn value_points(series: &TimeSeries) -> Vec<Option<(f32, f32)>> {
+    series
+        .as_filled_vec()
+        .iter()
+        .map(|(_, value)| value.map(|value| (0., value as f32)))
+        .collect()
+}
+
+/// `value_range` returns the lowest and highest Y of `points`
+fn value_range(points: &[Option<(f32, f32)>]) -> Option<(f64, f64)> {
+    points
+        .iter()
+        .filter_map(|point| point.map(|(_, y)| f64::from(y)))
+        .fold(None, |range, y| match range {
+            Some((min, max)) => Some((y.min(min), y.max(max))),
+            None => Some((y, y)),
+        })
+}
+
+/// `average` returns the average of `ys`
+fn average(ys: &[f32]) -> f32 {
+    ys.iter().sum::<f32>() / ys.len() as f32
+}
+
+/// `push_line` appends a GL_LINES segment to `vertices`
+fn push_line(vertices: &mut Vec<f32>, start: (f32, f32), end: (f32, f32)) {
+    vertices.extend_from_slice(&[start.0, start.1, end.0, end.1]);
+}
+
+/// `push_polyline` appends the segments between consecutive points, the
+/// missing points break the line
+fn push_polyline(vertices: &mut Vec<f32>, points: &[Option<(f32, f32)>]) {
+    for segment in points.windows(2) {
+        if let (Some(start), Some(end)) = (segment[0], segment[1]) {
+            push_line(vertices, start, end);
+        }
+    }
+}
+
+/// `rolling` applies `reduce` to the Y of the last `window` points of
+/// each point, the missing points are skipped. The point is missing when its
+/// window has no values.
+fn rolling<F>(points: &[Option<(f32, f32)>], window: usize, reduce: F) -> Vec<Option<(f32, f32)>>
+where
+    F: Fn(&[f32]) -> f32,
+{
+    let window = window.max(1);
+    let mut ys = Vec::with_capacity(window);
+    (0..points.len())
+        .map(|idx| {
+            let x = points[idx]?.0;
+            ys.clear();
+            ys.extend(
+                points[(idx + 1).saturating_sub(window)..=idx]
+                    .iter()
+                    .filter_map(|point| point.map(|(_, y)| y)),
+            );
+            Some((x, reduce(&ys)))
+        })
+        .collect()
+}
+
+/// `ThresholdDecoration` recolors the segments of a series above (or below)
+/// a value
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct ThresholdDecoration {
+    /// The value from which the series is recolored
+    pub value: f64,
+
+    /// Recolor the segments below the value instead of above
+    pub below: bool,
+
+    /// The index of the series in the chart to recolor
+    pub series_index: usize,
+
+    /// The color of the segments past the value, red by default
+    pub color: Option<Color>,
+
+    /// Transparency
+    pub alpha: f32,
+
+    /// The opengl vertices are stored in this vector
+    pub opengl_data: Vec<f32>,
+}
+
+impl Default for ThresholdDecoration {
+    fn default() -> ThresholdDecoration {
+        ThresholdDecoration {
+            value: 1.0,
+            below: false,
+            series_index: 0,
+            color: None,
+            alpha: 1.0,
+            opengl_data: vec![],
+        }
+    }
+}
+
+impl ThresholdDecoration {
+    /// `top_value` is the value, so that it is always in the chart
+    pub fn top_value(&self) -> f64 {
+        self.value
+    }
+
+    /// `bottom_value` is the value, so that it is always in the chart
+    pub fn bottom_value(&self) -> f64 {
+        self.value
+    }
+
+    /// `update_opengl_vecs` draws over the segments of the series that are
+    /// past the value, the segments crossing it are cut at the crossing
+    pub fn update_opengl_vecs(
+        &mut self,
+        display_size: SizeInfo,
+        chart_max_value: f64,
+        series: &[Vec<f32>],
+    ) {
+        self.opengl_data.clear();
+        let points = match series.get(self.series_index) {
+            Some(series) => finite_points(series),
+            None => return,
+        };
+        let threshold = display_size.scale_y(chart_max_value, self.value);
+        let below = self.below;
+        let is_past = |y: f32| if below { y < threshold } else { y > threshold };
+        for segment in points.windows(2) {
+            let (start, end) = match (segment[0], segment[1]) {
+                (Some(start), Some(end)) => (start, end),
+                _ => continue,
+            };
+            // The point of the segment at the threshold
+            let crossing = || {
+                let t = (threshold - start.1) / (end.1 - start.1);
+                (start.0 + t * (end.0 - start.0), threshold)
+            };
+            match (is_past(start.1), is_past(end.1)) {
+                (true, true) => push_line(&mut self.opengl_data, start, end),
+                (true, false) => push_line(&mut self.opengl_data, start, crossing()),
+                (false, true) => push_line(&mut self.opengl_data, crossing(), end),
+                (false, false) => {}
+            }
+        }
+        debug!(
+            "ThresholdDecoration: Finished update_opengl_vecs: {} lines",
+            self.opengl_data.len() / 4
+        );
+    }
+}
+
+/// `MovingAverageDecoration` draws the average of the last `window` values
+/// of a series over it
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct MovingAverageDecoration {
+    /// The number of values averaged, the missing values are skipped
+    pub window: usize,
+
+    /// The index of the series in the chart to average
+    pub series_index: usize,
+
+    /// The color of the average, red by default
+    pub color: Option<Color>,
+
+    /// Transparency
+    pub alpha: f32,
+
+    /// The lowest and highest averages of the series values
+    #[serde(skip)]
+    pub value_range: Option<(f64, f64)>,
+
+    /// The opengl vertices are stored in this vector
+    pub opengl_data: Vec<f32>,
+}
+
+impl Default for MovingAverageDecoration {
+    fn default() -> MovingAverageDecoration {
+        MovingAverageDecoration {
+            window: 10,
+            series_index: 0,
+            color: None,
+            alpha: 1.0,
+            value_range: None,
+            opengl_data: vec![],
+        }
+    }
+}
+
+impl MovingAverageDecoration {
+    /// `update_value_range` finds the lowest and highest averages of the
+    /// `series` values
+    pub fn update_value_range(&mut self, series: &TimeSeries) {
+        self.value_range = value_range(&rolling(&value_points(series), self.window, average));
+    }
+
+    /// `top_value` is the highest average, nothing while there are no values
+    pub fn top_value(&self) -> f64 {
+        self.value_range.map_or(f64::MIN, |(_, max)| max)
+    }
+
+    /// `bottom_value` is the lowest average, nothing while there are no values
+    pub fn bottom_value(&self) -> f64 {
+        self.value_range.map_or(f64::MAX, |(min, _)| min)
+    }
+
+    /// `update_opengl_vecs` draws the line of the averages
+    pub fn update_opengl_vecs(&mut self, series: &[Vec<f32>]) {
+        self.opengl_data.clear();
+        if let Some(series) = series.get(self.series_index) {
+            let averages = rolling(&finite_points(series), self.window, average);
+            push_polyline(&mut self.opengl_data, &averages);
+        }
+        debug!(
+            "MovingAverageDecoration: Finished update_opengl_vecs: {} lines",
+            self.opengl_data.len() / 4
+        );
+    }
+}
+
+/// `EnvelopeDecoration` draws a band between the minimum and the maximum of
+/// the last `window` values of a series
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct EnvelopeDecoration {
+    /// The number of values the minimum and maximum are taken from
+    pub window: usize,
+
+    /// The index of the series in the chart
+    pub series_index: usize,
+
+    /// The color of the band, red by default
+    pub color: Option<Color>,
+
+    /// Transparency, the band is usually translucent
+    pub alpha: f32,
+
+    /// The lowest minimum and the highest maximum of the band
+    #[serde(skip)]
+    pub value_range: Option<(f64, f64)>,
+
+    /// The opengl vertices are stored in this vector
+    pub opengl_data: Vec<f32>,
+}
+
+impl Default for EnvelopeDecoration {
+    fn default() -> EnvelopeDecoration {
+        EnvelopeDecoration {
+            window: 10,
+            series_index: 0,
+            color: None,
+            alpha: 0.3,
+            value_range: None,
+            opengl_data: vec![],
+        }
+    }
+}
+
+impl EnvelopeDecoration {
+    /// `update_value_range` finds the lowest minimum and the highest maximum
+    /// of the band drawn from the `series` values
+    pub fn update_value_range(&mut self, series: &TimeSeries) {
+        let points = value_points(series);
+        let max = value_range(&rolling(&points, self.window, |ys| {
+            ys.iter().cloned().fold(f32::MIN, f32::max)
+        }));
+        let min = value_range(&rolling(&points, self.window, |ys| {
+            ys.iter().cloned().fold(f32::MAX, f32::min)
+        }));
+        self.value_range = match (min, max) {
+            (Some((min, _)), Some((_, max))) => Some((min, max)),
+            _ => None,
+        };
+    }
+
+    /// `top_value` is the top of the band, nothing while there are no values
+    pub fn top_value(&self) -> f64 {
+        self.value_range.map_or(f64::MIN, |(_, max)| max)
+    }
+
+    /// `bottom_value` is the bottom of the band, nothing while there are no
+    /// values
+    pub fn bottom_value(&self) -> f64 {
+        self.value_range.map_or(f64::MAX, |(min, _)| min)
+    }
+
+    /// `update_opengl_vecs` draws the maximum and minimum lines, and fills
+    /// the band with a vertical line at each value
+    pub fn update_opengl_vecs(&mut self, series: &[Vec<f32>]) {
+        self.opengl_data.clear();
+        let points = match series.get(self.series_index) {
+            Some(series) => finite_points(series),
+            None => return,
+        };
+        let max = rolling(&points, self.window, |ys| {
+            ys.iter().cloned().fold(f32::MIN, f32::max)
+        });
+        let min = rolling(&points, self.window, |ys| {
+            ys.iter().cloned().fold(f32::MAX, f32::min)
+        });
+        push_polyline(&mut self.opengl_data, &max);
+        push_polyline(&mut self.opengl_data, &min);
+        for (top, bottom) in max.iter().zip(min.iter()) {
+            if let (Some(top), Some(bottom)) = (top, bottom) {
+                if top.1 > bottom.1 {
+                    push_line(&mut self.opengl_data, *bottom, *top);
+                }
+            }
+        }
+        debug!(
+            "EnvelopeDecoration: Finished update_opengl_vecs: {} lines",
+            self.opengl_data.len() / 4
+        );
+    }
+}
+
+/// `AlertDecoration` draws a marker pointing at the alert value in the right
+/// padding of the chart while the last value of a series is above it
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct AlertDecoration {
+    /// The value from which the alert fires
+    pub value: f64,
+
+    /// The index of the series in the chart to watch
+    pub series_index: usize,
+
+    /// The height of the marker, as a percentage of the value
+    pub height_multiplier: f64,
+
+    /// The color of the marker, red by default
+    pub color: Option<Color>,
+
+    /// Transparency
+    pub alpha: f32,
+
+    /// The pixels to separate from the left and right, the marker is drawn
+    /// in the right one
+    pub padding: Value2D,
+
+    /// The opengl vertices are stored in this vector, empty while the alert
+    /// does not fire
+    pub opengl_data: Vec<f32>,
+}
+
+impl Default for AlertDecoration {
+    fn default() -> AlertDecoration {
+        AlertDecoration {
+            value: 1.0,
+            series_index: 0,
+            height_multiplier: 0.05,
+            color: None,
+            alpha: 1.0,
+            padding: Value2D { x: 10., y: 0. },
+            opengl_data: vec![],
+        }
+    }
+}
+
+impl AlertDecoration {
+    /// `top_value` is the top of the marker, above the value even when it
+    /// is negative
+    pub fn top_value(&self) -> f64 {
+        self.value + self.value.abs() * self.height_multiplier
+    }
+
+    /// `bottom_value` is the bottom of the marker
+    pub fn bottom_value(&self) -> f64 {
+        self.value - self.value.abs() * self.height_multiplier
+    }
+
+    /// `is_firing` returns true when the last value of the series is above
+    /// the alert value
+    pub fn is_firing(&self, display_size: SizeInfo, chart_max_value: f64, series: &[f32]) -> bool {
+        let threshold = display_size.scale_y(chart_max_value, self.value);
+        finite_points(series)
+            .iter()
+            .rev()
+            .find_map(|point| *point)
+            .is_some_and(|(_, y)| y > threshold)
+    }
+
+    /// `update_opengl_vecs` draws a triangle pointing left at the value:
+    ///        x1,y2
+    /// x2,y1 <  |
+    ///        x1,y3
+    /// where x1 is the right edge of the chart and x2 is half the padding
+    /// left of it
+    pub fn update_opengl_vecs(
+        &mut self,
+        display_size: SizeInfo,
+        offset: Value2D,
+        chart_max_value: f64,
+        series: &[Vec<f32>],
+    ) {
+        self.opengl_data.clear();
+        let is_firing = series
+            .get(self.series_index)
+            .is_some_and(|series| self.is_firing(display_size, chart_max_value, series));
+        if !is_firing {
+            return;
+        }
+        let right = offset.x + display_size.chart_width;
+        let x1 = display_size.scale_x(right);
+        let x2 = display_size.scale_x(right - self.padding.x / 2.);
+        let y1 = display_size.scale_y(chart_max_value, self.value);
+        let y2 = display_size.scale_y(chart_max_value, self.top_value());
+        let y3 = display_size.scale_y(chart_max_value, self.bottom_value());
+        push_line(&mut self.opengl_data, (x1, y2), (x1, y3));
+        push_line(&mut self.opengl_data, (x1, y3), (x2, y1));
+        push_line(&mut self.opengl_data, (x2, y1), (x1, y2));
+        debug!(
+            "AlertDecoration: Finished update_opengl_vecs: {:?}",
+            self.opengl_data
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size() -> SizeInfo {
+        // 1 unit of value is 0.1 in opengl, from -1.0 for 0 to 1.0 for 20
+        SizeInfo {
+            width: 200.,
+            height: 200.,
+            chart_width: 200.,
+            chart_height: 200.,
+            ..SizeInfo::default()
+        }
+    }
+
+    /// `series` returns the vertices of the values, 0.1 apart on X
+    fn series(values: &[f32]) -> Vec<Vec<f32>> {
+        vec![values
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, value)| vec![idx as f32 / 10., value / 10. - 1.])
+            .collect()]
+    }
+
+    fn assert_vertices(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (actual, expected) in actual.iter().zip(expected.iter()) {
+            assert!((actual - expected).abs() < 1e-5, "{:?}", actual);
+        }
+    }
+
+    #[test]
+    fn it_recolors_past_a_threshold() {
+        let mut test = ThresholdDecoration {
+            value: 10.,
+            ..ThresholdDecoration::default()
+        };
+        let vertices = series(&[0., 20., 16., f32::NAN, 12., 4.]);
+        test.update_opengl_vecs(size(), 20., &vertices);
+        #[rustfmt::skip]
+        assert_vertices(
+            &test.opengl_data,
+            &[
+                0.05, 0., 0.1, 1.,      // Crossing up at 10
+                0.1, 1., 0.2, 0.6,      // Above
+                0.4, 0.2, 0.425, 0.,    // Crossing down, the gap is skipped
+            ],
+        );
+        test.below = true;
+        test.update_opengl_vecs(size(), 20., &vertices);
+        #[rustfmt::skip]
+        assert_vertices(
+            &test.opengl_data,
+            &[
+                0., -1., 0.05, 0.,
+                0.425, 0., 0.5, -0.6,
+            ],
+        );
+        test.series_index = 1;
+        test.update_opengl_vecs(size(), 20., &vertices);
+        assert!(test.opengl_data.is_empty());
+    }
+
+    #[test]
+    fn it_finds_the_value_range_of_averages_and_envelopes() {
+        let mut series = TimeSeries::default()
+            .with_capacity(5)
+            .with_missing_values_policy(String::from("gap"));
+        for (epoch, value) in &[(100, 2.), (101, 4.), (103, 12.), (104, 0.)] {
+            series.push((*epoch, *value));
+        }
+        let mut average = MovingAverageDecoration {
+            window: 2,
+            ..MovingAverageDecoration::default()
+        };
+        assert_eq!(average.top_value(), f64::MIN);
+        assert_eq!(average.bottom_value(), f64::MAX);
+        average.update_value_range(&series);
+        // The averages are 2, 3, missing, 12 (4 is out of the window), 6
+        assert_eq!(average.value_range, Some((2., 12.)));
+        assert_eq!(average.top_value(), 12.);
+        assert_eq!(average.bottom_value(), 2.);
+        let mut envelope = EnvelopeDecoration {
+            window: 3,
+            ..EnvelopeDecoration::default()
+        };
+        envelope.update_value_range(&series);
+        assert_eq!(envelope.top_value(), 12.);
+        assert_eq!(envelope.bottom_value(), 0.);
+    }
+
+    #[test]
+    fn it_draws_moving_averages_and_envelopes() {
+        let vertices = series(&[2., 4., f32::NAN, 12., 0.]);
+        let mut average = MovingAverageDecoration {
+            window: 2,
+            ..MovingAverageDecoration::default()
+        };
+        average.update_opengl_vecs(&vertices);
+        // The averages are 2, 3, missing, 12 (4 is out of the window), 6
+        #[rustfmt::skip]
+        assert_vertices(
+            &average.opengl_data,
+            &[
+                0., -0.8, 0.1, -0.7,
+                0.3, 0.2, 0.4, -0.4,
+            ],
+        );
+        let mut envelope = EnvelopeDecoration {
+            window: 3,
+            ..EnvelopeDecoration::default()
+        };
+        envelope.update_opengl_vecs(&vertices);
+        // The maximums are 2, 4, missing, 12, 12 and the minimums 2, 2,
+        // missing, 4, 0
+        #[rustfmt::skip]
+        assert_vertices(
+            &envelope.opengl_data,
+            &[
+                0., -0.8, 0.1, -0.6,
+                0.3, 0.2, 0.4, 0.2,
+                0., -0.8, 0.1, -0.8,
+                0.3, -0.6, 0.4, -1.,
+                0.1, -0.8, 0.1, -0.6,
+                0.3, -0.6, 0.3, 0.2,
+                0.4, -1., 0.4, 0.2,
+            ],
+        );
+    }
+
+    #[test]
+    fn it_fires_alerts() {
+        let mut test = AlertDecoration {
+            value: 10.,
+            height_multiplier: 0.1,
+            padding: Value2D { x: 20., y: 0. },
+            ..AlertDecoration::default()
+        };
+        assert_eq!(test.top_value(), 11.);
+        assert_eq!(test.bottom_value(), 9.);
+        let offset = Value2D { x: 0., y: 0. };
+        test.update_opengl_vecs(size(), offset, 20., &series(&[12., 8.]));
+        assert!(test.opengl_data.is_empty());
+        // Reaching the value is not above it
+        test.update_opengl_vecs(size(), offset, 20., &series(&[12., 10.]));
+        assert!(test.opengl_data.is_empty());
+        // The last value counts, the missing values are skipped
+        test.update_opengl_vecs(size(), offset, 20., &series(&[8., 12., f32::NAN]));
+        #[rustfmt::skip]
+        assert_vertices(
+            &test.opengl_data,
+            &[
+                1., 0.1, 1., -0.1,
+                1., -0.1, 0.9, 0.,
+                0.9, 0., 1., 0.1,
+            ],
+        );
+        let negative = AlertDecoration {
+            value: -10.,
+            height_multiplier: 0.1,
+            ..AlertDecoration::default()
+        };
+        assert_eq!(negative.top_value(), -9.);
+        assert_eq!(negative.bottom_value(), -11.);
+    }
+}
@@ -0,0 +1,409 @@
+//! Exposes the collected series and the internal stats of the polls in the
+//! Prometheus text format, so that the activity of the terminal itself can
+//! be scraped by an existing monitoring setup.
+use crate::coordinator::AsyncChartTask;
+use crate::{TimeSeriesChart, TimeSeriesSource};
+use futures::sync::{mpsc, oneshot};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::*;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use tokio::prelude::*;
+
+/// The prefix of the exported metric names
+const METRIC_PREFIX: &str = "circular_buffer_metrics_";
+
+/// `ExportConfig` contains the settings of the /metrics endpoint in charts.yml,
+/// the settings left out take the Default values
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct ExportConfig {
+    /// The address to listen on, i.e. 127.0.0.1:9833
+    pub listen: String,
+
+    /// The path the metrics are served on
+    pub path: String,
+}
+
+impl Default for ExportConfig {
+    fn default() -> ExportConfig {
+        ExportConfig {
+            listen: String::from("127.0.0.1:9833"),
+            path: String::from("/metrics"),
+        }
+    }
+}
+
+/// `ExportedFamily` accumulates the lines of a metric family
+struct ExportedFamily {
+    name: &'static str,
+    metric_type: &'static str,
+    help: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+impl ExportedFamily {
+    fn new(name: &'static str, metric_type: &'static str, help: &'static str) -> ExportedFamily {
+        ExportedFamily {
+            name,
+            metric_type,
+            help,
+            samples: vec![],
+        }
+    }
+
+    /// `write` appends the family to `output`, families without samples are
+    /// left out
+    fn write(&self, output: &mut String) {
+        if self.samples.is_empty() {
+            return;
+        }
+        let name = format!("{}{}", METRIC_PREFIX, self.name);
+        let _ = writeln!(output, "# HELP {} {}", name, self.help);
+        let _ = writeln!(output, "# TYPE {} {}", name, self.metric_type);
+        for (labels, value) in &self.samples {
+            let _ = writeln!(output, "{}{{{}}} {}", name, labels, format_value(*value));
+        }
+    }
+}
+
+/// `format_value` formats a sample value, infinities are written as +Inf
+/// and -Inf
+fn format_value(value: f64) -> String {
+    if value.is_infinite() {
+        String::from(if value > 0. { "+Inf" } else { "-Inf" })
+    } else {
+        value.to_string()
+    }
+}
+
+/// `escape_label` escapes the backslashes, quotes and newlines of a label
+/// value
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// `source_type` returns the type of a source as written in charts.yml
+fn source_type(source: &TimeSeriesSource) -> &'static str {
+    match source {
+        TimeSeriesSource::PrometheusTimeSeries(_) => "prometheus",
+        TimeSeriesSource::AlacrittyInput(_) => "alacritty_input",
+        TimeSeriesSource::AlacrittyOutput(_) => "alacritty_output",
+        TimeSeriesSource::AsyncLoadedItems(_) => "async_items_loaded",
+        TimeSeriesSource::ScrapeTimeSeries(_) => "scrape",
+        TimeSeriesSource::StatsdTimeSeries(_) => "statsd",
+        TimeSeriesSource::CommandTimeSeries(_) => "command",
+        TimeSeriesSource::TailTimeSeries(_) => "tail",
+        TimeSeriesSource::InfluxTimeSeries(_) => "influx",
+        TimeSeriesSource::GraphiteTimeSeries(_) => "graphite",
+    }
+}
+
+/// `render_exposition` returns the series of `charts` and the health of the
+/// sources loaded from the network at epoch `now` in the Prometheus text
+/// format. The series split by labels report the polls of their query. The
+/// `chart_index` and `series_index` labels keep the label sets unique when
+/// names repeat.
+pub fn render_exposition(charts: &[TimeSeriesChart], now: u64) -> String {
+    let mut last_value = ExportedFamily::new(
+        "series_last_value",
+        "gauge",
+        "The last value loaded into the series.",
+    );
+    let mut window_sum = ExportedFamily::new(
+        "series_window_sum",
+        "gauge",
+        "The sum of the values in the time range of the series.",
+    );
+    let mut dropped = ExportedFamily::new(
+        "series_dropped_items_total",
+        "counter",
+        "The items dropped because they were older than the time range.",
+    );
+    let mut successes = ExportedFamily::new(
+        "poll_successes_total",
+        "counter",
+        "The number of successful polls.",
+    );
+    let mut failures = ExportedFamily::new(
+        "poll_failures_total",
+        "counter",
+        "The number of failed polls.",
+    );
+    let mut consecutive_failures = ExportedFamily::new(
+        "poll_consecutive_failures",
+        "gauge",
+        "The number of failed polls since the last successful one.",
+    );
+    let mut duration = ExportedFamily::new(
+        "poll_duration_seconds",
+        "gauge",
+        "The time the last poll took.",
+    );
+    let mut last_success = ExportedFamily::new(
+        "poll_last_success_timestamp_seconds",
+        "gauge",
+        "The epoch of the last successful poll.",
+    );
+    let mut stale = ExportedFamily::new(
+        "series_stale",
+        "gauge",
+        "1 when the series has not been loaded for too long.",
+    );
+    let mut records_loaded = ExportedFamily::new(
+        "records_loaded_total",
+        "counter",
+        "The number of items loaded into the series.",
+    );
+    for (chart_index, chart) in charts.iter().enumerate() {
+        for (series_index, source) in chart.sources.iter().enumerate() {
+            let labels = format!(
+                "chart=\"{}\",chart_index=\"{}\",series=\"{}\",series_index=\"{}\",type=\"{}\"",
+                escape_label(&chart.name),
+                chart_index,
+                escape_label(&source.name()),
+                series_index,
+                source_type(source)
+            );
+            let series = source.series();
+            let values: Vec<f64> = series.iter().filter_map(|(_, value)| *value).collect();
+            if let Some(value) = values.last() {
+                last_value.samples.push((labels.clone(), *value));
+            }
+            window_sum
+                .samples
+                .push((labels.clone(), values.iter().sum()));
+            dropped
+                .samples
+                .push((labels.clone(), series.dropped_items as f64));
+            if let Some(health) = source.health() {
+                successes
+                    .samples
+                    .push((labels.clone(), health.successes as f64));
+                failures
+                    .samples
+                    .push((labels.clone(), health.failures as f64));
+                consecutive_failures
+                    .samples
+                    .push((labels.clone(), f64::from(health.consecutive_failures)));
+                records_loaded
+                    .samples
+                    .push((labels.clone(), health.records_loaded as f64));
+                if let Some(seconds) = health.last_poll_duration {
+                    duration.samples.push((labels.clone(), seconds));
+                }
+                if let Some(epoch) = health.last_success {
+                    last_success.samples.push((labels.clone(), epoch as f64));
+                }
+                let is_stale = if source.is_stale(now) { 1. } else { 0. };
+                stale.samples.push((labels, is_stale));
+            }
+        }
+    }
+    let mut output = String::new();
+    for family in &[
+        last_value,
+        window_sum,
+        dropped,
+        successes,
+        failures,
+        consecutive_failures,
+        duration,
+        last_success,
+        stale,
+        records_loaded,
+    ] {
+        family.write(&mut output);
+    }
+    output
+}
+
+/// `serve_exposition` asks the coordinator for the exposition of the charts
+fn serve_exposition(
+    tx: mpsc::Sender<AsyncChartTask>,
+) -> impl Future<Item = Response<Body>, Error = hyper::Error> {
+    let (exposition_tx, exposition_rx) = oneshot::channel();
+    tx.send(AsyncChartTask::GetExposition(exposition_tx))
+        .map_err(|e| format!("Sending GetExposition Task: err={:?}", e))
+        .and_then(|_| exposition_rx.map_err(|e| format!("Receiving the exposition: err={:?}", e)))
+        .then(|res| {
+            let response = match res {
+                Ok(exposition) => Response::builder()
+                    .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                    .body(Body::from(exposition)),
+                Err(err) => {
+                    error!("serve_exposition: {}", err);
+                    Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .body(Body::from(err))
+                }
+            };
+            Ok(response.unwrap())
+        })
+}
+
+/// `respond` serves the exposition on the configured path
+fn respond(
+    req: &Request<Body>,
+    path: &str,
+    tx: &mpsc::Sender<AsyncChartTask>,
+) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+    let status = if req.uri().path() != path {
+        StatusCode::NOT_FOUND
+    } else if req.method() != Method::GET && req.method() != Method::HEAD {
+        StatusCode::METHOD_NOT_ALLOWED
+    } else {
+        return Box::new(serve_exposition(tx.clone()));
+    };
+    Box::new(future::ok(
+        Response::builder()
+            .status(status)
+            .body(Body::from(status.to_string()))
+            .unwrap(),
+    ))
+}
+
+/// `spawn_export_server` binds the /metrics endpoint, it returns the bound
+/// address and the server to spawn on a tokio runtime
+pub fn spawn_export_server(
+    config: &ExportConfig,
+    tx: mpsc::Sender<AsyncChartTask>,
+) -> Result<(SocketAddr, impl Future<Item = (), Error = ()>), String> {
+    let addr: SocketAddr = config
+        .listen
+        .parse()
+        .map_err(|err| format!("Invalid listen address {}: {}", config.listen, err))?;
+    let path = config.path.clone();
+    let server = Server::try_bind(&addr)
+        .map_err(|err| format!("Unable to listen on {}: {}", addr, err))?
+        .serve(move || {
+            let tx = tx.clone();
+            let path = path.clone();
+            service_fn(move |req| respond(&req, &path, &tx))
+        });
+    let addr = server.local_addr();
+    info!("spawn_export_server: Serving {}{}", addr, config.path);
+    Ok((
+        addr,
+        server.map_err(|err| error!("spawn_export_server: {}", err)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrape::parse_exposition;
+
+    #[test]
+    fn it_defaults_export_config() {
+        let config: ExportConfig = serde_yaml::from_str("listen: 0.0.0.0:9833").unwrap();
+        assert_eq!(config.listen, "0.0.0.0:9833");
+        assert_eq!(config.path, "/metrics");
+        let config: ExportConfig = serde_yaml::from_str("path: /stats").unwrap();
+        assert_eq!(config.listen, "127.0.0.1:9833");
+        assert_eq!(config.path, "/stats");
+    }
+
+    #[test]
+    fn it_renders_the_exposition() {
+        let mut charts: Vec<TimeSeriesChart> = serde_yaml::from_str(
+            r#"
+            - name: 'input "keyboard"'
+              series:
+              - name: input keystrokes
+                type: alacritty_input
+            - name: load
+              series:
+              - name: load 1
+                type: prometheus
+                refresh: 15
+                source: 'http://localhost:9090/api/v1/query_range?query=node_load1'
+            "#,
+        )
+        .unwrap();
+        charts[0].sources[0].series_mut().push((100, 3.));
+        charts[0].sources[0].series_mut().push((101, 4.));
+        charts[1].record_failure(0, 100, "Timed out");
+        charts[1].record_success(0, 110, &[]);
+        charts[1].record_loaded(0, 42);
+        let exposition = render_exposition(&charts, 120);
+        assert!(exposition.contains(
+            "circular_buffer_metrics_series_last_value{chart=\"input \\\"keyboard\\\"\",\
+             chart_index=\"0\",series=\"input keystrokes\",series_index=\"0\",\
+             type=\"alacritty_input\"} 4\n"
+        ));
+        assert!(exposition.contains("# TYPE circular_buffer_metrics_poll_failures_total counter\n"));
+        // The exposition is valid
+        let families = parse_exposition(&exposition).unwrap();
+        let value = |name: &str, series: &str| -> Option<f64> {
+            families
+                .iter()
+                .flat_map(|family| family.samples.iter())
+                .find(|sample| {
+                    sample.name == format!("{}{}", METRIC_PREFIX, name)
+                        && sample.labels["series"] == series
+                })
+                .map(|sample| sample.value)
+        };
+        assert_eq!(value("series_window_sum", "input keystrokes"), Some(7.));
+        assert_eq!(value("poll_successes_total", "input keystrokes"), None);
+        assert_eq!(value("poll_successes_total", "load 1"), Some(1.));
+        assert_eq!(value("poll_failures_total", "load 1"), Some(1.));
+        assert_eq!(value("records_loaded_total", "load 1"), Some(42.));
+        assert_eq!(
+            value("poll_last_success_timestamp_seconds", "load 1"),
+            Some(110.)
+        );
+        assert_eq!(value("series_stale", "load 1"), Some(0.));
+        assert_eq!(value("series_last_value", "load 1"), None);
+        assert_eq!(value("poll_duration_seconds", "load 1"), None);
+    }
+
+    #[test]
+    fn it_keeps_repeated_names_unique() {
+        let mut charts: Vec<TimeSeriesChart> = serde_yaml::from_str(
+            "
+            - name: input
+              series:
+              - name: keys
+                type: alacritty_input
+              - name: keys
+                type: alacritty_input
+            - name: input
+              series:
+              - name: keys
+                type: alacritty_input
+            ",
+        )
+        .unwrap();
+        charts[0].sources[0].series_mut().push((100, 1.));
+        charts[0].sources[1].series_mut().push((100, 2.));
+        charts[1].sources[0].series_mut().push((100, 3.));
+        let exposition = render_exposition(&charts, 120);
+        let families = parse_exposition(&exposition).unwrap();
+        let sums: Vec<_> = families
+            .iter()
+            .flat_map(|family| family.samples.iter())
+            .filter(|sample| sample.name == format!("{}series_window_sum", METRIC_PREFIX))
+            .map(|sample| {
+                (
+                    sample.labels["chart_index"].clone(),
+                    sample.labels["series_index"].clone(),
+                    sample.value,
+                )
+            })
+            .collect();
+        assert_eq!(
+            sums,
+            vec![
+                (String::from("0"), String::from("0"), 1.),
+                (String::from("0"), String::from("1"), 2.),
+                (String::from("1"), String::from("0"), 3.),
+            ]
+        );
+    }
+}
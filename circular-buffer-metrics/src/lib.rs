@@ -13,39 +13,99 @@
 // -- The yaml should drive an array of activity dashboards
 // -- Tokio timers
 // -- Use prometheus queries instead of our own aggregation/etc.
+// -- mock the prometheus server and response
+// -- Group labels into separate colors
 // IN PROGRESS:
 // -- Logging
-// -- Group labels into separate colors (find something that does color spacing in rust)
 // TODO:
 // -- The dashboards should be toggable, some key combination
 // -- When activated on toggle it could blur a portion of the screen
 // -- derive builder
-// -- mock the prometheus server and response
 // -- We should re-use the circular_push for the opengl_vec
 
 extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+extern crate base64;
+extern crate bincode;
+extern crate crc32fast;
 extern crate futures;
 extern crate hyper;
+extern crate hyper_tls;
+extern crate native_tls;
 extern crate percent_encoding;
+extern crate regex;
 extern crate serde;
 extern crate serde_json;
 extern crate tokio;
 extern crate tokio_core;
-// use crate::term::color::Rgb;
+#[cfg(test)]
+extern crate tokio_tls;
+use crate::color::{Color, Rgb, DEFAULT_DECORATION_COLOR, DEFAULT_SERIES_COLOR};
 // use crate::term::SizeInfo;
 use log::*;
+use num_traits::{Bounded, Num, NumCast};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt::{self, Debug};
+use std::str::FromStr;
 use std::time::UNIX_EPOCH;
 
+pub mod color;
+pub mod command;
 pub mod config;
+pub mod coordinator;
+pub mod decoration;
+pub mod export;
+pub mod line_protocol;
+pub mod mock_prometheus;
 pub mod prometheus;
+pub mod render;
+pub mod rollup;
+pub mod scrape;
+pub mod snapshot;
+pub mod statsd;
+pub mod tail;
+pub mod terminal;
+
+/// `MetricValue` is implemented by the numeric types a TimeSeries can store,
+/// integer counters stay exact while floats can be used for gauges.
+pub trait MetricValue: Num + NumCast + Bounded + PartialOrd + Copy + Debug {
+    /// `bounded_add` adds `other`, stopping at the bounds of the type instead
+    /// of overflowing
+    fn bounded_add(self, other: Self) -> Self {
+        if other > Self::zero() && self > Self::max_value() - other {
+            Self::max_value()
+        } else if other < Self::zero() && self < Self::min_value() - other {
+            Self::min_value()
+        } else {
+            self + other
+        }
+    }
+
+    /// `bounded_sub` subtracts `other`, stopping at the bounds of the type
+    /// instead of overflowing, i.e. unsigned values stop at zero
+    fn bounded_sub(self, other: Self) -> Self {
+        if other > Self::zero() && self < Self::min_value() + other {
+            Self::min_value()
+        } else if other < Self::zero() && self > Self::max_value() + other {
+            Self::max_value()
+        } else {
+            self - other
+        }
+    }
+}
+
+impl<T> MetricValue for T where T: Num + NumCast + Bounded + PartialOrd + Copy + Debug {}
 
 /// `MissingValuesPolicy` provides several ways to deal with missing values
-/// when drawing the Metric
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// when drawing the Metric. It is read from its string representation, i.e.
+/// `zero`, `avg`, `p90`, `linear` or `fixed:2.5`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(try_from = "String", into = "String")]
 pub enum MissingValuesPolicy {
+    #[default]
     Zero,
     One,
     First,
@@ -54,75 +114,370 @@ pub enum MissingValuesPolicy {
     Avg,
     Max,
     Min,
+    Median,
+    P90,
+    P99,
+    /// Interpolates between the neighbouring filled slots
+    Linear,
+    /// Leaves the missing values out, breaking the drawn line
+    Gap,
+}
+
+impl FromStr for MissingValuesPolicy {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim().to_lowercase();
+        if let Some(value) = input.strip_prefix("fixed:") {
+            return value
+                .trim()
+                .parse::<f64>()
+                .map(MissingValuesPolicy::Fixed)
+                .map_err(|err| {
+                    format!("Invalid fixed missing values policy '{}': {}", value, err)
+                });
+        }
+        match input.as_ref() {
+            "zero" => Ok(MissingValuesPolicy::Zero),
+            "one" => Ok(MissingValuesPolicy::One),
+            "first" => Ok(MissingValuesPolicy::First),
+            "last" => Ok(MissingValuesPolicy::Last),
+            "avg" => Ok(MissingValuesPolicy::Avg),
+            "max" => Ok(MissingValuesPolicy::Max),
+            "min" => Ok(MissingValuesPolicy::Min),
+            "median" => Ok(MissingValuesPolicy::Median),
+            "p90" => Ok(MissingValuesPolicy::P90),
+            "p99" => Ok(MissingValuesPolicy::P99),
+            "linear" => Ok(MissingValuesPolicy::Linear),
+            "gap" => Ok(MissingValuesPolicy::Gap),
+            _ => Err(format!("Unknown missing values policy: '{}'", input)),
+        }
+    }
+}
+
+impl fmt::Display for MissingValuesPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissingValuesPolicy::Zero => write!(f, "zero"),
+            MissingValuesPolicy::One => write!(f, "one"),
+            MissingValuesPolicy::First => write!(f, "first"),
+            MissingValuesPolicy::Last => write!(f, "last"),
+            MissingValuesPolicy::Fixed(value) => write!(f, "fixed:{}", value),
+            MissingValuesPolicy::Avg => write!(f, "avg"),
+            MissingValuesPolicy::Max => write!(f, "max"),
+            MissingValuesPolicy::Min => write!(f, "min"),
+            MissingValuesPolicy::Median => write!(f, "median"),
+            MissingValuesPolicy::P90 => write!(f, "p90"),
+            MissingValuesPolicy::P99 => write!(f, "p99"),
+            MissingValuesPolicy::Linear => write!(f, "linear"),
+            MissingValuesPolicy::Gap => write!(f, "gap"),
+        }
+    }
+}
+
+impl TryFrom<String> for MissingValuesPolicy {
+    type Error = String;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+impl From<MissingValuesPolicy> for String {
+    fn from(policy: MissingValuesPolicy) -> String {
+        policy.to_string()
+    }
+}
+
+impl MissingValuesPolicy {
+    /// `required_stat` returns the StatKind that needs to be calculated to
+    /// fill the missing values, if it is not kept up to date on every push.
+    fn required_stat(&self) -> Option<StatKind> {
+        match self {
+            MissingValuesPolicy::Median => Some(StatKind::P50),
+            MissingValuesPolicy::P90 => Some(StatKind::P90),
+            MissingValuesPolicy::P99 => Some(StatKind::P99),
+            _ => None,
+        }
+    }
+}
+
+/// `StatKind` allows selecting one of the TimeSeriesStats, i.e. from the
+/// configuration to calculate additional stats or to draw a decoration.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatKind {
+    Max,
+    Min,
+    Avg,
+    Sum,
+    Count,
+    First,
+    Last,
+    P50,
+    P90,
+    P99,
+    StdDev,
+    Rate,
 }
 
-impl Default for MissingValuesPolicy {
-    fn default() -> MissingValuesPolicy {
-        MissingValuesPolicy::Zero
+impl StatKind {
+    /// `is_extra` is true for the stats that need to go through the whole
+    /// window and are only calculated when selected on the TimeSeries
+    pub fn is_extra(self) -> bool {
+        matches!(
+            self,
+            StatKind::P50 | StatKind::P90 | StatKind::P99 | StatKind::Rate
+        )
     }
 }
 
 /// `ValueCollisionPolicy` handles collisions when several values are collected
 /// for the same time unit, allowing for overwriting, incrementing, etc.
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub enum ValueCollisionPolicy {
     Overwrite,
+    #[default]
     Increment,
     Decrement,
     Ignore,
+    Min,
+    Max,
 }
 
-impl Default for ValueCollisionPolicy {
-    fn default() -> ValueCollisionPolicy {
-        ValueCollisionPolicy::Increment
+impl ValueCollisionPolicy {
+    /// `resolve` returns the value to keep when `new` collides with an
+    /// `existing` value. Incrementing and decrementing stop at the bounds of
+    /// the type, i.e. decrementing unsigned values stops at zero.
+    pub fn resolve<T: MetricValue>(&self, existing: T, new: T) -> T {
+        match self {
+            ValueCollisionPolicy::Increment => existing.bounded_add(new),
+            ValueCollisionPolicy::Overwrite => new,
+            ValueCollisionPolicy::Decrement => existing.bounded_sub(new),
+            ValueCollisionPolicy::Ignore => existing,
+            ValueCollisionPolicy::Min => {
+                if new < existing {
+                    new
+                } else {
+                    existing
+                }
+            }
+            ValueCollisionPolicy::Max => {
+                if new > existing {
+                    new
+                } else {
+                    existing
+                }
+            }
+        }
     }
 }
 
 /// `TimeSeriesStats` contains statistics about the current TimeSeries
+/// The average, standard deviation and rate are always a float, the rest of
+/// the stats keep the type of the metrics so that counters stay exact.
+/// The percentiles and rate are only calculated when selected, see
+/// `TimeSeries::extra_stats`.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-pub struct TimeSeriesStats {
-    max: f64,
-    min: f64,
+pub struct TimeSeriesStats<T = f64> {
+    max: T,
+    min: T,
     avg: f64, // Calculation may lead to overflow
-    first: f64,
-    last: f64,
+    first: T,
+    last: T,
     count: usize,
-    sum: f64, // May overflow
+    sum: T, // Stops at the bounds of the type
+    p50: T,
+    p90: T,
+    p99: T,
+    stddev: f64,
+    rate: f64, // Per second increase, counter resets are accounted for
     is_dirty: bool,
+    extra_is_dirty: bool,
 }
 
-impl Default for TimeSeriesStats {
-    fn default() -> TimeSeriesStats {
+impl<T: MetricValue> Default for TimeSeriesStats<T> {
+    fn default() -> TimeSeriesStats<T> {
         TimeSeriesStats {
-            max: 0f64,
-            min: 0f64,
+            max: T::zero(),
+            min: T::zero(),
             avg: 0f64,
-            first: 0f64,
-            last: 0f64,
+            first: T::zero(),
+            last: T::zero(),
             count: 0usize,
-            sum: 0f64,
+            sum: T::zero(),
+            p50: T::zero(),
+            p90: T::zero(),
+            p99: T::zero(),
+            stddev: 0f64,
+            rate: 0f64,
             is_dirty: false,
+            extra_is_dirty: false,
+        }
+    }
+}
+
+impl<T: MetricValue> TimeSeriesStats<T> {
+    /// `get` returns the requested stat as f64, for example to draw it
+    pub fn get(&self, kind: StatKind) -> f64 {
+        let value = match kind {
+            StatKind::Max => self.max,
+            StatKind::Min => self.min,
+            StatKind::Sum => self.sum,
+            StatKind::First => self.first,
+            StatKind::Last => self.last,
+            StatKind::P50 => self.p50,
+            StatKind::P90 => self.p90,
+            StatKind::P99 => self.p99,
+            StatKind::Avg => return self.avg,
+            StatKind::Count => return self.count as f64,
+            StatKind::StdDev => return self.stddev,
+            StatKind::Rate => return self.rate,
+        };
+        value.to_f64().unwrap_or_default()
+    }
+}
+
+/// `StatsWindow` maintains the stats of a TimeSeries incrementally as items
+/// are pushed and evicted. The last slot of the circular buffer is kept
+/// "open" because collisions may still change its value, once a new slot is
+/// pushed the open slot is closed and added to the running sum/count and to
+/// the monotonic deques used to find the min/max over the sliding window.
+#[derive(Debug, Clone, PartialEq)]
+struct StatsWindow<T> {
+    /// Candidates for the max, values decrease from front to back
+    max_deque: VecDeque<(u64, T)>,
+    /// Candidates for the min, values increase from front to back
+    min_deque: VecDeque<(u64, T)>,
+    /// The sum of the closed slots
+    sum: T,
+    /// The number of filled closed slots
+    count: usize,
+    /// The sum of the squares of the closed slots, for the stddev
+    sum_squares: f64,
+    /// The last slot, not yet added to the deques
+    open: Option<(u64, Option<T>)>,
+}
+
+impl<T: MetricValue> Default for StatsWindow<T> {
+    fn default() -> StatsWindow<T> {
+        StatsWindow {
+            max_deque: VecDeque::new(),
+            min_deque: VecDeque::new(),
+            sum: T::zero(),
+            count: 0usize,
+            sum_squares: 0f64,
+            open: None,
         }
     }
 }
 
+impl<T: MetricValue> StatsWindow<T> {
+    /// `close` adds the open slot to the deques and running sum
+    fn close(&mut self) {
+        if let Some((epoch, Some(value))) = self.open.take() {
+            while let Some(&(_, back)) = self.max_deque.back() {
+                if back > value {
+                    break;
+                }
+                self.max_deque.pop_back();
+            }
+            self.max_deque.push_back((epoch, value));
+            while let Some(&(_, back)) = self.min_deque.back() {
+                if back < value {
+                    break;
+                }
+                self.min_deque.pop_back();
+            }
+            self.min_deque.push_back((epoch, value));
+            self.sum = self.sum.bounded_add(value);
+            self.count += 1;
+            self.sum_squares += value.to_f64().unwrap_or_default().powi(2);
+        }
+    }
+
+    /// `open` sets the last slot of the circular buffer, closing the previous
+    fn open(&mut self, input: (u64, Option<T>)) {
+        self.close();
+        self.open = Some(input);
+    }
+
+    /// `evict` removes the oldest closed slot from the window
+    fn evict(&mut self, input: (u64, Option<T>)) {
+        if let (epoch, Some(value)) = input {
+            if self.max_deque.front().map(|x| x.0) == Some(epoch) {
+                self.max_deque.pop_front();
+            }
+            if self.min_deque.front().map(|x| x.0) == Some(epoch) {
+                self.min_deque.pop_front();
+            }
+            self.sum = self.sum.bounded_sub(value);
+            self.count -= 1;
+            self.sum_squares -= value.to_f64().unwrap_or_default().powi(2);
+        }
+    }
+
+    /// `update_stats` merges the closed slots with the open slot into the
+    /// TimeSeriesStats
+    fn update_stats(&self, stats: &mut TimeSeriesStats<T>) {
+        let mut max = self.max_deque.front().map_or(T::min_value(), |x| x.1);
+        let mut min = self.min_deque.front().map_or(T::max_value(), |x| x.1);
+        let mut sum = self.sum;
+        let mut count = self.count;
+        let mut sum_squares = self.sum_squares;
+        if let Some((_, Some(value))) = self.open {
+            if value > max {
+                max = value;
+            }
+            if value < min {
+                min = value;
+            }
+            sum = sum.bounded_add(value);
+            count += 1;
+            sum_squares += value.to_f64().unwrap_or_default().powi(2);
+            stats.last = value;
+        }
+        stats.max = max;
+        stats.min = min;
+        stats.sum = sum;
+        stats.count = count;
+        if count == 0 {
+            stats.avg = 0f64;
+            stats.stddev = 0f64;
+            return;
+        }
+        stats.avg = sum.to_f64().unwrap_or_default() / (count as f64);
+        // The running sum of squares may drift slightly below the square of
+        // the average when values are evicted.
+        let variance = sum_squares / (count as f64) - stats.avg.powi(2);
+        stats.stddev = variance.max(0f64).sqrt();
+    }
+}
+
 /// `TimeSeries` contains a vector of tuple (epoch, Option<value>)
 /// The vector behaves as a circular buffer to avoid shifting values.
 /// The circular buffer may be invalidated partially, for example when too much
 /// time has passed without metrics, the vecotr is allowed to shrink without
 /// memory rellocation, this is achieved by using two indexes for the first
 /// and last item.
+/// The value type defaults to f64, integer types can be used for counters.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-pub struct TimeSeries {
+#[serde(from = "TimeSeriesConfig<T>")]
+#[serde(bound(deserialize = "T: MetricValue + serde::Deserialize<'de>"))]
+pub struct TimeSeries<T = f64> {
     /// Capture events through time
     /// Contains one entry per time unit
-    pub metrics: Vec<(u64, Option<f64>)>,
+    pub metrics: Vec<(u64, Option<T>)>,
 
     /// Number of items to store in our metrics vec
     pub metrics_capacity: usize,
 
+    /// The number of seconds each slot covers, epochs are aligned to the
+    /// start of their slot when pushed
+    pub granularity: u64,
+
     /// Stats for the TimeSeries
-    pub stats: TimeSeriesStats,
+    pub stats: TimeSeriesStats<T>,
 
     /// Useful for records that do not increment but rather are a fixed
     /// or absolute value recorded at a given time
@@ -132,6 +487,10 @@ pub struct TimeSeries {
     /// recorded.
     pub missing_values_policy: MissingValuesPolicy,
 
+    /// Additional stats to calculate, percentiles and rate need to go
+    /// through the whole window so they are only calculated when selected.
+    pub extra_stats: Vec<StatKind>,
+
     /// The first item in the circular buffer
     pub first_idx: usize,
 
@@ -142,18 +501,115 @@ pub struct TimeSeries {
     /// indexes are the same, then the buffer is full or has one item
     /// By knowing the active_items in advance we know which situation is true
     pub active_items: usize,
+
+    /// The number of items that were older than the buffer when pushed
+    pub dropped_items: usize,
+
+    /// Maintains the stats as items are pushed or evicted
+    #[serde(skip)]
+    window: StatsWindow<T>,
+}
+
+/// `TimeSeriesConfig` reads a TimeSeries, from charts.yml or a snapshot. The
+/// settings left out take the Default values and the stats window is rebuilt
+/// from the metrics.
+#[derive(Deserialize)]
+#[serde(default)]
+#[serde(bound(deserialize = "T: MetricValue + serde::Deserialize<'de>"))]
+struct TimeSeriesConfig<T> {
+    metrics: Vec<(u64, Option<T>)>,
+    metrics_capacity: usize,
+    granularity: u64,
+    stats: TimeSeriesStats<T>,
+    collision_policy: ValueCollisionPolicy,
+    missing_values_policy: MissingValuesPolicy,
+    extra_stats: Vec<StatKind>,
+    first_idx: usize,
+    last_idx: usize,
+    active_items: usize,
+    dropped_items: usize,
+}
+
+impl<T: MetricValue> Default for TimeSeriesConfig<T> {
+    fn default() -> TimeSeriesConfig<T> {
+        let series = TimeSeries::default();
+        TimeSeriesConfig {
+            metrics: series.metrics,
+            metrics_capacity: series.metrics_capacity,
+            granularity: series.granularity,
+            stats: series.stats,
+            collision_policy: series.collision_policy,
+            missing_values_policy: series.missing_values_policy,
+            extra_stats: series.extra_stats,
+            first_idx: series.first_idx,
+            last_idx: series.last_idx,
+            active_items: series.active_items,
+            dropped_items: series.dropped_items,
+        }
+    }
+}
+
+impl<T: MetricValue> From<TimeSeriesConfig<T>> for TimeSeries<T> {
+    fn from(config: TimeSeriesConfig<T>) -> TimeSeries<T> {
+        let mut series = TimeSeries {
+            metrics: config.metrics,
+            metrics_capacity: config.metrics_capacity,
+            granularity: config.granularity,
+            stats: config.stats,
+            collision_policy: config.collision_policy,
+            missing_values_policy: config.missing_values_policy,
+            extra_stats: config.extra_stats,
+            first_idx: config.first_idx,
+            last_idx: config.last_idx,
+            active_items: config.active_items,
+            dropped_items: config.dropped_items,
+            window: StatsWindow::default(),
+        };
+        // The first eviction would otherwise find an empty window
+        if series.active_items > 0 {
+            series.calculate_stats();
+        }
+        series
+    }
+}
+
+/// `SeriesConfig` reads the `series` of a source from charts.yml, the
+/// collision_policy is kept apart to know whether it was set so that each
+/// source can choose its own default.
+#[derive(Deserialize, Default)]
+pub(crate) struct SeriesConfig {
+    #[serde(default)]
+    pub collision_policy: Option<ValueCollisionPolicy>,
+    #[serde(flatten)]
+    pub series: TimeSeries,
+}
+
+impl SeriesConfig {
+    /// `with_default_policy` returns the TimeSeries with `collision_policy`
+    /// unless another one was configured
+    pub fn with_default_policy(self, collision_policy: ValueCollisionPolicy) -> TimeSeries {
+        TimeSeries {
+            collision_policy: self.collision_policy.unwrap_or(collision_policy),
+            ..self.series
+        }
+    }
 }
 
 /// `IterTimeSeries` provides the Iterator Trait for TimeSeries metrics.
 /// The state for the iteration is held en "pos" field. The "current_item" is
 /// used to determine if further iterations on the circular buffer is needed.
-pub struct IterTimeSeries<'a> {
+/// Items are numbered from the first item in the circular buffer, the
+/// iteration goes over the items in [current_item, end_item) so that it can
+/// be limited to a range and consumed from both ends.
+pub struct IterTimeSeries<'a, T = f64> {
     /// The reference to the TimeSeries struct to iterate over.
-    inner: &'a TimeSeries,
+    inner: &'a TimeSeries<T>,
     /// The current position state
     pos: usize,
-    /// The current item number, to be compared with the active_items
+    /// The current item number, to be compared with the end_item
     current_item: usize,
+    /// The item number after the last item to return
+    end_item: usize,
 }
 
 /// `ReferencePointDecoration` draws a fixed point to give a reference point
@@ -161,8 +617,17 @@ pub struct IterTimeSeries<'a> {
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct ReferencePointDecoration {
     /// The value at which to draw the reference point
+    #[serde(default)]
     pub value: f64,
 
+    /// When set, the value follows this stat of one of the chart series
+    #[serde(default)]
+    pub stat: Option<StatKind>,
+
+    /// The index of the series in the chart to take the stat from
+    #[serde(default)]
+    pub series_index: usize,
+
     /// The reference point will use additional height for the axis line
     /// this makes it fit in the configured space, basically the value
     /// will be incremented by this additional percentage to give more
@@ -170,9 +635,9 @@ pub struct ReferencePointDecoration {
     #[serde(default)]
     pub height_multiplier: f64,
 
-    /// hexadecimal color
+    /// The color, red by default
     #[serde(default)]
-    pub color: String,
+    pub color: Option<Color>,
 
     /// Transparency
     #[serde(default)]
@@ -192,8 +657,10 @@ impl Default for ReferencePointDecoration {
     fn default() -> ReferencePointDecoration {
         ReferencePointDecoration {
             value: 1.0,
+            stat: None,
+            series_index: 0,
             height_multiplier: 0.05,
-            color: String::from("0xff0000"),
+            color: None,
             alpha: 1.0,
             padding: Value2D {
                 x: 1f32,
@@ -280,29 +747,32 @@ impl ReferencePointDecoration {
 }
 
 /// `Decoration` contains several types of decorations to add to a chart
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
 #[serde(tag = "type")]
 pub enum Decoration {
     #[serde(rename = "reference")]
     Reference(ReferencePointDecoration),
+    #[serde(rename = "threshold")]
+    Threshold(decoration::ThresholdDecoration),
+    #[serde(rename = "moving_average")]
+    MovingAverage(decoration::MovingAverageDecoration),
+    #[serde(rename = "envelope")]
+    Envelope(decoration::EnvelopeDecoration),
+    #[serde(rename = "alert")]
+    Alert(decoration::AlertDecoration),
+    #[default]
     None,
-    // Maybe add Average, threshold coloring (turn line red after a certain
-    // point)
-}
-
-impl Default for Decoration {
-    fn default() -> Decoration {
-        Decoration::None
-    }
 }
 
 impl Decoration {
     /// `width` of the Decoration as it may need space to be drawn, otherwise
-    /// the decoration and the data itself would overlap, these are pixels
+    /// the decoration and the data itself would overlap, these are pixels.
+    /// The decorations drawn from the series values are drawn over it.
     fn width(&self) -> f32 {
         match self {
             Decoration::Reference(d) => d.padding.x,
-            Decoration::None => 0f32,
+            Decoration::Alert(d) => d.padding.x,
+            _ => 0f32,
         }
     }
 
@@ -312,6 +782,10 @@ impl Decoration {
     fn top_value(&self) -> f64 {
         match self {
             Decoration::Reference(ref d) => d.top_value(),
+            Decoration::Threshold(ref d) => d.top_value(),
+            Decoration::MovingAverage(ref d) => d.top_value(),
+            Decoration::Envelope(ref d) => d.top_value(),
+            Decoration::Alert(ref d) => d.top_value(),
             Decoration::None => 0f64,
         }
     }
@@ -322,29 +796,73 @@ impl Decoration {
     fn bottom_value(&self) -> f64 {
         match self {
             Decoration::Reference(d) => d.value - d.value * d.height_multiplier,
+            Decoration::Threshold(d) => d.bottom_value(),
+            Decoration::MovingAverage(d) => d.bottom_value(),
+            Decoration::Envelope(d) => d.bottom_value(),
+            Decoration::Alert(d) => d.bottom_value(),
             Decoration::None => 0f64,
         }
     }
 
-    /// `update_opengl_vecs` calls the decoration update methods
+    /// `update_opengl_vecs` calls the decoration update methods, `series`
+    /// are the opengl vecs of the chart series
     fn update_opengl_vecs(
         &mut self,
         display_size: SizeInfo,
         offset: Value2D,
         chart_max_value: f64,
+        series: &[Vec<f32>],
     ) {
         match self {
             Decoration::Reference(ref mut d) => {
                 d.update_opengl_vecs(display_size, offset, chart_max_value)
             }
+            Decoration::Threshold(ref mut d) => {
+                d.update_opengl_vecs(display_size, chart_max_value, series)
+            }
+            Decoration::MovingAverage(ref mut d) => d.update_opengl_vecs(series),
+            Decoration::Envelope(ref mut d) => d.update_opengl_vecs(series),
+            Decoration::Alert(ref mut d) => {
+                d.update_opengl_vecs(display_size, offset, chart_max_value, series)
+            }
             Decoration::None => (),
         }
     }
+    /// `color` returns the color of the decoration
+    pub fn color(&self) -> Rgb {
+        self.color_alpha()
+            .0
+            .map_or(DEFAULT_DECORATION_COLOR, |c| c.rgb)
+    }
+
+    /// `alpha` returns the transparency of the decoration, the alpha of its
+    /// color when set
+    pub fn alpha(&self) -> f32 {
+        let (color, alpha) = self.color_alpha();
+        color.and_then(|c| c.alpha).unwrap_or(alpha)
+    }
+
+    /// `color_alpha` returns the configured color and transparency
+    fn color_alpha(&self) -> (Option<Color>, f32) {
+        match self {
+            Decoration::Reference(d) => (d.color, d.alpha),
+            Decoration::Threshold(d) => (d.color, d.alpha),
+            Decoration::MovingAverage(d) => (d.color, d.alpha),
+            Decoration::Envelope(d) => (d.color, d.alpha),
+            Decoration::Alert(d) => (d.color, d.alpha),
+            Decoration::None => (None, 0f32),
+        }
+    }
+
     /// `opengl_vertices` returns the representation of the decoration in
     /// opengl. These are for now GL_LINES and 2D
     pub fn opengl_vertices(&self) -> Vec<f32> {
         match self {
             Decoration::Reference(d) => d.opengl_vertices(),
+            Decoration::Threshold(d) => d.opengl_data.clone(),
+            Decoration::MovingAverage(d) => d.opengl_data.clone(),
+            Decoration::Envelope(d) => d.opengl_data.clone(),
+            Decoration::Alert(d) => d.opengl_data.clone(),
             Decoration::None => vec![],
         }
     }
@@ -353,6 +871,7 @@ impl Decoration {
 /// `ManualTimeSeries` is a 2D struct from top left being 0,0
 /// and bottom right being display limits in pixels
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(from = "ManualTimeSeriesConfig")]
 pub struct ManualTimeSeries {
     /// The name of the ManualTimeSeries
     pub name: String,
@@ -361,13 +880,9 @@ pub struct ManualTimeSeries {
     #[serde(default)]
     pub series: TimeSeries,
 
-    /// The granularity to store
-    #[serde(default)]
-    pub granularity: u64,
-
     /// The color of the TimeSeries
     #[serde(default)]
-    pub color: String,
+    pub color: Option<Color>,
 
     /// The transparency of the TimeSeries
     #[serde(default)]
@@ -379,17 +894,157 @@ impl Default for ManualTimeSeries {
         ManualTimeSeries {
             name: String::from("unkown"),
             series: TimeSeries::default(),
-            granularity: 1, // 1 second
-            color: String::from("0x00ff00"),
+            color: None,
             alpha: 1.0,
         }
     }
 }
 
+/// `ManualTimeSeriesConfig` reads a ManualTimeSeries from charts.yml, the
+/// deprecated `granularity` is now set on the series
+#[derive(Deserialize)]
+struct ManualTimeSeriesConfig {
+    name: String,
+    #[serde(default)]
+    series: TimeSeries,
+    #[serde(default)]
+    granularity: Option<u64>,
+    #[serde(default)]
+    color: Option<Color>,
+    #[serde(default)]
+    alpha: f32,
+}
+
+impl From<ManualTimeSeriesConfig> for ManualTimeSeries {
+    fn from(config: ManualTimeSeriesConfig) -> ManualTimeSeries {
+        let mut series = config.series;
+        if let Some(granularity) = config.granularity {
+            warn!(
+                "Series '{}': granularity is deprecated, use series.granularity instead",
+                config.name
+            );
+            // The series setting wins when both are set
+            if series.granularity <= 1 {
+                series = series.with_granularity(granularity);
+            }
+        }
+        ManualTimeSeries {
+            name: config.name,
+            series,
+            color: config.color,
+            alpha: config.alpha,
+        }
+    }
+}
+
+/// `SourceError` contains the ways loading a series may fail, whatever its
+/// source
+#[derive(Debug, PartialEq, Clone)]
+pub enum SourceError {
+    /// A Prometheus query or scrape failed
+    Prometheus(prometheus::PrometheusError),
+    /// The command or the read did not finish in time
+    Timeout(String),
+    /// The command could not be run or exited with an error
+    Execution(String),
+    /// The file could not be read
+    Read(String),
+    /// The output has no valid number
+    InvalidOutput(String),
+    /// The source is misconfigured
+    Config(String),
+    /// The samples could not be sent to the coordinator
+    Coordinator(String),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceError::Prometheus(err) => write!(f, "{}", err),
+            SourceError::Timeout(err) => write!(f, "Timed out: {}", err),
+            SourceError::Execution(err) => write!(f, "Execution failed: {}", err),
+            SourceError::Read(err) => write!(f, "Read failed: {}", err),
+            SourceError::InvalidOutput(err) => write!(f, "Invalid output: {}", err),
+            SourceError::Config(err) => write!(f, "Invalid configuration: {}", err),
+            SourceError::Coordinator(err) => write!(f, "Coordinator unreachable: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl From<prometheus::PrometheusError> for SourceError {
+    fn from(err: prometheus::PrometheusError) -> SourceError {
+        SourceError::Prometheus(err)
+    }
+}
+
+/// `SourceHealth` tracks the outcome of the latest loads of a series, so that
+/// stale series can be greyed out or annotated
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct SourceHealth {
+    /// The epoch of the last successful load
+    pub last_success: Option<u64>,
+
+    /// The epoch of the last failed load
+    pub last_failure: Option<u64>,
+
+    /// The number of failed loads since the last successful one
+    pub consecutive_failures: u32,
+
+    /// The error of the last failed load
+    pub last_error: Option<String>,
+
+    /// The warnings of the last successful load, i.e. a PromQL that matches
+    /// more series than expected
+    pub warnings: Vec<String>,
+
+    /// The number of successful loads since start
+    pub successes: u64,
+
+    /// The number of failed loads since start
+    pub failures: u64,
+
+    /// The number of items loaded since start
+    pub records_loaded: u64,
+
+    /// The time in seconds the last poll took, successful or not
+    pub last_poll_duration: Option<f64>,
+}
+
+impl SourceHealth {
+    /// `record_success` resets the failures after a load at `epoch`
+    pub fn record_success(&mut self, epoch: u64, warnings: &[String]) {
+        self.last_success = Some(epoch);
+        self.consecutive_failures = 0;
+        self.successes += 1;
+        self.warnings = warnings.to_vec();
+    }
+
+    /// `record_failure` keeps the `error` of a failed load at `epoch`
+    pub fn record_failure(&mut self, epoch: u64, error: String) {
+        self.last_failure = Some(epoch);
+        self.consecutive_failures += 1;
+        self.failures += 1;
+        self.last_error = Some(error);
+    }
+
+    /// `is_stale` returns true when there was no successful load in the
+    /// `max_age` seconds before `now`. A series that has not been loaded yet
+    /// is only stale once a load has failed.
+    pub fn is_stale(&self, now: u64, max_age: u64) -> bool {
+        match self.last_success {
+            Some(last_success) => last_success + max_age < now,
+            None => self.consecutive_failures > 0,
+        }
+    }
+}
+
 /// `TimeSeriesSource` contains several types of time series that can be extended
 /// with drawable data
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 #[serde(tag = "type")]
+#[allow(clippy::large_enum_variant)]
 pub enum TimeSeriesSource {
     #[serde(rename = "prometheus")]
     PrometheusTimeSeries(prometheus::PrometheusTimeSeries),
@@ -399,6 +1054,18 @@ pub enum TimeSeriesSource {
     AlacrittyOutput(ManualTimeSeries),
     #[serde(rename = "async_items_loaded")]
     AsyncLoadedItems(ManualTimeSeries),
+    #[serde(rename = "scrape")]
+    ScrapeTimeSeries(scrape::ScrapeTimeSeries),
+    #[serde(rename = "statsd")]
+    StatsdTimeSeries(statsd::StatsdTimeSeries),
+    #[serde(rename = "command")]
+    CommandTimeSeries(command::CommandTimeSeries),
+    #[serde(rename = "tail")]
+    TailTimeSeries(tail::TailTimeSeries),
+    #[serde(rename = "influx")]
+    InfluxTimeSeries(line_protocol::InfluxTimeSeries),
+    #[serde(rename = "graphite")]
+    GraphiteTimeSeries(line_protocol::GraphiteTimeSeries),
 }
 
 impl Default for TimeSeriesSource {
@@ -408,12 +1075,19 @@ impl Default for TimeSeriesSource {
 }
 
 impl TimeSeriesSource {
-    fn series(&self) -> TimeSeries {
+    /// `series` returns the TimeSeries of the source
+    pub fn series(&self) -> &TimeSeries {
         match self {
-            TimeSeriesSource::PrometheusTimeSeries(x) => x.series.clone(),
-            TimeSeriesSource::AlacrittyInput(x) => x.series.clone(),
-            TimeSeriesSource::AlacrittyOutput(x) => x.series.clone(),
-            TimeSeriesSource::AsyncLoadedItems(x) => x.series.clone(),
+            TimeSeriesSource::PrometheusTimeSeries(x) => &x.series,
+            TimeSeriesSource::AlacrittyInput(x) => &x.series,
+            TimeSeriesSource::AlacrittyOutput(x) => &x.series,
+            TimeSeriesSource::AsyncLoadedItems(x) => &x.series,
+            TimeSeriesSource::ScrapeTimeSeries(x) => &x.series,
+            TimeSeriesSource::StatsdTimeSeries(x) => &x.series,
+            TimeSeriesSource::CommandTimeSeries(x) => &x.series,
+            TimeSeriesSource::TailTimeSeries(x) => &x.series,
+            TimeSeriesSource::InfluxTimeSeries(x) => &x.series,
+            TimeSeriesSource::GraphiteTimeSeries(x) => &x.series,
         }
     }
     fn series_mut(&mut self) -> &mut TimeSeries {
@@ -422,6 +1096,12 @@ impl TimeSeriesSource {
             TimeSeriesSource::AlacrittyInput(x) => &mut x.series,
             TimeSeriesSource::AlacrittyOutput(x) => &mut x.series,
             TimeSeriesSource::AsyncLoadedItems(x) => &mut x.series,
+            TimeSeriesSource::ScrapeTimeSeries(x) => &mut x.series,
+            TimeSeriesSource::StatsdTimeSeries(x) => &mut x.series,
+            TimeSeriesSource::CommandTimeSeries(x) => &mut x.series,
+            TimeSeriesSource::TailTimeSeries(x) => &mut x.series,
+            TimeSeriesSource::InfluxTimeSeries(x) => &mut x.series,
+            TimeSeriesSource::GraphiteTimeSeries(x) => &mut x.series,
         }
     }
     pub fn name(&self) -> String {
@@ -430,6 +1110,99 @@ impl TimeSeriesSource {
             TimeSeriesSource::AlacrittyInput(x) => x.name.clone(),
             TimeSeriesSource::AlacrittyOutput(x) => x.name.clone(),
             TimeSeriesSource::AsyncLoadedItems(x) => x.name.clone(),
+            TimeSeriesSource::ScrapeTimeSeries(x) => x.name.clone(),
+            TimeSeriesSource::StatsdTimeSeries(x) => x.name.clone(),
+            TimeSeriesSource::CommandTimeSeries(x) => x.name.clone(),
+            TimeSeriesSource::TailTimeSeries(x) => x.name.clone(),
+            TimeSeriesSource::InfluxTimeSeries(x) => x.name.clone(),
+            TimeSeriesSource::GraphiteTimeSeries(x) => x.name.clone(),
+        }
+    }
+
+    /// `color_mut` returns the configured color of the series
+    fn color_mut(&mut self) -> &mut Option<Color> {
+        match self {
+            TimeSeriesSource::PrometheusTimeSeries(x) => &mut x.color,
+            TimeSeriesSource::AlacrittyInput(x) => &mut x.color,
+            TimeSeriesSource::AlacrittyOutput(x) => &mut x.color,
+            TimeSeriesSource::AsyncLoadedItems(x) => &mut x.color,
+            TimeSeriesSource::ScrapeTimeSeries(x) => &mut x.color,
+            TimeSeriesSource::StatsdTimeSeries(x) => &mut x.color,
+            TimeSeriesSource::CommandTimeSeries(x) => &mut x.color,
+            TimeSeriesSource::TailTimeSeries(x) => &mut x.color,
+            TimeSeriesSource::InfluxTimeSeries(x) => &mut x.color,
+            TimeSeriesSource::GraphiteTimeSeries(x) => &mut x.color,
+        }
+    }
+
+    /// `color_alpha` returns the configured color and transparency
+    fn color_alpha(&self) -> (Option<Color>, f32) {
+        match self {
+            TimeSeriesSource::PrometheusTimeSeries(x) => (x.color, x.alpha),
+            TimeSeriesSource::AlacrittyInput(x) => (x.color, x.alpha),
+            TimeSeriesSource::AlacrittyOutput(x) => (x.color, x.alpha),
+            TimeSeriesSource::AsyncLoadedItems(x) => (x.color, x.alpha),
+            TimeSeriesSource::ScrapeTimeSeries(x) => (x.color, x.alpha),
+            TimeSeriesSource::StatsdTimeSeries(x) => (x.color, x.alpha),
+            TimeSeriesSource::CommandTimeSeries(x) => (x.color, x.alpha),
+            TimeSeriesSource::TailTimeSeries(x) => (x.color, x.alpha),
+            TimeSeriesSource::InfluxTimeSeries(x) => (x.color, x.alpha),
+            TimeSeriesSource::GraphiteTimeSeries(x) => (x.color, x.alpha),
+        }
+    }
+
+    /// `color` returns the color of the series, DEFAULT_SERIES_COLOR if it
+    /// has not been set nor assigned from the palette
+    pub fn color(&self) -> Rgb {
+        self.color_alpha().0.map_or(DEFAULT_SERIES_COLOR, |c| c.rgb)
+    }
+
+    /// `alpha` returns the transparency of the series, the alpha of its
+    /// color when set
+    pub fn alpha(&self) -> f32 {
+        let (color, alpha) = self.color_alpha();
+        color.and_then(|c| c.alpha).unwrap_or(alpha)
+    }
+
+    /// `health` returns the load state of series fetched from the network,
+    /// the series filled locally are always up to date
+    pub fn health(&self) -> Option<&SourceHealth> {
+        match self {
+            TimeSeriesSource::PrometheusTimeSeries(x) => Some(&x.health),
+            TimeSeriesSource::ScrapeTimeSeries(x) => Some(&x.health),
+            TimeSeriesSource::CommandTimeSeries(x) => Some(&x.health),
+            TimeSeriesSource::TailTimeSeries(x) => Some(&x.health),
+            _ => None,
+        }
+    }
+
+    fn health_mut(&mut self) -> Option<&mut SourceHealth> {
+        match self {
+            TimeSeriesSource::PrometheusTimeSeries(x) => Some(&mut x.health),
+            TimeSeriesSource::ScrapeTimeSeries(x) => Some(&mut x.health),
+            TimeSeriesSource::CommandTimeSeries(x) => Some(&mut x.health),
+            TimeSeriesSource::TailTimeSeries(x) => Some(&mut x.health),
+            _ => None,
+        }
+    }
+
+    /// `is_stale` returns true when the series has not been loaded for
+    /// `stale_after` pull intervals at epoch `now`
+    pub fn is_stale(&self, now: u64) -> bool {
+        match self {
+            TimeSeriesSource::PrometheusTimeSeries(x) => x
+                .health
+                .is_stale(now, x.retry.stale_after * (x.pull_interval as u64).max(1)),
+            TimeSeriesSource::ScrapeTimeSeries(x) => x
+                .health
+                .is_stale(now, x.retry.stale_after * (x.pull_interval as u64).max(1)),
+            TimeSeriesSource::CommandTimeSeries(x) => x
+                .health
+                .is_stale(now, x.retry.stale_after * (x.pull_interval as u64).max(1)),
+            TimeSeriesSource::TailTimeSeries(x) => x
+                .health
+                .is_stale(now, x.retry.stale_after * (x.pull_interval as u64).max(1)),
+            _ => false,
         }
     }
 }
@@ -516,25 +1289,97 @@ pub struct TimeSeriesChart {
     /// The opengl representation of the each series.
     #[serde(default)]
     pub opengl_vecs: Vec<Vec<f32>>,
+
+    /// The tiers the series are rolled up into, so that the chart can show a
+    /// longer `range` than the capacity of the series
+    #[serde(default)]
+    pub rollup: Vec<rollup::RollupTierConfig>,
+
+    /// The number of seconds to show, the finest tier able to hold them is
+    /// drawn. The series are drawn at full resolution when unset.
+    #[serde(default)]
+    pub range: Option<u64>,
+
+    /// The rolled up tiers of each series
+    #[serde(skip)]
+    pub rollups: Vec<rollup::SeriesRollup>,
 }
 
 impl TimeSeriesChart {
+    /// `assign_colors` gives the series without a color the next color of
+    /// the palette
+    pub fn assign_colors(&mut self) {
+        for idx in 0..self.sources.len() {
+            if self.sources[idx].color_alpha().0.is_none() {
+                let rgb = self.unused_palette_color();
+                *self.sources[idx].color_mut() = Some(Color::from(rgb));
+            }
+        }
+    }
+
+    /// `unused_palette_color` returns the first color of the palette that no
+    /// series of the chart uses
+    fn unused_palette_color(&self) -> Rgb {
+        (0usize..)
+            .map(color::palette_color)
+            .find(|rgb| {
+                !self
+                    .sources
+                    .iter()
+                    .any(|source| source.color_alpha().0.map(|c| c.rgb) == Some(*rgb))
+            })
+            .unwrap_or(DEFAULT_SERIES_COLOR)
+    }
+
+    /// `validate` checks the settings that cannot be checked while parsing
+    pub fn validate(&self) -> Result<(), String> {
+        let out_of_range = |alpha: f32| !(0. ..=1.).contains(&alpha);
+        for source in &self.sources {
+            if out_of_range(source.alpha()) {
+                return Err(format!(
+                    "Chart '{}' series '{}': alpha {} is not in [0, 1]",
+                    self.name,
+                    source.name(),
+                    source.alpha()
+                ));
+            }
+        }
+        for decoration in &self.decorations {
+            if *decoration != Decoration::None && out_of_range(decoration.alpha()) {
+                return Err(format!(
+                    "Chart '{}' decoration: alpha {} is not in [0, 1]",
+                    self.name,
+                    decoration.alpha()
+                ));
+            }
+        }
+        Ok(())
+    }
+
     /// `update_opengl_vecs` Represents the activity levels values in a
-    /// drawable vector for opengl
+    /// drawable vector for opengl. Missing values are filled using the
+    /// MissingValuesPolicy of the series, for the Gap policy the Y value is
+    /// NaN so that the line segments touching it are not drawn.
     pub fn update_opengl_vecs(&mut self, series_idx: usize, display_size: SizeInfo) {
         debug!("Chart: Starting update_opengl_vecs");
-        if series_idx > self.sources.len() {
+        if series_idx >= self.sources.len() {
             error!("Request for out of bound series index: {}", series_idx);
             return;
         }
-        while self.opengl_vecs.capacity() < self.sources.capacity() {
+        while self.opengl_vecs.len() < self.sources.len() {
             self.opengl_vecs.push(vec![]);
         }
+        if !self.rollup.is_empty() {
+            while self.rollups.len() < self.sources.len() {
+                self.rollups.push(rollup::SeriesRollup::new(&self.rollup));
+            }
+            self.rollups[series_idx].update(self.sources[series_idx].series());
+        }
         let mut display_size = display_size;
         display_size.chart_height = self.height;
         display_size.chart_width = self.width;
         // Get the opengl representation of the vector
-        let opengl_vecs_capacity = self.sources[series_idx].series().active_items;
+        let opengl_vecs_capacity = self.drawn_series(series_idx).active_items;
         if opengl_vecs_capacity > self.opengl_vecs[series_idx].capacity() {
             let missing_capacity = opengl_vecs_capacity - self.opengl_vecs[series_idx].capacity();
             self.opengl_vecs[series_idx].reserve(missing_capacity);
@@ -559,25 +1404,24 @@ impl TimeSeriesChart {
             "Chart: width: {}, decorations_space: {}",
             self.width, decorations_space
         );
-        let missing_values_fill = self.sources[series_idx].series().get_missing_values_fill();
         debug!(
             "Chart: Using {} to fill missing values. Metrics capacity: {}",
-            missing_values_fill,
-            self.sources[series_idx].series().metrics_capacity
+            self.drawn_series(series_idx).missing_values_policy,
+            self.drawn_series(series_idx).metrics_capacity
         );
         let tick_spacing = (self.width - decorations_space)
-            / self.sources[series_idx].series().metrics_capacity as f32;
+            / self.drawn_series(series_idx).metrics_capacity as f32;
         debug!("Chart: Using tick_spacing {}", tick_spacing);
-        for (idx, metric) in self.sources[series_idx].series().iter().enumerate() {
+        let filled_metrics = self.drawn_series(series_idx).as_filled_vec();
+        for (idx, metric) in filled_metrics.iter().enumerate() {
             // The decorations width request is on both left and right.
             let x_value = idx as f32 * tick_spacing + (decorations_space / 2f32);
             // If there is a Marker Line, it takes 10% of the initial horizontal space
-            let y_value = match metric.1 {
-                Some(x) => x,
-                None => missing_values_fill,
-            };
             let scaled_x = display_size.scale_x(x_value + self.offset.x);
-            let scaled_y = display_size.scale_y(self.stats.max, y_value);
+            let scaled_y = match metric.1 {
+                Some(y_value) => display_size.scale_y(self.stats.max, y_value),
+                None => f32::NAN,
+            };
             // Adding twice to a vec, could this be made into one operation? Is this slow?
             // need to transform activity line values from varying levels into scaled [-1, 1]
             // XXX: Move to Circular Buffer
@@ -589,9 +1433,306 @@ impl TimeSeriesChart {
                 self.opengl_vecs[series_idx][idx * 2 + 1] = scaled_y;
             }
         }
+        // The buffer may have been invalidated and have less items now
+        self.opengl_vecs[series_idx].truncate(filled_metrics.len() * 2);
         for decoration in &mut self.decorations {
             debug!("Chart: Updating decoration {:?} vertices", decoration);
-            decoration.update_opengl_vecs(display_size, self.offset, self.stats.max);
+            decoration.update_opengl_vecs(
+                display_size,
+                self.offset,
+                self.stats.max,
+                &self.opengl_vecs,
+            );
+        }
+    }
+
+    /// `load_prometheus_response` loads the response into the Prometheus
+    /// series at `series_idx`. If the series is split by labels, each label
+    /// set is loaded into its own series, appended to the sources the first
+    /// time it is seen. The split series are retired once all their data is
+    /// older than the series time range. Returns the number of items loaded.
+    pub fn load_prometheus_response(
+        &mut self,
+        series_idx: usize,
+        res: prometheus::HTTPResponse,
+    ) -> Result<usize, prometheus::PrometheusError> {
+        let (parent, labeled_samples) = match self.sources.get_mut(series_idx) {
+            Some(TimeSeriesSource::PrometheusTimeSeries(ref mut prom)) => {
+                if !prom.split_by_labels {
+                    return prom.load_prometheus_response(res);
+                }
+                if let Some(err) = prometheus::PrometheusError::from_response(&res) {
+                    return Err(err);
+                }
+                let labeled_samples = prom.unloaded_samples(&res);
+                (prom.clone(), labeled_samples)
+            }
+            _ => {
+                return Err(prometheus::PrometheusError::Config(format!(
+                    "Series {} is not a Prometheus series",
+                    series_idx
+                )))
+            }
+        };
+        let mut loaded_items = 0usize;
+        let mut last_epoch = None;
+        for (labels, samples) in labeled_samples {
+            let split_series_idx = self.sources.iter().position(|source| match source {
+                TimeSeriesSource::PrometheusTimeSeries(prom) => {
+                    prom.split_from.as_ref() == Some(&parent.name) && prom.required_labels == labels
+                }
+                _ => false,
+            });
+            let split_series_idx = match split_series_idx {
+                Some(idx) => idx,
+                None => {
+                    let split = parent.split(&labels, self.unused_palette_color());
+                    info!(
+                        "Chart: '{}' adding split series '{}'",
+                        self.name, split.name
+                    );
+                    self.sources
+                        .push(TimeSeriesSource::PrometheusTimeSeries(split));
+                    self.sources.len() - 1
+                }
+            };
+            for sample in samples {
+                self.sources[split_series_idx].series_mut().push(sample);
+                last_epoch = last_epoch.max(Some(sample.0));
+                loaded_items += 1;
+            }
+        }
+        if let Some(last_epoch) = last_epoch {
+            self.retire_split_sources(&parent.name, last_epoch);
+        }
+        Ok(loaded_items)
+    }
+
+    /// `load_scrape` loads the metric families scraped at `epoch` into the
+    /// scrape series at `series_idx`. Returns the number of items loaded.
+    pub fn load_scrape(
+        &mut self,
+        series_idx: usize,
+        epoch: u64,
+        families: &[scrape::MetricFamily],
+    ) -> Result<usize, String> {
+        match self.sources.get_mut(series_idx) {
+            Some(TimeSeriesSource::ScrapeTimeSeries(ref mut scraped)) => {
+                scraped.load_families(epoch, families)
+            }
+            _ => Err(format!("Series {} is not a scrape series", series_idx)),
+        }
+    }
+
+    /// `load_samples` pushes the (epoch, value) `samples` into the series at
+    /// `series_idx`. Returns the number of items loaded.
+    pub fn load_samples(
+        &mut self,
+        series_idx: usize,
+        samples: &[(u64, f64)],
+    ) -> Result<usize, String> {
+        let source = self
+            .sources
+            .get_mut(series_idx)
+            .ok_or_else(|| format!("Series {} does not exist", series_idx))?;
+        for sample in samples {
+            source.series_mut().push(*sample);
+        }
+        Ok(samples.len())
+    }
+
+    /// `load_statsd` loads the StatsD `lines` received at `epoch` on the
+    /// `listen` address into the matching series. Returns the indexes of the
+    /// series loaded and the number of lines loaded into each.
+    pub fn load_statsd(
+        &mut self,
+        listen: &str,
+        epoch: u64,
+        lines: &[statsd::StatsdLine],
+    ) -> Vec<(usize, usize)> {
+        let mut loaded = vec![];
+        for (series_idx, source) in self.sources.iter_mut().enumerate() {
+            if let TimeSeriesSource::StatsdTimeSeries(ref mut statsd) = source {
+                if statsd.listen_addr() != listen {
+                    continue;
+                }
+                let num_records = lines
+                    .iter()
+                    .filter(|line| statsd.load_line(epoch, line))
+                    .count();
+                if num_records > 0 {
+                    loaded.push((series_idx, num_records));
+                }
+            }
+        }
+        loaded
+    }
+
+    /// `load_lines` loads the Influx or Graphite `samples` received at `epoch`
+    /// on the `listen` socket into the matching series, the samples without
+    /// timestamp are loaded at `epoch`. Returns the indexes of the series
+    /// loaded and the number of samples loaded into each.
+    pub fn load_lines(
+        &mut self,
+        listen: &str,
+        epoch: u64,
+        samples: &[line_protocol::LineSample],
+    ) -> Vec<(usize, usize)> {
+        let mut loaded = vec![];
+        for (series_idx, source) in self.sources.iter_mut().enumerate() {
+            let matching: Vec<&line_protocol::LineSample> = match source {
+                TimeSeriesSource::InfluxTimeSeries(ref influx)
+                    if influx.listen_addr() == listen =>
+                {
+                    samples
+                        .iter()
+                        .filter(|sample| influx.match_metric_labels(sample))
+                        .collect()
+                }
+                TimeSeriesSource::GraphiteTimeSeries(ref graphite)
+                    if graphite.listen_addr() == listen =>
+                {
+                    samples
+                        .iter()
+                        .filter(|sample| graphite.match_metric_labels(sample))
+                        .collect()
+                }
+                _ => continue,
+            };
+            if matching.is_empty() {
+                continue;
+            }
+            loaded.push((series_idx, matching.len()));
+            for sample in matching {
+                source
+                    .series_mut()
+                    .push((sample.epoch.unwrap_or(epoch), sample.value));
+            }
+        }
+        loaded
+    }
+
+    /// `record_success` updates the health of the series at `series_idx`, and
+    /// of the series split from it, after a successful load at `epoch`
+    pub fn record_success(&mut self, series_idx: usize, epoch: u64, warnings: &[String]) {
+        self.update_health(series_idx, |health| health.record_success(epoch, warnings));
+    }
+
+    /// `record_failure` updates the health of the series at `series_idx`, and
+    /// of the series split from it, after a failed load at `epoch`
+    pub fn record_failure(&mut self, series_idx: usize, epoch: u64, error: &str) {
+        self.update_health(series_idx, |health| {
+            health.record_failure(epoch, error.to_string())
+        });
+    }
+
+    /// `record_loaded` adds the items loaded into the series at `series_idx`,
+    /// the series split from it are not updated as they share these items
+    pub fn record_loaded(&mut self, series_idx: usize, records: usize) {
+        if let Some(health) = self
+            .sources
+            .get_mut(series_idx)
+            .and_then(TimeSeriesSource::health_mut)
+        {
+            health.records_loaded += records as u64;
+        }
+    }
+
+    /// `record_poll_duration` updates the time the last poll of the series at
+    /// `series_idx`, and of the series split from it, took
+    pub fn record_poll_duration(&mut self, series_idx: usize, duration: std::time::Duration) {
+        self.update_health(series_idx, |health| {
+            health.last_poll_duration = Some(duration.as_secs_f64())
+        });
+    }
+
+    fn update_health<F: Fn(&mut SourceHealth)>(&mut self, series_idx: usize, update: F) {
+        let name = match self.sources.get(series_idx) {
+            Some(source) => source.name(),
+            None => return,
+        };
+        let mut indexes: Vec<usize> = self.split_sources(&name).collect();
+        indexes.push(series_idx);
+        for idx in indexes {
+            if let Some(health) = self.sources[idx].health_mut() {
+                update(health);
+            }
+        }
+    }
+
+    /// `last_loaded_epoch` returns the epoch the incremental loads of the
+    /// Prometheus series at `series_idx` resume from, the oldest of the last
+    /// epochs loaded of its label sets, or None to request the full range.
+    pub fn last_loaded_epoch(&self, series_idx: usize) -> Option<u64> {
+        match self.sources.get(series_idx) {
+            Some(TimeSeriesSource::PrometheusTimeSeries(prom)) => prom.last_loaded_epoch(),
+            _ => None,
+        }
+    }
+
+    /// `loaded_series` returns `series_idx` and the indexes of the series
+    /// split from it, the series a load into `series_idx` may change
+    pub fn loaded_series(&self, series_idx: usize) -> Vec<usize> {
+        match self.sources.get(series_idx) {
+            Some(source) => std::iter::once(series_idx)
+                .chain(self.split_sources(&source.name()))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// `split_sources` returns the indexes of the series split from `name`
+    fn split_sources<'a>(&'a self, name: &'a str) -> impl Iterator<Item = usize> + 'a {
+        self.sources
+            .iter()
+            .enumerate()
+            .filter(move |(_, source)| match source {
+                TimeSeriesSource::PrometheusTimeSeries(prom) => {
+                    prom.split_from.as_deref() == Some(name)
+                }
+                _ => false,
+            })
+            .map(|(idx, _)| idx)
+    }
+
+    /// `retire_split_sources` removes the series split from `name` whose last
+    /// item has gone out of the time range at `epoch`
+    fn retire_split_sources(&mut self, name: &str, epoch: u64) {
+        let retired: Vec<usize> = self
+            .split_sources(name)
+            .filter(|idx| {
+                let series = self.sources[*idx].series();
+                let time_range = series.metrics_capacity as u64 * series.granularity.max(1);
+                series
+                    .get_last_epoch()
+                    .is_none_or(|last_epoch| last_epoch + time_range <= epoch)
+            })
+            .collect();
+        for idx in retired.into_iter().rev() {
+            info!(
+                "Chart: '{}' retiring split series '{}'",
+                self.name,
+                self.sources[idx].name()
+            );
+            self.sources.remove(idx);
+            if idx < self.opengl_vecs.len() {
+                self.opengl_vecs.remove(idx);
+            }
+            if idx < self.rollups.len() {
+                self.rollups.remove(idx);
+            }
+        }
+    }
+
+    /// `drawn_series` returns the TimeSeries drawn for the source at
+    /// `series_idx`, the rolled up tier that holds the chart `range` if any
+    pub fn drawn_series(&self, series_idx: usize) -> &TimeSeries {
+        let series = self.sources[series_idx].series();
+        match (self.range, self.rollups.get(series_idx)) {
+            (Some(range), Some(rollup)) => rollup
+                .tier_for_range(series, range)
+                .map_or(series, |tier| &tier.value),
+            _ => series,
         }
     }
 
@@ -599,29 +1740,57 @@ impl TimeSeriesChart {
     /// This will also go through the decorations and account for the requested
     /// draw space for them.
     pub fn calculate_stats(&mut self) {
-        let mut max_activity_value = std::f64::MIN;
-        let mut min_activity_value = std::f64::MAX;
+        let mut max_activity_value = f64::MIN;
+        let mut min_activity_value = f64::MAX;
         let mut sum_activity_values = 0f64;
         let mut filled_stats = 0usize;
+        // Decorations may follow a stat that is not calculated by default
+        for decoration in &self.decorations {
+            if let Decoration::Reference(ref d) = decoration {
+                if let (Some(kind), Some(source)) = (d.stat, self.sources.get_mut(d.series_index)) {
+                    source.series_mut().require_stat(kind);
+                }
+            }
+        }
         for source in &mut self.sources {
             if source.series_mut().stats.is_dirty {
                 source.series_mut().calculate_stats();
+            } else if source.series_mut().stats.extra_is_dirty {
+                source.series_mut().calculate_extra_stats();
             }
         }
-        for source in &self.sources {
-            if source.series().stats.max > max_activity_value {
-                max_activity_value = source.series().stats.max;
+        for decoration in &mut self.decorations {
+            if let Decoration::Reference(ref mut d) = decoration {
+                if let (Some(kind), Some(source)) = (d.stat, self.sources.get(d.series_index)) {
+                    d.value = source.series().stats.get(kind);
+                }
+            }
+        }
+        for series_idx in 0..self.sources.len() {
+            let stats = &self.drawn_series(series_idx).stats;
+            if stats.max > max_activity_value {
+                max_activity_value = stats.max;
             }
-            if source.series().stats.min < min_activity_value {
-                min_activity_value = source.series().stats.min;
+            if stats.min < min_activity_value {
+                min_activity_value = stats.min;
             }
-            sum_activity_values += source.series().stats.sum;
-            for series_idx in source.series().as_vec() {
-                if series_idx.1.is_some() {
-                    filled_stats += 1;
+            sum_activity_values += stats.sum;
+            filled_stats += stats.count;
+        }
+        // The averages and the envelopes follow the series as it is drawn
+        let mut decorations = std::mem::take(&mut self.decorations);
+        for decoration in &mut decorations {
+            match decoration {
+                Decoration::MovingAverage(ref mut d) if d.series_index < self.sources.len() => {
+                    d.update_value_range(self.drawn_series(d.series_index))
+                }
+                Decoration::Envelope(ref mut d) if d.series_index < self.sources.len() => {
+                    d.update_value_range(self.drawn_series(d.series_index))
                 }
+                _ => {}
             }
         }
+        self.decorations = decorations;
         // Account for the decoration requested height
         for decoration in &self.decorations {
             let top_value = decoration.top_value();
@@ -645,45 +1814,57 @@ impl TimeSeriesChart {
     }
 }
 
-impl Default for TimeSeries {
-    fn default() -> TimeSeries {
+impl<T: MetricValue> Default for TimeSeries<T> {
+    fn default() -> TimeSeries<T> {
         // This leads to 5 mins of metrics to show by default.
         let default_capacity = 300usize;
         TimeSeries {
             metrics_capacity: default_capacity,
+            granularity: 1,
             metrics: Vec::with_capacity(default_capacity),
             stats: TimeSeriesStats::default(),
             collision_policy: ValueCollisionPolicy::default(),
             missing_values_policy: MissingValuesPolicy::default(),
+            extra_stats: vec![],
             first_idx: 0,
             last_idx: 0,
             active_items: 0,
+            dropped_items: 0,
+            window: StatsWindow::default(),
         }
     }
 }
 
-impl TimeSeries {
+impl<T: MetricValue> TimeSeries<T> {
     /// `with_capacity` builder changes the amount of metrics in the vec
-    pub fn with_capacity(self, n: usize) -> TimeSeries {
+    pub fn with_capacity(self, n: usize) -> TimeSeries<T> {
         let mut new_self = self;
         new_self.metrics = Vec::with_capacity(n);
         new_self.metrics_capacity = n;
         new_self
     }
 
+    /// `with_granularity` builder changes the number of seconds each slot
+    /// covers, the time range of the buffer is `metrics_capacity` slots of
+    /// `granularity` seconds.
+    pub fn with_granularity(mut self, seconds: u64) -> TimeSeries<T> {
+        self.granularity = seconds.max(1);
+        self
+    }
+
+    /// `slot_epoch` returns the epoch at which the slot containing `epoch`
+    /// starts
+    pub fn slot_epoch(&self, epoch: u64) -> u64 {
+        epoch - epoch % self.granularity.max(1)
+    }
+
     /// `with_missing_values_policy` receives a String and returns
-    /// a MissingValuesPolicy, TODO: the "Fixed" value is not implemented.
-    pub fn with_missing_values_policy(mut self, policy_type: String) -> TimeSeries {
-        self.missing_values_policy = match policy_type.as_ref() {
-            "zero" => MissingValuesPolicy::Zero,
-            "one" => MissingValuesPolicy::One,
-            "min" => MissingValuesPolicy::Min,
-            "max" => MissingValuesPolicy::Max,
-            "last" => MissingValuesPolicy::Last,
-            "avg" => MissingValuesPolicy::Avg,
-            "first" => MissingValuesPolicy::First,
-            _ => {
-                // TODO: Implement FromStr somehow
+    /// a MissingValuesPolicy, unknown policies fall back to Zero.
+    pub fn with_missing_values_policy(mut self, policy_type: String) -> TimeSeries<T> {
+        self.missing_values_policy = match policy_type.parse() {
+            Ok(policy) => policy,
+            Err(err) => {
+                error!("TimeSeries: {}, using zero", err);
                 MissingValuesPolicy::Zero
             }
         };
@@ -691,102 +1872,270 @@ impl TimeSeries {
     }
 
     /// `calculate_stats` Iterates over the metrics and sets the stats
+    /// The stats are maintained incrementally by `push` and `circular_push`,
+    /// this rebuilds them from scratch, for example after the metrics or the
+    /// indexes have been modified directly or deserialized.
     pub fn calculate_stats(&mut self) {
-        // Recalculating seems to be necessary because we are constantly
-        // moving items out of the Vec<> so our cache can easily get out of
-        // sync
-        let mut max_activity_value = std::f64::MIN;
-        let mut min_activity_value = std::f64::MAX;
-        let mut sum_activity_values = 0f64;
-        let mut filled_metrics = 0usize;
+        let mut window = StatsWindow::default();
         for entry in self.iter() {
-            if let Some(metric) = entry.1 {
-                if metric > max_activity_value {
-                    max_activity_value = metric;
-                }
-                if metric < min_activity_value {
-                    min_activity_value = metric;
+            window.open(*entry);
+        }
+        self.window = window;
+        self.window.update_stats(&mut self.stats);
+        self.stats.is_dirty = false;
+        self.calculate_extra_stats();
+    }
+
+    /// `with_extra_stat` builder adds a stat to be calculated
+    pub fn with_extra_stat(mut self, kind: StatKind) -> TimeSeries<T> {
+        self.require_stat(kind);
+        self
+    }
+
+    /// `require_stat` makes sure the stat is calculated, i.e. when a
+    /// decoration draws it.
+    pub fn require_stat(&mut self, kind: StatKind) {
+        if kind.is_extra() && !self.extra_stats.contains(&kind) {
+            self.extra_stats.push(kind);
+            self.stats.extra_is_dirty = true;
+        }
+    }
+
+    /// `needs_stat` returns true if the extra stat was selected or is needed
+    /// by the missing values policy
+    fn needs_stat(&self, kind: StatKind) -> bool {
+        self.extra_stats.contains(&kind) || self.missing_values_policy.required_stat() == Some(kind)
+    }
+
+    /// `refresh_stats` is called after the window has changed
+    fn refresh_stats(&mut self) {
+        self.window.update_stats(&mut self.stats);
+        if !self.extra_stats.is_empty() || self.missing_values_policy.required_stat().is_some() {
+            self.stats.extra_is_dirty = true;
+        }
+    }
+
+    /// `calculate_extra_stats` goes through the window to calculate the
+    /// selected percentiles and rate, these are not kept up to date on every
+    /// push, the callers should check `stats.extra_is_dirty`.
+    pub fn calculate_extra_stats(&mut self) {
+        let wants_percentiles = [StatKind::P50, StatKind::P90, StatKind::P99]
+            .iter()
+            .any(|kind| self.needs_stat(*kind));
+        if wants_percentiles {
+            let mut values: Vec<T> = self.iter().filter_map(|entry| entry.1).collect();
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            self.stats.p50 = nearest_rank(&values, 50f64);
+            self.stats.p90 = nearest_rank(&values, 90f64);
+            self.stats.p99 = nearest_rank(&values, 99f64);
+        }
+        if self.needs_stat(StatKind::Rate) {
+            self.stats.rate = self.calculate_rate();
+        }
+        self.stats.extra_is_dirty = false;
+    }
+
+    /// `calculate_rate` returns the per second increase of a counter over the
+    /// window, a value lower than the previous one is considered a counter
+    /// reset, like Prometheus rate() does.
+    fn calculate_rate(&self) -> f64 {
+        let mut increase = 0f64;
+        let mut first_epoch = None;
+        let mut prev: Option<(u64, f64)> = None;
+        for entry in self.iter() {
+            if let Some(value) = entry.1 {
+                let value = value.to_f64().unwrap_or_default();
+                match prev {
+                    Some((_, prev_value)) if value >= prev_value => increase += value - prev_value,
+                    Some(_) => increase += value,
+                    None => first_epoch = Some(entry.0),
                 }
-                sum_activity_values += metric;
-                filled_metrics += 1;
+                prev = Some((entry.0, value));
             }
         }
-        self.stats.max = max_activity_value;
-        self.stats.min = min_activity_value;
-        self.stats.sum = sum_activity_values;
-        self.stats.avg = sum_activity_values / (filled_metrics as f64);
-        self.stats.is_dirty = false;
+        match (first_epoch, prev) {
+            (Some(first_epoch), Some((last_epoch, _))) if last_epoch > first_epoch => {
+                increase / (last_epoch - first_epoch) as f64
+            }
+            _ => 0f64,
+        }
     }
 
     /// `get_missing_values_fill` uses the MissingValuesPolicy to decide
     /// which value to place on empty metric timeslots when drawing
-    pub fn get_missing_values_fill(&self) -> f64 {
+    /// The Avg and Fixed values are truncated for integer types.
+    pub fn get_missing_values_fill(&self) -> T {
         match self.missing_values_policy {
-            MissingValuesPolicy::Zero => 0f64,
-            MissingValuesPolicy::One => 1f64,
+            MissingValuesPolicy::Zero => T::zero(),
+            MissingValuesPolicy::One => T::one(),
             MissingValuesPolicy::Min => self.stats.min,
             MissingValuesPolicy::Max => self.stats.max,
             MissingValuesPolicy::Last => self.get_last_filled(),
             MissingValuesPolicy::First => self.get_first_filled(),
-            MissingValuesPolicy::Avg => self.stats.avg,
-            MissingValuesPolicy::Fixed(val) => val,
+            MissingValuesPolicy::Avg => T::from(self.stats.avg).unwrap_or_else(T::zero),
+            MissingValuesPolicy::Fixed(val) => T::from(val).unwrap_or_else(T::zero),
+            MissingValuesPolicy::Median => self.stats.p50,
+            MissingValuesPolicy::P90 => self.stats.p90,
+            MissingValuesPolicy::P99 => self.stats.p99,
+            // These depend on the position of the slot, see `as_filled_vec`
+            MissingValuesPolicy::Linear | MissingValuesPolicy::Gap => T::zero(),
         }
     }
 
+    /// `as_filled_vec` Returns the circular buffer in flat vec format with the
+    /// missing values filled using the MissingValuesPolicy. Linear
+    /// interpolates between the neighbouring filled slots and repeats the
+    /// nearest one at the edges, Gap leaves the missing values as None.
+    pub fn as_filled_vec(&self) -> Vec<(u64, Option<T>)> {
+        let mut res = self.as_vec();
+        match self.missing_values_policy {
+            MissingValuesPolicy::Gap => {}
+            MissingValuesPolicy::Linear => {
+                let mut prev: Option<(u64, T)> = None;
+                let mut missing_from = 0usize;
+                for idx in 0..res.len() {
+                    let (epoch, value) = res[idx];
+                    if let Some(value) = value {
+                        for missing in &mut res[missing_from..idx] {
+                            missing.1 = Some(match prev {
+                                Some(prev) => interpolate(prev, (epoch, value), missing.0),
+                                None => value,
+                            });
+                        }
+                        prev = Some((epoch, value));
+                        missing_from = idx + 1;
+                    }
+                }
+                if let Some(prev) = prev {
+                    for missing in &mut res[missing_from..] {
+                        missing.1 = Some(prev.1);
+                    }
+                }
+            }
+            _ => {
+                let fill = self.get_missing_values_fill();
+                for entry in &mut res {
+                    if entry.1.is_none() {
+                        entry.1 = Some(fill);
+                    }
+                }
+            }
+        }
+        res
+    }
+
     /// `resolve_metric_collision` ensures the policy for colliding values is
     /// applied.
-    pub fn resolve_metric_collision(&self, existing: f64, new: f64) -> f64 {
-        match self.collision_policy {
-            ValueCollisionPolicy::Increment => existing + new,
-            ValueCollisionPolicy::Overwrite => new,
-            ValueCollisionPolicy::Decrement => existing - new,
-            ValueCollisionPolicy::Ignore => existing,
+    pub fn resolve_metric_collision(&self, existing: T, new: T) -> T {
+        self.collision_policy.resolve(existing, new)
+    }
+
+    /// `circular_push` an item to the circular buffer
+    /// The active items are `active_items` consecutive positions starting at
+    /// `first_idx`, wrapping around the end of the vec, `last_idx` is the
+    /// position right after the last item, in the range [1, metrics_capacity]
+    pub fn circular_push(&mut self, input: (u64, Option<T>)) {
+        if self.metrics_capacity == 0 {
+            return;
+        }
+        // The previous last item can no longer collide, it is closed before
+        // the oldest item may be evicted in case there is only one slot.
+        self.window.close();
+        let position = (self.first_idx + self.active_items) % self.metrics_capacity;
+        if self.active_items == self.metrics_capacity {
+            // The oldest item is overwritten
+            self.window.evict(self.metrics[self.first_idx]);
+            self.first_idx = (self.first_idx + 1) % self.metrics_capacity;
+        } else {
+            self.active_items += 1;
+        }
+        // The vector might have been invalidated because data was outdated.
+        // The first and last index shorten the vector but leave old data
+        // still, this data is overwritten as new items arrive.
+        if position == self.metrics.len() {
+            self.metrics.push(input);
+        } else {
+            self.metrics[position] = input;
+        }
+        self.window.open(input);
+        self.refresh_stats();
+        self.last_idx = position + 1;
+    }
+
+    /// `last_position` returns the position in the metrics vec of the last
+    /// item in the circular buffer, the vec must not be empty.
+    fn last_position(&self) -> usize {
+        if self.last_idx == 0 {
+            self.metrics.len() - 1
+        } else {
+            self.last_idx - 1
+        }
+    }
+
+    /// `position_of` returns the position in the metrics vec of the active
+    /// item with the requested epoch. Since `push` keeps one item per epoch
+    /// the position can be calculated from the first epoch, items added with
+    /// `circular_push` may have gaps, in which case the items are binary
+    /// searched.
+    fn position_of(&self, epoch: u64) -> Option<usize> {
+        if self.active_items == 0 {
+            return None;
+        }
+        let first_epoch = self.metrics[self.first_idx].0;
+        if epoch < first_epoch {
+            return None;
+        }
+        let offset = ((epoch - first_epoch) / self.granularity.max(1)) as usize;
+        if offset < self.active_items {
+            let position = (self.first_idx + offset) % self.metrics.len();
+            if self.metrics[position].0 == epoch {
+                return Some(position);
+            }
+        }
+        let item = self.item_at(epoch);
+        let position = (self.first_idx + item) % self.metrics.len();
+        if item < self.active_items && self.metrics[position].0 == epoch {
+            Some(position)
+        } else {
+            None
         }
     }
 
-    /// `circular_push` an item to the circular buffer
-    pub fn circular_push(&mut self, input: (u64, Option<f64>)) {
-        if self.metrics.len() < self.metrics_capacity {
-            self.metrics.push(input);
-            self.active_items += 1;
+    /// `get_last_epoch` Returns the epoch of the last item in the circular
+    /// buffer, filled or not.
+    pub fn get_last_epoch(&self) -> Option<u64> {
+        if self.metrics.is_empty() {
+            None
         } else {
-            // The vector might have been invalidated because data was outdated.
-            // The first and last index shorten the vector but leave old data
-            // still
-            if self.first_idx == 0 && self.last_idx < self.metrics_capacity {
-                self.metrics[self.last_idx] = input;
-            } else {
-                self.metrics[self.first_idx] = input;
-                self.first_idx = (self.first_idx + 1) % self.metrics_capacity;
-            }
-            if self.first_idx + self.last_idx < self.metrics_capacity {
-                self.active_items += 1;
-            }
+            Some(self.metrics[self.last_position()].0)
         }
-        self.stats.is_dirty = true;
-        self.last_idx = (self.last_idx + 1) % (self.metrics_capacity + 1);
     }
 
     /// `push` Adds values to the circular buffer adding empty entries for
-    /// missing entries, may invalidate the buffer if all data is outdated
-    pub fn push(&mut self, input: (u64, f64)) {
+    /// missing entries, may invalidate the buffer if all data is outdated.
+    /// Values older than the last item are inserted in their slot if it is
+    /// still inside the buffer, otherwise they are dropped and counted in
+    /// `dropped_items`.
+    pub fn push(&mut self, input: (u64, T)) {
+        let input = (self.slot_epoch(input.0), input.1);
         if !self.metrics.is_empty() {
-            let last_idx = if self.last_idx == self.metrics_capacity || self.last_idx == 0 {
-                self.metrics.len() - 1
-            } else {
-                self.last_idx - 1
-            };
-            let inactive_time = if input.0 > self.metrics[last_idx].0 {
-                (input.0 - self.metrics[last_idx].0) as usize
-            } else {
-                0usize
-            };
+            let last_idx = self.last_position();
+            let last_epoch = self.metrics[last_idx].0;
+            if input.0 < last_epoch {
+                self.backfill(input);
+                return;
+            }
+            let granularity = self.granularity.max(1);
+            let inactive_time = ((input.0 - last_epoch) / granularity) as usize;
             if inactive_time > self.metrics_capacity {
                 // The whole vector should be discarded
                 self.first_idx = 0;
                 self.last_idx = 1;
                 self.metrics[0] = (input.0, Some(input.1));
                 self.active_items = 1;
+                self.window = StatsWindow::default();
+                self.window.open(self.metrics[0]);
+                self.refresh_stats();
             } else if inactive_time == 0 {
                 // In this case, the last epoch and the current epoch match
                 if let Some(curr_val) = self.metrics[last_idx].1 {
@@ -795,10 +2144,14 @@ impl TimeSeries {
                 } else {
                     self.metrics[last_idx].1 = Some(input.1);
                 }
+                // Only the open slot changes, the closed slots are untouched.
+                self.window.open = Some(self.metrics[last_idx]);
+                self.refresh_stats();
             } else {
                 // Fill missing entries with None
-                let max_epoch = self.metrics[last_idx].0;
-                for fill_epoch in (max_epoch + 1)..input.0 {
+                for fill_epoch in
+                    ((last_epoch + granularity)..input.0).step_by(granularity as usize)
+                {
                     self.circular_push((fill_epoch, None));
                 }
                 self.circular_push((input.0, Some(input.1)));
@@ -808,37 +2161,44 @@ impl TimeSeries {
         }
     }
 
-    /// `get_last_filled` Returns the last filled entry in the circular buffer
-    pub fn get_last_filled(&self) -> f64 {
-        let mut idx = if self.last_idx == self.metrics_capacity {
-            0
-        } else {
-            self.last_idx - 1
-        };
-        loop {
-            if let Some(res) = self.metrics[idx].1 {
-                return res;
+    /// `backfill` applies a value older than the last item to its slot.
+    fn backfill(&mut self, input: (u64, T)) {
+        match self.position_of(input.0) {
+            Some(position) => {
+                self.metrics[position].1 = match self.metrics[position].1 {
+                    Some(curr_val) => Some(self.resolve_metric_collision(curr_val, input.1)),
+                    None => Some(input.1),
+                };
+                // The slot is already closed, the window has to be rebuilt
+                // so that the value is accounted for when it is evicted.
+                self.calculate_stats();
             }
-            idx = if idx == 0 {
-                self.metrics.len()
-            } else {
-                idx - 1
-            };
-            if idx == self.first_idx {
-                break;
+            None => {
+                debug!(
+                    "TimeSeries: Dropping item {:?} outside of the buffer",
+                    input
+                );
+                self.dropped_items += 1;
             }
         }
-        0f64
+    }
+
+    /// `get_last_filled` Returns the last filled entry in the circular buffer
+    pub fn get_last_filled(&self) -> T {
+        self.iter()
+            .rev()
+            .find_map(|entry| entry.1)
+            .unwrap_or_else(T::zero)
     }
 
     /// `get_first_filled` Returns the first filled entry in the circular buffer
-    pub fn get_first_filled(&self) -> f64 {
+    pub fn get_first_filled(&self) -> T {
         for entry in self.iter() {
             if let Some(metric) = entry.1 {
                 return metric;
             }
         }
-        0f64
+        T::zero()
     }
 
     /// `as_vec` Returns the circular buffer in flat vec format
@@ -855,18 +2215,18 @@ impl TimeSeries {
     //  ^  v                      # 0
     //  ^                       v # vec full
     //  v                    ^    # 7
-    pub fn as_vec(&self) -> Vec<(u64, Option<f64>)> {
+    pub fn as_vec(&self) -> Vec<(u64, Option<T>)> {
         if self.metrics.is_empty() {
             return vec![];
         }
-        let mut res: Vec<(u64, Option<f64>)> = Vec::with_capacity(self.metrics_capacity);
+        let mut res: Vec<(u64, Option<T>)> = Vec::with_capacity(self.metrics_capacity);
         for entry in self.iter() {
-            res.push(entry.clone());
+            res.push(*entry);
         }
         res
     }
 
-    pub fn push_current_epoch(&mut self, input: f64) {
+    pub fn push_current_epoch(&mut self, input: T) {
         let now = std::time::SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -874,29 +2234,129 @@ impl TimeSeries {
         self.push((now, input));
     }
 
-    // `iter` Returns an Iterator from the current start.
-    fn iter(&self) -> IterTimeSeries {
+    /// `iter` Returns an Iterator from the current start.
+    pub fn iter(&self) -> IterTimeSeries<'_, T> {
+        self.iter_items(0, self.active_items)
+    }
+
+    /// `iter_items` Returns an Iterator over the items numbered
+    /// [from_item, to_item) counting from the first item.
+    fn iter_items(&self, from_item: usize, to_item: usize) -> IterTimeSeries<'_, T> {
+        let pos = if self.metrics.is_empty() {
+            0
+        } else {
+            (self.first_idx + from_item) % self.metrics.len()
+        };
         IterTimeSeries {
             inner: self,
-            pos: self.first_idx,
-            current_item: 0,
+            pos,
+            current_item: from_item,
+            end_item: to_item,
+        }
+    }
+
+    /// `item_at` returns the item number, counting from the first item,
+    /// of the first item with an epoch equal or greater than `epoch`.
+    /// The epochs are sorted so the circular buffer is binary searched.
+    fn item_at(&self, epoch: u64) -> usize {
+        let (mut low, mut high) = (0usize, self.active_items);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let position = (self.first_idx + mid) % self.metrics.len();
+            if self.metrics[position].0 < epoch {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
         }
+        low
+    }
+
+    /// `range` Returns an Iterator over the items with epochs inside
+    /// [from_epoch, to_epoch)
+    pub fn range(&self, from_epoch: u64, to_epoch: u64) -> IterTimeSeries<'_, T> {
+        let from_item = self.item_at(from_epoch);
+        let to_item = self.item_at(to_epoch).max(from_item);
+        self.iter_items(from_item, to_item)
+    }
+
+    /// `last_n` Returns an Iterator over the last `n` items
+    pub fn last_n(&self, n: usize) -> IterTimeSeries<'_, T> {
+        self.iter_items(
+            self.active_items - n.min(self.active_items),
+            self.active_items,
+        )
+    }
+
+    /// `value_at` Returns the value of the slot containing `epoch`, None if
+    /// the slot is not in the circular buffer or has no value.
+    pub fn value_at(&self, epoch: u64) -> Option<T> {
+        self.position_of(self.slot_epoch(epoch))
+            .and_then(|position| self.metrics[position].1)
+    }
+}
+
+impl<'a, T: MetricValue> IntoIterator for &'a TimeSeries<T> {
+    type Item = &'a (u64, Option<T>);
+    type IntoIter = IterTimeSeries<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// `nearest_rank` returns the percentile of sorted values, using the nearest
+/// rank method the result is always one of the values.
+fn nearest_rank<T: MetricValue>(sorted_values: &[T], percentile: f64) -> T {
+    if sorted_values.is_empty() {
+        return T::zero();
     }
+    let rank = (percentile / 100f64 * sorted_values.len() as f64).ceil() as usize;
+    sorted_values[rank.max(1) - 1]
 }
 
-impl<'a> Iterator for IterTimeSeries<'a> {
-    type Item = &'a (u64, Option<f64>);
+/// `interpolate` returns the value at `epoch` on the line between two
+/// filled slots, integer types are truncated.
+fn interpolate<T: MetricValue>(from: (u64, T), to: (u64, T), epoch: u64) -> T {
+    let (from_value, to_value) = match (from.1.to_f64(), to.1.to_f64()) {
+        (Some(from_value), Some(to_value)) => (from_value, to_value),
+        _ => return from.1,
+    };
+    let ratio = (epoch - from.0) as f64 / (to.0 - from.0) as f64;
+    T::from(from_value + (to_value - from_value) * ratio).unwrap_or(from.1)
+}
+
+impl<'a, T> Iterator for IterTimeSeries<'a, T> {
+    type Item = &'a (u64, Option<T>);
     fn next(&mut self) -> Option<Self::Item> {
-        if self.inner.metrics.is_empty() || self.current_item == self.inner.active_items {
+        if self.inner.metrics.is_empty() || self.current_item >= self.end_item {
             return None;
         }
         let curr_pos = self.pos % self.inner.metrics.len();
-        self.pos = (self.pos + 1) % (self.inner.metrics.len() + 1);
+        self.pos = curr_pos + 1;
         self.current_item += 1;
         Some(&self.inner.metrics[curr_pos])
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.end_item.saturating_sub(self.current_item);
+        (remaining, Some(remaining))
+    }
 }
 
+impl<'a, T> DoubleEndedIterator for IterTimeSeries<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.inner.metrics.is_empty() || self.current_item >= self.end_item {
+            return None;
+        }
+        self.end_item -= 1;
+        let pos = (self.inner.first_idx + self.end_item) % self.inner.metrics.len();
+        Some(&self.inner.metrics[pos])
+    }
+}
+
+impl<'a, T> ExactSizeIterator for IterTimeSeries<'a, T> {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -936,7 +2396,7 @@ mod tests {
             ]
         );
         assert_eq!(test.first_idx, 1);
-        assert_eq!(test.last_idx, 0);
+        assert_eq!(test.last_idx, 1);
         test.circular_push((15, Some(5f64)));
         assert_eq!(
             test.metrics,
@@ -948,7 +2408,7 @@ mod tests {
             ]
         );
         assert_eq!(test.first_idx, 2);
-        assert_eq!(test.last_idx, 1);
+        assert_eq!(test.last_idx, 2);
     }
     #[test]
     fn it_gets_last_filled_value() {
@@ -1081,6 +2541,9 @@ mod tests {
         let mut test_avg = TimeSeries::default()
             .with_capacity(5)
             .with_missing_values_policy("avg".to_string());
+        let mut test_fixed = TimeSeries::default()
+            .with_capacity(5)
+            .with_missing_values_policy("fixed:2.5".to_string());
         test_zero.push((0, 9f64));
         test_zero.push((2, 1f64));
         test_one.push((0, 9f64));
@@ -1095,6 +2558,8 @@ mod tests {
         test_first.push((2, 1f64));
         test_avg.push((0, 9f64));
         test_avg.push((2, 1f64));
+        test_fixed.push((0, 9f64));
+        test_fixed.push((2, 1f64));
         test_zero.calculate_stats();
         test_one.calculate_stats();
         test_min.calculate_stats();
@@ -1102,6 +2567,7 @@ mod tests {
         test_last.calculate_stats();
         test_first.calculate_stats();
         test_avg.calculate_stats();
+        test_fixed.calculate_stats();
         assert_eq!(test_zero.get_missing_values_fill(), 0f64);
         assert_eq!(test_one.get_missing_values_fill(), 1f64);
         assert_eq!(test_min.get_missing_values_fill(), 1f64);
@@ -1109,7 +2575,445 @@ mod tests {
         assert_eq!(test_last.get_missing_values_fill(), 1f64);
         assert_eq!(test_first.get_missing_values_fill(), 9f64);
         assert_eq!(test_avg.get_missing_values_fill(), 5f64);
-        // TODO: add Fixed value test
+        assert_eq!(test_fixed.get_missing_values_fill(), 2.5f64);
+        assert_eq!(
+            test_fixed.as_filled_vec(),
+            vec![(0, Some(9f64)), (1, Some(2.5f64)), (2, Some(1f64))]
+        );
+    }
+    #[test]
+    fn it_keeps_integer_counters_exact() {
+        // 2^53 + 1 cannot be represented by an f64
+        let big_counter = 9_007_199_254_740_993u64;
+        let mut test: TimeSeries<u64> = TimeSeries::default().with_capacity(4);
+        test.push((10, big_counter));
+        test.push((10, 1));
+        test.push((12, 5));
+        assert_eq!(
+            test.as_vec(),
+            vec![(10, Some(big_counter + 1)), (11, None), (12, Some(5))]
+        );
+        test.calculate_stats();
+        assert_eq!(test.stats.max, big_counter + 1);
+        assert_eq!(test.stats.min, 5);
+        assert_eq!(test.stats.sum, big_counter + 6);
+        // Decrementing an unsigned counter stops at zero
+        test.collision_policy = ValueCollisionPolicy::Decrement;
+        test.push((12, 7));
+        assert_eq!(test.get_last_filled(), 0);
+        test.missing_values_policy = MissingValuesPolicy::Fixed(2.9);
+        assert_eq!(test.get_missing_values_fill(), 2);
+    }
+    #[test]
+    fn it_stops_small_integers_at_their_bounds() {
+        let mut test: TimeSeries<u8> = TimeSeries::default().with_capacity(4);
+        test.push((10, 200));
+        test.push((10, 100));
+        assert_eq!(test.get_last_filled(), 255);
+        test.push((11, 250));
+        test.push((12, 1));
+        assert_eq!(test.stats.sum, 255);
+        test.collision_policy = ValueCollisionPolicy::Decrement;
+        test.push((12, 3));
+        assert_eq!(test.get_last_filled(), 0);
+        let mut test: TimeSeries<i8> = TimeSeries::default().with_capacity(4);
+        test.collision_policy = ValueCollisionPolicy::Decrement;
+        test.push((10, -100));
+        test.push((10, 100));
+        assert_eq!(test.get_last_filled(), -128);
+        assert_eq!(ValueCollisionPolicy::Increment.resolve(100i8, 100), 127);
+        assert_eq!(ValueCollisionPolicy::Decrement.resolve(100i8, -100), 127);
+        assert_eq!(ValueCollisionPolicy::Increment.resolve(1.5f64, 2.), 3.5);
+    }
+    #[test]
+    fn it_parses_missing_values_policy() {
+        for policy in &[
+            "zero", "one", "first", "last", "avg", "max", "min", "median", "p90", "p99", "linear",
+            "gap",
+        ] {
+            let parsed: MissingValuesPolicy = policy.parse().unwrap();
+            assert_eq!(parsed.to_string(), *policy);
+        }
+        assert_eq!(
+            "fixed:2.5".parse::<MissingValuesPolicy>(),
+            Ok(MissingValuesPolicy::Fixed(2.5))
+        );
+        assert_eq!(
+            " Fixed: -1 ".parse::<MissingValuesPolicy>(),
+            Ok(MissingValuesPolicy::Fixed(-1f64))
+        );
+        assert!("fixed:".parse::<MissingValuesPolicy>().is_err());
+        assert!("nearest".parse::<MissingValuesPolicy>().is_err());
+        assert_eq!(
+            TimeSeries::<f64>::default()
+                .with_missing_values_policy("nearest".to_string())
+                .missing_values_policy,
+            MissingValuesPolicy::Zero
+        );
+        let test: TimeSeries = serde_yaml::from_str("missing_values_policy: fixed:2.5").unwrap();
+        assert_eq!(test.missing_values_policy, MissingValuesPolicy::Fixed(2.5));
+        let yaml = serde_yaml::to_string(&test).unwrap();
+        let test: TimeSeries = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(test.missing_values_policy, MissingValuesPolicy::Fixed(2.5));
+        let test: Result<TimeSeries, _> = serde_yaml::from_str("missing_values_policy: nearest");
+        assert!(test.is_err());
+    }
+    #[test]
+    fn it_fills_missing_values_linearly() {
+        let mut test = TimeSeries::default()
+            .with_capacity(10)
+            .with_missing_values_policy("linear".to_string());
+        test.circular_push((10, None));
+        test.push((11, 2f64));
+        test.push((15, 4f64));
+        test.circular_push((16, None));
+        assert_eq!(
+            test.as_filled_vec(),
+            vec![
+                (10, Some(2f64)),
+                (11, Some(2f64)),
+                (12, Some(2.5f64)),
+                (13, Some(3f64)),
+                (14, Some(3.5f64)),
+                (15, Some(4f64)),
+                (16, Some(4f64))
+            ]
+        );
+        // Integer types are truncated
+        let mut test = TimeSeries::<u64>::default()
+            .with_capacity(10)
+            .with_missing_values_policy("linear".to_string());
+        test.push((10, 1));
+        test.push((13, 2));
+        assert_eq!(
+            test.as_filled_vec(),
+            vec![(10, Some(1)), (11, Some(1)), (12, Some(1)), (13, Some(2))]
+        );
+        // Gap keeps the missing values
+        test.missing_values_policy = MissingValuesPolicy::Gap;
+        assert_eq!(test.as_filled_vec(), test.as_vec());
+        // Without filled slots there is nothing to interpolate
+        let mut test =
+            TimeSeries::<f64>::default().with_missing_values_policy("linear".to_string());
+        test.circular_push((10, None));
+        assert_eq!(test.as_filled_vec(), vec![(10, None)]);
+    }
+    #[test]
+    fn it_maintains_stats_incrementally() {
+        let mut test = TimeSeries::default().with_capacity(8);
+        // A deterministic sequence with gaps, collisions, wraparound and a
+        // full invalidation of the buffer.
+        let mut epoch = 100u64;
+        for step in 0..200u64 {
+            let value = ((step * 7919) % 23) as f64 - 5f64;
+            epoch += match step % 11 {
+                0 => 0,  // Collision on the last slot
+                1 => 3,  // Gap filled with None
+                7 => 20, // Everything is outdated
+                _ => 1,
+            };
+            test.push((epoch, value));
+            let mut expected = test.clone();
+            expected.calculate_stats();
+            assert_eq!(test.stats, expected.stats, "step {}", step);
+            assert_eq!(test.active_items, test.as_vec().len());
+        }
+    }
+    #[test]
+    fn it_evicts_from_stats_window() {
+        let mut test = TimeSeries {
+            collision_policy: ValueCollisionPolicy::Overwrite,
+            ..TimeSeries::default()
+        }
+        .with_capacity(3);
+        test.push((10, 9f64));
+        test.push((11, 1f64));
+        test.push((12, 5f64));
+        assert_eq!(test.stats.max, 9f64);
+        assert_eq!(test.stats.count, 3);
+        // Overwriting the last slot only changes the open slot
+        test.push((12, 0f64));
+        assert_eq!(test.stats.min, 0f64);
+        assert_eq!(test.stats.sum, 10f64);
+        // The max is evicted from the window
+        test.push((13, 2f64));
+        assert_eq!(test.stats.max, 2f64);
+        assert_eq!(test.stats.min, 0f64);
+        assert_eq!(test.stats.sum, 3f64);
+        assert_eq!(test.stats.count, 3);
+        assert!(!test.stats.is_dirty);
+        // Collisions after wrapping around only change the open slot
+        test.push((13, 7f64));
+        assert_eq!(test.stats.max, 7f64);
+        assert_eq!(test.stats.sum, 8f64);
+        test.push((14, 3f64));
+        test.push((15, 4f64));
+        assert_eq!(test.stats.max, 7f64);
+        assert_eq!(test.stats.min, 3f64);
+        test.push((16, 1f64));
+        assert_eq!(test.stats.max, 4f64);
+        assert_eq!(test.stats.min, 1f64);
+        assert_eq!(test.stats.sum, 8f64);
+        assert_eq!(test.stats.count, 3);
+        assert!(!test.stats.is_dirty);
+    }
+    #[test]
+    fn it_wraps_around_several_times() {
+        let mut test = TimeSeries::default().with_capacity(3);
+        for epoch in 10..20 {
+            test.push((epoch, epoch as f64));
+            assert_eq!(test.active_items, std::cmp::min(3, (epoch - 9) as usize));
+            assert_eq!(test.get_last_epoch(), Some(epoch));
+            assert_eq!(test.get_last_filled(), epoch as f64);
+        }
+        assert_eq!(test.first_idx, 1);
+        assert_eq!(test.last_idx, 1);
+        assert_eq!(
+            test.as_vec(),
+            vec![(17, Some(17f64)), (18, Some(18f64)), (19, Some(19f64))]
+        );
+        // Collisions after the wrap resolve on the last item
+        test.push((19, 1f64));
+        assert_eq!(
+            test.as_vec(),
+            vec![(17, Some(17f64)), (18, Some(18f64)), (19, Some(20f64))]
+        );
+        // Gaps wrap around too
+        test.push((21, 21f64));
+        assert_eq!(
+            test.as_vec(),
+            vec![(19, Some(20f64)), (20, None), (21, Some(21f64))]
+        );
+        assert_eq!(test.first_idx, 0);
+        assert_eq!(test.last_idx, 3);
+        assert_eq!(test.stats.count, 2);
+        assert_eq!(test.stats.max, 21f64);
+    }
+    #[test]
+    fn it_aligns_items_to_granularity() {
+        let mut test = TimeSeries::default().with_capacity(4).with_granularity(5);
+        test.push((101, 1f64));
+        test.push((104, 2f64));
+        assert_eq!(test.as_vec(), vec![(100, Some(3f64))]);
+        // Missing slots are filled every 5 seconds
+        test.push((117, 4f64));
+        assert_eq!(
+            test.as_vec(),
+            vec![
+                (100, Some(3f64)),
+                (105, None),
+                (110, None),
+                (115, Some(4f64))
+            ]
+        );
+        // Four slots of five seconds are kept
+        test.push((120, 5f64));
+        assert_eq!(
+            test.as_vec(),
+            vec![
+                (105, None),
+                (110, None),
+                (115, Some(4f64)),
+                (120, Some(5f64))
+            ]
+        );
+        test.push((111, 6f64));
+        assert_eq!(test.as_vec()[1], (110, Some(6f64)));
+        test.push((100, 6f64));
+        assert_eq!(test.dropped_items, 1);
+        // The buffer is invalidated after more than 20 seconds without data
+        test.push((140, 7f64));
+        assert_eq!(test.as_vec().len(), 4);
+        test.push((166, 8f64));
+        assert_eq!(test.as_vec(), vec![(165, Some(8f64))]);
+    }
+    #[test]
+    fn it_backfills_out_of_order_items() {
+        let mut test = TimeSeries::default().with_capacity(4);
+        test.push((10, 0f64));
+        test.push((13, 3f64));
+        // Items older than the last one are added to their slot
+        test.push((11, 1f64));
+        test.push((12, 2f64));
+        test.push((12, 2f64));
+        assert_eq!(
+            test.as_vec(),
+            vec![
+                (10, Some(0f64)),
+                (11, Some(1f64)),
+                (12, Some(4f64)),
+                (13, Some(3f64))
+            ]
+        );
+        assert_eq!(test.stats.sum, 8f64);
+        assert_eq!(test.stats.count, 4);
+        // The buffer wraps around and the slot is found on the other side
+        test.push((15, 5f64));
+        assert_eq!(test.first_idx, 2);
+        test.push((14, 4f64));
+        assert_eq!(
+            test.as_vec(),
+            vec![
+                (12, Some(4f64)),
+                (13, Some(3f64)),
+                (14, Some(4f64)),
+                (15, Some(5f64))
+            ]
+        );
+        assert_eq!(test.metrics[0], (14, Some(4f64)));
+        assert_eq!(test.get_last_filled(), 5f64);
+        assert_eq!(test.dropped_items, 0);
+        // Items added with circular_push may have gaps in the epochs
+        let mut test = TimeSeries::default().with_capacity(4);
+        test.circular_push((10, Some(0f64)));
+        test.circular_push((12, None));
+        test.circular_push((13, Some(3f64)));
+        test.push((12, 2f64));
+        assert_eq!(
+            test.as_vec(),
+            vec![(10, Some(0f64)), (12, Some(2f64)), (13, Some(3f64))]
+        );
+        // Epochs between the slots have no place and are dropped
+        test.push((11, 1f64));
+        assert_eq!(test.dropped_items, 1);
+    }
+    #[test]
+    fn it_rebuilds_stats_after_deserializing() {
+        let mut test = TimeSeries::default().with_capacity(3);
+        // The average of an empty series is zero, not NaN
+        test.calculate_stats();
+        assert_eq!(test.stats.avg, 0f64);
+        assert_eq!(test.stats.stddev, 0f64);
+        for (epoch, value) in &[(10, 1f64), (11, 2f64), (12, 3f64)] {
+            test.push((*epoch, *value));
+        }
+        let yaml = serde_yaml::to_string(&test).unwrap();
+        let mut test: TimeSeries = serde_yaml::from_str(&yaml).unwrap();
+        test.push((13, 4f64));
+        test.push((14, 5f64));
+        assert_eq!(test.stats.count, 3);
+        assert_eq!(test.stats.sum, 12f64);
+        assert_eq!(test.stats.min, 3f64);
+        assert_eq!(test.stats.avg, 4f64);
+    }
+
+    #[test]
+    fn it_evicts_backfilled_items_from_stats() {
+        let mut test = TimeSeries::default().with_capacity(3);
+        test.push((10, 5f64));
+        test.push((12, 1f64));
+        // The slot at 11 is closed and empty, the backfill fills it
+        test.push((11, 9f64));
+        assert_eq!(test.stats.max, 9f64);
+        assert_eq!(test.stats.sum, 15f64);
+        // Evicting 10 and then the backfilled 11 from the window
+        test.push((13, 2f64));
+        assert_eq!(test.stats.max, 9f64);
+        assert_eq!(test.stats.sum, 12f64);
+        assert_eq!(test.stats.count, 3);
+        test.push((14, 3f64));
+        assert_eq!(
+            test.as_vec(),
+            vec![(12, Some(1f64)), (13, Some(2f64)), (14, Some(3f64))]
+        );
+        assert_eq!(test.stats.max, 3f64);
+        assert_eq!(test.stats.min, 1f64);
+        assert_eq!(test.stats.sum, 6f64);
+        assert_eq!(test.stats.count, 3);
+        assert_eq!(test.stats.avg, 2f64);
+        assert!(!test.stats.is_dirty);
+    }
+    #[test]
+    fn it_drops_items_older_than_buffer() {
+        let mut test = TimeSeries::default().with_capacity(3);
+        for epoch in 10..15 {
+            test.push((epoch, 1f64));
+        }
+        test.push((11, 1f64));
+        test.push((5, 1f64));
+        assert_eq!(test.dropped_items, 2);
+        // The oldest item is still accepted
+        test.push((12, 1f64));
+        assert_eq!(test.dropped_items, 2);
+        assert_eq!(
+            test.as_vec(),
+            vec![(12, Some(2f64)), (13, Some(1f64)), (14, Some(1f64))]
+        );
+        // The buffer is invalidated and the old items are dropped
+        test.push((20, 1f64));
+        test.push((14, 1f64));
+        assert_eq!(test.dropped_items, 3);
+        assert_eq!(test.as_vec(), vec![(20, Some(1f64))]);
+    }
+    #[test]
+    fn it_calculates_extra_stats() {
+        let mut test = TimeSeries::default()
+            .with_capacity(20)
+            .with_extra_stat(StatKind::P90)
+            .with_extra_stat(StatKind::Rate);
+        for value in 1..=10 {
+            test.push((value, value as f64));
+        }
+        assert!(test.stats.extra_is_dirty);
+        test.calculate_extra_stats();
+        assert!(!test.stats.extra_is_dirty);
+        assert_eq!(test.stats.get(StatKind::P50), 5f64);
+        assert_eq!(test.stats.get(StatKind::P90), 9f64);
+        assert_eq!(test.stats.get(StatKind::P99), 10f64);
+        assert_eq!(test.stats.get(StatKind::Rate), 1f64);
+        // sqrt(((1-5.5)^2 + ... + (10-5.5)^2) / 10)
+        assert!((test.stats.get(StatKind::StdDev) - 8.25f64.sqrt()).abs() < 1e-9);
+        // A counter reset is accounted as an increase from zero
+        test.push((11, 2f64));
+        test.push((12, 4f64));
+        test.calculate_extra_stats();
+        assert_eq!(test.stats.get(StatKind::Rate), 13f64 / 11f64);
+    }
+    #[test]
+    fn it_fills_missing_values_with_median() {
+        let mut test = TimeSeries::default().with_capacity(10);
+        test.missing_values_policy = MissingValuesPolicy::Median;
+        test.push((0, 9f64));
+        test.push((2, 1f64));
+        test.push((3, 4f64));
+        assert!(test.stats.extra_is_dirty);
+        test.calculate_extra_stats();
+        assert_eq!(test.get_missing_values_fill(), 4f64);
+    }
+    #[test]
+    fn it_loads_extra_stats_from_config() {
+        let test: TimeSeriesSource = serde_yaml::from_str(
+            r#"
+            type: alacritty_input
+            name: input
+            series:
+              metrics_capacity: 60
+              extra_stats: [p50, rate]
+              missing_values_policy: P90
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.series().metrics_capacity, 60);
+        assert_eq!(
+            test.series().extra_stats,
+            vec![StatKind::P50, StatKind::Rate]
+        );
+        assert_eq!(
+            test.series().missing_values_policy,
+            MissingValuesPolicy::P90
+        );
+        let test: Decoration = serde_yaml::from_str(
+            r#"
+            type: reference
+            stat: p90
+            "#,
+        )
+        .unwrap();
+        if let Decoration::Reference(d) = test {
+            assert_eq!(d.stat, Some(StatKind::P90));
+        } else {
+            panic!("Expected a reference decoration");
+        }
     }
     #[test]
     fn it_iterates_trait() {
@@ -1175,6 +3079,55 @@ mod tests {
         assert_eq!(iter_test3.next(), Some(&(11, Some(1f64))));
     }
 
+    #[test]
+    fn it_queries_ranges() {
+        let mut test = TimeSeries::default().with_capacity(5);
+        for epoch in 10..18 {
+            test.push((epoch, epoch as f64));
+        }
+        test.push((19, 19f64));
+        // The buffer has wrapped around: 15, 16, 17, 18(None), 19
+        fn epochs<'a>(iter: impl Iterator<Item = &'a (u64, Option<f64>)>) -> Vec<u64> {
+            iter.map(|entry| entry.0).collect()
+        }
+        assert_eq!(epochs(test.iter()), vec![15, 16, 17, 18, 19]);
+        assert_eq!(epochs(test.range(16, 19)), vec![16, 17, 18]);
+        assert_eq!(epochs(test.range(16, 19).rev()), vec![18, 17, 16]);
+        assert_eq!(epochs(test.range(0, 100)), vec![15, 16, 17, 18, 19]);
+        assert!(test.range(20, 30).next().is_none());
+        assert!(test.range(17, 16).next().is_none());
+        assert_eq!(test.range(15, 18).len(), 3);
+        assert_eq!(epochs(test.last_n(2)), vec![18, 19]);
+        assert_eq!(epochs(test.last_n(10)), vec![15, 16, 17, 18, 19]);
+        // Both ends meet in the middle
+        let mut iter = test.iter();
+        assert_eq!(iter.next(), Some(&(15, Some(15f64))));
+        assert_eq!(iter.next_back(), Some(&(19, Some(19f64))));
+        assert_eq!(iter.len(), 3);
+        assert_eq!(epochs(iter), vec![16, 17, 18]);
+        assert_eq!(test.value_at(16), Some(16f64));
+        assert_eq!(test.value_at(18), None);
+        assert_eq!(test.value_at(14), None);
+        assert_eq!(test.value_at(20), None);
+        // The sum of the filled slots by borrowing the TimeSeries
+        let mut sum = 0f64;
+        for entry in &test {
+            sum += entry.1.unwrap_or(0f64);
+        }
+        assert_eq!(sum, 67f64);
+        // Epochs inside a slot find the slot
+        let mut test = TimeSeries::default().with_capacity(5).with_granularity(10);
+        test.push((100, 1f64));
+        test.push((120, 3f64));
+        assert_eq!(test.value_at(127), Some(3f64));
+        assert_eq!(test.value_at(115), None);
+        assert_eq!(epochs(test.range(105, 125)), vec![110, 120]);
+        let empty: TimeSeries = TimeSeries::default();
+        assert!(empty.range(0, 100).next().is_none());
+        assert!(empty.last_n(3).next_back().is_none());
+        assert_eq!(empty.value_at(0), None);
+    }
+
     #[test]
     fn it_scales_x_to_display_size() {
         let mut test = SizeInfo {
@@ -1307,6 +3260,215 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_honours_missing_values_policy_in_opengl_vertices() {
+        let (size_test, mut chart_test) = simple_chart_setup_with_none();
+        // The None at epoch 14 is between 2 (epoch 12) and 4 (epoch 15)
+        chart_test.sources[0].series_mut().missing_values_policy = MissingValuesPolicy::Linear;
+        chart_test.update_opengl_vecs(0, size_test);
+        assert_eq!(chart_test.opengl_vecs[0].len(), 10);
+        assert!((chart_test.opengl_vecs[0][7] - (-1.0 + 0.025 * 10.0 / 3.0)).abs() < 1e-6);
+        // The line is broken by a NaN Y coordinate
+        chart_test.sources[0].series_mut().missing_values_policy = MissingValuesPolicy::Gap;
+        chart_test.update_opengl_vecs(0, size_test);
+        assert_eq!(chart_test.opengl_vecs[0][6], -0.97);
+        assert!(chart_test.opengl_vecs[0][7].is_nan());
+        assert_eq!(chart_test.opengl_vecs[0][9], -0.9);
+    }
+
+    #[test]
+    fn it_tracks_source_health() {
+        let mut chart_test: TimeSeriesChart = serde_yaml::from_str(
+            "
+            name: load
+            series:
+            - name: load
+              type: prometheus
+              refresh: 15
+              source: 'http://localhost:9090/api/v1/query_range?query=node_load1'
+            - name: cpu 0
+              type: prometheus
+              split_from: load
+            - name: input
+              type: alacritty_input
+            ",
+        )
+        .unwrap();
+        // Not loaded yet
+        assert!(!chart_test.sources[0].is_stale(1000));
+        chart_test.record_failure(0, 1000, "Timed out after 15s");
+        for series_idx in 0..2 {
+            let health = chart_test.sources[series_idx].health().unwrap();
+            assert_eq!(health.consecutive_failures, 1);
+            assert_eq!(health.last_error, Some(String::from("Timed out after 15s")));
+            assert!(chart_test.sources[series_idx].is_stale(1000));
+        }
+        chart_test.record_success(0, 1010, &[]);
+        let health = chart_test.sources[1].health().unwrap();
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.last_success, Some(1010));
+        assert_eq!(health.last_failure, Some(1000));
+        // Stale after 3 pull intervals of 15 seconds
+        assert!(!chart_test.sources[0].is_stale(1055));
+        assert!(chart_test.sources[0].is_stale(1056));
+        chart_test.record_success(0, 1020, &[String::from("results truncated")]);
+        assert_eq!(
+            chart_test.sources[1].health().unwrap().warnings,
+            vec![String::from("results truncated")]
+        );
+        chart_test.record_success(0, 1010, &[]);
+        assert!(chart_test.sources[1].health().unwrap().warnings.is_empty());
+        // The totals since start
+        chart_test.record_loaded(0, 30);
+        chart_test.record_poll_duration(0, std::time::Duration::from_millis(250));
+        let health = chart_test.sources[0].health().unwrap();
+        assert_eq!((health.successes, health.failures), (3, 1));
+        assert_eq!(health.records_loaded, 30);
+        assert_eq!(health.last_poll_duration, Some(0.25));
+        let health = chart_test.sources[1].health().unwrap();
+        assert_eq!(health.records_loaded, 0);
+        assert_eq!(health.last_poll_duration, Some(0.25));
+        // Manual series are never stale
+        assert!(chart_test.sources[2].health().is_none());
+        assert!(!chart_test.sources[2].is_stale(5000));
+        // Out of bounds are ignored
+        chart_test.record_failure(5, 1000, "Unknown");
+    }
+
+    #[test]
+    fn it_splits_prometheus_series_by_labels() {
+        let matrix = |epoch: u64, cpus: &[&str]| -> prometheus::HTTPResponse {
+            let results: Vec<String> = cpus
+                .iter()
+                .map(|cpu| {
+                    format!(
+                        r#"{{"metric":{{"__name__":"node_cpu","cpu":"{}"}},
+                            "values":[[{},"1"],[{},"2"]]}}"#,
+                        cpu,
+                        epoch,
+                        epoch + 1
+                    )
+                })
+                .collect();
+            serde_json::from_str(&format!(
+                r#"{{"status":"success","data":{{"resultType":"matrix","result":[{}]}}}}"#,
+                results.join(",")
+            ))
+            .unwrap()
+        };
+        let mut chart_test: TimeSeriesChart = serde_yaml::from_str(
+            "
+            name: cpu
+            series:
+            - name: cpu
+              type: prometheus
+              source: 'http://localhost:9090/api/v1/query_range?query=node_cpu'
+              split_by_labels: true
+              label_template: 'cpu {cpu}'
+              series:
+                metrics_capacity: 10
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            chart_test.load_prometheus_response(0, matrix(100, &["0", "1"])),
+            Ok(4)
+        );
+        let names: Vec<String> = chart_test.sources.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["cpu", "cpu 0", "cpu 1"]);
+        assert_eq!(chart_test.loaded_series(0), vec![0, 1, 2]);
+        assert_eq!(chart_test.loaded_series(1), vec![1]);
+        assert!(chart_test.loaded_series(3).is_empty());
+        assert!(chart_test.sources[0].series().as_vec().is_empty());
+        assert_eq!(
+            chart_test.sources[2].series().as_vec(),
+            vec![(100, Some(1f64)), (101, Some(2f64))]
+        );
+        if let (
+            TimeSeriesSource::PrometheusTimeSeries(cpu0),
+            TimeSeriesSource::PrometheusTimeSeries(cpu1),
+        ) = (&chart_test.sources[1], &chart_test.sources[2])
+        {
+            assert_ne!(cpu0.color, cpu1.color);
+            assert_eq!(cpu1.series.metrics_capacity, 10);
+        }
+        for series_idx in 0..chart_test.sources.len() {
+            chart_test.update_opengl_vecs(series_idx, SizeInfo::default());
+        }
+        assert_eq!(chart_test.opengl_vecs.len(), 3);
+        // cpu 1 disappears but still has data in the time range
+        assert_eq!(
+            chart_test.load_prometheus_response(0, matrix(105, &["0"])),
+            Ok(2)
+        );
+        assert_eq!(chart_test.sources.len(), 3);
+        // A new label set is appended
+        chart_test
+            .load_prometheus_response(0, matrix(108, &["0", "2"]))
+            .unwrap();
+        assert_eq!(chart_test.sources[3].name(), "cpu 2");
+        // cpu 1 data is out of the time range of 10 seconds
+        chart_test
+            .load_prometheus_response(0, matrix(110, &["0", "2"]))
+            .unwrap();
+        let names: Vec<String> = chart_test.sources.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["cpu", "cpu 0", "cpu 2"]);
+        assert_eq!(chart_test.opengl_vecs.len(), 2);
+        chart_test.update_opengl_vecs(2, SizeInfo::default());
+        assert_eq!(chart_test.opengl_vecs.len(), 3);
+        // The color of the retired cpu 1 is reused instead of the one of cpu 2
+        chart_test
+            .load_prometheus_response(0, matrix(111, &["0", "2", "3"]))
+            .unwrap();
+        let colors: Vec<Option<Rgb>> = chart_test
+            .sources
+            .iter()
+            .map(|s| s.color_alpha().0.map(|c| c.rgb))
+            .collect();
+        assert_eq!(
+            colors,
+            vec![
+                None,
+                Some(color::palette_color(0)),
+                Some(color::palette_color(2)),
+                Some(color::palette_color(1))
+            ]
+        );
+        // Series that are not split are loaded as before
+        chart_test.sources.truncate(1);
+        if let TimeSeriesSource::PrometheusTimeSeries(ref mut prom) = chart_test.sources[0] {
+            prom.split_by_labels = false;
+        }
+        chart_test
+            .load_prometheus_response(0, matrix(100, &["0", "1"]))
+            .unwrap();
+        assert_eq!(chart_test.sources.len(), 1);
+        assert_eq!(chart_test.sources[0].series().as_vec().len(), 2);
+        assert!(chart_test
+            .load_prometheus_response(3, matrix(100, &["0"]))
+            .is_err());
+    }
+
+    #[test]
+    fn it_follows_stat_on_reference_point() {
+        let (size_test, mut chart_test) = simple_chart_setup_with_none();
+        chart_test
+            .decorations
+            .push(Decoration::Reference(ReferencePointDecoration {
+                stat: Some(StatKind::P50),
+                ..ReferencePointDecoration::default()
+            }));
+        chart_test.update_opengl_vecs(0, size_test);
+        // The values are 0, 1, 2, 4
+        if let Decoration::Reference(ref d) = chart_test.decorations[0] {
+            assert_eq!(d.value, 1f64);
+        }
+        assert_eq!(
+            chart_test.sources[0].series().extra_stats,
+            vec![StatKind::P50]
+        );
+    }
+
     #[test]
     fn it_calculates_reference_point() {
         let (size_test, mut chart_test) = simple_chart_setup_with_none();
@@ -1357,6 +3519,97 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn it_maps_deprecated_manual_granularity() {
+        let test: ManualTimeSeries = serde_yaml::from_str(
+            "
+            name: input
+            granularity: 5
+            alpha: 0.5
+            ",
+        )
+        .unwrap();
+        assert_eq!(test.series.granularity, 5);
+        assert_eq!(test.alpha, 0.5);
+        let test: ManualTimeSeries = serde_yaml::from_str(
+            "
+            name: input
+            granularity: 5
+            series:
+              granularity: 10
+            ",
+        )
+        .unwrap();
+        assert_eq!(test.series.granularity, 10);
+        let test: ManualTimeSeries = serde_yaml::from_str("name: input").unwrap();
+        assert_eq!(test.series.granularity, 1);
+    }
+
+    #[test]
+    fn it_draws_rolled_up_tiers() {
+        let (size_test, mut chart_test) = simple_chart_setup_with_none();
+        chart_test.sources[0] = TimeSeriesSource::default();
+        chart_test.sources[0].series_mut().metrics_capacity = 10;
+        chart_test.rollup = serde_yaml::from_str("[{resolution: 10, capacity: 6}]").unwrap();
+        // The tiers catch up with the series each time the chart is drawn
+        for epoch in 100..126 {
+            chart_test.sources[0].series_mut().push((epoch, 1f64));
+            if epoch % 10 == 9 {
+                chart_test.update_opengl_vecs(0, size_test);
+            }
+        }
+        chart_test.update_opengl_vecs(0, size_test);
+        // Without a range the full resolution series is drawn
+        assert_eq!(chart_test.opengl_vecs[0].len(), 20);
+        assert_eq!(chart_test.stats.max, 1f64);
+        // A minute does not fit in the 10 seconds of the series
+        chart_test.range = Some(60);
+        chart_test.update_opengl_vecs(0, size_test);
+        assert_eq!(
+            chart_test.drawn_series(0).as_vec(),
+            vec![(100, Some(10f64)), (110, Some(10f64)), (120, Some(6f64))]
+        );
+        assert_eq!(chart_test.opengl_vecs[0].len(), 6);
+        assert_eq!(chart_test.stats.max, 10f64);
+    }
+
+    #[test]
+    fn it_draws_series_decorations() {
+        let (size_test, mut chart_test) = simple_chart_setup_with_none();
+        chart_test.decorations = serde_yaml::from_str(
+            "
+            - type: threshold
+              value: 10
+            - type: moving_average
+              window: 2
+            - type: envelope
+            - type: alert
+              value: 3
+              padding:
+                x: 2
+            ",
+        )
+        .unwrap();
+        chart_test.update_opengl_vecs(0, size_test);
+        // The threshold extends the values range, only the alert marker
+        // takes space
+        assert_eq!(chart_test.stats.max, 10f64);
+        assert_eq!(chart_test.stats.min, 0f64);
+        assert_eq!(chart_test.decorations[0].top_value(), 10f64);
+        assert_eq!(chart_test.decorations[2].top_value(), 4f64);
+        assert_eq!(chart_test.decorations[2].bottom_value(), 0f64);
+        let widths: Vec<f32> = chart_test.decorations.iter().map(|d| d.width()).collect();
+        assert_eq!(widths, vec![0., 0., 0., 2.]);
+        // No value is above 10
+        assert!(chart_test.decorations[0].opengl_vertices().is_empty());
+        // Four lines between the five values
+        assert_eq!(chart_test.decorations[1].opengl_vertices().len(), 16);
+        assert_eq!(chart_test.decorations[2].alpha(), 0.3);
+        // The last value, 4, is above the alert value
+        assert_eq!(chart_test.decorations[3].opengl_vertices().len(), 12);
+        assert_eq!(chart_test.decorations[3].color(), DEFAULT_DECORATION_COLOR);
+    }
 }
 // TODO: `init_opengl_context` provides a default initialization of OpengL
 // context. This function is called previous to sending the vector data.
@@ -0,0 +1,521 @@
+//! Receives the InfluxDB line protocol and the Graphite plaintext protocol on
+//! TCP or UDP sockets, the samples are routed to the series whose
+//! measurement and field, or dotted path, and labels match.
+use log::*;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// `INFLUX_DEFAULT_LISTEN` is the address used when an Influx series does not
+/// set `listen`
+pub const INFLUX_DEFAULT_LISTEN: &str = "udp://127.0.0.1:8089";
+
+/// `GRAPHITE_DEFAULT_LISTEN` is the address used when a Graphite series does
+/// not set `listen`
+pub const GRAPHITE_DEFAULT_LISTEN: &str = "tcp://127.0.0.1:2003";
+
+/// `LineProtocol` is the format of the lines received on a socket
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LineProtocol {
+    Influx,
+    Graphite,
+}
+
+/// `Transport` is the kind of socket a protocol is received on
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// `parse_listen` parses a `tcp://host:port` or `udp://host:port` address
+pub fn parse_listen(listen: &str) -> Result<(Transport, SocketAddr), String> {
+    let (transport, addr) = if let Some(addr) = listen.strip_prefix("tcp://") {
+        (Transport::Tcp, addr)
+    } else if let Some(addr) = listen.strip_prefix("udp://") {
+        (Transport::Udp, addr)
+    } else {
+        return Err(format!("Expected tcp:// or udp:// in '{}'", listen));
+    };
+    let addr = addr
+        .parse()
+        .map_err(|err| format!("Invalid address '{}': {}", listen, err))?;
+    Ok((transport, addr))
+}
+
+/// `LineSample` is a value received in a line, the Graphite samples have no
+/// field
+#[derive(Debug, PartialEq, Clone)]
+pub struct LineSample {
+    /// The Influx measurement or the Graphite path
+    pub name: String,
+    pub field: String,
+    pub labels: HashMap<String, String>,
+    pub value: f64,
+    /// The epoch in seconds, the time of reception is used when missing
+    pub epoch: Option<u64>,
+}
+
+/// `split_unescaped` splits `input` on `separator` when it is not escaped
+/// with a backslash nor inside double quotes
+fn split_unescaped(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut start, mut escaped, mut quoted) = (0, false, false);
+    for (idx, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            _ if c == separator && !quoted => {
+                parts.push(&input[start..idx]);
+                start = idx + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+/// `unescape` removes the backslashes of the escaped characters
+fn unescape(input: &str) -> String {
+    let mut res = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => res.extend(chars.next()),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+/// `parse_key_value` splits a `key=value` pair of a line
+fn parse_key_value(pair: &str, line: &str) -> Result<(String, String), String> {
+    match split_unescaped(pair, '=').as_slice() {
+        [key, value] if !key.is_empty() => Ok((unescape(key), value.to_string())),
+        _ => Err(format!("Invalid key=value '{}' in '{}'", pair, line)),
+    }
+}
+
+/// `parse_influx_value` parses a field value, integers have an i or u
+/// suffix, booleans are 1 or 0 and strings are not supported
+fn parse_influx_value(value: &str) -> Option<f64> {
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => Some(1.),
+        "f" | "F" | "false" | "False" | "FALSE" => Some(0.),
+        _ if value.starts_with('"') => None,
+        _ => value
+            .strip_suffix('i')
+            .or_else(|| value.strip_suffix('u'))
+            .unwrap_or(value)
+            .parse()
+            .ok(),
+    }
+}
+
+/// `parse_influx_line` parses an InfluxDB line protocol line,
+/// `measurement,tag=value field=1.5,other=2i 1556000000000000000`, into a
+/// sample per numeric field. The timestamp is in nanoseconds.
+pub fn parse_influx_line(line: &str) -> Result<Vec<LineSample>, String> {
+    let sections = split_unescaped(line.trim(), ' ');
+    let (series, fields, timestamp) = match sections.as_slice() {
+        [series, fields] => (*series, *fields, None),
+        [series, fields, timestamp] => (*series, *fields, Some(*timestamp)),
+        _ => {
+            return Err(format!(
+                "Expected measurement, fields and timestamp in '{}'",
+                line
+            ))
+        }
+    };
+    let mut series = split_unescaped(series, ',').into_iter();
+    let name = unescape(series.next().unwrap_or_default());
+    if name.is_empty() {
+        return Err(format!("Missing measurement in '{}'", line));
+    }
+    let mut labels = HashMap::new();
+    for tag in series {
+        let (key, value) = parse_key_value(tag, line)?;
+        labels.insert(key, unescape(&value));
+    }
+    let epoch = match timestamp {
+        Some(timestamp) => Some(
+            timestamp
+                .parse::<u64>()
+                .map_err(|err| format!("Invalid timestamp in '{}': {}", line, err))?
+                / 1_000_000_000,
+        ),
+        None => None,
+    };
+    let mut samples = vec![];
+    for field in split_unescaped(fields, ',') {
+        let (field, value) = parse_key_value(field, line)?;
+        match parse_influx_value(&value) {
+            Some(value) => samples.push(LineSample {
+                name: name.clone(),
+                field,
+                labels: labels.clone(),
+                value,
+                epoch,
+            }),
+            None if value.starts_with('"') => debug!("Skipping string field {}", field),
+            None => return Err(format!("Invalid value for {} in '{}'", field, line)),
+        }
+    }
+    Ok(samples)
+}
+
+/// `parse_graphite_line` parses a Graphite plaintext line,
+/// `servers.web1.load;dc=eu 0.5 1556000000`, the tags are optional and a
+/// timestamp of -1 or no timestamp is the time of reception
+pub fn parse_graphite_line(line: &str) -> Result<LineSample, String> {
+    let mut fields = line.split_whitespace();
+    let (path, value, timestamp) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(path), Some(value), timestamp) if fields.next().is_none() => (path, value, timestamp),
+        _ => return Err(format!("Expected 'path value timestamp' in '{}'", line)),
+    };
+    let mut path = path.split(';');
+    let name = path.next().unwrap_or_default().to_string();
+    let mut labels = HashMap::new();
+    for tag in path {
+        let (key, value) = parse_key_value(tag, line)?;
+        labels.insert(key, value);
+    }
+    let value = value
+        .parse()
+        .map_err(|err| format!("Invalid value in '{}': {}", line, err))?;
+    let epoch = match timestamp {
+        Some("-1") | None => None,
+        Some(timestamp) => Some(
+            timestamp
+                .parse::<f64>()
+                .map_err(|err| format!("Invalid timestamp in '{}': {}", line, err))?
+                as u64,
+        ),
+    };
+    Ok(LineSample {
+        name,
+        field: String::new(),
+        labels,
+        value,
+        epoch,
+    })
+}
+
+/// `parse_lines` parses the lines received in `protocol`, the invalid lines
+/// are logged and skipped
+pub fn parse_lines(protocol: LineProtocol, text: &str) -> Vec<LineSample> {
+    let mut samples = vec![];
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let res = match protocol {
+            LineProtocol::Influx => parse_influx_line(line),
+            LineProtocol::Graphite => parse_graphite_line(line).map(|sample| vec![sample]),
+        };
+        match res {
+            Ok(mut parsed) => samples.append(&mut parsed),
+            Err(err) => warn!("parse_lines: {:?}: {}", protocol, err),
+        }
+    }
+    samples
+}
+
+/// `glob_match` matches a Graphite path against a pattern where `*` matches
+/// any characters inside a path component, i.e. `servers.*.load`
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let patterns: Vec<&str> = pattern.split('.').collect();
+    let components: Vec<&str> = path.split('.').collect();
+    patterns.len() == components.len()
+        && patterns
+            .iter()
+            .zip(components.iter())
+            .all(|(pattern, component)| wildcard_match(pattern.as_bytes(), component.as_bytes()))
+}
+
+/// `wildcard_match` matches `input` against `pattern` with `*` wildcards.
+/// On a mismatch the last `*` takes one more byte of the input, earlier
+/// wildcards never need to be retried, so it runs in O(pattern * input).
+fn wildcard_match(pattern: &[u8], input: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // The position of the last `*` in the pattern and of the input it matched
+    let mut last_star = None;
+    while i < input.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                last_star = Some((p, i));
+                p += 1;
+            }
+            Some(byte) if *byte == input[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match last_star {
+                Some((star_p, star_i)) => {
+                    last_star = Some((star_p, star_i + 1));
+                    p = star_p + 1;
+                    i = star_i + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|byte| *byte == b'*')
+}
+
+/// `match_labels` returns true when `labels` contains all the `required`
+/// labels with the same values
+fn match_labels(required: &HashMap<String, String>, labels: &HashMap<String, String>) -> bool {
+    required
+        .iter()
+        .all(|(label, value)| labels.get(label) == Some(value))
+}
+
+/// `InfluxTimeSeries` loads a field of a measurement received in the InfluxDB
+/// line protocol
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct InfluxTimeSeries {
+    /// The name of the series
+    #[serde(default)]
+    pub name: String,
+
+    /// The TimeSeries that contains the data
+    #[serde(default = "crate::prometheus::default_series")]
+    #[serde(deserialize_with = "crate::prometheus::deserialize_series")]
+    pub series: crate::TimeSeries,
+
+    /// The socket to listen on, defaults to INFLUX_DEFAULT_LISTEN
+    #[serde(default)]
+    pub listen: String,
+
+    /// The measurement to load, i.e. cpu
+    #[serde(default)]
+    pub measurement: String,
+
+    /// The field to load, `value` by default
+    #[serde(default)]
+    pub field: String,
+
+    /// The tags key and value, if any, to match the lines
+    #[serde(default)]
+    #[serde(rename = "labels")]
+    pub required_labels: HashMap<String, String>,
+
+    /// The color of the TimeSeries
+    #[serde(default)]
+    pub color: Option<crate::color::Color>,
+
+    /// The transparency of the TimeSeries
+    #[serde(default)]
+    pub alpha: f32,
+}
+
+impl Default for InfluxTimeSeries {
+    fn default() -> InfluxTimeSeries {
+        InfluxTimeSeries {
+            name: String::from("Unset"),
+            series: crate::prometheus::default_series(),
+            listen: String::from(""),
+            measurement: String::from(""),
+            field: String::from(""),
+            required_labels: HashMap::new(),
+            color: None,
+            alpha: 1.0,
+        }
+    }
+}
+
+impl InfluxTimeSeries {
+    /// `listen_addr` returns the socket to listen on
+    pub fn listen_addr(&self) -> &str {
+        if self.listen.is_empty() {
+            INFLUX_DEFAULT_LISTEN
+        } else {
+            &self.listen
+        }
+    }
+
+    /// `match_metric_labels` returns true when `sample` is for this series
+    pub fn match_metric_labels(&self, sample: &LineSample) -> bool {
+        let field = if self.field.is_empty() {
+            "value"
+        } else {
+            &self.field
+        };
+        sample.name == self.measurement
+            && sample.field == field
+            && match_labels(&self.required_labels, &sample.labels)
+    }
+}
+
+/// `GraphiteTimeSeries` loads the Graphite paths matching a pattern
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct GraphiteTimeSeries {
+    /// The name of the series
+    #[serde(default)]
+    pub name: String,
+
+    /// The TimeSeries that contains the data
+    #[serde(default = "crate::prometheus::default_series")]
+    #[serde(deserialize_with = "crate::prometheus::deserialize_series")]
+    pub series: crate::TimeSeries,
+
+    /// The socket to listen on, defaults to GRAPHITE_DEFAULT_LISTEN
+    #[serde(default)]
+    pub listen: String,
+
+    /// The dotted path to load, `*` matches any characters inside a path
+    /// component, i.e. servers.*.load
+    #[serde(default)]
+    pub path: String,
+
+    /// The tags key and value, if any, to match the lines
+    #[serde(default)]
+    #[serde(rename = "labels")]
+    pub required_labels: HashMap<String, String>,
+
+    /// The color of the TimeSeries
+    #[serde(default)]
+    pub color: Option<crate::color::Color>,
+
+    /// The transparency of the TimeSeries
+    #[serde(default)]
+    pub alpha: f32,
+}
+
+impl Default for GraphiteTimeSeries {
+    fn default() -> GraphiteTimeSeries {
+        GraphiteTimeSeries {
+            name: String::from("Unset"),
+            series: crate::prometheus::default_series(),
+            listen: String::from(""),
+            path: String::from(""),
+            required_labels: HashMap::new(),
+            color: None,
+            alpha: 1.0,
+        }
+    }
+}
+
+impl GraphiteTimeSeries {
+    /// `listen_addr` returns the socket to listen on
+    pub fn listen_addr(&self) -> &str {
+        if self.listen.is_empty() {
+            GRAPHITE_DEFAULT_LISTEN
+        } else {
+            &self.listen
+        }
+    }
+
+    /// `match_metric_labels` returns true when `sample` is for this series
+    pub fn match_metric_labels(&self, sample: &LineSample) -> bool {
+        glob_match(&self.path, &sample.name) && match_labels(&self.required_labels, &sample.labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_influx_lines() {
+        let samples = parse_influx_line(
+            "cpu,host=web\\ 1,region=eu usage_idle=87.5,usage_user=3i,ok=true,note=\"a b,c=d\" 1556000000123456789",
+        )
+        .unwrap();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[0].name, "cpu");
+        assert_eq!(samples[0].field, "usage_idle");
+        assert_eq!(samples[0].value, 87.5);
+        assert_eq!(samples[0].labels["host"], "web 1");
+        assert_eq!(samples[0].labels["region"], "eu");
+        assert_eq!(samples[0].epoch, Some(1_556_000_000));
+        assert_eq!(samples[1].value, 3.);
+        assert_eq!(samples[2].value, 1.);
+        let samples = parse_influx_line("disk\\,free value=12").unwrap();
+        assert_eq!(samples[0].name, "disk,free");
+        assert_eq!(samples[0].epoch, None);
+        assert!(parse_influx_line("cpu").is_err());
+        assert!(parse_influx_line("cpu value=x").is_err());
+        assert!(parse_influx_line("cpu,host value=1").is_err());
+        assert!(parse_influx_line("cpu value=1 yesterday").is_err());
+        assert_eq!(
+            parse_lines(
+                LineProtocol::Influx,
+                "# comment\ncpu value=1\ninvalid\nmem value=2 1556000000000000000\n"
+            )
+            .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn it_parses_graphite_lines() {
+        let sample = parse_graphite_line("servers.web1.load 0.5 1556000000").unwrap();
+        assert_eq!(sample.name, "servers.web1.load");
+        assert_eq!(sample.value, 0.5);
+        assert_eq!(sample.epoch, Some(1_556_000_000));
+        let sample = parse_graphite_line("disk.used;dc=eu;host=a 42 -1").unwrap();
+        assert_eq!(sample.name, "disk.used");
+        assert_eq!(sample.labels["dc"], "eu");
+        assert_eq!(sample.epoch, None);
+        assert_eq!(parse_graphite_line("up 1").unwrap().epoch, None);
+        assert!(parse_graphite_line("up").is_err());
+        assert!(parse_graphite_line("up one 1556000000").is_err());
+        assert!(parse_graphite_line("up 1 1556000000 extra").is_err());
+        assert!(parse_graphite_line("up;dc 1").is_err());
+    }
+
+    #[test]
+    fn it_matches_lines_to_series() {
+        assert!(glob_match("servers.*.load", "servers.web1.load"));
+        assert!(glob_match("servers.web*.load", "servers.web1.load"));
+        assert!(glob_match("servers.*1.*", "servers.web1.load"));
+        assert!(!glob_match("servers.*.load", "servers.web1.cpu.load"));
+        assert!(!glob_match("servers.db*.load", "servers.web1.load"));
+        assert!(glob_match("servers.**.load", "servers..load"));
+        assert!(!glob_match("servers.web?.load", "servers.web1.load"));
+        // Long components and backtracking patterns do not blow up
+        let long_component = format!("servers.{}.load", "w".repeat(60 * 1024));
+        assert!(glob_match("servers.*.load", &long_component));
+        assert!(glob_match("servers.w*w*w.load", &long_component));
+        assert!(!glob_match("servers.*x.load", &long_component));
+        let pathological = "a".repeat(40);
+        assert!(!glob_match("*a*a*a*a*a*a*b", &pathological));
+        assert!(glob_match("*a*a*a*a*a*a*", &pathological));
+        let mut graphite = GraphiteTimeSeries {
+            path: String::from("servers.*.load"),
+            ..GraphiteTimeSeries::default()
+        };
+        let sample = parse_graphite_line("servers.web1.load;dc=eu 1").unwrap();
+        assert!(graphite.match_metric_labels(&sample));
+        graphite
+            .required_labels
+            .insert(String::from("dc"), String::from("us"));
+        assert!(!graphite.match_metric_labels(&sample));
+        let influx: InfluxTimeSeries = serde_yaml::from_str(
+            "
+            name: idle
+            measurement: cpu
+            field: usage_idle
+            labels:
+              host: a
+            ",
+        )
+        .unwrap();
+        assert_eq!(influx.listen_addr(), "udp://127.0.0.1:8089");
+        let samples = parse_influx_line("cpu,host=a usage_idle=90,usage_user=5").unwrap();
+        assert!(influx.match_metric_labels(&samples[0]));
+        assert!(!influx.match_metric_labels(&samples[1]));
+        let samples = parse_influx_line("cpu,host=b usage_idle=90").unwrap();
+        assert!(!influx.match_metric_labels(&samples[0]));
+        assert_eq!(
+            parse_listen("tcp://127.0.0.1:2003"),
+            Ok((Transport::Tcp, "127.0.0.1:2003".parse().unwrap()))
+        );
+        assert!(parse_listen("127.0.0.1:2003").is_err());
+        assert!(parse_listen("udp://localhost").is_err());
+    }
+}
@@ -1,265 +1,133 @@
 //! Loads prometheus metrics every now and then and displays stats
 use circular_buffer_metrics::config::Config;
-use circular_buffer_metrics::prometheus;
-use circular_buffer_metrics::SizeInfo; // XXX: remove on merge.
+use circular_buffer_metrics::coordinator::{
+    async_coordinator, restore_snapshot, spawn_line_listeners, spawn_prometheus_polls,
+    spawn_snapshot_writes, spawn_statsd_listeners, AsyncChartTask,
+};
+use circular_buffer_metrics::export::spawn_export_server;
+use circular_buffer_metrics::render::update_charts;
+use circular_buffer_metrics::terminal::{
+    render_ansi, terminal_size_info, Glyphs, DEFAULT_CELL_HEIGHT, DEFAULT_CELL_WIDTH,
+};
 use circular_buffer_metrics::TimeSeriesChart;
-use circular_buffer_metrics::TimeSeriesSource;
 use env_logger::Env;
 use futures::future::lazy;
 use futures::sync::{mpsc, oneshot};
 use log::*;
+use std::fs::File;
+use std::io::{self, Write};
+use std::process::Command;
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use tokio::prelude::*;
-use tokio::timer::Interval;
 
-// TODO:
-// - Add color fetch
-// - Maybe the coordinator should talk to OpenGL directly to avoid copyign arrays.
-/// `MetricRequest` contains a way to address a particular
-/// item in our TimeSeriesCharts vectors
-#[derive(Debug, Clone)]
-pub struct MetricRequest {
-    pull_interval: u64,
-    source_url: String,
-    chart_index: usize,  // For Vec<TimeSeriesChart>
-    series_index: usize, // For Vec<TimeSeriesSource>
-    data: Option<prometheus::HTTPResponse>,
-    capacity: usize, // This maps to the time range in seconds to query.
-}
-
-/// `AsyncChartTask` contains message types that async_coordinator can work on
-#[derive(Debug)]
-pub enum AsyncChartTask {
-    LoadResponse(MetricRequest),
-    GetMetricsOpenGLData(usize, usize, oneshot::Sender<Vec<f32>>),
-    GetDecorationsOpenGLData(usize, usize, oneshot::Sender<Vec<f32>>),
-}
-
-/// `load_http_response` is called by async_coordinator when a task of type
-/// LoadResponse is received
-pub fn load_http_response(charts: &mut Vec<TimeSeriesChart>, response: MetricRequest) {
-    if let Some(data) = response.data {
-        let mut ok_records = 0;
-        if response.chart_index < charts.len()
-            && response.series_index < charts[response.chart_index].sources.len()
-        {
-            if let TimeSeriesSource::PrometheusTimeSeries(ref mut prom) =
-                charts[response.chart_index].sources[response.series_index]
-            {
-                match prom.load_prometheus_response(data) {
-                    Ok(num_records) => {
-                        info!(
-                            "Loaded {} records from {} into TimeSeries",
-                            num_records, response.source_url
-                        );
-                        ok_records = num_records;
-                    }
-                    Err(err) => {
-                        debug!(
-                            "Error from {} into TimeSeries: {:?}",
-                            response.source_url, err
-                        );
-                    }
-                }
-            }
-            charts[response.chart_index].update_opengl_vecs(
-                response.series_index,
-                SizeInfo {
-                    padding_x: 0.,
-                    padding_y: 0.,
-                    height: 100.,
-                    width: 100.,
-                    ..SizeInfo::default()
-                },
-            );
-        }
-        for chart in charts {
-            // Update the loaded item counters
-            info!("Searching for AsyncLoadedItems in '{}'", chart.name);
-            for series in &mut chart.sources {
-                if let TimeSeriesSource::AsyncLoadedItems(ref mut loaded) = series {
-                    loaded.series.push_current_epoch(ok_records as f64);
-                }
+/// `terminal_grid` returns the columns and rows of the controlling terminal,
+/// 80x24 if it cannot be queried
+fn terminal_grid() -> (u32, u32) {
+    let output =
+        File::open("/dev/tty").and_then(|tty| Command::new("stty").arg("size").stdin(tty).output());
+    if let Ok(output) = output {
+        let text = String::from_utf8_lossy(&output.stdout);
+        let mut fields = text.split_whitespace().map(str::parse::<u32>);
+        if let (Some(Ok(rows)), Some(Ok(columns))) = (fields.next(), fields.next()) {
+            if rows > 0 && columns > 0 {
+                return (columns, rows);
             }
         }
     }
+    (80, 24)
 }
 
-/// `get_opengl_vecs` is called by async_coordinator when an task or type GetMetricsOpenGLData
-/// is received, it should contain the chart index to represent
-pub fn get_opengl_vecs(
-    charts: &[TimeSeriesChart],
-    chart_index: usize,
-    data_index: usize,
-    channel: oneshot::Sender<Vec<f32>>,
-    is_decoration: bool,
-) {
-    debug!("get_opengl_vecs for chart_index: {}", chart_index);
-    match channel.send(if chart_index >= charts.len() {
-        vec![]
-    } else if is_decoration {
-        if data_index >= charts[chart_index].decorations.len() {
-            vec![]
-        } else {
-            charts[chart_index].decorations[data_index].opengl_vertices()
-        }
-    } else {
-        if data_index >= charts[chart_index].opengl_vecs.len() {
-            vec![]
-        } else {
-            charts[chart_index].opengl_vecs[data_index].clone()
-        }
-    }) {
-        Ok(()) => {
-            if chart_index > charts.len() {
-                debug!(
-                    "get_opengl_vecs: oneshot::message sent for {}[OutOfBounds]",
-                    chart_index
-                );
-            } else {
-                debug!(
-                    "get_opengl_vecs: oneshot::message sent for {}[InsideBounds]",
-                    chart_index
-                );
-            }
-        }
-        Err(err) => error!("get_opengl_vecs: Error sending: {:?}", err),
-    };
-    /*.and_then(|_| {
-        let vec_len = charts[chart_index].series_opengl_vecs.len();
-        debug!("Sent vec with {} items", vec_len);
-        Ok(vec_len)
-    })
-    .map_err(|e| {
-        error!("get_opengl_vecs; err={:?}", e);
-    });*/
+/// `get_chart` requests a copy of the chart at `chart_idx` to the coordinator,
+/// it blocks the thread
+fn get_chart(tx: &mpsc::Sender<AsyncChartTask>, chart_idx: usize) -> Option<TimeSeriesChart> {
+    let (chart_tx, chart_rx) = oneshot::channel();
+    if let Err(e) = tx
+        .clone()
+        .send(AsyncChartTask::GetChart(chart_idx, chart_tx))
+        .wait()
+    {
+        error!("Sending GetChart Task: err={:?}", e);
+        return None;
+    }
+    chart_rx.wait().ok().and_then(|chart| chart)
 }
 
-/// `async_coordinator` receives messages from the tasks about data loaded from
-/// the network, it owns the charts data.
-fn async_coordinator(
-    rx: mpsc::Receiver<AsyncChartTask>,
-    mut charts: Vec<TimeSeriesChart>,
-) -> impl Future<Item = (), Error = ()> {
-    debug!("async_coordinator: Starting");
-    rx.for_each(move |message| {
-        debug!("async_coordinator: message: {:?}", message);
-        match message {
-            AsyncChartTask::LoadResponse(req) => load_http_response(&mut charts, req),
-            AsyncChartTask::GetMetricsOpenGLData(chart_index, data_index, channel) => {
-                get_opengl_vecs(&charts, chart_index, data_index, channel, false);
-            }
-            AsyncChartTask::GetDecorationsOpenGLData(chart_index, data_index, channel) => {
-                get_opengl_vecs(&charts, chart_index, data_index, channel, true);
+/// `run_tui` redraws the charts in the terminal every time the coordinator
+/// loads data, until the coordinator stops. It blocks the thread, so it must
+/// not run on the tokio runtime.
+fn run_tui(tx: mpsc::Sender<AsyncChartTask>, chart_count: usize, glyphs: Glyphs) {
+    let (notify_tx, notify_rx) = mpsc::channel(0);
+    if let Err(e) = tx.clone().send(AsyncChartTask::Subscribe(notify_tx)).wait() {
+        error!("Sending Subscribe Task: err={:?}", e);
+        return;
+    }
+    // Clear the screen once, the redraws overwrite every cell
+    print!("\x1b[2J");
+    for _ in notify_rx.wait() {
+        let (columns, rows) = terminal_grid();
+        let size = terminal_size_info(columns, rows, DEFAULT_CELL_WIDTH, DEFAULT_CELL_HEIGHT);
+        let mut charts: Vec<TimeSeriesChart> = (0..chart_count)
+            .filter_map(|chart_idx| get_chart(&tx, chart_idx))
+            .collect();
+        update_charts(&mut charts, size);
+        match render_ansi(&charts, size, glyphs) {
+            Ok(screen) => {
+                print!("\x1b[H{}", screen);
+                if let Err(err) = io::stdout().flush() {
+                    error!("Unable to draw the charts: {}", err);
+                    break;
+                }
             }
-        };
-        Ok(())
-    })
+            Err(err) => error!("Unable to render the charts: {}", err),
+        }
+    }
 }
 
-/// `fetch_prometheus_response` gets data from prometheus and once data is ready
-/// it sends the results to the coordinator.
-fn fetch_prometheus_response(
-    item: MetricRequest,
-    tx: mpsc::Sender<AsyncChartTask>,
-) -> impl Future<Item = (), Error = ()> {
-    debug!("fetch_prometheus_response: Starting");
-    let url = prometheus::PrometheusTimeSeries::prepare_url(&item.source_url, item.capacity as u64)
-        .unwrap();
-    prometheus::get_from_prometheus(url.clone())
-        .timeout(Duration::from_secs(item.pull_interval))
-        .map_err(|e| error!("get_from_prometheus; err={:?}", e))
-        .and_then(move |value| {
-            debug!("Got prometheus raw value={:?}", value);
-            let res = prometheus::parse_json(&value);
-            debug!("Parsed JSON to res={:?}", res);
-            tx.send(AsyncChartTask::LoadResponse(MetricRequest {
-                source_url: item.source_url.clone(),
-                chart_index: item.chart_index,
-                series_index: item.series_index,
-                pull_interval: item.pull_interval,
-                data: res.clone(),
-                capacity: item.capacity,
-            }))
-            .map_err(|e| {
-                error!(
-                    "fetch_prometheus_response: send data back to coordinator; err={:?}",
-                    e
-                )
-            })
-            .and_then(|res| {
-                debug!("fetch_prometheus_response: res={:?}", res);
-                Ok(())
-            })
-        })
-        .map_err(|e| error!("Sending result to coordinator; err={:?}", e))
-}
-/// `spawn_interval_polls` creates intervals for each series requested
-/// Each series will have to reply to a mspc tx with the data
-fn spawn_interval_polls(
-    item: &MetricRequest,
-    tx: mpsc::Sender<AsyncChartTask>,
-) -> impl Future<Item = (), Error = ()> {
-    debug!("spawn_interval_polls: Starting for item={:?}", item);
-    Interval::new(Instant::now(), Duration::from_secs(item.pull_interval))
-        //.take(10) //  Test 10 times first
-        .map_err(|e| panic!("interval errored; err={:?}", e))
-        .fold(
-            MetricRequest {
-                source_url: item.source_url.clone(),
-                chart_index: item.chart_index,
-                series_index: item.series_index,
-                pull_interval: item.pull_interval,
-                data: None,
-                capacity: item.capacity,
-            },
-            move |async_metric_item, instant| {
-                debug!(
-                    "Interval triggered for {:?} at instant={:?}",
-                    async_metric_item.source_url, instant
-                );
-                fetch_prometheus_response(async_metric_item.clone(), tx.clone()).and_then(|res| {
-                    debug!("Got response {:?}", res);
-                    Ok(async_metric_item)
-                })
-            },
-        )
-        .map(|_| ())
-}
 fn main() {
+    // --tui draws the charts in the terminal, --tui=blocks without braille
+    let tui = std::env::args().find_map(|arg| match arg.as_str() {
+        "--tui" => Some(Glyphs::Braille),
+        "--tui=blocks" => Some(Glyphs::Block),
+        _ => None,
+    });
     println!("Starting program");
-    env_logger::from_env(Env::default().default_filter_or("info")).init();
+    // The log would scroll the charts away
+    let log_level = if tui.is_some() { "warn" } else { "info" };
+    env_logger::from_env(Env::default().default_filter_or(log_level)).init();
     let config = Config::load_config_file();
-    let charts = config.charts.clone();
+    let mut charts = config.charts.clone();
+    if let Some(ref snapshot) = config.snapshot {
+        restore_snapshot(&mut charts, &snapshot.path);
+    }
     let collected_data = config.clone();
-    let mut chart_index = 0usize;
     // Create the channel that is used to communicate with the
     // background task.
     let (tx, rx) = mpsc::channel(4_096usize);
     let poll_tx = tx.clone();
     tokio::run(lazy(move || {
         tokio::spawn(lazy(move || async_coordinator(rx, charts)));
-        for chart in config.charts {
-            debug!("Loading chart series with name: '{}'", chart.name);
-            let mut series_index = 0usize;
-            for series in chart.sources {
-                if let TimeSeriesSource::PrometheusTimeSeries(ref prom) = series {
-                    debug!(" - Found time_series, adding interval run");
-                    let data_request = MetricRequest {
-                        source_url: prom.source.clone(),
-                        pull_interval: prom.pull_interval as u64,
-                        chart_index,
-                        series_index,
-                        capacity: prom.series.metrics_capacity,
-                        data: None,
-                    };
-                    let poll_tx = poll_tx.clone();
-                    tokio::spawn(lazy(move || spawn_interval_polls(&data_request, poll_tx)));
+        if let Some(snapshot) = config.snapshot {
+            let snapshot_tx = poll_tx.clone();
+            tokio::spawn(lazy(move || {
+                spawn_snapshot_writes(snapshot.path, snapshot.interval.max(1), snapshot_tx)
+            }));
+        }
+        if let Some(ref export) = config.export {
+            match spawn_export_server(export, poll_tx.clone()) {
+                Ok((_addr, server)) => {
+                    tokio::spawn(server);
                 }
-                series_index += 1;
+                Err(err) => error!("Not exporting metrics: {}", err),
             }
-            chart_index += 1;
+        }
+        spawn_prometheus_polls(&config.charts, &poll_tx);
+        spawn_statsd_listeners(&config.charts, &poll_tx);
+        spawn_line_listeners(&config.charts, &poll_tx);
+        if let Some(glyphs) = tui {
+            let chart_count = collected_data.charts.len();
+            thread::spawn(move || run_tui(tx, chart_count, glyphs));
+            return Ok(());
         }
         let mut counter = 0;
         loop {
@@ -0,0 +1,310 @@
+//! `MockPrometheus` is a small in-process server for the Prometheus HTTP API,
+//! it serves the configured responses for each query and records the
+//! requests so that tests and demos don't need a running Prometheus.
+use futures::future::{self, lazy, Either};
+use hyper::rt::Future;
+use hyper::service::service_fn_ok;
+use hyper::{Body, Request, Response, Server, StatusCode};
+use log::*;
+use percent_encoding::percent_decode;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+/// `MockValueFn` returns the value of a series at an epoch
+pub type MockValueFn = Arc<dyn Fn(u64) -> f64 + Send + Sync>;
+
+/// `MockSeries` is a label set and the function that generates its values
+#[derive(Clone)]
+pub struct MockSeries {
+    pub labels: HashMap<String, String>,
+    pub value: MockValueFn,
+}
+
+impl MockSeries {
+    /// `new` creates a series with `labels` whose value at an epoch is
+    /// returned by `value`
+    pub fn new<F>(labels: &[(&str, &str)], value: F) -> MockSeries
+    where
+        F: Fn(u64) -> f64 + Send + Sync + 'static,
+    {
+        MockSeries {
+            labels: labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            value: Arc::new(value),
+        }
+    }
+}
+
+/// `MockResponse` is the reply to a query
+#[derive(Clone)]
+pub enum MockResponse {
+    /// A vector for instant queries or a matrix with one value per step for
+    /// range queries
+    Series(Vec<MockSeries>),
+    /// A scalar for instant queries
+    Scalar(f64),
+    /// A Prometheus error with its HTTP status, i.e. 400 and "bad_data"
+    Error {
+        status: u16,
+        error_type: String,
+        error: String,
+    },
+    /// A raw body, i.e. to return invalid JSON
+    Raw { status: u16, body: String },
+}
+
+impl MockResponse {
+    /// `constant` returns a single series without labels with `value`
+    pub fn constant(value: f64) -> MockResponse {
+        MockResponse::Series(vec![MockSeries::new(&[], move |_| value)])
+    }
+
+    /// `error` returns a Prometheus error with the HTTP `status`
+    pub fn error(status: u16, error_type: &str, error: &str) -> MockResponse {
+        MockResponse::Error {
+            status,
+            error_type: error_type.to_string(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// `MockRequest` is a request received by the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockRequest {
+    /// The path, i.e. /api/v1/query_range
+    pub path: String,
+
+    /// The decoded query string parameters
+    pub params: HashMap<String, String>,
+
+    /// The request headers, the names are lowercase
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Default)]
+struct MockState {
+    responses: HashMap<String, MockResponse>,
+    metrics: String,
+    requests: Vec<MockRequest>,
+}
+
+/// `MockPrometheus` listens on a random port of localhost until dropped
+pub struct MockPrometheus {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    _runtime: tokio::runtime::Runtime,
+}
+
+impl MockPrometheus {
+    /// `start` binds the server and serves it from its own runtime
+    pub fn start() -> Result<MockPrometheus, String> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")
+            .map_err(|err| format!("Unable to bind mock server: {}", err))?;
+        let addr = listener
+            .local_addr()
+            .map_err(|err| format!("Unable to get mock server address: {}", err))?;
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let mut runtime = tokio::runtime::Runtime::new()
+            .map_err(|err| format!("Unable to start mock server runtime: {}", err))?;
+        let server_state = state.clone();
+        runtime.spawn(lazy(move || match Server::from_tcp(listener) {
+            Ok(builder) => Either::A(
+                builder
+                    .serve(move || {
+                        let state = server_state.clone();
+                        service_fn_ok(move |req| respond(&state, &req))
+                    })
+                    .map_err(|err| error!("MockPrometheus: {}", err)),
+            ),
+            Err(err) => {
+                error!("MockPrometheus: Unable to serve: {}", err);
+                Either::B(future::err(()))
+            }
+        }));
+        info!("MockPrometheus: Listening on {}", addr);
+        Ok(MockPrometheus {
+            addr,
+            state,
+            _runtime: runtime,
+        })
+    }
+
+    /// `base_url` returns the URL of the server, i.e. http://127.0.0.1:9090
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// `set_response` replies `response` to the queries with `expr`
+    pub fn set_response(&self, expr: &str, response: MockResponse) {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .insert(expr.to_string(), response);
+    }
+
+    /// `set_metrics` serves `exposition` on /metrics, to be scraped directly
+    pub fn set_metrics(&self, exposition: &str) {
+        self.state.lock().unwrap().metrics = exposition.to_string();
+    }
+
+    /// `requests` returns the requests received so far
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+/// `decode_query` parses a query string the way Prometheus does, `+` is a
+/// space and the rest is percent decoded
+fn decode_query(query: &str) -> HashMap<String, String> {
+    let decode = |input: &str| -> String {
+        percent_decode(input.replace('+', " ").as_bytes())
+            .decode_utf8_lossy()
+            .to_string()
+    };
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let mut key_value = param.splitn(2, '=');
+            (
+                decode(key_value.next().unwrap_or_default()),
+                decode(key_value.next().unwrap_or_default()),
+            )
+        })
+        .collect()
+}
+
+/// `respond` builds the reply to a request from the configured responses
+fn respond(state: &Mutex<MockState>, req: &Request<Body>) -> Response<Body> {
+    let request = MockRequest {
+        path: req.uri().path().to_string(),
+        params: decode_query(req.uri().query().unwrap_or_default()),
+        headers: req
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| {
+                value
+                    .to_str()
+                    .ok()
+                    .map(|value| (name.as_str().to_string(), value.to_string()))
+            })
+            .collect(),
+    };
+    debug!("MockPrometheus: {:?}", request);
+    let mut state = state.lock().unwrap();
+    state.requests.push(request.clone());
+    let is_range = match request.path.as_str() {
+        "/api/v1/query" => false,
+        "/api/v1/query_range" => true,
+        "/metrics" => {
+            return Response::builder()
+                .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(state.metrics.clone()))
+                .unwrap()
+        }
+        _ => return reply(404, String::from("404 page not found")),
+    };
+    let param = |name: &str| -> Option<u64> {
+        request
+            .params
+            .get(name)
+            .and_then(|value| value.parse::<f64>().ok())
+            .map(|value| value as u64)
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let expr = request.params.get("query").cloned().unwrap_or_default();
+    let response = state
+        .responses
+        .get(&expr)
+        .cloned()
+        .unwrap_or_else(|| MockResponse::Series(vec![]));
+    let data = match response {
+        MockResponse::Raw { status, body } => return reply(status, body),
+        MockResponse::Error {
+            status,
+            error_type,
+            error,
+        } => {
+            return reply(
+                status,
+                json!({"status": "error", "errorType": error_type, "error": error}).to_string(),
+            )
+        }
+        MockResponse::Scalar(value) => {
+            let time = param("time").unwrap_or(now);
+            json!({"resultType": "scalar", "result": [time, value.to_string()]})
+        }
+        MockResponse::Series(series) if is_range => {
+            let (start, end, step) = match (param("start"), param("end"), param("step")) {
+                (Some(start), Some(end), Some(step)) if step > 0 && start <= end => {
+                    (start, end, step)
+                }
+                _ => {
+                    return reply(
+                        400,
+                        json!({
+                            "status": "error",
+                            "errorType": "bad_data",
+                            "error": "invalid start, end or step"
+                        })
+                        .to_string(),
+                    )
+                }
+            };
+            let result: Vec<serde_json::Value> = series
+                .iter()
+                .map(|item| {
+                    let values: Vec<serde_json::Value> = (start..=end)
+                        .step_by(step as usize)
+                        .map(|epoch| json!([epoch, (item.value)(epoch).to_string()]))
+                        .collect();
+                    json!({"metric": item.labels, "values": values})
+                })
+                .collect();
+            json!({"resultType": "matrix", "result": result})
+        }
+        MockResponse::Series(series) => {
+            let time = param("time").unwrap_or(now);
+            let result: Vec<serde_json::Value> = series
+                .iter()
+                .map(|item| {
+                    json!({"metric": item.labels, "value": [time, (item.value)(time).to_string()]})
+                })
+                .collect();
+            json!({"resultType": "vector", "result": result})
+        }
+    };
+    reply(200, json!({"status": "success", "data": data}).to_string())
+}
+
+/// `reply` builds a JSON response with `status`
+fn reply(status: u16, body: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_decodes_query_strings() {
+        let params = decode_query("query=a%20%2B%20b+c%7Bjob%3D%22x%22%7D&step=15&empty");
+        assert_eq!(params["query"], "a + b c{job=\"x\"}");
+        assert_eq!(params["step"], "15");
+        assert_eq!(params["empty"], "");
+    }
+}
@@ -1,10 +1,19 @@
+use crate::color::{Color, Rgb};
 use crate::ValueCollisionPolicy;
+use futures::future::{self, Either};
 /// `Prometheus HTTP API` data structures
+use hyper::client::HttpConnector;
 use hyper::rt::{Future, Stream};
-use hyper::Client;
+use hyper::{Body, Client, Request};
+use hyper_tls::HttpsConnector;
 use log::*;
-use percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
-use std::collections::HashMap;
+use percent_encoding::{
+    define_encode_set, percent_decode, utf8_percent_encode, USERINFO_ENCODE_SET,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::time::UNIX_EPOCH;
 // The below data structures for parsing something like:
 //  {
@@ -69,8 +78,111 @@ impl Default for HTTPResponseData {
 
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
 pub struct HTTPResponse {
+    /// The result, missing when the status is "error"
+    #[serde(default)]
     pub data: HTTPResponseData,
+
+    /// Either "success" or "error"
     pub status: String,
+
+    /// The kind of error, i.e. bad_data, timeout, execution
+    #[serde(default)]
+    #[serde(rename = "errorType")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_type: Option<String>,
+
+    /// The error message
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// Warnings that did not prevent the query from being executed
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// `PrometheusError` contains the ways a Prometheus query may fail
+#[derive(Debug, PartialEq, Clone)]
+pub enum PrometheusError {
+    /// The query is invalid, errorType bad_data
+    BadQuery(String),
+    /// The query took too long, errorType timeout or canceled, or there was
+    /// no response in time
+    Timeout(String),
+    /// The query failed while running, errorType execution or internal
+    Execution(String),
+    /// The server cannot run queries, errorType unavailable
+    Unavailable(String),
+    /// Any other errorType returned by the API
+    Api { error_type: String, error: String },
+    /// The request could not be sent or the response read
+    Request(String),
+    /// The response is not a valid API response
+    InvalidResponse(String),
+    /// The source is misconfigured, i.e. the token file does not exist
+    Config(String),
+}
+
+impl PrometheusError {
+    /// `from_response` returns the error of a response whose status is not
+    /// "success"
+    pub fn from_response(res: &HTTPResponse) -> Option<PrometheusError> {
+        if res.status == "success" {
+            return None;
+        }
+        let error = res
+            .error
+            .clone()
+            .unwrap_or_else(|| format!("status {}", res.status));
+        let error_type = res.error_type.clone().unwrap_or_default();
+        Some(match error_type.as_str() {
+            "bad_data" => PrometheusError::BadQuery(error),
+            "timeout" | "canceled" => PrometheusError::Timeout(error),
+            "execution" | "internal" => PrometheusError::Execution(error),
+            "unavailable" => PrometheusError::Unavailable(error),
+            _ => PrometheusError::Api { error_type, error },
+        })
+    }
+}
+
+impl fmt::Display for PrometheusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrometheusError::BadQuery(err) => write!(f, "Bad query: {}", err),
+            PrometheusError::Timeout(err) => write!(f, "Query timed out: {}", err),
+            PrometheusError::Execution(err) => write!(f, "Query execution failed: {}", err),
+            PrometheusError::Unavailable(err) => write!(f, "Prometheus unavailable: {}", err),
+            PrometheusError::Api { error_type, error } => {
+                write!(f, "Prometheus error {}: {}", error_type, error)
+            }
+            PrometheusError::Request(err) => write!(f, "Request failed: {}", err),
+            PrometheusError::InvalidResponse(err) => write!(f, "Invalid response: {}", err),
+            PrometheusError::Config(err) => write!(f, "Invalid configuration: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PrometheusError {}
+
+impl HTTPResponse {
+    /// `last_epoch` returns the most recent epoch in the response, if any
+    pub fn last_epoch(&self) -> Option<u64> {
+        match &self.data {
+            HTTPResponseData::Vector { result } => result
+                .iter()
+                .filter_map(|item| item.value.first().and_then(prometheus_epoch_to_u64))
+                .max(),
+            HTTPResponseData::Matrix { result } => result
+                .iter()
+                .flat_map(|item| item.values.iter())
+                .filter_map(|value| value.first().and_then(prometheus_epoch_to_u64))
+                .max(),
+            HTTPResponseData::Scalar { result } | HTTPResponseData::String { result } => {
+                result.first().and_then(prometheus_epoch_to_u64)
+            }
+        }
+    }
 }
 
 /// Transforms an serde_json::Value into an optional u64
@@ -97,14 +209,359 @@ pub fn serde_json_to_num(input: &serde_json::Value) -> Option<f64> {
     None
 }
 
+/// `BasicAuth` contains the credentials for HTTP basic authentication, the
+/// password may be read from a file to keep it out of charts.yml
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct BasicAuth {
+    pub username: String,
+
+    #[serde(default)]
+    pub password: String,
+
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+}
+
+/// `TLSConfig` contains the settings to verify the server certificate
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct TLSConfig {
+    /// A PEM file with the CA certificate to trust besides the system ones
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+
+    /// Accept invalid certificates, only for testing
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+/// `HTTPClientConfig` contains the authentication and TLS settings of the
+/// requests to a Prometheus server.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct HTTPClientConfig {
+    /// A file containing the token sent in the Authorization header, it is
+    /// read on every request so that the token can be rotated
+    #[serde(default)]
+    pub bearer_token_file: Option<PathBuf>,
+
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuth>,
+
+    /// Additional headers to send, i.e. X-Scope-OrgID
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    #[serde(default)]
+    pub tls: TLSConfig,
+}
+
+/// `read_secret_file` returns the trimmed contents of a file with a secret
+fn read_secret_file(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path)
+        .map(|contents| contents.trim().to_string())
+        .map_err(|err| format!("Unable to read {:?}: {}", path, err))
+}
+
+impl HTTPClientConfig {
+    /// `authorization` returns the value of the Authorization header, if any
+    pub fn authorization(&self) -> Result<Option<String>, String> {
+        if let Some(ref path) = self.bearer_token_file {
+            return Ok(Some(format!("Bearer {}", read_secret_file(path)?)));
+        }
+        if let Some(ref basic_auth) = self.basic_auth {
+            let password = match basic_auth.password_file {
+                Some(ref path) => read_secret_file(path)?,
+                None => basic_auth.password.clone(),
+            };
+            let credentials = format!("{}:{}", basic_auth.username, password);
+            return Ok(Some(format!("Basic {}", base64::encode(&credentials))));
+        }
+        Ok(None)
+    }
+
+    /// `request` builds a GET request to `url` with the configured headers
+    pub fn request(&self, url: hyper::Uri) -> Result<Request<Body>, String> {
+        let mut builder = Request::get(url);
+        if let Some(authorization) = self.authorization()? {
+            builder.header(hyper::header::AUTHORIZATION, authorization);
+        }
+        for (name, value) in &self.headers {
+            builder.header(name.as_str(), value.as_str());
+        }
+        builder
+            .body(Body::empty())
+            .map_err(|err| format!("Unable to build request: {}", err))
+    }
+
+    /// `connector` returns a connector for both HTTP and HTTPS URLs that
+    /// trusts the configured CA
+    pub fn connector(&self) -> Result<HttpsConnector<HttpConnector>, String> {
+        let mut tls = native_tls::TlsConnector::builder();
+        if let Some(ref path) = self.tls.ca_file {
+            let pem =
+                fs::read(path).map_err(|err| format!("Unable to read {:?}: {}", path, err))?;
+            let cert = native_tls::Certificate::from_pem(&pem)
+                .map_err(|err| format!("Invalid CA certificate {:?}: {}", path, err))?;
+            tls.add_root_certificate(cert);
+        }
+        tls.danger_accept_invalid_certs(self.tls.insecure_skip_verify);
+        let tls = tls
+            .build()
+            .map_err(|err| format!("Unable to build TLS connector: {}", err))?;
+        let mut http = HttpConnector::new(1);
+        http.enforce_http(false);
+        Ok(HttpsConnector::from((http, tls)))
+    }
+}
+
+/// `RetryConfig` controls the timeout of the requests and the exponential
+/// backoff between retries after a failure
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// The request timeout in seconds, defaults to the pull interval
+    pub timeout: Option<u64>,
+
+    /// The seconds to wait after the first failure
+    pub initial_backoff: u64,
+
+    /// The maximum number of seconds to wait between retries
+    pub max_backoff: u64,
+
+    /// The backoff is multiplied by this value after each failure
+    pub multiplier: f64,
+
+    /// The fraction of the backoff added or substracted at random so that
+    /// the sources do not retry in lockstep
+    pub jitter: f64,
+
+    /// The number of pull intervals without a successful load after which
+    /// the series is considered stale
+    pub stale_after: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            timeout: None,
+            initial_backoff: 1,
+            max_backoff: 300,
+            multiplier: 2.0,
+            jitter: 0.1,
+            stale_after: 3,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// `backoff` returns the time to wait after `consecutive_failures`,
+    /// `random` is a number in [0, 1) used to apply the jitter
+    pub fn backoff(&self, consecutive_failures: u32, random: f64) -> std::time::Duration {
+        let exponent = consecutive_failures.saturating_sub(1).min(64) as i32;
+        let backoff = (self.initial_backoff as f64 * self.multiplier.max(1.).powi(exponent))
+            .min(self.max_backoff as f64);
+        let jitter = backoff * self.jitter.clamp(0., 1.) * (2. * random - 1.);
+        std::time::Duration::from_millis(((backoff + jitter).max(0.) * 1000.) as u64)
+    }
+}
+
+define_encode_set! {
+    /// `QUERY_COMPONENT_ENCODE_SET` encodes the characters that would change
+    /// the meaning of a query string key or value, so that `+`, `&`, `=`, `{}`
+    /// and quotes in PromQL reach Prometheus untouched
+    pub QUERY_COMPONENT_ENCODE_SET = [USERINFO_ENCODE_SET] | {'&', '+', '%', '$', ',', '#'}
+}
+
+/// `INCREMENTAL_OVERLAP_STEPS` is the number of steps before the last loaded
+/// epoch that are requested again by incremental range queries
+pub const INCREMENTAL_OVERLAP_STEPS: u64 = 2;
+
+/// `QueryEndpoint` is the Prometheus HTTP API endpoint used by a query
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryEndpoint {
+    /// `/api/v1/query`, evaluated at a single point in time
+    Instant,
+    /// `/api/v1/query_range`, evaluated every step over a time range
+    #[default]
+    Range,
+}
+
+/// `PrometheusQuery` is the structured version of a Prometheus source URL,
+/// every parameter is percent encoded when the URL is built
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct PrometheusQuery {
+    /// The Prometheus server, i.e. http://localhost:9090, may contain a path
+    /// prefix when Prometheus is behind a proxy
+    #[serde(default)]
+    pub base_url: String,
+
+    /// The PromQL expression
+    #[serde(default)]
+    pub expr: String,
+
+    /// The API endpoint to query
+    #[serde(default)]
+    pub endpoint: QueryEndpoint,
+
+    /// The number of seconds to look back in range queries, defaults to the
+    /// capacity of the TimeSeries times the step
+    #[serde(default)]
+    pub range: Option<u64>,
+
+    /// The resolution of range queries in seconds, defaults to the
+    /// granularity of the TimeSeries
+    #[serde(default)]
+    pub step: Option<u64>,
+
+    /// The evaluation epoch of instant queries, defaults to now
+    #[serde(default)]
+    pub time: Option<u64>,
+
+    /// Additional parameters, i.e. timeout or dedup
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+}
+
+/// `decode_component` percent decodes a source URL component, invalid
+/// escapes like the `% 2` of a modulo are kept verbatim.
+fn decode_component(component: &str) -> String {
+    percent_decode(component.as_bytes())
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+impl PrometheusQuery {
+    /// `from_source` parses a source URL like
+    /// http://localhost:9090/api/v1/query_range?query=node_load1
+    /// Percent encoded keys and values are decoded so that they are not
+    /// encoded twice when the URL is built, a `+` is kept as is.
+    /// A `start`, `end`, `step` or `time` in the source is kept as an extra
+    /// parameter and replaced when the URL is built.
+    pub fn from_source(source: &str) -> Result<PrometheusQuery, String> {
+        let mut url_parts = source.splitn(2, '?');
+        let url_base_path = url_parts.next().unwrap_or_default();
+        let url_params = match url_parts.next() {
+            Some(params) => params,
+            None => {
+                return Err(String::from(
+                    "Unable to get url_parts, expected http://host:port/location?params",
+                ))
+            }
+        };
+        let (base_url, endpoint) = if let Some(base_url) =
+            url_base_path.strip_suffix("/api/v1/query_range")
+        {
+            (base_url, QueryEndpoint::Range)
+        } else if let Some(base_url) = url_base_path.strip_suffix("/api/v1/query") {
+            (base_url, QueryEndpoint::Instant)
+        } else {
+            return Err(format!(
+                "Unsupported Prometheus API path in {}, expected /api/v1/query or /api/v1/query_range",
+                source
+            ));
+        };
+        let mut res = PrometheusQuery {
+            base_url: base_url.to_string(),
+            endpoint,
+            ..PrometheusQuery::default()
+        };
+        // PromQL has no & operator, so & only separates parameters.
+        for param in url_params.split('&').filter(|param| !param.is_empty()) {
+            let mut key_value = param.splitn(2, '=');
+            let key = decode_component(key_value.next().unwrap_or_default());
+            let value = decode_component(key_value.next().unwrap_or_default());
+            if key == "query" {
+                res.expr = value;
+            } else {
+                res.params.insert(key, value);
+            }
+        }
+        if res.expr.is_empty() {
+            return Err(format!("Missing query parameter in {}", source));
+        }
+        Ok(res)
+    }
+
+    /// `to_url` builds the URL of the query at epoch `now`, range queries
+    /// return one value per step for `metrics_capacity` steps by default.
+    /// When `last_loaded` is the last epoch already loaded, range queries
+    /// only request the steps after it, plus an overlap of
+    /// `INCREMENTAL_OVERLAP_STEPS` to replace the values that were still
+    /// being evaluated.
+    pub fn to_url(
+        &self,
+        metrics_capacity: u64,
+        granularity: u64,
+        now: u64,
+        last_loaded: Option<u64>,
+    ) -> Result<hyper::Uri, String> {
+        let mut params = self.params.clone();
+        let path = match self.endpoint {
+            QueryEndpoint::Instant => {
+                params.insert(String::from("time"), self.time.unwrap_or(now).to_string());
+                "/api/v1/query"
+            }
+            QueryEndpoint::Range => {
+                let step = self.step.unwrap_or(granularity).max(1);
+                let range = self.range.unwrap_or(metrics_capacity * step);
+                let mut start = now.saturating_sub(range);
+                if let Some(last_loaded) = last_loaded {
+                    let overlap_start =
+                        last_loaded.saturating_sub(INCREMENTAL_OVERLAP_STEPS * step);
+                    start = start.max(overlap_start / step * step).min(now);
+                }
+                params.insert(String::from("start"), start.to_string());
+                params.insert(String::from("end"), now.to_string());
+                params.insert(String::from("step"), step.to_string());
+                "/api/v1/query_range"
+            }
+        };
+        let encoded_params: Vec<String> = std::iter::once((&String::from("query"), &self.expr))
+            .chain(params.iter())
+            .map(|(key, value)| {
+                format!(
+                    "{}={}",
+                    utf8_percent_encode(key, QUERY_COMPONENT_ENCODE_SET),
+                    utf8_percent_encode(value, QUERY_COMPONENT_ENCODE_SET)
+                )
+            })
+            .collect();
+        let encoded_url = format!(
+            "{}{}?{}",
+            self.base_url.trim_end_matches('/'),
+            path,
+            encoded_params.join("&")
+        );
+        match encoded_url.parse::<hyper::Uri>() {
+            Ok(url) => {
+                if url.scheme_part() == Some(&hyper::http::uri::Scheme::HTTP)
+                    || url.scheme_part() == Some(&hyper::http::uri::Scheme::HTTPS)
+                {
+                    debug!("Setting url to: {:?}", url);
+                    Ok(url)
+                } else {
+                    error!("Only HTTP and HTTPS protocols are supported");
+                    Err(format!("Unsupported protocol: {:?}", url.scheme_part()))
+                }
+            }
+            Err(err) => {
+                error!("Unable to parse url: {}", err);
+                Err(format!("Unable to parse URL: {:?}", err))
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PrometheusTimeSeries {
     /// The Name of this TimesSeries
     #[serde(default)]
     pub name: String,
 
-    /// The TimeSeries metrics storage
-    #[serde(default)]
+    /// The TimeSeries metrics storage, the samples overwrite the values
+    /// already loaded unless another collision_policy is configured
+    #[serde(default = "default_series")]
+    #[serde(deserialize_with = "deserialize_series")]
     pub series: crate::TimeSeries,
 
     /// The TimeSeries metrics storage
@@ -115,6 +572,10 @@ pub struct PrometheusTimeSeries {
     #[serde(default)]
     pub source: String,
 
+    /// The structured query, takes precedence over `source`
+    #[serde(default)]
+    pub query: Option<PrometheusQuery>,
+
     /// The URL were Prometheus metrics may be acquaired
     #[serde(skip)]
     pub url: hyper::Uri,
@@ -136,29 +597,97 @@ pub struct PrometheusTimeSeries {
 
     /// The color of the TimeSeries
     #[serde(default)]
-    pub color: String,
+    pub color: Option<crate::color::Color>,
 
     /// The transparency of the TimeSeries
     #[serde(default)]
     pub alpha: f32,
+
+    /// Creates one series per distinct label set in the response instead of
+    /// loading all the results into `series`
+    #[serde(default)]
+    pub split_by_labels: bool,
+
+    /// The name of the split series, `{label}` is replaced by the value of
+    /// the label, i.e. `cpu {cpu} {mode}`
+    #[serde(default)]
+    pub label_template: Option<String>,
+
+    /// The name of the series this one was split from, if any
+    #[serde(default)]
+    pub split_from: Option<String>,
+
+    /// The authentication and TLS settings
+    #[serde(default)]
+    pub http: HTTPClientConfig,
+
+    /// The timeout and retry settings
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// The outcome of the latest loads
+    #[serde(skip)]
+    pub health: crate::SourceHealth,
+
+    /// The first and last epoch loaded of each label set
+    #[serde(skip)]
+    pub loaded_ranges: BTreeMap<BTreeMap<String, String>, (u64, u64)>,
+
+    /// A label set was first seen in an incremental load, the next query
+    /// requests the full range to load its older samples
+    #[serde(skip)]
+    pub reload: bool,
 }
 
 impl Default for PrometheusTimeSeries {
     fn default() -> PrometheusTimeSeries {
         PrometheusTimeSeries {
             name: String::from("Unset"),
-            series: crate::TimeSeries::default(),
+            series: default_series(),
             data: HTTPResponseData::default(),
             source: String::from(""),
+            query: None,
             url: hyper::Uri::default(),
             pull_interval: 15,
             data_type: String::from("vector"),
             required_labels: HashMap::new(),
-            color: String::from("0x00ff00"),
+            color: None,
             alpha: 1.0,
+            split_by_labels: false,
+            label_template: None,
+            split_from: None,
+            http: HTTPClientConfig::default(),
+            retry: RetryConfig::default(),
+            health: crate::SourceHealth::default(),
+            loaded_ranges: BTreeMap::new(),
+            reload: false,
         }
     }
 }
+
+/// `default_series` is the TimeSeries of a PrometheusTimeSeries, the same
+/// samples are loaded again by the overlapping queries so they overwrite
+pub(crate) fn default_series() -> crate::TimeSeries {
+    crate::TimeSeries {
+        collision_policy: ValueCollisionPolicy::Overwrite,
+        ..crate::TimeSeries::default()
+    }
+}
+
+/// `deserialize_series` loads the TimeSeries config, the collision_policy
+/// defaults to Overwrite instead of the TimeSeries default
+pub(crate) fn deserialize_series<'de, D>(deserializer: D) -> Result<crate::TimeSeries, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    let config = crate::SeriesConfig::deserialize(deserializer)?;
+    Ok(config.with_default_policy(ValueCollisionPolicy::Overwrite))
+}
+
+/// `LabeledSamples` contains the (epoch, value) samples of each label set
+pub type LabeledSamples = Vec<(HashMap<String, String>, Vec<(u64, f64)>)>;
+
 impl PrometheusTimeSeries {
     /// `new` returns a new PrometheusTimeSeries. it takes a URL where to load
     /// the data from and a pull_interval, this should match scrape interval in
@@ -181,7 +710,11 @@ impl PrometheusTimeSeries {
             ..PrometheusTimeSeries::default()
         };
         res.series.collision_policy = ValueCollisionPolicy::Overwrite;
-        match PrometheusTimeSeries::prepare_url(&res.source, res.series.metrics_capacity as u64) {
+        match PrometheusTimeSeries::prepare_url(
+            &res.source,
+            res.series.metrics_capacity as u64,
+            res.series.granularity,
+        ) {
             Ok(url) => {
                 res.url = url;
                 Ok(res)
@@ -192,50 +725,33 @@ impl PrometheusTimeSeries {
 
     /// `prepare_url` loads self.source into a hyper::Uri
     /// It also adds a epoch-start and epoch-end to the
-    /// URL depending on the metrics capacity
-    pub fn prepare_url(source: &str, metrics_capacity: u64) -> Result<hyper::Uri, String> {
-        // url should be like ("http://localhost:9090/api/v1/query?{}",query)
-        // We split self.source into url_base_path?params
-        // XXX: We only support one param, if more params are added with &
-        //      they are percent encoded.
-        // But sounds like configuration would become easy to mess up.
-        let url_parts: Vec<&str> = source.split('?').collect();
-        if url_parts.len() < 2 {
-            return Err(String::from(
-                "Unable to get url_parts, expected http://host:port/location?params",
-            ));
-        }
-        let url_base_path = url_parts[0];
-        // XXX: We only support one input param
-        let url_param = url_parts[1..].join("");
-        let encoded_url_param = utf8_percent_encode(&url_param, DEFAULT_ENCODE_SET).to_string();
-        let mut encoded_url = format!("{}?{}", url_base_path, encoded_url_param);
-        // If this is a query_range, we need to add time range
-        if encoded_url.contains("/api/v1/query_range?") {
-            let end = std::time::SystemTime::now()
+    /// URL depending on the metrics capacity, the step is the granularity
+    /// of the TimeSeries slots so that one value is returned per slot.
+    pub fn prepare_url(
+        source: &str,
+        metrics_capacity: u64,
+        granularity: u64,
+    ) -> Result<hyper::Uri, String> {
+        PrometheusQuery::from_source(source)?.to_url(
+            metrics_capacity,
+            granularity,
+            std::time::SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
-                .as_secs();
-            let start = end - metrics_capacity;
-            let step = "1"; // Maybe we can change granularity later
-            encoded_url = format!("{}&start={}&end={}&step={}", encoded_url, start, end, step);
-        }
-        match encoded_url.parse::<hyper::Uri>() {
-            Ok(url) => {
-                if url.scheme_part() == Some(&hyper::http::uri::Scheme::HTTP) {
-                    debug!("Setting url to: {:?}", url);
-                    Ok(url)
-                } else {
-                    error!("Only HTTP protocol is supported");
-                    Err(format!("Unsupported protocol: {:?}", url.scheme_part()))
-                }
-            }
-            Err(err) => {
-                error!("Unable to parse url: {}", err);
-                Err(format!("Unable to parse URL: {:?}", err))
-            }
+                .as_secs(),
+            None,
+        )
+    }
+
+    /// `prometheus_query` returns the structured query if configured,
+    /// otherwise the query parsed from `source`
+    pub fn prometheus_query(&self) -> Result<PrometheusQuery, String> {
+        match self.query {
+            Some(ref query) => Ok(query.clone()),
+            None => PrometheusQuery::from_source(&self.source),
         }
     }
+
     /// `match_metric_labels` checks the labels in the incoming
     /// PrometheusData contains the required labels
     pub fn match_metric_labels(&self, metric_labels: &HashMap<String, String>) -> bool {
@@ -261,81 +777,217 @@ impl PrometheusTimeSeries {
         true
     }
 
-    /// `load_prometheus_response` loads data from PrometheusResponse into
-    /// the internal `series`, returns the number of items or an error
-    /// string
-    pub fn load_prometheus_response(&mut self, res: HTTPResponse) -> Result<usize, String> {
-        let mut loaded_items = 0;
+    /// `labeled_samples` returns the samples in the response that match the
+    /// required labels grouped by label set, in the order of the response.
+    pub fn labeled_samples(&self, res: &HTTPResponse) -> LabeledSamples {
+        let mut res_samples = vec![];
         if res.status != "success" {
-            return Ok(0usize);
+            return res_samples;
         }
         debug!("Checking data: {:?}", res.data);
+        let to_sample = |item: &[serde_json::Value]| -> Option<(u64, f64)> {
+            let opt_epoch = prometheus_epoch_to_u64(&item[0]);
+            let opt_value = serde_json_to_num(&item[1]);
+            match (opt_epoch, opt_value) {
+                (Some(epoch), Some(value)) => Some((epoch, value)),
+                _ => None,
+            }
+        };
         match res.data {
-            HTTPResponseData::Vector { result: results } => {
+            HTTPResponseData::Vector { ref result } => {
                 // labeled metrics returned as a 2 items vector AFAIK:
                 // [ {metric: {l: X}, value: [epoch1,sample2]}
                 //   {metric: {l: Y}, value: [epoch3,sample4]} ]
-                for metric_data in results.iter() {
+                for metric_data in result.iter() {
                     if self.match_metric_labels(&metric_data.labels) {
                         // The result array is  [epoch, value, epoch, value]
-                        for item in metric_data.value.chunks_exact(2) {
-                            let opt_epoch = prometheus_epoch_to_u64(&item[0]);
-                            let opt_value = serde_json_to_num(&item[1]);
-                            if let (Some(epoch), Some(value)) = (opt_epoch, opt_value) {
-                                self.series.push((epoch, value));
-                                loaded_items += 1;
-                            }
-                        }
+                        let samples = metric_data.value.chunks_exact(2).filter_map(to_sample);
+                        res_samples.push((metric_data.labels.clone(), samples.collect()));
                     }
                 }
             }
-            HTTPResponseData::Matrix { result: results } => {
+            HTTPResponseData::Matrix { ref result } => {
                 // labeled metrics returned as a matrix:
                 // [ {metric: {l: X}, value: [[epoch1,sample2],[...]]}
                 //   {metric: {l: Y}, value: [[epoch3,sample4],[...]]} ]
-                for metric_data in results.iter() {
+                for metric_data in result.iter() {
                     if self.match_metric_labels(&metric_data.labels) {
-                        // The result array is  [epoch, value, epoch, value]
-                        for item_value in &metric_data.values {
-                            for item in item_value.chunks_exact(2) {
-                                let opt_epoch = prometheus_epoch_to_u64(&item[0]);
-                                let opt_value = serde_json_to_num(&item[1]);
-                                if let (Some(epoch), Some(value)) = (opt_epoch, opt_value) {
-                                    self.series.push((epoch, value));
-                                    loaded_items += 1;
-                                }
-                            }
-                        }
+                        let samples = metric_data
+                            .values
+                            .iter()
+                            .flat_map(|item_value| item_value.chunks_exact(2))
+                            .filter_map(to_sample);
+                        res_samples.push((metric_data.labels.clone(), samples.collect()));
                     }
                 }
             }
-            HTTPResponseData::Scalar { result } | HTTPResponseData::String { result } => {
+            HTTPResponseData::Scalar { ref result } | HTTPResponseData::String { ref result } => {
                 // unlabeled metrics returned as a 2 items vector
                 // [epoch1,sample2]
                 // XXX: no example found for String.
                 if result.len() > 1 {
-                    let opt_epoch = prometheus_epoch_to_u64(&result[0]);
-                    let opt_value = serde_json_to_num(&result[1]);
-                    if let (Some(epoch), Some(value)) = (opt_epoch, opt_value) {
-                        self.series.push((epoch, value));
-                        loaded_items += 1;
-                    }
+                    let samples = to_sample(&result[0..2]).into_iter().collect();
+                    res_samples.push((HashMap::new(), samples));
                 }
             }
         };
-        if loaded_items > 0 {
-            self.series.calculate_stats();
+        res_samples
+    }
+
+    /// `unloaded_samples` returns the labeled samples of the response that
+    /// were not loaded before. The overlapping queries return again the
+    /// samples between the first and last epoch loaded of a label set, they
+    /// are skipped unless the collision_policy is Overwrite so that they are
+    /// not counted twice. A label set first seen in an incremental load
+    /// requests the full range on the next query.
+    pub fn unloaded_samples(&mut self, res: &HTTPResponse) -> LabeledSamples {
+        let labeled_samples = self.labeled_samples(res);
+        let overwrite = self.series.collision_policy == ValueCollisionPolicy::Overwrite;
+        let incremental = !self.loaded_ranges.is_empty();
+        self.reload = false;
+        let mut res_samples = Vec::with_capacity(labeled_samples.len());
+        for (labels, samples) in labeled_samples {
+            let key: BTreeMap<String, String> = labels.clone().into_iter().collect();
+            let loaded_range = self.loaded_ranges.get(&key).cloned();
+            if loaded_range.is_none() && incremental && !samples.is_empty() {
+                self.reload = true;
+            }
+            let samples: Vec<(u64, f64)> = samples
+                .into_iter()
+                .filter(|(epoch, _)| {
+                    overwrite
+                        || loaded_range.is_none_or(|(first, last)| *epoch < first || *epoch > last)
+                })
+                .collect();
+            let epochs = samples.iter().map(|(epoch, _)| *epoch);
+            if let (Some(first), Some(last)) = (epochs.clone().min(), epochs.max()) {
+                let range = match loaded_range {
+                    Some((loaded_first, loaded_last)) => {
+                        (first.min(loaded_first), last.max(loaded_last))
+                    }
+                    None => (first, last),
+                };
+                self.loaded_ranges.insert(key, range);
+            }
+            res_samples.push((labels, samples));
+        }
+        // Label sets that are no longer returned are forgotten once they are
+        // out of the time range
+        let time_range = self.series.metrics_capacity as u64 * self.series.granularity.max(1);
+        if let Some(newest) = self.loaded_ranges.values().map(|range| range.1).max() {
+            self.loaded_ranges
+                .retain(|_, range| range.1 + time_range > newest);
+        }
+        res_samples
+    }
+
+    /// `last_loaded_epoch` returns the epoch incremental queries resume
+    /// from, the oldest of the last epochs loaded of the label sets, or
+    /// None when the full range should be requested again.
+    pub fn last_loaded_epoch(&self) -> Option<u64> {
+        if self.reload {
+            return None;
+        }
+        self.loaded_ranges.values().map(|range| range.1).min()
+    }
+
+    /// `load_prometheus_response` loads data from PrometheusResponse into
+    /// the internal `series`, returns the number of items or an error
+    /// string
+    pub fn load_prometheus_response(
+        &mut self,
+        res: HTTPResponse,
+    ) -> Result<usize, PrometheusError> {
+        if let Some(err) = PrometheusError::from_response(&res) {
+            return Err(err);
+        }
+        let mut loaded_items = 0;
+        for (_labels, samples) in self.unloaded_samples(&res) {
+            for sample in samples {
+                self.series.push(sample);
+                loaded_items += 1;
+            }
         }
         Ok(loaded_items)
     }
+
+    /// `split_name` renders the `label_template` for a label set, without a
+    /// template the labels are appended to the name, i.e. `cpu{mode="idle"}`
+    pub fn split_name(&self, labels: &HashMap<String, String>) -> String {
+        let sorted_labels: BTreeMap<&String, &String> = labels
+            .iter()
+            .filter(|(label, _)| label.as_str() != "__name__")
+            .collect();
+        match self.label_template {
+            Some(ref template) => {
+                let mut res = template.clone();
+                for (label, value) in labels {
+                    res = res.replace(&format!("{{{}}}", label), value);
+                }
+                res
+            }
+            None => {
+                let labels: Vec<String> = sorted_labels
+                    .iter()
+                    .map(|(label, value)| format!("{}=\"{}\"", label, value))
+                    .collect();
+                format!("{}{{{}}}", self.name, labels.join(","))
+            }
+        }
+    }
+
+    /// `split` returns the series for one of the label sets of the response,
+    /// drawn with `rgb` and the alpha of the color of this series.
+    pub fn split(&self, labels: &HashMap<String, String>, rgb: Rgb) -> PrometheusTimeSeries {
+        let series = crate::TimeSeries {
+            collision_policy: self.series.collision_policy.clone(),
+            missing_values_policy: self.series.missing_values_policy.clone(),
+            extra_stats: self.series.extra_stats.clone(),
+            ..crate::TimeSeries::default()
+        }
+        .with_capacity(self.series.metrics_capacity)
+        .with_granularity(self.series.granularity);
+        PrometheusTimeSeries {
+            name: self.split_name(labels),
+            series,
+            source: self.source.clone(),
+            query: self.query.clone(),
+            url: self.url.clone(),
+            data_type: self.data_type.clone(),
+            required_labels: labels.clone(),
+            pull_interval: self.pull_interval,
+            color: Some(Color {
+                rgb,
+                alpha: self.color.and_then(|color| color.alpha),
+            }),
+            alpha: self.alpha,
+            split_from: Some(self.name.clone()),
+            http: self.http.clone(),
+            retry: self.retry.clone(),
+            health: self.health.clone(),
+            ..PrometheusTimeSeries::default()
+        }
+    }
 }
 
 /// `get_from_prometheus` is an async operation that returns an Optional
-/// PrometheusResponse
-pub fn get_from_prometheus(url: hyper::Uri) -> impl Future<Item = hyper::Chunk, Error = ()> {
+/// PrometheusResponse, the request is sent with the authentication and TLS
+/// settings of `http`
+pub fn get_from_prometheus(
+    url: hyper::Uri,
+    http: &HTTPClientConfig,
+) -> impl Future<Item = hyper::Chunk, Error = PrometheusError> {
     info!("Loading Prometheus URL: {}", url);
-    Client::new()
-        .get(url.clone())
+    let (connector, request) = match (http.connector(), http.request(url)) {
+        (Ok(connector), Ok(request)) => (connector, request),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("get_from_prometheus: {}", err);
+            return Either::A(future::err(PrometheusError::Config(err)));
+        }
+    };
+    let res = Client::builder()
+        .build::<_, Body>(connector)
+        .request(request)
         .and_then(|res| {
             debug!("get_from_prometheus: Response={:?}", res);
             res.into_body()
@@ -351,24 +1003,41 @@ pub fn get_from_prometheus(url: hyper::Uri) -> impl Future<Item = hyper::Chunk,
         })
         .map_err(|err| {
             error!("Error: {}", err);
-        })
+            PrometheusError::Request(err.to_string())
+        });
+    Either::B(res)
 }
 /// `parse_json` transforms a hyper body chunk into a possible
 /// PrometheusResponse, mostly used for testing
 pub fn parse_json(body: &hyper::Chunk) -> Option<HTTPResponse> {
-    let prom_res: Result<HTTPResponse, serde_json::Error> = serde_json::from_slice(&body);
-    // XXX: Figure out how to return the error
-    match prom_res {
-        Ok(v) => {
-            debug!("parse_json: returned JSON={:?}", v);
-            Some(v)
-        }
+    match parse_response(body) {
+        Ok(v) => Some(v),
         Err(err) => {
-            error!("Unable to parse JSON err={:?}", err);
+            error!("parse_json: {}", err);
             None
         }
     }
 }
+
+/// `parse_response` transforms a hyper body chunk into a PrometheusResponse,
+/// the responses with an error status are returned as a PrometheusError
+pub fn parse_response(body: &hyper::Chunk) -> Result<HTTPResponse, PrometheusError> {
+    let res: HTTPResponse = serde_json::from_slice(body).map_err(|err| {
+        PrometheusError::InvalidResponse(format!(
+            "{}: {}",
+            err,
+            String::from_utf8_lossy(&body[..body.len().min(128)])
+        ))
+    })?;
+    debug!("parse_response: returned JSON={:?}", res);
+    for warning in &res.warnings {
+        warn!("parse_response: Prometheus warning: {}", warning);
+    }
+    match PrometheusError::from_response(&res) {
+        Some(err) => Err(err),
+        None => Ok(res),
+    }
+}
 /// XXX: REMOVE
 /// Implement PartialEq for PrometheusTimeSeries because the field
 /// tokio_core should be ignored
@@ -381,8 +1050,11 @@ impl PartialEq<PrometheusTimeSeries> for PrometheusTimeSeries {
 }
 
 #[cfg(test)]
+// The baseline tests compare against literal bools
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crate::color::palette_color;
     use tokio_core::reactor::Core;
     fn init_log() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -412,6 +1084,255 @@ mod tests {
         assert_eq!(res0_json.is_none(), true);
     }
 
+    #[test]
+    fn it_uses_granularity_as_step() {
+        let url = PrometheusTimeSeries::prepare_url(
+            "http://localhost:9090/api/v1/query_range?query=node_load1",
+            60,
+            15,
+        )
+        .unwrap();
+        let query = url.query().unwrap();
+        let param = |name: &str| -> u64 {
+            query
+                .split('&')
+                .find(|param| param.starts_with(name))
+                .and_then(|param| param[name.len()..].parse().ok())
+                .unwrap()
+        };
+        assert_eq!(param("step="), 15);
+        assert_eq!(param("end=") - param("start="), 900);
+    }
+    #[test]
+    fn it_parses_prometheus_errors() {
+        let error = |error_type: &str, error: &str| -> Result<HTTPResponse, PrometheusError> {
+            parse_response(&hyper::Chunk::from(format!(
+                r#"{{"status":"error","errorType":"{}","error":"{}"}}"#,
+                error_type, error
+            )))
+        };
+        assert_eq!(
+            error("bad_data", "parse error at char 5: unexpected \\\"}\\\""),
+            Err(PrometheusError::BadQuery(String::from(
+                "parse error at char 5: unexpected \"}\""
+            )))
+        );
+        assert_eq!(
+            error("timeout", "query timed out in expression evaluation"),
+            Err(PrometheusError::Timeout(String::from(
+                "query timed out in expression evaluation"
+            )))
+        );
+        assert_eq!(
+            error("execution", "many-to-many matching not allowed"),
+            Err(PrometheusError::Execution(String::from(
+                "many-to-many matching not allowed"
+            )))
+        );
+        let unknown = error("not_acceptable", "nope").unwrap_err();
+        assert_eq!(
+            unknown,
+            PrometheusError::Api {
+                error_type: String::from("not_acceptable"),
+                error: String::from("nope")
+            }
+        );
+        assert_eq!(unknown.to_string(), "Prometheus error not_acceptable: nope");
+        match parse_response(&hyper::Chunk::from("Unauthorized")) {
+            Err(PrometheusError::InvalidResponse(err)) => assert!(err.ends_with("Unauthorized")),
+            res => panic!("Unexpected {:?}", res),
+        }
+        // Warnings do not fail the query
+        let res = parse_response(&hyper::Chunk::from(
+            r#"{"status":"success","warnings":["results truncated"],
+                "data":{"resultType":"vector","result":[]}}"#,
+        ))
+        .unwrap();
+        assert_eq!(res.warnings, vec![String::from("results truncated")]);
+        // The series refuse error responses
+        let mut test = PrometheusTimeSeries::default();
+        let res = HTTPResponse {
+            status: String::from("error"),
+            error_type: Some(String::from("unavailable")),
+            error: Some(String::from("too many queries")),
+            ..HTTPResponse::default()
+        };
+        assert_eq!(
+            test.load_prometheus_response(res).unwrap_err().to_string(),
+            "Prometheus unavailable: too many queries"
+        );
+    }
+
+    #[test]
+    fn it_backs_off_exponentially() {
+        let retry = RetryConfig {
+            jitter: 0.,
+            ..RetryConfig::default()
+        };
+        let backoffs: Vec<u64> = (1..=10)
+            .map(|failures| retry.backoff(failures, 0.5).as_secs())
+            .collect();
+        assert_eq!(backoffs, vec![1, 2, 4, 8, 16, 32, 64, 128, 256, 300]);
+        assert_eq!(retry.backoff(u32::MAX, 0.5).as_secs(), 300);
+        // The jitter spreads the backoff +/- 10%
+        let retry = RetryConfig::default();
+        assert_eq!(retry.backoff(4, 0.).as_millis(), 7200);
+        assert_eq!(retry.backoff(4, 0.5).as_millis(), 8000);
+        assert_eq!(retry.backoff(4, 0.75).as_millis(), 8400);
+        let test: PrometheusTimeSeries = serde_yaml::from_str(
+            "
+            source: 'http://localhost:9090/api/v1/query?query=up'
+            retry:
+              timeout: 5
+              max_backoff: 60
+            ",
+        )
+        .unwrap();
+        assert_eq!(test.retry.timeout, Some(5));
+        assert_eq!(test.retry.max_backoff, 60);
+        assert_eq!(test.retry.initial_backoff, 1);
+        assert_eq!(test.retry.stale_after, 3);
+    }
+
+    #[test]
+    fn it_encodes_structured_queries() {
+        let query: PrometheusQuery = serde_yaml::from_str(
+            r#"
+            base_url: 'https://prometheus.example.com/proxy/'
+            expr: 'sum(rate(http_requests_total{path="/a&b", code=~"5.+"}[5m])) + 1'
+            range: 3600
+            step: 60
+            params:
+              timeout: 10s
+            "#,
+        )
+        .unwrap();
+        assert_eq!(query.endpoint, QueryEndpoint::Range);
+        let url = query.to_url(300, 15, 1_558_253_479, None).unwrap();
+        assert_eq!(url.path(), "/proxy/api/v1/query_range");
+        assert_eq!(
+            url.query().unwrap(),
+            "query=sum(rate(http_requests_total%7Bpath%3D%22%2Fa%26b%22%2C%20code%3D~%225.%2B%22%7D%5B5m%5D))%20%2B%201\
+             &end=1558253479&start=1558249879&step=60&timeout=10s"
+        );
+        // Instant queries are evaluated at now unless a time is configured
+        let mut instant = PrometheusQuery {
+            base_url: String::from("http://localhost:9090"),
+            expr: String::from("up"),
+            endpoint: QueryEndpoint::Instant,
+            ..PrometheusQuery::default()
+        };
+        assert_eq!(
+            instant
+                .to_url(300, 15, 1_558_253_479, None)
+                .unwrap()
+                .to_string(),
+            "http://localhost:9090/api/v1/query?query=up&time=1558253479"
+        );
+        instant.time = Some(1_558_000_000);
+        assert_eq!(
+            instant
+                .to_url(300, 15, 1_558_253_479, None)
+                .unwrap()
+                .query(),
+            Some("query=up&time=1558000000")
+        );
+    }
+
+    #[test]
+    fn it_requests_only_the_missing_range() {
+        let query =
+            PrometheusQuery::from_source("http://localhost:9090/api/v1/query_range?query=up")
+                .unwrap();
+        // The first poll requests the whole time range
+        assert_eq!(
+            query.to_url(300, 15, 10_000, None).unwrap().query(),
+            Some("query=up&end=10000&start=5500&step=15")
+        );
+        // Then from two steps before the last loaded epoch, aligned to the step
+        assert_eq!(
+            query.to_url(300, 15, 10_000, Some(9_985)).unwrap().query(),
+            Some("query=up&end=10000&start=9945&step=15")
+        );
+        // After a long outage no more than the time range is requested
+        assert_eq!(
+            query.to_url(300, 15, 10_000, Some(1_000)).unwrap().query(),
+            Some("query=up&end=10000&start=5500&step=15")
+        );
+        // Nor a start after the end
+        assert_eq!(
+            query.to_url(300, 15, 10_000, Some(20_000)).unwrap().query(),
+            Some("query=up&end=10000&start=10000&step=15")
+        );
+        // Instant queries are not affected
+        let instant =
+            PrometheusQuery::from_source("http://localhost:9090/api/v1/query?query=up").unwrap();
+        assert_eq!(
+            instant
+                .to_url(300, 15, 10_000, Some(9_985))
+                .unwrap()
+                .query(),
+            Some("query=up&time=10000")
+        );
+        let matrix: HTTPResponse = serde_json::from_str(
+            r#"{"status":"success","data":{"resultType":"matrix","result":[
+              {"metric":{"cpu":"0"},"values":[[9970,"1"],[9985,"2"]]},
+              {"metric":{"cpu":"1"},"values":[[9955,"1"],[9970.5,"2"]]}]}}"#,
+        )
+        .unwrap();
+        assert_eq!(matrix.last_epoch(), Some(9985));
+        let vector: HTTPResponse = serde_json::from_str(
+            r#"{"status":"success","data":{"resultType":"vector","result":[]}}"#,
+        )
+        .unwrap();
+        assert_eq!(vector.last_epoch(), None);
+    }
+
+    #[test]
+    fn it_parses_source_urls() {
+        let query = PrometheusQuery::from_source(
+            "http://localhost:9090/api/v1/query_range?query=a + b{job=\"x\"}&step=1&start=0",
+        )
+        .unwrap();
+        assert_eq!(query.base_url, "http://localhost:9090");
+        assert_eq!(query.endpoint, QueryEndpoint::Range);
+        assert_eq!(query.expr, "a + b{job=\"x\"}");
+        // start, end and step in the source are replaced
+        assert_eq!(
+            query.to_url(10, 1, 100, None).unwrap().query(),
+            Some("query=a%20%2B%20b%7Bjob%3D%22x%22%7D&end=100&start=90&step=1")
+        );
+        // sources that were already percent encoded are not encoded twice
+        let encoded = PrometheusQuery::from_source(
+            "http://localhost:9090/api/v1/query?query=sum%20by%20(job)%20(up)%20% 2&timeout=5%73",
+        )
+        .unwrap();
+        assert_eq!(encoded.expr, "sum by (job) (up) % 2");
+        assert_eq!(
+            encoded.params.get("timeout").map(String::as_str),
+            Some("5s")
+        );
+        assert_eq!(
+            encoded.to_url(10, 1, 100, None).unwrap().query(),
+            Some("query=sum%20by%20(job)%20(up)%20%25%202&time=100&timeout=5s")
+        );
+        let instant = PrometheusQuery::from_source("http://localhost:9090/api/v1/query?query=up");
+        assert_eq!(instant.unwrap().endpoint, QueryEndpoint::Instant);
+        assert!(PrometheusQuery::from_source("http://localhost:9090/api/v1/query").is_err());
+        assert!(PrometheusQuery::from_source("http://localhost:9090/graph?g0.expr=up").is_err());
+        assert!(PrometheusQuery::from_source("http://localhost:9090/api/v1/query?time=1").is_err());
+        let test: PrometheusTimeSeries = serde_yaml::from_str(
+            "
+            source: 'http://localhost:9090/api/v1/query?query=up'
+            query:
+              base_url: 'http://localhost:9090'
+              expr: 'down'
+            ",
+        )
+        .unwrap();
+        assert_eq!(test.prometheus_query().unwrap().expr, "down");
+    }
+
     #[test]
     fn it_loads_prometheus_scalars() {
         let test0_res: Result<PrometheusTimeSeries, String> = PrometheusTimeSeries::new(
@@ -616,40 +1537,327 @@ mod tests {
         assert_eq!(res1_load, Ok(0usize));
     }
 
+    #[test]
+    fn it_names_split_series() {
+        let mut test = PrometheusTimeSeries {
+            name: String::from("cpu"),
+            ..PrometheusTimeSeries::default()
+        };
+        let mut labels = HashMap::new();
+        labels.insert(String::from("__name__"), String::from("node_cpu"));
+        labels.insert(String::from("mode"), String::from("idle"));
+        labels.insert(String::from("cpu"), String::from("0"));
+        assert_eq!(test.split_name(&labels), r#"cpu{cpu="0",mode="idle"}"#);
+        test.label_template = Some(String::from("{__name__} {cpu}/{mode} {missing}"));
+        assert_eq!(test.split_name(&labels), "node_cpu 0/idle {missing}");
+        let split = test.split(&labels, palette_color(1));
+        assert_eq!(split.required_labels, labels);
+        assert_eq!(split.split_from, Some(String::from("cpu")));
+        assert!(!split.split_by_labels);
+        assert_eq!(split.color, Some(Color::from(palette_color(1))));
+    }
+
+    /// `tls_test_server` starts a HTTPS server on a random port with the
+    /// certificate for localhost in tests/fixtures. It answers with a vector
+    /// when the request has one of the `authorizations` and the X-Scope-OrgID
+    /// header, otherwise it returns a 401.
+    fn tls_test_server(runtime: &mut tokio::runtime::Runtime, authorizations: Vec<String>) -> u16 {
+        use hyper::service::service_fn_ok;
+        use hyper::{Response, StatusCode};
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listener = tokio::net::TcpListener::bind(&addr).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let identity = native_tls::Identity::from_pkcs8(
+            include_bytes!("../tests/fixtures/localhost.pem"),
+            include_bytes!("../tests/fixtures/localhost.key"),
+        )
+        .unwrap();
+        let acceptor =
+            tokio_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+        let server = listener
+            .incoming()
+            .map_err(|err| error!("tls_test_server: accept: {}", err))
+            .for_each(move |tcp| {
+                let authorizations = authorizations.clone();
+                let respond = move |req: Request<Body>| {
+                    let authorized = req
+                        .headers()
+                        .get(hyper::header::AUTHORIZATION)
+                        .and_then(|value| value.to_str().ok())
+                        .is_some_and(|value| authorizations.iter().any(|a| a == value));
+                    let org_id = req.headers().get("X-Scope-OrgID");
+                    if authorized && org_id.is_some_and(|value| value == "team-a") {
+                        Response::new(Body::from(
+                            r#"{"status":"success","data":{"resultType":"vector","result":[
+                              {"metric":{"__name__":"up"},"value":[1558270835.417,"1"]}]}}"#,
+                        ))
+                    } else {
+                        Response::builder()
+                            .status(StatusCode::UNAUTHORIZED)
+                            .body(Body::from("Unauthorized"))
+                            .unwrap()
+                    }
+                };
+                let connection = acceptor
+                    .accept(tcp)
+                    .map_err(|err| debug!("tls_test_server: handshake: {}", err))
+                    .and_then(move |tls| {
+                        hyper::server::conn::Http::new()
+                            .serve_connection(tls, service_fn_ok(respond))
+                            .map_err(|err| debug!("tls_test_server: connection: {}", err))
+                    });
+                tokio::spawn(connection);
+                Ok(())
+            });
+        runtime.spawn(server);
+        port
+    }
+
+    #[test]
+    fn it_gets_prometheus_metrics_over_tls() {
+        init_log();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let token_file =
+            std::env::temp_dir().join(format!("cbm-test-token-{}", std::process::id()));
+        fs::write(&token_file, "s3cr3t\n").unwrap();
+        let port = tls_test_server(
+            &mut runtime,
+            vec![
+                String::from("Bearer s3cr3t"),
+                format!("Basic {}", base64::encode("admin:hunter2")),
+            ],
+        );
+        let url: hyper::Uri = format!("https://localhost:{}/api/v1/query?query=up", port)
+            .parse()
+            .unwrap();
+        let mut http = HTTPClientConfig {
+            bearer_token_file: Some(token_file.clone()),
+            tls: TLSConfig {
+                ca_file: Some(PathBuf::from(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/tests/fixtures/localhost.pem"
+                ))),
+                insecure_skip_verify: false,
+            },
+            ..HTTPClientConfig::default()
+        };
+        http.headers
+            .insert(String::from("X-Scope-OrgID"), String::from("team-a"));
+        let body = runtime
+            .block_on(get_from_prometheus(url.clone(), &http))
+            .unwrap();
+        let res = parse_json(&body).unwrap();
+        assert_eq!(res.status, "success");
+        // Basic auth with the password in a file
+        fs::write(&token_file, "hunter2").unwrap();
+        http.bearer_token_file = None;
+        http.basic_auth = Some(BasicAuth {
+            username: String::from("admin"),
+            password_file: Some(token_file.clone()),
+            ..BasicAuth::default()
+        });
+        let body = runtime
+            .block_on(get_from_prometheus(url.clone(), &http))
+            .unwrap();
+        assert!(parse_json(&body).is_some());
+        // Wrong credentials
+        http.basic_auth = Some(BasicAuth {
+            username: String::from("admin"),
+            password: String::from("admin"),
+            ..BasicAuth::default()
+        });
+        let body = runtime
+            .block_on(get_from_prometheus(url.clone(), &http))
+            .unwrap();
+        assert!(parse_json(&body).is_none());
+        // The certificate is not trusted without the CA file
+        http.tls.ca_file = None;
+        assert!(runtime
+            .block_on(get_from_prometheus(url.clone(), &http))
+            .is_err());
+        http.tls.insecure_skip_verify = true;
+        assert!(runtime
+            .block_on(get_from_prometheus(url.clone(), &http))
+            .is_ok());
+        // A missing token file fails before connecting
+        http.bearer_token_file = Some(PathBuf::from("/nonexistent/token"));
+        assert!(http.authorization().is_err());
+        assert!(runtime.block_on(get_from_prometheus(url, &http)).is_err());
+        fs::remove_file(&token_file).unwrap();
+    }
+
+    #[test]
+    fn it_overwrites_samples_loaded_again() {
+        let matrix: HTTPResponse = serde_json::from_str(
+            r#"{"status":"success","data":{"resultType":"matrix","result":[
+              {"metric":{},"values":[[100,"1"],[101,"2"]]}]}}"#,
+        )
+        .unwrap();
+        for config in &["name: up", "series:\n  metrics_capacity: 10"] {
+            let mut test: PrometheusTimeSeries = serde_yaml::from_str(config).unwrap();
+            assert_eq!(
+                test.series.collision_policy,
+                ValueCollisionPolicy::Overwrite
+            );
+            test.load_prometheus_response(matrix.clone()).unwrap();
+            test.load_prometheus_response(matrix.clone()).unwrap();
+            assert_eq!(
+                test.series.as_vec(),
+                vec![(100, Some(1f64)), (101, Some(2f64))]
+            );
+        }
+        let test: PrometheusTimeSeries =
+            serde_yaml::from_str("series:\n  collision_policy: Max").unwrap();
+        assert_eq!(test.series.collision_policy, ValueCollisionPolicy::Max);
+    }
+
+    #[test]
+    fn it_skips_samples_loaded_again() {
+        let matrix = |values: &str| -> HTTPResponse {
+            serde_json::from_str(&format!(
+                r#"{{"status":"success","data":{{"resultType":"matrix","result":[{}]}}}}"#,
+                values
+            ))
+            .unwrap()
+        };
+        let mut test: PrometheusTimeSeries =
+            serde_yaml::from_str("series:\n  collision_policy: Increment\n  metrics_capacity: 20")
+                .unwrap();
+        assert_eq!(test.last_loaded_epoch(), None);
+        let first = matrix(r#"{"metric":{"job":"a"},"values":[[100,"1"],[101,"2"]]}"#);
+        assert_eq!(test.load_prometheus_response(first), Ok(2));
+        assert_eq!(test.last_loaded_epoch(), Some(101));
+        // The overlap is not counted twice
+        let overlap = matrix(r#"{"metric":{"job":"a"},"values":[[101,"2"],[102,"3"]]}"#);
+        assert_eq!(test.load_prometheus_response(overlap), Ok(1));
+        assert_eq!(
+            test.series.as_vec(),
+            vec![(100, Some(1f64)), (101, Some(2f64)), (102, Some(3f64))]
+        );
+        // A label set first seen in an incremental load requests the full range
+        let new_label_set = matrix(
+            r#"{"metric":{"job":"a"},"values":[[102,"3"],[103,"4"]]},
+               {"metric":{"job":"b"},"values":[[102,"5"],[103,"6"]]}"#,
+        );
+        assert_eq!(test.load_prometheus_response(new_label_set), Ok(3));
+        assert_eq!(test.last_loaded_epoch(), None);
+        let full_range = matrix(
+            r#"{"metric":{"job":"a"},"values":[[100,"1"],[101,"2"],[102,"3"],[103,"4"]]},
+               {"metric":{"job":"b"},"values":[[100,"7"],[101,"8"],[102,"5"],[103,"6"]]}"#,
+        );
+        assert_eq!(test.load_prometheus_response(full_range), Ok(2));
+        assert_eq!(test.last_loaded_epoch(), Some(103));
+        assert_eq!(
+            test.series.as_vec(),
+            vec![
+                (100, Some(8f64)),
+                (101, Some(10f64)),
+                (102, Some(8f64)),
+                (103, Some(10f64))
+            ]
+        );
+    }
+
+    #[test]
+    fn it_loads_http_config() {
+        let test: PrometheusTimeSeries = serde_yaml::from_str(
+            "
+            name: up
+            source: 'https://prometheus:9090/api/v1/query?query=up'
+            http:
+              bearer_token_file: /etc/prometheus/token
+              headers:
+                X-Scope-OrgID: team-a
+              tls:
+                ca_file: /etc/prometheus/ca.pem
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            test.http.bearer_token_file,
+            Some(PathBuf::from("/etc/prometheus/token"))
+        );
+        assert_eq!(test.http.headers["X-Scope-OrgID"], "team-a");
+        assert!(!test.http.tls.insecure_skip_verify);
+        let basic_auth = HTTPClientConfig {
+            basic_auth: Some(BasicAuth {
+                username: String::from("Aladdin"),
+                password: String::from("open sesame"),
+                password_file: None,
+            }),
+            ..HTTPClientConfig::default()
+        };
+        assert_eq!(
+            basic_auth.authorization(),
+            Ok(Some(String::from("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==")))
+        );
+        assert_eq!(HTTPClientConfig::default().authorization(), Ok(None));
+    }
+
     #[test]
     fn it_gets_prometheus_metrics() {
         init_log();
+        let mock = crate::mock_prometheus::MockPrometheus::start().unwrap();
+        mock.set_response(
+            "up",
+            crate::mock_prometheus::MockResponse::Series(vec![
+                crate::mock_prometheus::MockSeries::new(
+                    &[
+                        ("__name__", "up"),
+                        ("instance", "localhost:9090"),
+                        ("job", "prometheus"),
+                    ],
+                    |_| 1.,
+                ),
+                crate::mock_prometheus::MockSeries::new(
+                    &[
+                        ("__name__", "up"),
+                        ("instance", "localhost:9100"),
+                        ("job", "node_exporter"),
+                    ],
+                    |_| 1.,
+                ),
+            ]),
+        );
         // Create a Tokio Core to use for testing
         let mut core = Core::new().unwrap();
         let mut test_labels = HashMap::new();
         test_labels.insert(String::from("name"), String::from("up"));
         test_labels.insert(String::from("job"), String::from("prometheus"));
         test_labels.insert(String::from("instance"), String::from("localhost:9090"));
-        // Test non plain http error:
+        // Test non http error:
         let test0_res: Result<PrometheusTimeSeries, String> = PrometheusTimeSeries::new(
-            String::from("https://localhost:9090/api/v1/query?query=up"),
+            String::from("ftp://localhost:9090/api/v1/query?query=up"),
             15,
             String::from("vector"),
             test_labels.clone(),
         );
         assert_eq!(
             test0_res,
-            Err(String::from("Unsupported protocol: Some(\"https\")"))
+            Err(String::from("Unsupported protocol: Some(\"ftp\")"))
         );
+        assert!(PrometheusTimeSeries::new(
+            String::from("https://localhost:9090/api/v1/query?query=up"),
+            15,
+            String::from("vector"),
+            test_labels.clone(),
+        )
+        .is_ok());
         let test1_res: Result<PrometheusTimeSeries, String> = PrometheusTimeSeries::new(
-            String::from("http://localhost:9090/api/v1/query?query=up"),
+            format!("{}/api/v1/query?query=up", mock.base_url()),
             15,
             String::from("vector"),
             test_labels.clone(),
         );
         assert_eq!(test1_res.is_ok(), true);
         let test1 = test1_res.unwrap();
-        let res1_get = core.run(get_from_prometheus(test1.url.clone()));
+        let res1_get = core.run(get_from_prometheus(
+            test1.url.clone(),
+            &HTTPClientConfig::default(),
+        ));
         println!("get_from_prometheus: {:?}", res1_get);
         assert_eq!(res1_get.is_ok(), true);
         if let Some(prom_response) = parse_json(&res1_get.unwrap()) {
-            // This requires a Prometheus Server running locally
-            // XXX: mock this.
             // Example playload:
             // {"status":"success","data":{"resultType":"vector","result":[
             //   {"metric":{"__name__":"up","instance":"localhost:9090","job":"prometheus"},
@@ -0,0 +1,544 @@
+//! Renders the TimeSeriesChart vertices without an OpenGL context, so that
+//! dashboards can be written as SVG documents or PNG images on a headless
+//! box, i.e. to attach them to reports or to compare them in CI.
+//! The vertices are in the [-1.0, 1.0] plane of the display, the image has
+//! the `width` and `height` of the SizeInfo the charts were updated with.
+use crate::color::Rgb;
+use crate::{SizeInfo, TimeSeriesChart};
+use log::*;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// The bytes every PNG file starts with
+pub const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// The largest payload of a stored deflate block
+const DEFLATE_MAX_STORED: usize = 65_535;
+
+/// `Stroke` is a polyline in pixels, from the top left of the image
+#[derive(Debug, PartialEq, Clone)]
+pub struct Stroke {
+    pub color: Rgb,
+    pub alpha: f32,
+    pub points: Vec<(f32, f32)>,
+}
+
+/// `to_pixels` converts an OpenGL vertex to pixels, None if it is not drawn
+fn to_pixels(x: f32, y: f32, width: f32, height: f32) -> Option<(f32, f32)> {
+    if x.is_finite() && y.is_finite() {
+        Some(((x + 1.) / 2. * width, (1. - y) / 2. * height))
+    } else {
+        None
+    }
+}
+
+/// `chart_strokes` returns the polylines of the series, split where a
+/// vertex is not drawn (the Gap policy), followed by the GL_LINES of the
+/// decorations. The opengl vecs of the chart must be up to date.
+pub fn chart_strokes(chart: &TimeSeriesChart, size: SizeInfo) -> Vec<Stroke> {
+    let mut strokes = vec![];
+    for (source, vertices) in chart.sources.iter().zip(chart.opengl_vecs.iter()) {
+        let color = source.color();
+        let mut points = vec![];
+        for vertex in vertices.chunks(2).filter(|vertex| vertex.len() == 2) {
+            match to_pixels(vertex[0], vertex[1], size.width, size.height) {
+                Some(point) => points.push(point),
+                None if points.len() > 1 => strokes.push(Stroke {
+                    color,
+                    alpha: source.alpha(),
+                    points: std::mem::take(&mut points),
+                }),
+                None => points.clear(),
+            }
+        }
+        if points.len() > 1 {
+            strokes.push(Stroke {
+                color,
+                alpha: source.alpha(),
+                points,
+            });
+        }
+    }
+    for decoration in &chart.decorations {
+        let vertices = decoration.opengl_vertices();
+        if vertices.is_empty() {
+            continue;
+        }
+        let color = decoration.color();
+        for line in vertices.chunks(4).filter(|line| line.len() == 4) {
+            let start = to_pixels(line[0], line[1], size.width, size.height);
+            let end = to_pixels(line[2], line[3], size.width, size.height);
+            if let (Some(start), Some(end)) = (start, end) {
+                strokes.push(Stroke {
+                    color,
+                    alpha: decoration.alpha(),
+                    points: vec![start, end],
+                });
+            }
+        }
+    }
+    strokes
+}
+
+/// `update_charts` updates the opengl vecs of all the series and decorations
+/// of `charts` for `size`
+pub fn update_charts(charts: &mut [TimeSeriesChart], size: SizeInfo) {
+    for chart in charts.iter_mut() {
+        for series_idx in 0..chart.sources.len() {
+            chart.update_opengl_vecs(series_idx, size);
+        }
+    }
+}
+
+/// `xml_escape` escapes the text of an XML attribute or element
+fn xml_escape(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `render_svg` draws `charts` in an SVG document, each chart is a group
+/// titled by its name. The background is transparent unless set.
+pub fn render_svg(charts: &[TimeSeriesChart], size: SizeInfo, background: Option<Rgb>) -> String {
+    let (width, height) = (size.width.round() as u32, size.height.round() as u32);
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" \
+         viewBox=\"0 0 {0} {1}\">\n",
+        width, height
+    );
+    if let Some(background) = background {
+        let _ = writeln!(
+            svg,
+            "<rect width=\"100%\" height=\"100%\" fill=\"#{:02x}{:02x}{:02x}\"/>",
+            background.r, background.g, background.b
+        );
+    }
+    for chart in charts {
+        let _ = writeln!(svg, "<g><title>{}</title>", xml_escape(&chart.name));
+        for stroke in chart_strokes(chart, size) {
+            let points: Vec<String> = stroke
+                .points
+                .iter()
+                .map(|(x, y)| format!("{:.2},{:.2}", x, y))
+                .collect();
+            let _ = writeln!(
+                svg,
+                "<polyline points=\"{}\" fill=\"none\" stroke=\"#{:02x}{:02x}{:02x}\" \
+                 stroke-opacity=\"{}\" stroke-width=\"1\"/>",
+                points.join(" "),
+                stroke.color.r,
+                stroke.color.g,
+                stroke.color.b,
+                stroke.alpha.clamp(0., 1.)
+            );
+        }
+        svg.push_str("</g>\n");
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// `Canvas` is an RGBA image the strokes are rasterized into, the colors are
+/// not premultiplied by alpha
+#[derive(Debug, PartialEq, Clone)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    /// The rows of RGBA pixels, from the top
+    pub pixels: Vec<u8>,
+}
+
+impl Canvas {
+    /// `new` returns a transparent canvas
+    pub fn new(width: u32, height: u32) -> Canvas {
+        Canvas {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize * 4],
+        }
+    }
+
+    /// `with_background` fills the canvas with an opaque `color`
+    pub fn with_background(mut self, color: Rgb) -> Self {
+        for pixel in self.pixels.chunks_mut(4) {
+            pixel.copy_from_slice(&[color.r, color.g, color.b, 255]);
+        }
+        self
+    }
+
+    /// `pixel` returns the RGBA pixel at `x`, `y`
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let idx = (y as usize * self.width as usize + x as usize) * 4;
+        [
+            self.pixels[idx],
+            self.pixels[idx + 1],
+            self.pixels[idx + 2],
+            self.pixels[idx + 3],
+        ]
+    }
+
+    /// `blend` draws `color` with `alpha` over the pixel at `x`, `y`
+    fn blend(&mut self, x: i64, y: i64, color: Rgb, alpha: f32) {
+        if x < 0 || y < 0 || x >= i64::from(self.width) || y >= i64::from(self.height) {
+            return;
+        }
+        let idx = (y as usize * self.width as usize + x as usize) * 4;
+        let dst_alpha = f32::from(self.pixels[idx + 3]) / 255.;
+        let out_alpha = alpha + dst_alpha * (1. - alpha);
+        if out_alpha <= 0. {
+            return;
+        }
+        for (dst, src) in self.pixels[idx..idx + 3]
+            .iter_mut()
+            .zip([color.r, color.g, color.b].iter())
+        {
+            let blended = f32::from(*src) * alpha + f32::from(*dst) * dst_alpha * (1. - alpha);
+            *dst = (blended / out_alpha).round() as u8;
+        }
+        self.pixels[idx + 3] = (out_alpha * 255.).round() as u8;
+    }
+
+    /// `clip` clips the segment to the canvas (Liang-Barsky), None when it is
+    /// outside
+    fn clip(&self, start: (f32, f32), end: (f32, f32)) -> Option<((f32, f32), (f32, f32))> {
+        let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+        let (max_x, max_y) = (self.width as f32 - 0.5, self.height as f32 - 0.5);
+        let (mut t0, mut t1) = (0f32, 1f32);
+        for (p, q) in &[
+            (-dx, start.0 + 0.5),
+            (dx, max_x - start.0),
+            (-dy, start.1 + 0.5),
+            (dy, max_y - start.1),
+        ] {
+            if *p == 0. {
+                if *q < 0. {
+                    return None;
+                }
+            } else {
+                let t = q / p;
+                if *p < 0. {
+                    t0 = t0.max(t);
+                } else {
+                    t1 = t1.min(t);
+                }
+                if t0 > t1 {
+                    return None;
+                }
+            }
+        }
+        Some((
+            (start.0 + t0 * dx, start.1 + t0 * dy),
+            (start.0 + t1 * dx, start.1 + t1 * dy),
+        ))
+    }
+
+    /// `draw_line` draws a one pixel wide segment (Bresenham), the first
+    /// pixel is skipped when `skip_start` so that the joints of a polyline
+    /// are not blended twice
+    fn draw_line(
+        &mut self,
+        start: (f32, f32),
+        end: (f32, f32),
+        color: Rgb,
+        alpha: f32,
+        skip_start: bool,
+    ) {
+        let (clipped_start, clipped_end) = match self.clip(start, end) {
+            Some(clipped) => clipped,
+            None => return,
+        };
+        let skip_start = skip_start && clipped_start == start;
+        // The clipped points are within half a pixel of the canvas
+        let (max_x, max_y) = (i64::from(self.width) - 1, i64::from(self.height) - 1);
+        let to_pixel = |point: (f32, f32)| {
+            (
+                (point.0.round() as i64).max(0).min(max_x),
+                (point.1.round() as i64).max(0).min(max_y),
+            )
+        };
+        let (mut x, mut y) = to_pixel(clipped_start);
+        let (x1, y1) = to_pixel(clipped_end);
+        let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+        let (step_x, step_y) = (if x < x1 { 1 } else { -1 }, if y < y1 { 1 } else { -1 });
+        let mut err = dx + dy;
+        let mut first = true;
+        loop {
+            if !(first && skip_start) {
+                self.blend(x, y, color, alpha);
+            }
+            first = false;
+            if x == x1 && y == y1 {
+                break;
+            }
+            let err2 = 2 * err;
+            if err2 >= dy {
+                err += dy;
+                x += step_x;
+            }
+            if err2 <= dx {
+                err += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// `draw_stroke` draws the segments of `stroke`
+    pub fn draw_stroke(&mut self, stroke: &Stroke) {
+        let alpha = stroke.alpha.clamp(0., 1.);
+        for (idx, segment) in stroke.points.windows(2).enumerate() {
+            self.draw_line(segment[0], segment[1], stroke.color, alpha, idx > 0);
+        }
+    }
+
+    /// `to_png` encodes the canvas as an 8 bit RGBA PNG, the image data is
+    /// stored without compression
+    pub fn to_png(&self) -> Vec<u8> {
+        let row_len = self.width as usize * 4;
+        let mut raw = Vec::with_capacity((row_len + 1) * self.height as usize);
+        for row in self.pixels.chunks(row_len.max(1)) {
+            // Filter type None
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        let mut header = vec![];
+        header.extend_from_slice(&self.width.to_be_bytes());
+        header.extend_from_slice(&self.height.to_be_bytes());
+        // Bit depth 8, color type RGBA, deflate, adaptive filtering, no interlace
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+        let mut png = PNG_SIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", &header);
+        write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+        write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+/// `write_chunk` appends a PNG chunk with its length and crc
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(kind);
+    hasher.update(data);
+    png.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+/// `adler32` is the checksum of a zlib stream
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5_552) {
+        for byte in chunk {
+            a += u32::from(*byte);
+            b += a;
+        }
+        a %= 65_521;
+        b %= 65_521;
+    }
+    (b << 16) | a
+}
+
+/// `zlib_stored` wraps `data` in a zlib stream of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, no preset dictionary, fastest level
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = data.chunks(DEFLATE_MAX_STORED).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        let len = block.len() as u16;
+        zlib.push(is_final as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(data).to_be_bytes());
+    zlib
+}
+
+/// `render_canvas` rasterizes `charts` in a canvas, the background is
+/// transparent unless set
+pub fn render_canvas(
+    charts: &[TimeSeriesChart],
+    size: SizeInfo,
+    background: Option<Rgb>,
+) -> Canvas {
+    let mut canvas = Canvas::new(
+        size.width.max(0.).round() as u32,
+        size.height.max(0.).round() as u32,
+    );
+    if let Some(background) = background {
+        canvas = canvas.with_background(background);
+    }
+    for chart in charts {
+        for stroke in chart_strokes(chart, size) {
+            canvas.draw_stroke(&stroke);
+        }
+    }
+    canvas
+}
+
+/// `render_png` draws `charts` in a PNG image
+pub fn render_png(charts: &[TimeSeriesChart], size: SizeInfo, background: Option<Rgb>) -> Vec<u8> {
+    render_canvas(charts, size, background).to_png()
+}
+
+/// `write_image` updates the opengl vecs of `charts` for `size` and writes
+/// them to `path`, as PNG if the extension is `png` and as SVG otherwise
+pub fn write_image(
+    path: &Path,
+    charts: &mut [TimeSeriesChart],
+    size: SizeInfo,
+    background: Option<Rgb>,
+) -> Result<(), String> {
+    update_charts(charts, size);
+    let contents = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("png") => render_png(charts, size, background),
+        _ => render_svg(charts, size, background).into_bytes(),
+    };
+    fs::write(path, contents).map_err(|err| format!("Unable to write {:?}: {}", path, err))?;
+    info!("write_image: Wrote {} charts to {:?}", charts.len(), path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::{
+        Decoration, ManualTimeSeries, ReferencePointDecoration, TimeSeriesSource, Value2D,
+    };
+
+    fn test_chart() -> (SizeInfo, TimeSeriesChart) {
+        let size = SizeInfo {
+            width: 12.,
+            height: 8.,
+            ..SizeInfo::default()
+        };
+        let mut series = ManualTimeSeries {
+            name: String::from("load"),
+            color: Some(Color::from(Rgb::new(0xff, 0, 0))),
+            ..ManualTimeSeries::default()
+        };
+        series.series.metrics_capacity = 12;
+        series.series.missing_values_policy = crate::MissingValuesPolicy::Gap;
+        for (epoch, value) in [(0, 0.), (1, 2.), (2, 4.), (3, 6.), (4, 8.), (5, 8.)].iter() {
+            series.series.circular_push((*epoch, Some(*value)));
+        }
+        series.series.circular_push((6, None));
+        for epoch in 7..12 {
+            series.series.circular_push((epoch, Some(4.)));
+        }
+        let chart = TimeSeriesChart {
+            name: String::from("load & <more>"),
+            sources: vec![TimeSeriesSource::AlacrittyInput(series)],
+            decorations: vec![Decoration::Reference(ReferencePointDecoration {
+                value: 4.,
+                height_multiplier: 0.,
+                color: Some(Color::from(Rgb::new(0, 0, 0xff))),
+                alpha: 0.5,
+                padding: Value2D { x: 0., y: 0. },
+                ..ReferencePointDecoration::default()
+            })],
+            width: 12.,
+            height: 8.,
+            ..TimeSeriesChart::default()
+        };
+        (size, chart)
+    }
+
+    /// `as_ascii` draws the canvas with one character per pixel: `.` for
+    /// transparent, `R` for red, `B` for blue and `M` for a blend
+    fn as_ascii(canvas: &Canvas) -> String {
+        let mut ascii = String::new();
+        for y in 0..canvas.height {
+            for x in 0..canvas.width {
+                ascii.push(match canvas.pixel(x, y) {
+                    [_, _, _, 0] => '.',
+                    [255, 0, 0, 255] => 'R',
+                    [0, 0, 255, _] => 'B',
+                    _ => 'M',
+                });
+            }
+            ascii.push('\n');
+        }
+        ascii
+    }
+
+    #[test]
+    fn it_renders_svg() {
+        let (size, chart) = test_chart();
+        let mut charts = vec![chart];
+        update_charts(&mut charts, size);
+        let svg = render_svg(&charts, size, Some(Rgb::default()));
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"12\""));
+        assert!(svg.contains("<rect width=\"100%\" height=\"100%\" fill=\"#000000\"/>"));
+        assert!(svg.contains("<title>load &amp; &lt;more&gt;</title>"));
+        // The gap splits the series in two polylines, plus the 3 lines of the
+        // reference point
+        assert_eq!(svg.matches("stroke=\"#ff0000\"").count(), 2);
+        assert_eq!(svg.matches("stroke=\"#0000ff\"").count(), 3);
+        assert!(
+            svg.contains("points=\"0.00,8.00 1.00,6.00 2.00,4.00 3.00,2.00 4.00,0.00 5.00,0.00\"")
+        );
+        assert!(svg.contains("stroke-opacity=\"0.5\""));
+    }
+
+    #[test]
+    fn it_rasterizes_charts() {
+        let (size, chart) = test_chart();
+        let mut charts = vec![chart];
+        update_charts(&mut charts, size);
+        let canvas = render_canvas(&charts, size, None);
+        assert_eq!(
+            as_ascii(&canvas),
+            "\
+             ....RR......\n\
+             ....R.......\n\
+             ...R........\n\
+             ...R........\n\
+             BBMBBBBMMMMM\n\
+             ..R.........\n\
+             .R..........\n\
+             R...........\n"
+        );
+        // The translucent reference line is blended over the series
+        assert_eq!(canvas.pixel(2, 4), [128, 0, 128, 255]);
+        assert_eq!(canvas.pixel(1, 4), [0, 0, 255, 128]);
+        let opaque = render_canvas(&charts, size, Some(Rgb::default()));
+        assert_eq!(opaque.pixel(1, 4), [0, 0, 128, 255]);
+        assert_eq!(opaque.pixel(1, 0), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn it_encodes_png() {
+        let mut canvas = Canvas::new(2, 1);
+        canvas.blend(1, 0, Rgb::new(255, 0, 0), 1.);
+        let png = canvas.to_png();
+        assert_eq!(&png[..8], PNG_SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 1]);
+        assert_eq!(&png[24..29], &[8, 6, 0, 0, 0]);
+        let idat_len = u32::from_be_bytes([png[33], png[34], png[35], png[36]]) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let idat = &png[41..41 + idat_len];
+        // A single stored block with the filter byte and the 2 pixels
+        assert_eq!(&idat[..7], &[0x78, 0x01, 1, 9, 0, 0xf6, 0xff]);
+        assert_eq!(&idat[7..16], &[0, 0, 0, 0, 0, 255, 0, 0, 255]);
+        assert_eq!(&idat[16..], &adler32(&idat[7..16]).to_be_bytes());
+        let crc = crc32fast::hash(&png[37..41 + idat_len]);
+        assert_eq!(&png[41 + idat_len..45 + idat_len], &crc.to_be_bytes());
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+        // The reference values of the zlib checksum
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(
+            zlib_stored(&vec![7; 70_000]).len(),
+            2 + 5 + 65_535 + 5 + 4_465 + 4
+        );
+    }
+}
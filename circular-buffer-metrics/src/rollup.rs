@@ -0,0 +1,515 @@
+//! Multi-resolution rollups for TimeSeries
+//! A `SeriesRollup` rolls the full resolution `TimeSeries` of a chart source
+//! up into coarser tiers, for example 10s, 1m and 1h.
+//! Each tier is made of circular buffers with a granularity of one bucket,
+//! so that a chart can show a whole day without keeping 86400 slots around.
+//! Each tier is built from the tier below it, the full resolution series
+//! feeds the first tier, the first tier feeds the second and so on.
+//! Charts configure their tiers with `rollup` and keep a `SeriesRollup` per
+//! series, the tier drawn is the one that fits the chart `range`.
+use crate::{TimeSeries, ValueCollisionPolicy};
+use log::*;
+
+/// `RollupBucket` contains the aggregated values of one slot of a tier.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct RollupBucket {
+    /// The epoch at which the bucket starts
+    pub epoch: u64,
+    /// The lower tier values folded with the tier `collision_policy`
+    pub value: f64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: usize,
+}
+
+impl RollupBucket {
+    /// `avg` returns the average of the values rolled into the bucket
+    pub fn avg(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+
+    /// `merge` folds another bucket of a lower tier into this one
+    fn merge(&mut self, other: &RollupBucket, policy: &ValueCollisionPolicy) {
+        self.value = policy.resolve(self.value, other.value);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// `RollupTier` is a downsampled version of a TimeSeries, each slot in the
+/// circular buffers covers `resolution` seconds, that is, the granularity of
+/// the inner TimeSeries is the resolution of the tier.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RollupTier {
+    /// The number of seconds each slot covers
+    pub resolution: u64,
+
+    /// How the values of the lower tier are folded into the bucket value,
+    /// i.e. Increment adds up counters, Max keeps the peak of a gauge.
+    #[serde(default)]
+    pub collision_policy: ValueCollisionPolicy,
+
+    /// The folded value of each bucket
+    #[serde(default)]
+    pub value: TimeSeries,
+
+    /// The minimum value seen on each bucket
+    #[serde(default)]
+    pub min: TimeSeries,
+
+    /// The maximum value seen on each bucket
+    #[serde(default)]
+    pub max: TimeSeries,
+
+    /// The sum of the values on each bucket
+    #[serde(default)]
+    pub sum: TimeSeries,
+
+    /// The number of filled full resolution slots on each bucket
+    #[serde(default)]
+    pub count: TimeSeries<u64>,
+}
+
+impl Default for RollupTier {
+    fn default() -> RollupTier {
+        // 10 seconds buckets for an hour.
+        RollupTier::new(10, 360)
+    }
+}
+
+impl RollupTier {
+    /// `new` returns a RollupTier of `capacity` buckets of `resolution`
+    /// seconds each.
+    pub fn new(resolution: u64, capacity: usize) -> RollupTier {
+        let resolution = resolution.max(1);
+        let series: TimeSeries = TimeSeries {
+            collision_policy: ValueCollisionPolicy::Overwrite,
+            ..TimeSeries::default()
+        }
+        .with_capacity(capacity)
+        .with_granularity(resolution);
+        RollupTier {
+            resolution,
+            collision_policy: ValueCollisionPolicy::default(),
+            value: series.clone(),
+            min: series.clone(),
+            max: series.clone(),
+            sum: series,
+            count: TimeSeries {
+                collision_policy: ValueCollisionPolicy::Overwrite,
+                ..TimeSeries::default()
+            }
+            .with_capacity(capacity)
+            .with_granularity(resolution),
+        }
+    }
+
+    /// `with_collision_policy` builder changes the way lower tier values are
+    /// folded into a bucket value
+    pub fn with_collision_policy(mut self, policy: ValueCollisionPolicy) -> RollupTier {
+        self.collision_policy = policy;
+        self
+    }
+
+    /// `store` overwrites the bucket in the circular buffers
+    fn store(&mut self, bucket: &RollupBucket) {
+        self.value.push((bucket.epoch, bucket.value));
+        self.min.push((bucket.epoch, bucket.min));
+        self.max.push((bucket.epoch, bucket.max));
+        self.sum.push((bucket.epoch, bucket.sum));
+        self.count.push((bucket.epoch, bucket.count as u64));
+    }
+
+    /// `buckets` Returns the filled buckets of the tier in flat vec format
+    pub fn buckets(&self) -> Vec<RollupBucket> {
+        self.buckets_in(0, u64::MAX).collect()
+    }
+
+    /// `buckets_in` Returns the filled buckets starting inside [from, to),
+    /// the circular buffers are searched so only the range is visited
+    fn buckets_in(&self, from: u64, to: u64) -> impl Iterator<Item = RollupBucket> + '_ {
+        self.value
+            .range(from, to)
+            .zip(self.min.range(from, to))
+            .zip(self.max.range(from, to))
+            .zip(self.sum.range(from, to))
+            .zip(self.count.range(from, to))
+            .filter_map(|((((value, min), max), sum), count)| {
+                match (value.1, min.1, max.1, sum.1, count.1) {
+                    (Some(v), Some(min), Some(max), Some(sum), Some(count)) => Some(RollupBucket {
+                        epoch: value.0,
+                        value: v,
+                        min,
+                        max,
+                        sum,
+                        count: count as usize,
+                    }),
+                    _ => None,
+                }
+            })
+    }
+
+    /// `as_vec` Returns the bucket values in flat vec format
+    pub fn as_vec(&self) -> Vec<(u64, Option<f64>)> {
+        self.value.as_vec()
+    }
+
+    /// `aggregate` folds the buckets starting inside [from, to) into one
+    fn aggregate(&self, from: u64, to: u64, policy: &ValueCollisionPolicy) -> Option<RollupBucket> {
+        let mut res: Option<RollupBucket> = None;
+        for bucket in self.buckets_in(from, to) {
+            match res {
+                Some(ref mut acc) => acc.merge(&bucket, policy),
+                None => {
+                    res = Some(RollupBucket {
+                        epoch: from,
+                        ..bucket
+                    })
+                }
+            }
+        }
+        res
+    }
+}
+
+/// `aggregate_series` folds the full resolution filled slots of a TimeSeries
+/// with epoch inside [from, to) into a bucket
+fn aggregate_series(
+    series: &TimeSeries,
+    from: u64,
+    to: u64,
+    policy: &ValueCollisionPolicy,
+) -> Option<RollupBucket> {
+    let mut res: Option<RollupBucket> = None;
+    for entry in series.range(from, to) {
+        if let Some(value) = entry.1 {
+            let bucket = RollupBucket {
+                epoch: from,
+                value,
+                min: value,
+                max: value,
+                sum: value,
+                count: 1,
+            };
+            match res {
+                Some(ref mut acc) => acc.merge(&bucket, policy),
+                None => res = Some(bucket),
+            }
+        }
+    }
+    res
+}
+
+/// `rollup_tiers` recalculates the bucket that contains `epoch` on every
+/// tier from `first_tier`. The bucket is recalculated from the lower tier
+/// instead of being incremented so that collisions in the lower tier, such
+/// as Overwrite, are reflected on the bucket.
+fn rollup_tiers(series: &TimeSeries, tiers: &mut [RollupTier], epoch: u64, first_tier: usize) {
+    for tier_idx in first_tier..tiers.len() {
+        let (lower, upper) = tiers.split_at_mut(tier_idx);
+        let tier = &mut upper[0];
+        let from = epoch - epoch % tier.resolution;
+        let to = from + tier.resolution;
+        let bucket = match lower.last() {
+            Some(lower_tier) => lower_tier.aggregate(from, to, &tier.collision_policy),
+            None => aggregate_series(series, from, to, &tier.collision_policy),
+        };
+        if let Some(bucket) = bucket {
+            debug!(
+                "rollup_tiers: Updating {}s tier bucket: {:?}",
+                tier.resolution, bucket
+            );
+            tier.store(&bucket);
+        }
+    }
+}
+
+/// `tier_for_range` returns the tier with the finest resolution able to
+/// hold `seconds` of data, None means the full resolution series covers the
+/// range. If no tier is big enough the coarsest tier is returned.
+fn tier_for_range<'a>(
+    series: &TimeSeries,
+    tiers: &'a [RollupTier],
+    seconds: u64,
+) -> Option<&'a RollupTier> {
+    if seconds <= series.metrics_capacity as u64 * series.granularity.max(1) {
+        return None;
+    }
+    for tier in tiers {
+        if seconds <= tier.resolution * tier.value.metrics_capacity as u64 {
+            return Some(tier);
+        }
+    }
+    tiers.last()
+}
+
+/// `RollupTierConfig` configures a tier of the series of a chart
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RollupTierConfig {
+    /// The number of seconds each bucket covers
+    pub resolution: u64,
+
+    /// The number of buckets to keep
+    pub capacity: usize,
+
+    /// How the values of the lower tier are folded into the bucket value
+    #[serde(default)]
+    pub collision_policy: ValueCollisionPolicy,
+}
+
+impl RollupTierConfig {
+    /// `build` returns an empty RollupTier with this configuration
+    pub fn build(&self) -> RollupTier {
+        RollupTier::new(self.resolution, self.capacity)
+            .with_collision_policy(self.collision_policy.clone())
+    }
+}
+
+/// `SeriesRollup` contains the tiers the series of a chart source is rolled
+/// up into. The sources are loaded in several ways (push, circular_push,
+/// Prometheus responses) so instead of feeding the tiers on each of them,
+/// `update` catches the tiers up with the series before it is drawn.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SeriesRollup {
+    /// The downsampled tiers, sorted by resolution
+    pub tiers: Vec<RollupTier>,
+
+    /// The last epoch of the series on the previous update
+    last_epoch: Option<u64>,
+}
+
+impl SeriesRollup {
+    /// `new` returns a SeriesRollup with empty tiers built from `config`
+    pub fn new(config: &[RollupTierConfig]) -> SeriesRollup {
+        let mut tiers: Vec<RollupTier> = config.iter().map(RollupTierConfig::build).collect();
+        tiers.sort_by_key(|tier| tier.resolution);
+        SeriesRollup {
+            tiers,
+            last_epoch: None,
+        }
+    }
+
+    /// `update` rolls up the items of `series` loaded since the previous
+    /// update. The bucket of the previous last epoch is recalculated as more
+    /// values may have been added to it. Items backfilled before it are not
+    /// rolled up. When the beginning of a bucket has already been evicted
+    /// from the series, the newer items are folded into the stored bucket.
+    pub fn update(&mut self, series: &TimeSeries) {
+        let (first_epoch, last_epoch) = match (series.into_iter().next(), series.get_last_epoch()) {
+            (Some(first), Some(last)) => (first.0, last),
+            _ => return,
+        };
+        let step = match self.tiers.first() {
+            Some(tier) => tier.resolution,
+            None => return,
+        };
+        // The series may have been invalidated and start over
+        let previous = self.last_epoch.filter(|epoch| *epoch <= last_epoch);
+        let start = previous.map_or(first_epoch, |epoch| epoch.max(first_epoch));
+        for tier in &mut self.tiers {
+            tier.value.missing_values_policy = series.missing_values_policy.clone();
+        }
+        let policy = self.tiers[0].collision_policy.clone();
+        let mut from = start - start % step;
+        while from <= last_epoch {
+            let to = from + step;
+            let bucket = match previous {
+                Some(previous) if from < first_epoch => {
+                    let newer = aggregate_series(series, (previous + 1).max(from), to, &policy);
+                    match (self.tiers[0].buckets_in(from, from + 1).next(), newer) {
+                        (Some(mut stored), Some(newer)) => {
+                            stored.merge(&newer, &policy);
+                            Some(stored)
+                        }
+                        (stored, newer) => stored.or(newer),
+                    }
+                }
+                _ => aggregate_series(series, from, to, &policy),
+            };
+            if let Some(bucket) = bucket {
+                self.tiers[0].store(&bucket);
+                rollup_tiers(series, &mut self.tiers, from, 1);
+            }
+            from = to;
+        }
+        self.last_epoch = Some(last_epoch);
+    }
+
+    /// `tier_for_range` returns the tier of `series` to draw `seconds` of
+    /// data, None when the full resolution series covers them
+    pub fn tier_for_range(&self, series: &TimeSeries, seconds: u64) -> Option<&RollupTier> {
+        tier_for_range(series, &self.tiers, seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `tiers` returns the tier configurations of `(resolution, capacity)`
+    fn tiers(config: &[(u64, usize)]) -> Vec<RollupTierConfig> {
+        config
+            .iter()
+            .map(|&(resolution, capacity)| RollupTierConfig {
+                resolution,
+                capacity,
+                collision_policy: ValueCollisionPolicy::default(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_rolls_up_into_tiers() {
+        let mut test = SeriesRollup::new(&tiers(&[(60, 5), (10, 6)]));
+        // Tiers are sorted by resolution
+        assert_eq!(test.tiers[0].resolution, 10);
+        assert_eq!(test.tiers[1].resolution, 60);
+        let mut series = TimeSeries::default().with_capacity(30);
+        series.push((100, 1f64));
+        series.push((101, 3f64));
+        series.push((105, 2f64));
+        series.push((110, 7f64));
+        test.update(&series);
+        assert_eq!(
+            test.tiers[0].buckets(),
+            vec![
+                RollupBucket {
+                    epoch: 100,
+                    value: 6f64,
+                    min: 1f64,
+                    max: 3f64,
+                    sum: 6f64,
+                    count: 3,
+                },
+                RollupBucket {
+                    epoch: 110,
+                    value: 7f64,
+                    min: 7f64,
+                    max: 7f64,
+                    sum: 7f64,
+                    count: 1,
+                },
+            ]
+        );
+        assert_eq!(test.tiers[0].buckets()[0].avg(), 2f64);
+        // The 1 minute tier is built from the 10 seconds tier
+        assert_eq!(
+            test.tiers[1].buckets(),
+            vec![RollupBucket {
+                epoch: 60,
+                value: 13f64,
+                min: 1f64,
+                max: 7f64,
+                sum: 13f64,
+                count: 4,
+            }]
+        );
+        series.push((125, 1f64));
+        test.update(&series);
+        assert_eq!(
+            test.tiers[0].as_vec(),
+            vec![(100, Some(6f64)), (110, Some(7f64)), (120, Some(1f64))]
+        );
+        assert_eq!(
+            test.tiers[1].as_vec(),
+            vec![(60, Some(13f64)), (120, Some(1f64))]
+        );
+    }
+
+    #[test]
+    fn it_applies_tier_collision_policy() {
+        let config: Vec<RollupTierConfig> = serde_yaml::from_str(
+            "
+            - resolution: 10
+              capacity: 6
+              collision_policy: Max
+            ",
+        )
+        .unwrap();
+        let mut test = SeriesRollup::new(&config);
+        let mut series = TimeSeries {
+            collision_policy: ValueCollisionPolicy::Overwrite,
+            ..TimeSeries::default()
+        };
+        series.push((10, 4f64));
+        series.push((11, 9f64));
+        series.push((12, 2f64));
+        test.update(&series);
+        assert_eq!(test.tiers[0].as_vec(), vec![(10, Some(9f64))]);
+        // Overwriting the last full resolution slot recalculates the bucket
+        series.push((12, 12f64));
+        test.update(&series);
+        assert_eq!(test.tiers[0].as_vec(), vec![(10, Some(12f64))]);
+        assert_eq!(test.tiers[0].buckets()[0].sum, 25f64);
+        assert_eq!(test.tiers[0].buckets()[0].count, 3);
+    }
+
+    #[test]
+    fn it_catches_up_series_rollups() {
+        let config: Vec<RollupTierConfig> = serde_yaml::from_str(
+            "
+            - resolution: 60
+              capacity: 10
+              collision_policy: Max
+            - resolution: 10
+              capacity: 6
+            ",
+        )
+        .unwrap();
+        let mut test = SeriesRollup::new(&config);
+        assert_eq!(test.tiers[0].resolution, 10);
+        let mut series = TimeSeries::default().with_capacity(30);
+        series.push((100, 1f64));
+        series.push((105, 2f64));
+        series.push((118, 4f64));
+        test.update(&series);
+        assert_eq!(
+            test.tiers[0].as_vec(),
+            vec![(100, Some(3f64)), (110, Some(4f64))]
+        );
+        assert_eq!(test.tiers[1].as_vec(), vec![(60, Some(4f64))]);
+        // The last bucket is completed by the next update
+        series.push((119, 5f64));
+        series.push((121, 1f64));
+        test.update(&series);
+        assert_eq!(
+            test.tiers[0].as_vec(),
+            vec![(100, Some(3f64)), (110, Some(9f64)), (120, Some(1f64))]
+        );
+        assert_eq!(
+            test.tiers[1].as_vec(),
+            vec![(60, Some(9f64)), (120, Some(1f64))]
+        );
+        // The tiers keep the buckets evicted from the series
+        for epoch in 122..160 {
+            series.push((epoch, 1f64));
+        }
+        test.update(&series);
+        assert_eq!(test.tiers[0].as_vec()[0], (100, Some(3f64)));
+        assert_eq!(test.tier_for_range(&series, 30), None);
+        assert_eq!(test.tier_for_range(&series, 60).unwrap().resolution, 10);
+        assert_eq!(test.tier_for_range(&series, 600).unwrap().resolution, 60);
+    }
+
+    #[test]
+    fn it_selects_tier_for_range() {
+        // 5 minutes at full resolution, 1 hour at 10s, 1 day at 1m and a
+        // week at 1h.
+        let series = TimeSeries::default();
+        let test = SeriesRollup::new(&tiers(&[(10, 360), (60, 1440), (3600, 168)]));
+        assert!(test.tier_for_range(&series, 300).is_none());
+        assert_eq!(test.tier_for_range(&series, 600).unwrap().resolution, 10);
+        assert_eq!(test.tier_for_range(&series, 86400).unwrap().resolution, 60);
+        assert_eq!(
+            test.tier_for_range(&series, 7 * 86400).unwrap().resolution,
+            3600
+        );
+        assert_eq!(
+            test.tier_for_range(&series, 30 * 86400).unwrap().resolution,
+            3600
+        );
+    }
+}
@@ -0,0 +1,597 @@
+//! Scrapes the `/metrics` endpoint of a target directly, the Prometheus text
+//! exposition format and OpenMetrics are parsed into metric families and the
+//! selected samples are loaded into a TimeSeries.
+use crate::prometheus::{HTTPClientConfig, RetryConfig};
+use log::*;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// `SCRAPE_ACCEPT_HEADER` prefers OpenMetrics and falls back to the text format
+pub const SCRAPE_ACCEPT_HEADER: &str =
+    "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5";
+
+/// `MetricType` is the TYPE of a metric family
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+    GaugeHistogram,
+    StateSet,
+    Info,
+    #[default]
+    Untyped,
+}
+
+impl MetricType {
+    /// `suffixes` returns the suffixes of the sample names of a family
+    fn suffixes(self) -> &'static [&'static str] {
+        match self {
+            MetricType::Counter => &["_total", "_created"],
+            MetricType::Histogram => &["_bucket", "_sum", "_count", "_created"],
+            MetricType::Summary => &["_sum", "_count", "_created"],
+            MetricType::GaugeHistogram => &["_bucket", "_gsum", "_gcount"],
+            MetricType::Info => &["_info"],
+            _ => &[],
+        }
+    }
+}
+
+impl FromStr for MetricType {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<MetricType, String> {
+        match input {
+            "counter" => Ok(MetricType::Counter),
+            "gauge" => Ok(MetricType::Gauge),
+            "histogram" => Ok(MetricType::Histogram),
+            "summary" => Ok(MetricType::Summary),
+            "gaugehistogram" => Ok(MetricType::GaugeHistogram),
+            "stateset" => Ok(MetricType::StateSet),
+            "info" => Ok(MetricType::Info),
+            "untyped" | "unknown" => Ok(MetricType::Untyped),
+            _ => Err(format!("Unknown metric type: {}", input)),
+        }
+    }
+}
+
+/// `Sample` is a line of the exposition, the timestamp is in seconds
+#[derive(Debug, PartialEq, Clone)]
+pub struct Sample {
+    pub name: String,
+    pub labels: HashMap<String, String>,
+    pub value: f64,
+    pub timestamp: Option<u64>,
+}
+
+/// `MetricFamily` contains the samples of a metric, i.e. the `_bucket`,
+/// `_sum` and `_count` samples of a histogram
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct MetricFamily {
+    pub name: String,
+    pub help: String,
+    pub unit: String,
+    pub metric_type: MetricType,
+    pub samples: Vec<Sample>,
+}
+
+/// `family_index` returns the family of a sample name, the families declared
+/// with a TYPE own the sample names with the suffixes of their type
+fn family_index(families: &[MetricFamily], sample_name: &str) -> Option<usize> {
+    families.iter().rposition(|family| {
+        sample_name == family.name
+            || family.metric_type.suffixes().iter().any(|suffix| {
+                sample_name.len() == family.name.len() + suffix.len()
+                    && sample_name.starts_with(family.name.as_str())
+                    && sample_name.ends_with(suffix)
+            })
+    })
+}
+
+/// `declared_family` returns the family `name` from a HELP, TYPE or UNIT
+/// line, creating it the first time it is seen
+fn declared_family<'a>(families: &'a mut Vec<MetricFamily>, name: &str) -> &'a mut MetricFamily {
+    match families.iter().position(|family| family.name == name) {
+        Some(idx) => &mut families[idx],
+        None => {
+            families.push(MetricFamily {
+                name: name.to_string(),
+                ..MetricFamily::default()
+            });
+            families.last_mut().unwrap()
+        }
+    }
+}
+
+/// `unescape` replaces the escape sequences of HELP and label values
+fn unescape(input: &str) -> String {
+    let mut res = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => res.push('\n'),
+                Some(other) => res.push(other),
+                None => res.push('\\'),
+            }
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+/// `parse_value` parses a sample value, including +Inf, -Inf and NaN
+fn parse_value(input: &str) -> Result<f64, String> {
+    match input {
+        "+Inf" | "Inf" => Ok(f64::INFINITY),
+        "-Inf" => Ok(f64::NEG_INFINITY),
+        "NaN" => Ok(f64::NAN),
+        _ => input
+            .parse()
+            .map_err(|err| format!("Invalid value '{}': {}", input, err)),
+    }
+}
+
+/// `parse_labels` parses `{name="value",...}` at the start of `input` and
+/// returns the labels and the rest of the line
+fn parse_labels(input: &str) -> Result<(HashMap<String, String>, &str), String> {
+    let mut labels = HashMap::new();
+    let mut rest = input[1..].trim_start();
+    loop {
+        if let Some(after) = rest.strip_prefix('}') {
+            return Ok((labels, after));
+        }
+        let name_end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+        rest = match rest.strip_prefix('=') {
+            Some(after) => after.trim_start(),
+            None => return Err(format!("Expected = after label '{}'", name)),
+        };
+        rest = match rest.strip_prefix('"') {
+            Some(after) => after,
+            None => return Err(format!("Expected quoted value for label '{}'", name)),
+        };
+        let mut value_end = None;
+        let mut escaped = false;
+        for (idx, c) in rest.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => {
+                    value_end = Some(idx);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let value_end = value_end.ok_or_else(|| format!("Unterminated label '{}'", name))?;
+        labels.insert(name.to_string(), unescape(&rest[..value_end]));
+        rest = rest[value_end + 1..].trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after.trim_start();
+        } else if !rest.starts_with('}') {
+            return Err(format!("Expected , or }} after label '{}'", name));
+        }
+    }
+}
+
+/// `parse_sample` parses a sample line, the timestamp is in milliseconds in
+/// the text format and in seconds in OpenMetrics
+fn parse_sample(line: &str, openmetrics: bool) -> Result<Sample, String> {
+    let name_end = line
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == ':'))
+        .unwrap_or(line.len());
+    if name_end == 0 {
+        return Err(String::from("Missing metric name"));
+    }
+    let name = line[..name_end].to_string();
+    let mut rest = line[name_end..].trim_start();
+    let mut labels = HashMap::new();
+    if rest.starts_with('{') {
+        let (parsed_labels, after) = parse_labels(rest)?;
+        labels = parsed_labels;
+        rest = after;
+    }
+    // Exemplars come after a #
+    let rest = rest.split(" # ").next().unwrap_or_default();
+    let mut fields = rest.split_whitespace();
+    let value = parse_value(fields.next().ok_or("Missing value")?)?;
+    let timestamp = match fields.next() {
+        Some(timestamp) => {
+            let timestamp: f64 = timestamp
+                .parse()
+                .map_err(|err| format!("Invalid timestamp '{}': {}", timestamp, err))?;
+            Some(if openmetrics {
+                timestamp as u64
+            } else {
+                (timestamp / 1000.) as u64
+            })
+        }
+        None => None,
+    };
+    Ok(Sample {
+        name,
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+/// `parse_exposition` parses the Prometheus text exposition format or
+/// OpenMetrics, which is recognized by its `# EOF` line. The samples without
+/// a TYPE are grouped in untyped families.
+pub fn parse_exposition(text: &str) -> Result<Vec<MetricFamily>, String> {
+    let openmetrics = text.lines().any(|line| line.trim() == "# EOF");
+    let mut families: Vec<MetricFamily> = vec![];
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut tokens = comment.trim_start().splitn(3, ' ');
+            let keyword = tokens.next().unwrap_or_default();
+            let name = tokens.next().unwrap_or_default();
+            let text = tokens.next().unwrap_or_default().trim();
+            match keyword {
+                "HELP" => declared_family(&mut families, name).help = unescape(text),
+                "UNIT" => declared_family(&mut families, name).unit = text.to_string(),
+                "TYPE" => {
+                    declared_family(&mut families, name).metric_type = text
+                        .parse()
+                        .map_err(|err| format!("Line {}: {}", line_idx + 1, err))?
+                }
+                "EOF" => break,
+                _ => {}
+            }
+            continue;
+        }
+        let sample = parse_sample(line, openmetrics)
+            .map_err(|err| format!("Line {}: {}", line_idx + 1, err))?;
+        match family_index(&families, &sample.name) {
+            Some(idx) => families[idx].samples.push(sample),
+            None => families.push(MetricFamily {
+                name: sample.name.clone(),
+                samples: vec![sample],
+                ..MetricFamily::default()
+            }),
+        }
+    }
+    Ok(families)
+}
+
+/// `ScrapeTimeSeries` charts a sample of a target's `/metrics` endpoint,
+/// i.e. a local exporter with no Prometheus in between
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScrapeTimeSeries {
+    /// The Name of this TimesSeries
+    #[serde(default)]
+    pub name: String,
+
+    /// The TimeSeries metrics storage
+    #[serde(default = "crate::prometheus::default_series")]
+    #[serde(deserialize_with = "crate::prometheus::deserialize_series")]
+    pub series: crate::TimeSeries,
+
+    /// The URL of the metrics endpoint, i.e. http://localhost:9100/metrics
+    #[serde(default)]
+    pub source: String,
+
+    /// The sample name to chart, i.e. node_load1, http_requests_total or
+    /// http_request_duration_seconds_bucket
+    #[serde(default)]
+    pub metric: String,
+
+    /// The labels the samples must have, i.e. `le: "0.5"` or `quantile: "0.99"`,
+    /// the values of all the matching samples are added
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+
+    /// Charts the per second increase of counters instead of their value
+    #[serde(default)]
+    pub rate: bool,
+
+    /// The time in seconds between scrapes
+    #[serde(default)]
+    #[serde(rename = "refresh")]
+    pub pull_interval: usize,
+
+    /// The color of the TimeSeries
+    #[serde(default)]
+    pub color: Option<crate::color::Color>,
+
+    /// The transparency of the TimeSeries
+    #[serde(default)]
+    pub alpha: f32,
+
+    /// The authentication and TLS settings
+    #[serde(default)]
+    pub http: HTTPClientConfig,
+
+    /// The timeout and retry settings
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// The outcome of the latest scrapes
+    #[serde(skip)]
+    pub health: crate::SourceHealth,
+
+    /// The epoch and value of the previous scrape, to calculate the rate
+    #[serde(skip)]
+    pub last_sample: Option<(u64, f64)>,
+}
+
+impl Default for ScrapeTimeSeries {
+    fn default() -> ScrapeTimeSeries {
+        ScrapeTimeSeries {
+            name: String::from("Unset"),
+            series: crate::prometheus::default_series(),
+            source: String::from(""),
+            metric: String::from(""),
+            labels: HashMap::new(),
+            rate: false,
+            pull_interval: 15,
+            color: None,
+            alpha: 1.0,
+            http: HTTPClientConfig::default(),
+            retry: RetryConfig::default(),
+            health: crate::SourceHealth::default(),
+            last_sample: None,
+        }
+    }
+}
+
+impl PartialEq<ScrapeTimeSeries> for ScrapeTimeSeries {
+    fn eq(&self, other: &ScrapeTimeSeries) -> bool {
+        self.series == other.series
+            && self.source == other.source
+            && self.metric == other.metric
+            && self.labels == other.labels
+            && self.pull_interval == other.pull_interval
+    }
+}
+
+impl ScrapeTimeSeries {
+    /// `url` parses the source, only HTTP and HTTPS are supported
+    pub fn url(&self) -> Result<hyper::Uri, String> {
+        let url: hyper::Uri = self
+            .source
+            .parse()
+            .map_err(|err| format!("Unable to parse URL {}: {}", self.source, err))?;
+        if url.scheme_part() == Some(&hyper::http::uri::Scheme::HTTP)
+            || url.scheme_part() == Some(&hyper::http::uri::Scheme::HTTPS)
+        {
+            Ok(url)
+        } else {
+            Err(format!("Unsupported protocol: {:?}", url.scheme_part()))
+        }
+    }
+
+    /// `load_families` adds the samples matching `metric` and `labels` scraped
+    /// at `epoch`, the timestamp of the samples is used when exposed. Returns
+    /// the number of items loaded, the first scrape of a rate loads none.
+    pub fn load_families(
+        &mut self,
+        epoch: u64,
+        families: &[MetricFamily],
+    ) -> Result<usize, String> {
+        let matching: Vec<&Sample> = families
+            .iter()
+            .flat_map(|family| family.samples.iter())
+            .filter(|sample| {
+                sample.name == self.metric
+                    && self
+                        .labels
+                        .iter()
+                        .all(|(label, value)| sample.labels.get(label) == Some(value))
+            })
+            .collect();
+        if matching.is_empty() {
+            return Err(format!(
+                "No samples of {} with labels {:?}",
+                self.metric, self.labels
+            ));
+        }
+        let value: f64 = matching.iter().map(|sample| sample.value).sum();
+        let epoch = matching
+            .iter()
+            .filter_map(|sample| sample.timestamp)
+            .max()
+            .unwrap_or(epoch);
+        debug!(
+            "ScrapeTimeSeries '{}': {} samples at {} add up to {}",
+            self.name,
+            matching.len(),
+            epoch,
+            value
+        );
+        if !self.rate {
+            self.series.push((epoch, value));
+            return Ok(1);
+        }
+        let previous = self.last_sample.replace((epoch, value));
+        match previous {
+            Some((last_epoch, last_value)) if epoch > last_epoch => {
+                // A counter that goes down has been reset
+                let increase = if value >= last_value {
+                    value - last_value
+                } else {
+                    value
+                };
+                self.series
+                    .push((epoch, increase / (epoch - last_epoch) as f64));
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_text_exposition() {
+        let families = parse_exposition(
+            r#"
+# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="400"}    3 1395066363000
+
+# A comment
+msdos_file_access_time_seconds{path="C:\\DIR\\FILE.TXT",error="Cannot find file:\n\"FILE.TXT\""} 1.458255915e9
+metric_without_timestamp_and_labels 12.47
+
+# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 24054
+http_request_duration_seconds_bucket{le="+Inf"} 144320
+http_request_duration_seconds_sum 53423
+http_request_duration_seconds_count 144320
+# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.99"} NaN
+rpc_duration_seconds_sum 1.7560473e+07
+rpc_duration_seconds_count 2693
+"#,
+        )
+        .unwrap();
+        let names: Vec<&str> = families.iter().map(|family| family.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "http_requests_total",
+                "msdos_file_access_time_seconds",
+                "metric_without_timestamp_and_labels",
+                "http_request_duration_seconds",
+                "rpc_duration_seconds"
+            ]
+        );
+        let requests = &families[0];
+        assert_eq!(requests.metric_type, MetricType::Counter);
+        assert_eq!(requests.help, "The total number of HTTP requests.");
+        assert_eq!(requests.samples.len(), 2);
+        assert_eq!(requests.samples[1].value, 3.);
+        assert_eq!(requests.samples[1].labels["code"], "400");
+        assert_eq!(requests.samples[1].timestamp, Some(1_395_066_363));
+        let msdos = &families[1].samples[0];
+        assert_eq!(msdos.labels["path"], "C:\\DIR\\FILE.TXT");
+        assert_eq!(msdos.labels["error"], "Cannot find file:\n\"FILE.TXT\"");
+        assert_eq!(msdos.value, 1.458255915e9);
+        assert_eq!(families[2].metric_type, MetricType::Untyped);
+        assert_eq!(families[2].samples[0].timestamp, None);
+        let histogram = &families[3];
+        assert_eq!(histogram.metric_type, MetricType::Histogram);
+        assert_eq!(histogram.samples.len(), 4);
+        assert_eq!(histogram.samples[1].labels["le"], "+Inf");
+        let summary = &families[4];
+        assert_eq!(summary.metric_type, MetricType::Summary);
+        assert!(summary.samples[0].value.is_nan());
+        assert_eq!(summary.samples[2].name, "rpc_duration_seconds_count");
+    }
+
+    #[test]
+    fn it_parses_openmetrics() {
+        let families = parse_exposition(
+            r#"# TYPE acme_http_router_request_seconds summary
+# UNIT acme_http_router_request_seconds seconds
+# HELP acme_http_router_request_seconds Latency though all of ACME's HTTP request router.
+acme_http_router_request_seconds_sum{path="/api/v1",method="GET"} 9036.32 1520430000.123
+acme_http_router_request_seconds_count{path="/api/v1",method="GET"} 807283.0 1520430000.123
+acme_http_router_request_seconds_created{path="/api/v1",method="GET"} 1605281325.0
+# TYPE foo histogram
+foo_bucket{le="1.0"} 0 # {trace_id="KOO5S4vxi0o"} 0.67
+foo_bucket{le="+Inf"} 17
+# TYPE build info
+build_info{version="1.2.3"} 1
+# EOF
+ignored_after_eof 1
+"#,
+        )
+        .unwrap();
+        assert_eq!(families.len(), 3);
+        let summary = &families[0];
+        assert_eq!(summary.unit, "seconds");
+        assert_eq!(summary.samples.len(), 3);
+        assert_eq!(summary.samples[0].timestamp, Some(1_520_430_000));
+        assert_eq!(families[1].samples[0].value, 0.);
+        assert_eq!(families[1].samples[0].labels.len(), 1);
+        assert_eq!(families[2].metric_type, MetricType::Info);
+        assert_eq!(families[2].samples[0].labels["version"], "1.2.3");
+    }
+
+    #[test]
+    fn it_reports_invalid_exposition() {
+        assert_eq!(
+            parse_exposition("up 1\nup{job=\"x} 1\n"),
+            Err(String::from("Line 2: Unterminated label 'job'"))
+        );
+        assert_eq!(
+            parse_exposition("up one"),
+            Err(String::from(
+                "Line 1: Invalid value 'one': invalid float literal"
+            ))
+        );
+        assert!(parse_exposition("# TYPE up thing\nup 1").is_err());
+        assert!(parse_exposition("{job=\"x\"} 1").is_err());
+        assert!(parse_exposition("up").is_err());
+    }
+
+    #[test]
+    fn it_loads_scraped_samples() {
+        let scrape = |requests: f64| -> Vec<MetricFamily> {
+            parse_exposition(&format!(
+                "# TYPE http_requests_total counter\n\
+                 http_requests_total{{code=\"200\"}} {}\n\
+                 http_requests_total{{code=\"500\"}} 10\n",
+                requests
+            ))
+            .unwrap()
+        };
+        let mut test: ScrapeTimeSeries = serde_yaml::from_str(
+            "
+            name: requests
+            source: 'http://localhost:9100/metrics'
+            metric: http_requests_total
+            ",
+        )
+        .unwrap();
+        assert!(test.url().is_ok());
+        // All the label sets are added
+        assert_eq!(test.load_families(100, &scrape(90.)), Ok(1));
+        assert_eq!(test.series.as_vec(), vec![(100, Some(100f64))]);
+        test.labels
+            .insert(String::from("code"), String::from("404"));
+        assert!(test.load_families(101, &scrape(90.)).is_err());
+        // The rate of a counter
+        let mut test = ScrapeTimeSeries {
+            metric: String::from("http_requests_total"),
+            rate: true,
+            ..ScrapeTimeSeries::default()
+        };
+        test.labels
+            .insert(String::from("code"), String::from("200"));
+        assert_eq!(test.load_families(100, &scrape(90.)), Ok(0));
+        assert_eq!(test.load_families(110, &scrape(140.)), Ok(1));
+        // The counter was reset
+        assert_eq!(test.load_families(120, &scrape(30.)), Ok(1));
+        let loaded: Vec<(u64, f64)> = test
+            .series
+            .iter()
+            .filter_map(|(epoch, value)| value.map(|value| (*epoch, value)))
+            .collect();
+        assert_eq!(loaded, vec![(110, 5.), (120, 3.)]);
+        let test = ScrapeTimeSeries {
+            source: String::from("ftp://localhost/metrics"),
+            ..ScrapeTimeSeries::default()
+        };
+        assert!(test.url().is_err());
+    }
+}
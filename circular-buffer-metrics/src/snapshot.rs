@@ -0,0 +1,343 @@
+//! Persists the TimeSeriesChart buffers to disk so that they can be restored
+//! when the process restarts.
+//! The file is made of a header and a bincode payload:
+//! | magic (4 bytes) | version (u16) | crc32 of payload (u32) | payload len (u64) |
+//! All the numbers are little endian. Only the filled slots are stored.
+use crate::TimeSeriesChart;
+use log::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The magic bytes at the start of a snapshot file
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"CBMS";
+
+/// The version of the snapshot format, files of other versions are rejected
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// The size of the header preceding the payload
+const HEADER_LEN: usize = 4 + 2 + 4 + 8;
+
+/// `SnapshotConfig` contains the snapshot settings in charts.yml, the
+/// settings left out take the Default values
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    /// The file to write the snapshot to and to restore it from
+    pub path: PathBuf,
+
+    /// The time in seconds between writes of the snapshot
+    pub interval: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> SnapshotConfig {
+        SnapshotConfig {
+            path: PathBuf::from("charts.snapshot"),
+            interval: 60,
+        }
+    }
+}
+
+/// `SeriesSnapshot` contains the filled slots of a TimeSeries
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SeriesSnapshot {
+    pub name: String,
+    pub metrics: Vec<(u64, f64)>,
+}
+
+/// `ChartSnapshot` contains the series of a TimeSeriesChart
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct ChartSnapshot {
+    pub name: String,
+    pub series: Vec<SeriesSnapshot>,
+}
+
+/// `Snapshot` contains the data of all the charts at a given time.
+/// Charts and series are matched by name on restore so that the
+/// configuration can be reordered between restarts.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct Snapshot {
+    /// The epoch at which the snapshot was taken
+    pub epoch: u64,
+    pub charts: Vec<ChartSnapshot>,
+}
+
+impl Snapshot {
+    /// `from_charts` takes a snapshot of the charts buffers
+    pub fn from_charts(charts: &[TimeSeriesChart]) -> Snapshot {
+        let epoch = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Snapshot {
+            epoch,
+            charts: charts
+                .iter()
+                .map(|chart| ChartSnapshot {
+                    name: chart.name.clone(),
+                    series: chart
+                        .sources
+                        .iter()
+                        .map(|source| SeriesSnapshot {
+                            name: source.name(),
+                            metrics: source
+                                .series()
+                                .iter()
+                                .filter_map(|entry| entry.1.map(|value| (entry.0, value)))
+                                .collect(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// `restore` pushes the snapshot data into the charts with matching
+    /// names. Slots older than the time range of the series relative to
+    /// `now` are trimmed. Returns the number of restored slots.
+    pub fn restore(&self, charts: &mut [TimeSeriesChart], now: u64) -> usize {
+        let mut restored_items = 0usize;
+        for chart_snapshot in &self.charts {
+            let chart = match charts.iter_mut().find(|c| c.name == chart_snapshot.name) {
+                Some(chart) => chart,
+                None => {
+                    debug!(
+                        "Snapshot: chart '{}' no longer configured",
+                        chart_snapshot.name
+                    );
+                    continue;
+                }
+            };
+            for series_snapshot in &chart_snapshot.series {
+                let source = match chart
+                    .sources
+                    .iter_mut()
+                    .find(|s| s.name() == series_snapshot.name)
+                {
+                    Some(source) => source,
+                    None => continue,
+                };
+                let series = source.series_mut();
+                let range = series.metrics_capacity as u64 * series.granularity.max(1);
+                for &(epoch, value) in &series_snapshot.metrics {
+                    if epoch + range > now {
+                        series.push((epoch, value));
+                        restored_items += 1;
+                    }
+                }
+            }
+        }
+        info!("Snapshot: restored {} items", restored_items);
+        restored_items
+    }
+
+    /// `to_bytes` serializes the snapshot with its header
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let payload = bincode::serialize(self)
+            .map_err(|err| format!("Unable to serialize snapshot: {}", err))?;
+        let mut res = Vec::with_capacity(HEADER_LEN + payload.len());
+        res.extend_from_slice(SNAPSHOT_MAGIC);
+        res.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        res.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        res.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        res.extend_from_slice(&payload);
+        Ok(res)
+    }
+
+    /// `from_bytes` validates the header and checksum and deserializes the
+    /// snapshot
+    pub fn from_bytes(input: &[u8]) -> Result<Snapshot, String> {
+        if input.len() < HEADER_LEN || &input[0..4] != SNAPSHOT_MAGIC {
+            return Err(String::from("Not a snapshot file"));
+        }
+        let mut version = [0u8; 2];
+        version.copy_from_slice(&input[4..6]);
+        let version = u16::from_le_bytes(version);
+        if version != SNAPSHOT_VERSION {
+            return Err(format!("Unsupported snapshot version: {}", version));
+        }
+        let mut checksum = [0u8; 4];
+        checksum.copy_from_slice(&input[6..10]);
+        let checksum = u32::from_le_bytes(checksum);
+        let mut payload_len = [0u8; 8];
+        payload_len.copy_from_slice(&input[10..18]);
+        let payload_len = u64::from_le_bytes(payload_len) as usize;
+        let payload = &input[HEADER_LEN..];
+        if payload.len() != payload_len {
+            return Err(format!(
+                "Truncated snapshot, expected {} bytes, found {}",
+                payload_len,
+                payload.len()
+            ));
+        }
+        if crc32fast::hash(payload) != checksum {
+            return Err(String::from("Snapshot checksum mismatch"));
+        }
+        bincode::deserialize(payload).map_err(|err| format!("Unable to parse snapshot: {}", err))
+    }
+
+    /// `write_to_file` writes the snapshot to a temporary file that is then
+    /// renamed, so that a crash while writing does not corrupt the previous
+    /// snapshot. The temporary file has a unique name next to `path` and is
+    /// never opened if it exists, so a planted symlink is not followed.
+    pub fn write_to_file(&self, path: &Path) -> Result<(), String> {
+        let bytes = self.to_bytes()?;
+        let nanos = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.subsec_nanos())
+            .unwrap_or_default();
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(format!(".{}.{}.tmp", std::process::id(), nanos));
+        let tmp_path = path.with_file_name(tmp_name);
+        let res = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .and_then(|mut file| {
+                let written = file.write_all(&bytes).and_then(|_| file.sync_all());
+                if written.is_err() {
+                    let _ = fs::remove_file(&tmp_path);
+                }
+                written
+            })
+            .and_then(|_| fs::rename(&tmp_path, path));
+        res.map_err(|err| format!("Unable to write snapshot to {:?}: {}", path, err))
+    }
+
+    /// `read_from_file` loads a snapshot from disk
+    pub fn read_from_file(path: &Path) -> Result<Snapshot, String> {
+        let mut bytes = vec![];
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(|err| format!("Unable to read snapshot from {:?}: {}", path, err))?;
+        Snapshot::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualTimeSeries, TimeSeries, TimeSeriesSource};
+
+    fn chart_with_series(name: &str, series: TimeSeries) -> TimeSeriesChart {
+        TimeSeriesChart {
+            name: String::from(name),
+            sources: vec![TimeSeriesSource::AlacrittyInput(ManualTimeSeries {
+                name: String::from("input"),
+                series,
+                ..ManualTimeSeries::default()
+            })],
+            ..TimeSeriesChart::default()
+        }
+    }
+
+    #[test]
+    fn it_defaults_snapshot_config() {
+        let config: SnapshotConfig = serde_yaml::from_str("path: /tmp/charts.snapshot").unwrap();
+        assert_eq!(config.path, PathBuf::from("/tmp/charts.snapshot"));
+        assert_eq!(config.interval, 60);
+        let config: SnapshotConfig = serde_yaml::from_str("interval: 5").unwrap();
+        assert_eq!(config.path, PathBuf::from("charts.snapshot"));
+        assert_eq!(config.interval, 5);
+    }
+
+    #[test]
+    fn it_roundtrips_snapshot_bytes() {
+        let mut series = TimeSeries::default().with_capacity(10);
+        series.push((100, 1f64));
+        series.push((103, 4f64));
+        let snapshot = Snapshot::from_charts(&[chart_with_series("keys", series)]);
+        assert_eq!(
+            snapshot.charts[0].series[0].metrics,
+            vec![(100, 1f64), (103, 4f64)]
+        );
+        let bytes = snapshot.to_bytes().unwrap();
+        assert_eq!(&bytes[0..4], SNAPSHOT_MAGIC);
+        assert_eq!(Snapshot::from_bytes(&bytes), Ok(snapshot.clone()));
+        // Corrupted payload
+        let mut corrupted = bytes.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        assert_eq!(
+            Snapshot::from_bytes(&corrupted),
+            Err(String::from("Snapshot checksum mismatch"))
+        );
+        // Different version
+        let mut other_version = bytes.clone();
+        other_version[4] = 9;
+        assert_eq!(
+            Snapshot::from_bytes(&other_version),
+            Err(String::from("Unsupported snapshot version: 9"))
+        );
+        // Truncated file
+        assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Snapshot::from_bytes(b"CBM").is_err());
+    }
+
+    #[test]
+    fn it_restores_and_trims_snapshot() {
+        let mut series = TimeSeries::default().with_capacity(10);
+        series.push((100, 1f64));
+        series.push((105, 5f64));
+        series.push((108, 8f64));
+        let snapshot = Snapshot::from_charts(&[chart_with_series("keys", series)]);
+        let mut charts = vec![
+            chart_with_series("other", TimeSeries::default().with_capacity(10)),
+            chart_with_series("keys", TimeSeries::default().with_capacity(10)),
+        ];
+        // At epoch 112 only the slots after 102 fit in the capacity
+        assert_eq!(snapshot.restore(&mut charts, 112), 2);
+        assert_eq!(
+            charts[1].sources[0].series().as_vec(),
+            vec![
+                (105, Some(5f64)),
+                (106, None),
+                (107, None),
+                (108, Some(8f64))
+            ]
+        );
+        assert_eq!(charts[1].sources[0].series().stats.max, 8f64);
+        assert!(charts[0].sources[0].series().as_vec().is_empty());
+    }
+
+    #[test]
+    fn it_writes_and_reads_snapshot_file() {
+        let path = std::env::temp_dir().join(format!(
+            "circular-buffer-metrics-test-{}.snapshot",
+            std::process::id()
+        ));
+        let mut series = TimeSeries::default();
+        series.push((100, 1f64));
+        let snapshot = Snapshot::from_charts(&[chart_with_series("keys", series)]);
+        snapshot.write_to_file(&path).unwrap();
+        assert_eq!(Snapshot::read_from_file(&path), Ok(snapshot));
+        fs::remove_file(&path).unwrap();
+        assert!(Snapshot::read_from_file(&path).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_does_not_follow_planted_symlinks() {
+        let dir = std::env::temp_dir().join(format!(
+            "circular-buffer-metrics-symlink-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let victim = dir.join("victim");
+        fs::write(&victim, "untouched").unwrap();
+        let path = dir.join("charts.snapshot");
+        std::os::unix::fs::symlink(&victim, path.with_extension("tmp")).unwrap();
+        let mut series = TimeSeries::default();
+        series.push((100, 1f64));
+        let snapshot = Snapshot::from_charts(&[chart_with_series("keys", series)]);
+        snapshot.write_to_file(&path).unwrap();
+        snapshot.write_to_file(&path).unwrap();
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "untouched");
+        assert_eq!(Snapshot::read_from_file(&path), Ok(snapshot));
+        // Only the snapshot, the victim and the symlink are left
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
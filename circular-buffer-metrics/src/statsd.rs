@@ -0,0 +1,363 @@
+//! Receives StatsD lines on a UDP socket, so that other processes can push
+//! values into the series the same way they are pushed in-process into a
+//! ManualTimeSeries.
+//! The supported line format is `name:value|type|@sample_rate|#tag:value`,
+//! the tags can also be appended to the name InfluxDB style, i.e.
+//! `name,tag=value:1|c`.
+use crate::ValueCollisionPolicy;
+use log::*;
+use std::collections::HashMap;
+
+/// `STATSD_DEFAULT_LISTEN` is the address used when `listen` is not set
+pub const STATSD_DEFAULT_LISTEN: &str = "127.0.0.1:8125";
+
+/// `StatsdMetricType` is the type of a StatsD line
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StatsdMetricType {
+    /// `c`, the values are added
+    Counter,
+    /// `g`, the last value is kept, values with a sign change the gauge
+    Gauge,
+    /// `ms`, `h` or `d`, the last value is kept
+    Timer,
+}
+
+/// `StatsdLine` is a metric received from a StatsD client
+#[derive(Debug, PartialEq, Clone)]
+pub struct StatsdLine {
+    pub name: String,
+    pub value: f64,
+    pub metric_type: StatsdMetricType,
+    pub sample_rate: f64,
+    pub tags: HashMap<String, String>,
+    /// A gauge value prefixed with + or -, it is added to the current value
+    pub is_delta: bool,
+}
+
+/// `parse_tags` parses comma separated tags, `separator` is `:` for
+/// DogStatsD tags and `=` for InfluxDB tags. A tag without value is kept
+/// with an empty value.
+fn parse_tags(input: &str, separator: char, tags: &mut HashMap<String, String>) {
+    for tag in input.split(',').filter(|tag| !tag.is_empty()) {
+        let mut key_value = tag.splitn(2, separator);
+        tags.insert(
+            key_value.next().unwrap_or_default().to_string(),
+            key_value.next().unwrap_or_default().to_string(),
+        );
+    }
+}
+
+/// `parse_line` parses a StatsD line
+pub fn parse_line(line: &str) -> Result<StatsdLine, String> {
+    let mut fields = line.trim().split('|');
+    let name_value = fields.next().unwrap_or_default();
+    let separator = name_value
+        .rfind(':')
+        .ok_or_else(|| format!("Missing value in '{}'", line))?;
+    let (name, value) = (&name_value[..separator], &name_value[separator + 1..]);
+    let mut tags = HashMap::new();
+    let name = match name.find(',') {
+        Some(idx) => {
+            parse_tags(&name[idx + 1..], '=', &mut tags);
+            &name[..idx]
+        }
+        None => name,
+    };
+    if name.is_empty() {
+        return Err(format!("Missing name in '{}'", line));
+    }
+    let metric_type = match fields.next() {
+        Some("c") => StatsdMetricType::Counter,
+        Some("g") => StatsdMetricType::Gauge,
+        Some("ms") | Some("h") | Some("d") => StatsdMetricType::Timer,
+        Some(other) => return Err(format!("Unsupported type '{}' in '{}'", other, line)),
+        None => return Err(format!("Missing type in '{}'", line)),
+    };
+    let is_delta = metric_type == StatsdMetricType::Gauge
+        && (value.starts_with('+') || value.starts_with('-'));
+    let value: f64 = value
+        .parse()
+        .map_err(|err| format!("Invalid value in '{}': {}", line, err))?;
+    let mut sample_rate = 1.;
+    for field in fields {
+        if let Some(rate) = field.strip_prefix('@') {
+            sample_rate = rate
+                .parse()
+                .map_err(|err| format!("Invalid sample rate in '{}': {}", line, err))?;
+            if sample_rate <= 0. || sample_rate > 1. {
+                return Err(format!("Sample rate out of (0, 1] in '{}'", line));
+            }
+        } else if let Some(tag_list) = field.strip_prefix('#') {
+            parse_tags(tag_list, ':', &mut tags);
+        }
+    }
+    Ok(StatsdLine {
+        name: name.to_string(),
+        value,
+        metric_type,
+        sample_rate,
+        tags,
+        is_delta,
+    })
+}
+
+/// `parse_packet` parses the lines of a datagram, the invalid lines are
+/// logged and skipped
+pub fn parse_packet(packet: &str) -> Vec<StatsdLine> {
+    packet
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match parse_line(line) {
+            Ok(line) => Some(line),
+            Err(err) => {
+                warn!("parse_packet: {}", err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// `StatsdTimeSeries` is a series filled by the StatsD lines received on a
+/// UDP socket, several series can share the same socket
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(from = "StatsdTimeSeriesConfig")]
+pub struct StatsdTimeSeries {
+    /// The name of the series
+    pub name: String,
+
+    /// The TimeSeries that contains the data, the collision policy is set by
+    /// the type of each line unless it is set in charts.yml
+    pub series: crate::TimeSeries,
+
+    /// Whether the collision policy of the series was set in charts.yml
+    #[serde(skip)]
+    pub custom_collision_policy: bool,
+
+    /// The UDP address to listen on, defaults to STATSD_DEFAULT_LISTEN
+    pub listen: String,
+
+    /// The name of the StatsD metric to load
+    pub metric: String,
+
+    /// The tags the lines must have to be loaded
+    pub tags: HashMap<String, String>,
+
+    /// The color of the TimeSeries
+    pub color: Option<crate::color::Color>,
+
+    /// The transparency of the TimeSeries
+    pub alpha: f32,
+}
+
+impl Default for StatsdTimeSeries {
+    fn default() -> StatsdTimeSeries {
+        StatsdTimeSeries {
+            name: String::from("Unset"),
+            series: crate::TimeSeries::default(),
+            custom_collision_policy: false,
+            listen: String::from(""),
+            metric: String::from(""),
+            tags: HashMap::new(),
+            color: None,
+            alpha: 1.0,
+        }
+    }
+}
+
+/// `StatsdTimeSeriesConfig` reads a StatsdTimeSeries from charts.yml, keeping
+/// track of whether the series sets its own collision policy
+#[derive(Deserialize)]
+struct StatsdTimeSeriesConfig {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    series: crate::SeriesConfig,
+    #[serde(default)]
+    listen: String,
+    #[serde(default)]
+    metric: String,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    #[serde(default)]
+    color: Option<crate::color::Color>,
+    #[serde(default)]
+    alpha: f32,
+}
+
+impl From<StatsdTimeSeriesConfig> for StatsdTimeSeries {
+    fn from(config: StatsdTimeSeriesConfig) -> StatsdTimeSeries {
+        let custom_collision_policy = config.series.collision_policy.is_some();
+        let series = config
+            .series
+            .with_default_policy(ValueCollisionPolicy::default());
+        StatsdTimeSeries {
+            name: config.name,
+            series,
+            custom_collision_policy,
+            listen: config.listen,
+            metric: config.metric,
+            tags: config.tags,
+            color: config.color,
+            alpha: config.alpha,
+        }
+    }
+}
+
+impl StatsdTimeSeries {
+    /// `listen_addr` returns the UDP address to listen on
+    pub fn listen_addr(&self) -> &str {
+        if self.listen.is_empty() {
+            STATSD_DEFAULT_LISTEN
+        } else {
+            &self.listen
+        }
+    }
+
+    /// `matches` returns true when `line` is for this series
+    pub fn matches(&self, line: &StatsdLine) -> bool {
+        line.name == self.metric
+            && self
+                .tags
+                .iter()
+                .all(|(tag, value)| line.tags.get(tag) == Some(value))
+    }
+
+    /// `load_line` pushes `line` received at `epoch`. Counters are scaled by
+    /// their sample rate and added to the slot, gauges and timers overwrite
+    /// it, unless the series sets its own collision policy. Returns true when
+    /// the line is for this series.
+    pub fn load_line(&mut self, epoch: u64, line: &StatsdLine) -> bool {
+        if !self.matches(line) {
+            return false;
+        }
+        let (value, collision_policy) = match line.metric_type {
+            StatsdMetricType::Counter => (
+                line.value / line.sample_rate,
+                ValueCollisionPolicy::Increment,
+            ),
+            StatsdMetricType::Gauge if line.is_delta => (
+                self.series.get_last_filled() + line.value,
+                ValueCollisionPolicy::Overwrite,
+            ),
+            StatsdMetricType::Gauge | StatsdMetricType::Timer => {
+                (line.value, ValueCollisionPolicy::Overwrite)
+            }
+        };
+        if !self.custom_collision_policy {
+            self.series.collision_policy = collision_policy;
+        }
+        self.series.push((epoch, value));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_statsd_lines() {
+        let line = parse_line("page.views:1|c").unwrap();
+        assert_eq!(line.name, "page.views");
+        assert_eq!(line.value, 1.);
+        assert_eq!(line.metric_type, StatsdMetricType::Counter);
+        assert_eq!(line.sample_rate, 1.);
+        assert!(line.tags.is_empty());
+        let line = parse_line("requests:3|c|@0.1|#env:prod,region:eu,canary").unwrap();
+        assert_eq!(line.sample_rate, 0.1);
+        assert_eq!(line.tags["env"], "prod");
+        assert_eq!(line.tags["region"], "eu");
+        assert_eq!(line.tags["canary"], "");
+        let line = parse_line("cpu,host=a,core=0:42.5|g").unwrap();
+        assert_eq!(line.name, "cpu");
+        assert_eq!(line.tags["host"], "a");
+        assert_eq!(line.value, 42.5);
+        assert!(!line.is_delta);
+        let line = parse_line("queue:-4|g").unwrap();
+        assert!(line.is_delta);
+        assert_eq!(line.value, -4.);
+        let line = parse_line("latency:320|ms").unwrap();
+        assert_eq!(line.metric_type, StatsdMetricType::Timer);
+        assert!(parse_line("latency|ms").is_err());
+        assert!(parse_line(":1|c").is_err());
+        assert!(parse_line("users:42|s").is_err());
+        assert!(parse_line("users:x|c").is_err());
+        assert!(parse_line("users:1").is_err());
+        assert!(parse_line("users:1|c|@2").is_err());
+        assert_eq!(
+            parse_packet("a:1|c\n\nb:2|g\ninvalid\n").len(),
+            2,
+            "invalid lines are skipped"
+        );
+    }
+
+    #[test]
+    fn it_loads_statsd_lines() {
+        let mut test: StatsdTimeSeries = serde_yaml::from_str(
+            "
+            name: requests
+            metric: requests
+            tags:
+              env: prod
+            ",
+        )
+        .unwrap();
+        assert_eq!(test.listen_addr(), "127.0.0.1:8125");
+        let lines = parse_packet(
+            "requests:1|c|#env:prod\n\
+             requests:2|c|@0.5|#env:prod\n\
+             requests:100|c|#env:dev\n\
+             other:1|c|#env:prod",
+        );
+        let loaded: Vec<bool> = lines.iter().map(|line| test.load_line(100, line)).collect();
+        assert_eq!(loaded, vec![true, true, false, false]);
+        assert_eq!(test.series.as_vec(), vec![(100, Some(5.))]);
+        // Gauges overwrite the slot, or change it with a sign
+        let mut test = StatsdTimeSeries {
+            metric: String::from("queue"),
+            ..StatsdTimeSeries::default()
+        };
+        for line in parse_packet("queue:10|g\nqueue:7|g\nqueue:+3|g") {
+            test.load_line(100, &line);
+        }
+        assert_eq!(test.series.as_vec(), vec![(100, Some(10.))]);
+        test.load_line(101, &parse_line("queue:-4|g").unwrap());
+        assert_eq!(test.series.get_last_filled(), 6.);
+    }
+
+    #[test]
+    fn it_keeps_the_configured_collision_policy() {
+        let mut test: StatsdTimeSeries = serde_yaml::from_str(
+            "
+            name: queue
+            metric: queue
+            series:
+              metrics_capacity: 10
+              collision_policy: Max
+            ",
+        )
+        .unwrap();
+        assert!(test.custom_collision_policy);
+        assert_eq!(test.series.metrics_capacity, 10);
+        for line in parse_packet("queue:10|g\nqueue:7|g\nqueue:3|c") {
+            test.load_line(100, &line);
+        }
+        assert_eq!(test.series.collision_policy, ValueCollisionPolicy::Max);
+        assert_eq!(test.series.as_vec(), vec![(100, Some(10.))]);
+        // Setting the default policy is kept too
+        let mut test: StatsdTimeSeries = serde_yaml::from_str(
+            "
+            metric: queue
+            series:
+              collision_policy: Increment
+            ",
+        )
+        .unwrap();
+        for line in parse_packet("queue:10|g\nqueue:7|g") {
+            test.load_line(100, &line);
+        }
+        assert_eq!(test.series.as_vec(), vec![(100, Some(17.))]);
+        let test: StatsdTimeSeries = serde_yaml::from_str("metric: queue").unwrap();
+        assert!(!test.custom_collision_policy);
+    }
+}
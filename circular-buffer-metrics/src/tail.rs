@@ -0,0 +1,189 @@
+//! Charts the `epoch value` lines appended to a file, the file is read from
+//! where the previous read stopped every `refresh` seconds.
+use crate::prometheus::RetryConfig;
+use crate::SourceError;
+use log::*;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// `TAIL_INITIAL_BYTES` is how much of the end of the file is read the first
+/// time, so that a large file does not stall the first load
+pub const TAIL_INITIAL_BYTES: u64 = 1 << 20;
+
+/// `TailTimeSeries` loads the lines appended to a file
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TailTimeSeries {
+    /// The name of the series
+    #[serde(default)]
+    pub name: String,
+
+    /// The TimeSeries that contains the data
+    #[serde(default = "crate::prometheus::default_series")]
+    #[serde(deserialize_with = "crate::prometheus::deserialize_series")]
+    pub series: crate::TimeSeries,
+
+    /// The file containing `epoch value` lines
+    #[serde(default)]
+    pub path: PathBuf,
+
+    /// The time in seconds between reads
+    #[serde(default)]
+    #[serde(rename = "refresh")]
+    pub pull_interval: usize,
+
+    /// The color of the TimeSeries
+    #[serde(default)]
+    pub color: Option<crate::color::Color>,
+
+    /// The transparency of the TimeSeries
+    #[serde(default)]
+    pub alpha: f32,
+
+    /// The retry settings
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// The outcome of the latest reads
+    #[serde(skip)]
+    pub health: crate::SourceHealth,
+}
+
+impl Default for TailTimeSeries {
+    fn default() -> TailTimeSeries {
+        TailTimeSeries {
+            name: String::from("Unset"),
+            series: crate::prometheus::default_series(),
+            path: PathBuf::new(),
+            pull_interval: 1,
+            color: None,
+            alpha: 1.0,
+            retry: RetryConfig::default(),
+            health: crate::SourceHealth::default(),
+        }
+    }
+}
+
+impl PartialEq<TailTimeSeries> for TailTimeSeries {
+    fn eq(&self, other: &TailTimeSeries) -> bool {
+        self.series == other.series
+            && self.path == other.path
+            && self.pull_interval == other.pull_interval
+    }
+}
+
+/// `parse_tail_line` parses an `epoch value` line, the epoch is in seconds
+pub fn parse_tail_line(line: &str) -> Result<(u64, f64), String> {
+    let mut fields = line.split_whitespace();
+    let (epoch, value) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(epoch), Some(value), None) => (epoch, value),
+        _ => return Err(format!("Expected 'epoch value' in '{}'", line)),
+    };
+    let epoch: f64 = epoch
+        .parse()
+        .map_err(|err| format!("Invalid epoch in '{}': {}", line, err))?;
+    let value: f64 = value
+        .parse()
+        .map_err(|err| format!("Invalid value in '{}': {}", line, err))?;
+    Ok((epoch as u64, value))
+}
+
+/// `read_appended` reads the complete lines of `path` after `offset`, the
+/// first read (no offset) starts at most TAIL_INITIAL_BYTES from the end.
+/// The file is read from the start again if it is shorter than `offset`.
+/// Returns the samples and the offset of the next read. It blocks the thread.
+pub fn read_appended(
+    path: &Path,
+    offset: Option<u64>,
+) -> Result<(Vec<(u64, f64)>, u64), SourceError> {
+    let mut file = File::open(path)
+        .map_err(|err| SourceError::Read(format!("Unable to open {:?}: {}", path, err)))?;
+    let len = file
+        .metadata()
+        .map_err(|err| SourceError::Read(format!("Unable to stat {:?}: {}", path, err)))?
+        .len();
+    let (start, skip_partial_line) = match offset {
+        Some(offset) if offset <= len => (offset, false),
+        Some(_) => {
+            info!("read_appended: {:?} was truncated, reading it again", path);
+            (0, false)
+        }
+        None if len > TAIL_INITIAL_BYTES => (len - TAIL_INITIAL_BYTES, true),
+        None => (0, false),
+    };
+    let mut contents = vec![];
+    file.seek(SeekFrom::Start(start))
+        .and_then(|_| file.take(len - start).read_to_end(&mut contents))
+        .map_err(|err| SourceError::Read(format!("Unable to read {:?}: {}", path, err)))?;
+    // The last line may still be being written
+    let complete = match contents.iter().rposition(|byte| *byte == b'\n') {
+        Some(idx) => idx + 1,
+        None => return Ok((vec![], start)),
+    };
+    let text = String::from_utf8_lossy(&contents[..complete]);
+    let mut lines = text.lines();
+    if skip_partial_line {
+        lines.next();
+    }
+    let samples = lines
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match parse_tail_line(line) {
+            Ok(sample) => Some(sample),
+            Err(err) => {
+                warn!("read_appended: {:?}: {}", path, err);
+                None
+            }
+        })
+        .collect();
+    Ok((samples, start + complete as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    fn it_parses_tail_lines() {
+        assert_eq!(parse_tail_line("1556000000 42"), Ok((1_556_000_000, 42.)));
+        assert_eq!(
+            parse_tail_line("  1556000000.25\t-0.5 "),
+            Ok((1_556_000_000, -0.5))
+        );
+        assert!(parse_tail_line("42").is_err());
+        assert!(parse_tail_line("1556000000 42 extra").is_err());
+        assert!(parse_tail_line("yesterday 42").is_err());
+        assert!(parse_tail_line("1556000000 many").is_err());
+    }
+
+    #[test]
+    fn it_reads_appended_lines() {
+        let path = std::env::temp_dir().join(format!("tail-test-{}.log", std::process::id()));
+        let append = |contents: &str| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap()
+                .write_all(contents.as_bytes())
+                .unwrap();
+        };
+        assert!(read_appended(&path, None).is_err());
+        append("# epoch value\n100 1\n101 2\ninvalid\n102 3");
+        let (samples, offset) = read_appended(&path, None).unwrap();
+        assert_eq!(samples, vec![(100, 1.), (101, 2.)]);
+        // The incomplete line is read once complete
+        append("\n103 4\n");
+        let (samples, offset) = read_appended(&path, Some(offset)).unwrap();
+        assert_eq!(samples, vec![(102, 3.), (103, 4.)]);
+        assert_eq!(read_appended(&path, Some(offset)).unwrap().0, vec![]);
+        // Truncated
+        std::fs::write(&path, "200 9\n").unwrap();
+        let (samples, offset) = read_appended(&path, Some(offset)).unwrap();
+        assert_eq!(samples, vec![(200, 9.)]);
+        assert_eq!(offset, 6);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
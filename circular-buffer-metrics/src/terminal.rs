@@ -0,0 +1,260 @@
+//! Draws the charts with Unicode braille or block characters and 24-bit ANSI
+//! colors, so that they can be displayed in any terminal.
+//! The display of the SizeInfo is divided in cells of `cell_width` by
+//! `cell_height` pixels, the `offset`, `width` and `height` of the charts
+//! are thus placed on the terminal grid. Each cell holds 2x4 braille dots or
+//! 1x2 half blocks.
+use crate::color::Rgb;
+use crate::render::render_canvas;
+use crate::{SizeInfo, TimeSeriesChart};
+
+/// `DEFAULT_CELL_WIDTH` is the width in pixels of a cell when the terminal
+/// does not tell
+pub const DEFAULT_CELL_WIDTH: f32 = 8.;
+
+/// `DEFAULT_CELL_HEIGHT` is the height in pixels of a cell when the terminal
+/// does not tell
+pub const DEFAULT_CELL_HEIGHT: f32 = 16.;
+
+/// The first braille character, without dots
+const BRAILLE_BLANK: u32 = 0x2800;
+
+/// The bit of each braille dot, by row and column
+const BRAILLE_DOTS: [[u8; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+/// `Glyphs` are the characters used to draw the lines
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Glyphs {
+    /// 2x4 dots per cell
+    #[default]
+    Braille,
+    /// 1x2 half blocks per cell, for fonts without braille
+    Block,
+}
+
+impl Glyphs {
+    /// `dots` returns the number of dots per cell, horizontally and vertically
+    pub fn dots(self) -> (u32, u32) {
+        match self {
+            Glyphs::Braille => (2, 4),
+            Glyphs::Block => (1, 2),
+        }
+    }
+
+    /// `glyph` returns the character with the dots of `lit`, indexed by row
+    /// and column, set
+    fn glyph(self, lit: &[[bool; 2]; 4]) -> char {
+        match self {
+            Glyphs::Braille => {
+                let mut bits = 0u32;
+                for (row, dots) in lit.iter().zip(BRAILLE_DOTS.iter()) {
+                    for (is_lit, dot) in row.iter().zip(dots.iter()) {
+                        if *is_lit {
+                            bits |= u32::from(*dot);
+                        }
+                    }
+                }
+                std::char::from_u32(BRAILLE_BLANK + bits).unwrap_or(' ')
+            }
+            Glyphs::Block => match (lit[0][0], lit[1][0]) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            },
+        }
+    }
+}
+
+/// `Cell` is a character of the terminal grid, the color is None for blank
+/// cells
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Cell {
+    pub glyph: char,
+    pub color: Option<Rgb>,
+}
+
+impl Default for Cell {
+    fn default() -> Cell {
+        Cell {
+            glyph: ' ',
+            color: None,
+        }
+    }
+}
+
+/// `grid_size` returns the columns and rows of the display of `size`
+pub fn grid_size(size: SizeInfo) -> Result<(u32, u32), String> {
+    if size.cell_width <= 0. || size.cell_height <= 0. {
+        return Err(format!(
+            "Invalid cell size {}x{}",
+            size.cell_width, size.cell_height
+        ));
+    }
+    Ok((
+        (size.width / size.cell_width).round().max(0.) as u32,
+        (size.height / size.cell_height).round().max(0.) as u32,
+    ))
+}
+
+/// `terminal_size_info` returns the SizeInfo of a terminal of `columns` and
+/// `rows` cells of `cell_width` by `cell_height` pixels
+pub fn terminal_size_info(columns: u32, rows: u32, cell_width: f32, cell_height: f32) -> SizeInfo {
+    SizeInfo {
+        width: columns as f32 * cell_width,
+        height: rows as f32 * cell_height,
+        cell_width,
+        cell_height,
+        ..SizeInfo::default()
+    }
+}
+
+/// `render_cells` draws `charts` on the terminal grid, the opengl vecs of the
+/// charts must be up to date for `size`. The color of a cell is the average
+/// color of its dots, translucent series are darkened as if drawn over black.
+pub fn render_cells(
+    charts: &[TimeSeriesChart],
+    size: SizeInfo,
+    glyphs: Glyphs,
+) -> Result<Vec<Vec<Cell>>, String> {
+    let (columns, rows) = grid_size(size)?;
+    let (dots_x, dots_y) = glyphs.dots();
+    let dots_size = SizeInfo {
+        width: (columns * dots_x) as f32,
+        height: (rows * dots_y) as f32,
+        ..size
+    };
+    let canvas = render_canvas(charts, dots_size, None);
+    let mut grid = vec![vec![Cell::default(); columns as usize]; rows as usize];
+    for (row, cells) in grid.iter_mut().enumerate() {
+        for (column, cell) in cells.iter_mut().enumerate() {
+            let mut lit = [[false; 2]; 4];
+            let mut color_sum = [0f32; 3];
+            let mut count = 0f32;
+            for dot_y in 0..dots_y {
+                for dot_x in 0..dots_x {
+                    let [r, g, b, a] =
+                        canvas.pixel(column as u32 * dots_x + dot_x, row as u32 * dots_y + dot_y);
+                    if a == 0 {
+                        continue;
+                    }
+                    lit[dot_y as usize][dot_x as usize] = true;
+                    let alpha = f32::from(a) / 255.;
+                    for (sum, channel) in color_sum.iter_mut().zip([r, g, b].iter()) {
+                        *sum += f32::from(*channel) * alpha;
+                    }
+                    count += 1.;
+                }
+            }
+            if count > 0. {
+                *cell = Cell {
+                    glyph: glyphs.glyph(&lit),
+                    color: Some(Rgb::new(
+                        (color_sum[0] / count).round() as u8,
+                        (color_sum[1] / count).round() as u8,
+                        (color_sum[2] / count).round() as u8,
+                    )),
+                };
+            }
+        }
+    }
+    Ok(grid)
+}
+
+/// `render_ansi` draws `charts` as lines of text with 24-bit foreground
+/// colors, the lines are separated by "\r\n" and the last one is not
+/// terminated so that the terminal does not scroll.
+pub fn render_ansi(
+    charts: &[TimeSeriesChart],
+    size: SizeInfo,
+    glyphs: Glyphs,
+) -> Result<String, String> {
+    let grid = render_cells(charts, size, glyphs)?;
+    let mut lines = Vec::with_capacity(grid.len());
+    for cells in grid {
+        let mut line = String::new();
+        let mut current_color = None;
+        for cell in cells {
+            if let Some(color) = cell.color {
+                if current_color != cell.color {
+                    line.push_str(&format!("\x1b[38;2;{};{};{}m", color.r, color.g, color.b));
+                    current_color = cell.color;
+                }
+            }
+            line.push(cell.glyph);
+        }
+        if current_color.is_some() {
+            line.push_str("\x1b[0m");
+        }
+        lines.push(line);
+    }
+    Ok(lines.join("\r\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::render::update_charts;
+    use crate::{ManualTimeSeries, TimeSeriesSource, Value2D};
+
+    fn test_chart() -> TimeSeriesChart {
+        let mut series = ManualTimeSeries {
+            name: String::from("ramp"),
+            color: Some(Color::from(Rgb::new(0xff, 0x80, 0))),
+            ..ManualTimeSeries::default()
+        };
+        series.series.metrics_capacity = 8;
+        for epoch in 0..8 {
+            series
+                .series
+                .circular_push((epoch, Some(epoch as f64 + 1.)));
+        }
+        TimeSeriesChart {
+            name: String::from("ramp"),
+            sources: vec![TimeSeriesSource::AlacrittyInput(series)],
+            // One cell from the left, 4 cells wide and 2 cells high
+            offset: Value2D { x: 10., y: 0. },
+            width: 40.,
+            height: 40.,
+            ..TimeSeriesChart::default()
+        }
+    }
+
+    #[test]
+    fn it_draws_glyphs() {
+        let mut lit = [[false; 2]; 4];
+        assert_eq!(Glyphs::Braille.glyph(&lit), '⠀');
+        lit[0][0] = true;
+        assert_eq!(Glyphs::Braille.glyph(&lit), '⠁');
+        assert_eq!(Glyphs::Block.glyph(&lit), '▀');
+        lit[3][1] = true;
+        assert_eq!(Glyphs::Braille.glyph(&lit), '⢁');
+        lit[1][0] = true;
+        assert_eq!(Glyphs::Block.glyph(&lit), '█');
+        assert!(grid_size(SizeInfo::default()).is_err());
+    }
+
+    #[test]
+    fn it_renders_charts_in_cells() {
+        let size = terminal_size_info(6, 3, 10., 20.);
+        let mut charts = vec![test_chart()];
+        update_charts(&mut charts, size);
+        let grid = render_cells(&charts, size, Glyphs::Braille).unwrap();
+        let text: Vec<String> = grid
+            .iter()
+            .map(|cells| cells.iter().map(|cell| cell.glyph).collect())
+            .collect();
+        assert_eq!(text, vec!["      ", "   ⡠⠊ ", " ⡠⠊   "]);
+        assert_eq!(grid[2][1].color, Some(Rgb::new(255, 128, 0)));
+        assert_eq!(grid[0][0].color, None);
+        let blocks = render_cells(&charts, size, Glyphs::Block).unwrap();
+        let text: String = blocks[2].iter().map(|cell| cell.glyph).collect();
+        assert_eq!(text, " ▄▄▀  ");
+        let ansi = render_ansi(&charts, size, Glyphs::Braille).unwrap();
+        assert_eq!(
+            ansi,
+            "      \r\n   \x1b[38;2;255;128;0m⡠⠊ \x1b[0m\r\n \x1b[38;2;255;128;0m⡠⠊   \x1b[0m"
+        );
+    }
+}
@@ -0,0 +1,624 @@
+//! Drives the coordinator and the Prometheus polls end-to-end against the
+//! mock Prometheus server.
+use circular_buffer_metrics::coordinator::{
+    async_coordinator, epoch_now, spawn_line_listeners, spawn_prometheus_polls,
+    spawn_statsd_listeners, AsyncChartTask,
+};
+use circular_buffer_metrics::export::{spawn_export_server, ExportConfig};
+use circular_buffer_metrics::mock_prometheus::{MockPrometheus, MockResponse, MockSeries};
+use circular_buffer_metrics::scrape::{parse_exposition, MetricFamily};
+use circular_buffer_metrics::TimeSeriesChart;
+use futures::future::lazy;
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Sink, Stream};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::prelude::FutureExt;
+
+fn init_log() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+/// `get_chart` asks the coordinator for a copy of the chart at `chart_index`
+fn get_chart(
+    runtime: &mut tokio::runtime::Runtime,
+    tx: &mpsc::Sender<AsyncChartTask>,
+    chart_index: usize,
+) -> TimeSeriesChart {
+    let (chart_tx, chart_rx) = oneshot::channel();
+    runtime
+        .block_on(
+            tx.clone()
+                .send(AsyncChartTask::GetChart(chart_index, chart_tx)),
+        )
+        .unwrap();
+    runtime.block_on(chart_rx).unwrap().unwrap()
+}
+
+/// `WAIT_TIMEOUT` bounds how long the tests wait for the polls and listeners
+const WAIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// `wait_for` calls `get` until its result is `ready` or `WAIT_TIMEOUT`
+/// elapses, the last result is returned either way so that the assertions
+/// report what is missing
+fn wait_for<T>(mut get: impl FnMut() -> T, ready: impl Fn(&T) -> bool) -> T {
+    let deadline = Instant::now() + WAIT_TIMEOUT;
+    loop {
+        let res = get();
+        if ready(&res) || Instant::now() >= deadline {
+            return res;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// `charts` returns the charts polling the mock server at `base_url`
+fn charts(base_url: &str) -> Vec<TimeSeriesChart> {
+    serde_yaml::from_str(&format!(
+        "
+        - name: load
+          series:
+          - name: load 1
+            type: prometheus
+            refresh: 1
+            query:
+              base_url: '{base_url}'
+              expr: 'node_load1{{job=\"node\"}} + 0'
+            series:
+              metrics_capacity: 30
+          - name: cpu
+            type: prometheus
+            refresh: 1
+            split_by_labels: true
+            label_template: 'cpu {{cpu}}'
+            source: '{base_url}/api/v1/query_range?query=node_cpu'
+            series:
+              metrics_capacity: 30
+          - name: broken
+            type: prometheus
+            refresh: 1
+            source: '{base_url}/api/v1/query_range?query=broken'
+            retry:
+              initial_backoff: 1
+              stale_after: 1
+        - name: scraped
+          series:
+          - name: requests
+            type: scrape
+            refresh: 1
+            source: '{base_url}/metrics'
+            metric: http_requests_total
+            labels:
+              code: '200'
+          - name: missing
+            type: scrape
+            refresh: 1
+            source: '{base_url}/metrics'
+            metric: missing_total
+        - name: filtered
+          series:
+          - name: other job
+            type: prometheus
+            refresh: 1
+            source: '{base_url}/api/v1/query_range?query=filtered'
+            labels:
+              job: other
+            series:
+              metrics_capacity: 30
+        ",
+        base_url = base_url
+    ))
+    .unwrap()
+}
+
+#[test]
+fn it_polls_prometheus_end_to_end() {
+    init_log();
+    let mock = MockPrometheus::start().unwrap();
+    mock.set_response(
+        "node_load1{job=\"node\"} + 0",
+        MockResponse::Series(vec![MockSeries::new(&[("job", "node")], |epoch| {
+            (epoch % 100) as f64
+        })]),
+    );
+    mock.set_response(
+        "node_cpu",
+        MockResponse::Series(vec![
+            MockSeries::new(&[("cpu", "0")], |_| 10.),
+            MockSeries::new(&[("cpu", "1")], |_| 20.),
+        ]),
+    );
+    mock.set_response(
+        "broken",
+        MockResponse::error(503, "unavailable", "too many queries"),
+    );
+    mock.set_response(
+        "filtered",
+        MockResponse::Series(vec![MockSeries::new(&[("job", "node")], |_| 1.)]),
+    );
+    mock.set_metrics(
+        "# TYPE http_requests_total counter\n\
+         http_requests_total{code=\"200\",method=\"get\"} 7\n\
+         http_requests_total{code=\"200\",method=\"post\"} 3\n\
+         http_requests_total{code=\"500\",method=\"get\"} 1\n",
+    );
+    let charts = charts(&mock.base_url());
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (tx, rx) = mpsc::channel(1_024usize);
+    let poll_tx = tx.clone();
+    runtime.spawn(lazy(move || {
+        async_coordinator(rx, charts.clone())
+            .join(lazy(move || {
+                spawn_prometheus_polls(&charts, &poll_tx);
+                Ok(())
+            }))
+            .map(|_| ())
+    }));
+    // Wait for the incremental polls of the range queries and the scrapes
+    let count_requests = |query: &str| {
+        mock.requests()
+            .iter()
+            .filter(|request| request.params.get("query").map(String::as_str) == Some(query))
+            .count()
+    };
+    wait_for(
+        || {
+            (
+                count_requests("node_load1{job=\"node\"} + 0"),
+                count_requests("filtered"),
+                mock.requests()
+                    .iter()
+                    .filter(|request| request.path == "/metrics")
+                    .count(),
+            )
+        },
+        |(load, filtered, scrapes)| *load >= 2 && *filtered >= 2 && *scrapes >= 4,
+    );
+    let chart = wait_for(
+        || get_chart(&mut runtime, &tx, 0),
+        |chart| {
+            chart.sources.len() == 5
+                && chart.sources[0].series().active_items >= 30
+                && chart.sources[2]
+                    .health()
+                    .is_some_and(|health| health.consecutive_failures >= 1)
+        },
+    );
+    let names: Vec<String> = chart.sources.iter().map(|source| source.name()).collect();
+    assert_eq!(names, vec!["load 1", "cpu", "broken", "cpu 0", "cpu 1"]);
+
+    // The values are the ones generated by the mock server
+    let load = chart.sources[0].series();
+    assert!(load.active_items >= 30);
+    for (epoch, value) in load.iter() {
+        assert_eq!(*value, Some((epoch % 100) as f64));
+    }
+    assert_eq!(chart.sources[3].series().get_last_filled(), 10.);
+    assert_eq!(chart.sources[4].series().get_last_filled(), 20.);
+    assert!(chart.opengl_vecs.len() >= 3);
+
+    // The health of each series
+    let now = epoch_now();
+    let health = chart.sources[0].health().unwrap();
+    assert!(health.last_success.is_some());
+    assert_eq!(health.consecutive_failures, 0);
+    assert!(!chart.sources[0].is_stale(now));
+    assert!(chart.sources[3].health().unwrap().last_success.is_some());
+    let health = chart.sources[2].health().unwrap();
+    assert!(health.consecutive_failures >= 1);
+    assert_eq!(health.last_success, None);
+    assert_eq!(
+        health.last_error,
+        Some(String::from("Prometheus unavailable: too many queries"))
+    );
+    assert!(chart.sources[2].is_stale(now));
+
+    // The PromQL reaches the server untouched and only the first request
+    // asks for the whole time range
+    let load_requests: Vec<_> = mock
+        .requests()
+        .into_iter()
+        .filter(|request| {
+            request.params.get("query").map(String::as_str) == Some("node_load1{job=\"node\"} + 0")
+        })
+        .collect();
+    assert!(load_requests.len() >= 2);
+    let param =
+        |idx: usize, name: &str| -> u64 { load_requests[idx].params[name].parse().unwrap() };
+    assert_eq!(load_requests[0].path, "/api/v1/query_range");
+    assert_eq!(param(0, "end") - param(0, "start"), 30);
+    assert_eq!(param(0, "step"), 1);
+    assert!(param(1, "start") >= param(0, "end") - 2);
+    assert!(param(1, "end") - param(1, "start") < 30);
+
+    // Nothing matches the labels so every request asks for the whole range
+    let filtered_requests: Vec<_> = mock
+        .requests()
+        .into_iter()
+        .filter(|request| request.params.get("query").map(String::as_str) == Some("filtered"))
+        .collect();
+    assert!(filtered_requests.len() >= 2);
+    for request in &filtered_requests {
+        let param = |name: &str| -> u64 { request.params[name].parse().unwrap() };
+        assert_eq!(param("end") - param("start"), 30);
+    }
+    let chart = get_chart(&mut runtime, &tx, 2);
+    assert_eq!(chart.sources[0].series().active_items, 0);
+
+    // The metrics endpoint is scraped directly
+    let chart = wait_for(
+        || get_chart(&mut runtime, &tx, 1),
+        |chart| {
+            chart.sources[1]
+                .health()
+                .is_some_and(|health| health.consecutive_failures >= 1)
+        },
+    );
+    assert_eq!(chart.sources[0].series().get_last_filled(), 10.);
+    assert!(chart.sources[0].health().unwrap().last_success.is_some());
+    let health = chart.sources[1].health().unwrap();
+    assert_eq!(health.last_success, None);
+    assert!(health.consecutive_failures >= 1);
+    let scrapes: Vec<_> = mock
+        .requests()
+        .into_iter()
+        .filter(|request| request.path == "/metrics")
+        .collect();
+    assert!(scrapes.len() >= 4);
+    assert!(scrapes[0].headers["accept"].starts_with("application/openmetrics-text"));
+    runtime.shutdown_now().wait().unwrap();
+}
+
+/// `http_get` sends a GET request for `path` and returns the raw response
+fn http_get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, addr
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+/// `exposed_value` returns the value of the sample `name` of `series`
+fn exposed_value(families: &[MetricFamily], name: &str, series: &str) -> Option<f64> {
+    families
+        .iter()
+        .flat_map(|family| family.samples.iter())
+        .find(|sample| sample.name == name && sample.labels["series"] == series)
+        .map(|sample| sample.value)
+}
+
+#[test]
+fn it_exports_metrics() {
+    init_log();
+    let mock = MockPrometheus::start().unwrap();
+    mock.set_response("node_load1{job=\"node\"} + 0", MockResponse::constant(5.));
+    mock.set_response(
+        "broken",
+        MockResponse::error(400, "bad_data", "parse error"),
+    );
+    let charts = charts(&mock.base_url());
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (tx, rx) = mpsc::channel(1_024usize);
+    let poll_tx = tx.clone();
+    let config = ExportConfig {
+        listen: String::from("127.0.0.1:0"),
+        ..ExportConfig::default()
+    };
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    runtime.spawn(lazy(move || {
+        let (addr, server) = spawn_export_server(&config, poll_tx.clone()).unwrap();
+        addr_tx.send(addr).unwrap();
+        tokio::spawn(server);
+        async_coordinator(rx, charts.clone())
+            .join(lazy(move || {
+                spawn_prometheus_polls(&charts, &poll_tx);
+                Ok(())
+            }))
+            .map(|_| ())
+    }));
+    let addr = addr_rx.recv().unwrap();
+    let families = wait_for(
+        || {
+            let response = http_get(addr, "/metrics");
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+            let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+            parse_exposition(body).unwrap()
+        },
+        |families| {
+            let value = |name: &str, series: &str| exposed_value(families, name, series);
+            value("circular_buffer_metrics_records_loaded_total", "load 1") >= Some(30.)
+                && value("circular_buffer_metrics_poll_failures_total", "broken") >= Some(1.)
+                && value("circular_buffer_metrics_series_last_value", "load 1").is_some()
+        },
+    );
+    let value = |name: &str, series: &str| exposed_value(&families, name, series);
+    assert!(value("circular_buffer_metrics_poll_successes_total", "load 1").unwrap() >= 1.);
+    assert!(value("circular_buffer_metrics_records_loaded_total", "load 1").unwrap() >= 30.);
+    assert!(value("circular_buffer_metrics_poll_duration_seconds", "load 1").is_some());
+    assert!(value("circular_buffer_metrics_poll_failures_total", "broken").unwrap() >= 1.);
+    assert_eq!(
+        value("circular_buffer_metrics_series_stale", "broken"),
+        Some(1.)
+    );
+    assert_eq!(
+        value("circular_buffer_metrics_series_last_value", "load 1"),
+        Some(5.)
+    );
+    assert!(http_get(addr, "/other").starts_with("HTTP/1.1 404 Not Found"));
+    runtime.shutdown_now().wait().unwrap();
+}
+
+/// `sum_values` adds up the values of the series at `series_index`
+fn sum_values(chart: &TimeSeriesChart, series_index: usize) -> f64 {
+    chart.sources[series_index]
+        .series()
+        .iter()
+        .filter_map(|(_, value)| *value)
+        .sum()
+}
+
+#[test]
+fn it_listens_for_statsd() {
+    init_log();
+    let charts: Vec<TimeSeriesChart> = serde_yaml::from_str(
+        "
+        - name: app
+          series:
+          - name: requests
+            type: statsd
+            listen: '127.0.0.1:0'
+            metric: requests
+            tags:
+              env: prod
+          - name: queue
+            type: statsd
+            listen: '127.0.0.1:0'
+            metric: queue
+          - name: loaded
+            type: async_items_loaded
+        ",
+    )
+    .unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (tx, rx) = mpsc::channel(1_024usize);
+    let listen_tx = tx.clone();
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    runtime.spawn(lazy(move || {
+        addr_tx
+            .send(spawn_statsd_listeners(&charts, &listen_tx))
+            .unwrap();
+        async_coordinator(rx, charts)
+    }));
+    let bound = addr_rx.recv().unwrap();
+    assert_eq!(bound.len(), 1);
+    let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    for packet in &[
+        "requests:1|c|#env:prod\nqueue:12|g",
+        "requests:1|c|@0.5|#env:prod\nrequests:7|c|#env:dev",
+        "invalid\nqueue:-2|g",
+    ] {
+        client.send_to(packet.as_bytes(), bound[0].1).unwrap();
+    }
+    let chart = wait_for(
+        || get_chart(&mut runtime, &tx, 0),
+        |chart| sum_values(chart, 2) >= 4.,
+    );
+    let requests = chart.sources[0].series();
+    let total: f64 = requests.iter().filter_map(|(_, value)| *value).sum();
+    assert_eq!(total, 3.);
+    assert_eq!(chart.sources[1].series().get_last_filled(), 10.);
+    // The loaded item counter adds up the lines loaded into the series
+    assert_eq!(sum_values(&chart, 2), 4.);
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn it_polls_commands_and_files() {
+    init_log();
+    let path = std::env::temp_dir().join(format!("coordinator-tail-{}.log", std::process::id()));
+    let now = epoch_now();
+    std::fs::write(&path, format!("{} 1\n{} 2\n", now - 2, now - 1)).unwrap();
+    let charts: Vec<TimeSeriesChart> = serde_yaml::from_str(&format!(
+        "
+        - name: local
+          series:
+          - name: disk
+            type: command
+            refresh: 1
+            command: 'echo \"/dev/sda1 used 42%\"'
+            regex: '(\\d+)%'
+          - name: failing
+            type: command
+            refresh: 1
+            command: 'echo nothing here'
+          - name: log
+            type: tail
+            refresh: 1
+            path: '{}'
+        ",
+        path.display()
+    ))
+    .unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (tx, rx) = mpsc::channel(1_024usize);
+    let poll_tx = tx.clone();
+    runtime.spawn(lazy(move || {
+        async_coordinator(rx, charts.clone())
+            .join(lazy(move || {
+                spawn_prometheus_polls(&charts, &poll_tx);
+                Ok(())
+            }))
+            .map(|_| ())
+    }));
+    let log_records = |chart: &TimeSeriesChart| {
+        chart.sources[2]
+            .health()
+            .map_or(0, |health| health.records_loaded)
+    };
+    wait_for(
+        || get_chart(&mut runtime, &tx, 0),
+        |chart| log_records(chart) >= 2,
+    );
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    writeln!(file, "{} 3", now).unwrap();
+    let chart = wait_for(
+        || get_chart(&mut runtime, &tx, 0),
+        |chart| {
+            log_records(chart) >= 3
+                && chart.sources[0].series().active_items > 0
+                && chart.sources[1]
+                    .health()
+                    .is_some_and(|health| health.last_error.is_some())
+        },
+    );
+    assert_eq!(chart.sources[0].series().get_last_filled(), 42.);
+    assert!(chart.sources[0].health().unwrap().successes >= 1);
+    let health = chart.sources[1].health().unwrap();
+    assert_eq!(health.last_success, None);
+    assert_eq!(
+        health.last_error,
+        Some(String::from("Invalid output: No number in output"))
+    );
+    let log: Vec<(u64, f64)> = chart.sources[2]
+        .series()
+        .iter()
+        .filter_map(|(epoch, value)| value.map(|value| (*epoch, value)))
+        .collect();
+    assert_eq!(log, vec![(now - 2, 1.), (now - 1, 2.), (now, 3.)]);
+    assert_eq!(chart.sources[2].health().unwrap().records_loaded, 3);
+    std::fs::remove_file(&path).unwrap();
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn it_listens_for_influx_and_graphite_lines() {
+    init_log();
+    let charts: Vec<TimeSeriesChart> = serde_yaml::from_str(
+        "
+        - name: pushed
+          series:
+          - name: idle
+            type: influx
+            listen: 'udp://127.0.0.1:0'
+            measurement: cpu
+            field: usage_idle
+            labels:
+              host: a
+          - name: web load
+            type: graphite
+            listen: 'tcp://127.0.0.1:0'
+            path: 'servers.web*.load'
+            labels:
+              dc: eu
+            series:
+              collision_policy: Increment
+          - name: loaded
+            type: async_items_loaded
+        ",
+    )
+    .unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (tx, rx) = mpsc::channel(1_024usize);
+    let listen_tx = tx.clone();
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    runtime.spawn(lazy(move || {
+        addr_tx
+            .send(spawn_line_listeners(&charts, &listen_tx))
+            .unwrap();
+        async_coordinator(rx, charts)
+    }));
+    let bound = addr_rx.recv().unwrap();
+    let addr = |listen: &str| -> SocketAddr {
+        bound
+            .iter()
+            .find(|(other, _)| other == listen)
+            .map(|(_, addr)| *addr)
+            .unwrap()
+    };
+    let now = epoch_now();
+    let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .send_to(
+            format!(
+                "cpu,host=a usage_idle=87.5,usage_user=3 {}\ncpu,host=b usage_idle=10",
+                now * 1_000_000_000
+            )
+            .as_bytes(),
+            addr("udp://127.0.0.1:0"),
+        )
+        .unwrap();
+    let mut stream = TcpStream::connect(addr("tcp://127.0.0.1:0")).unwrap();
+    write!(
+        stream,
+        "servers.web1.load;dc=eu 1.5 {now}\nservers.web2.load;dc=eu 2 {now}\n\
+         servers.web3.load;dc=us 100 {now}\nservers.db1.load;dc=eu 100 {now}\n",
+        now = now
+    )
+    .unwrap();
+    drop(stream);
+    let chart = wait_for(
+        || get_chart(&mut runtime, &tx, 0),
+        |chart| sum_values(chart, 2) >= 3.,
+    );
+    assert_eq!(chart.sources[0].series().as_vec(), vec![(now, Some(87.5))]);
+    assert_eq!(chart.sources[1].series().as_vec(), vec![(now, Some(3.5))]);
+    assert_eq!(sum_values(&chart, 2), 3.);
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn it_notifies_subscribers() {
+    init_log();
+    let charts: Vec<TimeSeriesChart> = serde_yaml::from_str(
+        "
+        - name: local
+          series:
+          - name: log
+            type: tail
+            path: '/nonexistent'
+        ",
+    )
+    .unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (tx, rx) = mpsc::channel(1_024usize);
+    runtime.spawn(lazy(move || async_coordinator(rx, charts)));
+    let (notify_tx, notify_rx) = mpsc::channel(0);
+    runtime
+        .block_on(tx.clone().send(AsyncChartTask::Subscribe(notify_tx)))
+        .unwrap();
+    let now = epoch_now();
+    for value in 1..4 {
+        runtime
+            .block_on(tx.clone().send(AsyncChartTask::LoadSamples(
+                0,
+                0,
+                vec![(now, f64::from(value))],
+            )))
+            .unwrap();
+    }
+    let chart = get_chart(&mut runtime, &tx, 0);
+    assert_eq!(chart.sources[0].series().get_last_filled(), 3.);
+    // The loads are coalesced in the pending notification
+    let (notification, notify_rx) = runtime
+        .block_on(notify_rx.into_future().map_err(|_| ()))
+        .unwrap();
+    assert_eq!(notification, Some(()));
+    let pending = runtime.block_on(
+        notify_rx
+            .into_future()
+            .map_err(|_| ())
+            .timeout(Duration::from_millis(200)),
+    );
+    assert!(pending.is_err());
+    runtime.shutdown_now().wait().unwrap();
+}